@@ -54,6 +54,11 @@ impl Timestamp {
     pub fn as_duration(&self) -> std::time::Duration {
         std::time::Duration::from_nanos(self.0)
     }
+
+    /// returns the timestamp `duration` after this one
+    pub fn after(&self, duration: std::time::Duration) -> Self {
+        Self(self.0.saturating_add(duration.as_nanos() as u64))
+    }
 }
 
 impl Default for Timestamp {
@@ -270,6 +275,14 @@ impl Id {
 
         uuid.into()
     }
+
+    /// returns the creation time embedded in the id. only v7 ids (the ones generated by [Id::new]) carry a timestamp,
+    /// will return [None] for any other uuid version.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        let (secs, nanos) = Uuid::from_bytes_ref(&self.0).get_timestamp()?.to_unix();
+
+        Some(Timestamp(secs * 1_000_000_000 + (nanos as u64)))
+    }
 }
 
 impl From<Uuid> for Id {
@@ -441,6 +454,20 @@ mod tests {
         let converted_uuid: Uuid = id.into();
         assert_eq!(converted_uuid, uuid);
     }
+
+    #[test]
+    fn test_id_timestamp() {
+        let before = Timestamp::new();
+        let id = Id::new(&[0u8; UUID_MAX_SOURCE_LEN]);
+
+        // v7 ids are only millisecond precise
+        let timestamp = id.timestamp().unwrap();
+        assert!(timestamp.inner() / 1_000_000 >= before.inner() / 1_000_000);
+
+        // v4 ids does not carry any timestamp
+        let id = id!("97780ca3-a626-4fc5-b150-7fa8bc665df6");
+        assert!(id.timestamp().is_none());
+    }
 }
 
 #[derive(Encode, Debug, Decode, Clone, PartialEq, Eq, Serialize, PartialOrd, Ord, Default)]
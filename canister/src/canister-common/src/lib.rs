@@ -4,6 +4,7 @@ pub mod macros;
 pub mod common;
 pub mod random;
 pub mod id_generator;
+pub mod rate_limit;
 
 pub mod statistics ;
 #[cfg(feature = "test-utils")]
@@ -0,0 +1,309 @@
+//! token bucket rate limiter keyed by caller and method name.
+//!
+//! every (caller, method) pair gets a bucket of `capacity` tokens, a call takes one token and a token
+//! is given back every `refill_interval_secs`. buckets are kept in stable memory so limits survive upgrades.
+//! use [RateLimiter::consume] in guard functions and [RateLimiter::check] in `inspect_message`,
+//! as state changes made during `inspect_message` are discarded anyway.
+use std::time::Duration;
+
+use candid::{ CandidType, Principal };
+use ic_stable_structures::{ memory_manager::MemoryId, BTreeMap };
+use parity_scale_codec::{ Decode, Encode };
+use serde::Deserialize;
+
+use crate::{
+    common::{ AsciiKeyError, AsciiRecordsKey, Get, PrincipalBytes, Timestamp },
+    impl_max_size,
+    impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{ Memory, Stable, ToStable },
+};
+
+// max amount of buckets removed in a single prune
+const MAX_PRUNE_PER_ROUND: usize = 500;
+
+#[derive(thiserror::Error, Debug)]
+pub enum RateLimitError {
+    #[error("rate limit exceeded for method {method}, try again in {retry_after_secs} seconds")]
+    Exceeded {
+        method: String,
+        retry_after_secs: u64,
+    },
+
+    #[error(transparent)]
+    InvalidMethod(#[from] AsciiKeyError),
+}
+
+pub type RateLimitResult<T = ()> = Result<T, RateLimitError>;
+
+/// limit for a single method, `capacity` calls in a burst and one more call every `refill_interval_secs`
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub refill_interval_secs: u64,
+}
+
+impl RateLimit {
+    pub const fn new(capacity: u32, refill_interval: Duration) -> Self {
+        Self {
+            capacity,
+            refill_interval_secs: refill_interval.as_secs(),
+        }
+    }
+
+    fn refill_interval(&self) -> Duration {
+        // zero interval would never refill, treat it as refilling every second
+        Duration::from_secs(self.refill_interval_secs.max(1))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MethodRateLimit {
+    pub method: String,
+    pub limit: RateLimit,
+}
+
+/// per method rate limits, meant to be stored in the canister config
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateLimits(Vec<MethodRateLimit>);
+
+impl RateLimits {
+    pub fn new(limits: impl IntoIterator<Item = (&'static str, RateLimit)>) -> Self {
+        Self(
+            limits
+                .into_iter()
+                .map(|(method, limit)| MethodRateLimit { method: method.to_string(), limit })
+                .collect()
+        )
+    }
+
+    pub fn get(&self, method: &str) -> Option<RateLimit> {
+        self.0
+            .iter()
+            .find(|l| l.method == method)
+            .map(|l| l.limit)
+    }
+
+    /// set the limit for a method, `None` removes the limit
+    pub fn set(&mut self, method: String, limit: Option<RateLimit>) {
+        self.0.retain(|l| l.method != method);
+
+        if let Some(limit) = limit {
+            self.0.push(MethodRateLimit { method, limit });
+        }
+    }
+
+    pub fn list(&self) -> &[MethodRateLimit] {
+        &self.0
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub struct RateLimitKey {
+    caller: PrincipalBytes,
+    method: AsciiRecordsKey,
+}
+
+impl_max_size!(for RateLimitKey: PrincipalBytes, AsciiRecordsKey);
+impl_mem_bound!(for RateLimitKey: bounded; fixed_size: true);
+
+impl RateLimitKey {
+    pub fn new(caller: Principal, method: &str) -> RateLimitResult<Self> {
+        Ok(Self {
+            caller: PrincipalBytes::from(caller),
+            method: AsciiRecordsKey::new(method)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct Bucket {
+    tokens: u32,
+    last_refill: Timestamp,
+    /// when the bucket is back to full capacity, at which point it's the same as not having a bucket at all
+    full_at: Timestamp,
+}
+
+impl_max_size!(for Bucket: u32, Timestamp, Timestamp);
+impl_mem_bound!(for Bucket: bounded; fixed_size: true);
+
+impl Bucket {
+    fn full(limit: &RateLimit, now: Timestamp) -> Self {
+        Self {
+            tokens: limit.capacity,
+            last_refill: now,
+            full_at: now,
+        }
+    }
+
+    /// add the tokens accumulated since the last refill
+    fn refill(&mut self, limit: &RateLimit, now: Timestamp) {
+        let interval = limit.refill_interval();
+        let elapsed = now.as_duration().saturating_sub(self.last_refill.as_duration());
+        let refills = (elapsed.as_nanos() / interval.as_nanos()) as u64;
+
+        self.tokens = self.tokens.saturating_add(refills.min(u32::MAX as u64) as u32).min(limit.capacity);
+
+        // keep the leftover so partial intervals are not lost
+        self.last_refill = match self.tokens == limit.capacity {
+            true => now,
+            false => self.last_refill.after(interval.saturating_mul(refills as u32)),
+        };
+    }
+
+    fn take(&mut self, limit: &RateLimit, method: &str, now: Timestamp) -> RateLimitResult {
+        self.refill(limit, now);
+
+        if self.tokens == 0 {
+            let next = self.last_refill.after(limit.refill_interval());
+
+            return Err(RateLimitError::Exceeded {
+                method: method.to_string(),
+                retry_after_secs: next.as_duration().saturating_sub(now.as_duration()).as_secs().max(1),
+            });
+        }
+
+        self.tokens -= 1;
+
+        let missing = limit.capacity - self.tokens;
+        self.full_at = self.last_refill.after(limit.refill_interval().saturating_mul(missing));
+
+        Ok(())
+    }
+}
+
+pub struct RateLimiter(BTreeMap<Stable<RateLimitKey>, Stable<Bucket>, Memory>);
+
+impl RateLimiter {
+    pub fn init<M: Get<MemoryId>>(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, M>(BTreeMap::init))
+    }
+
+    fn bucket(&self, key: &RateLimitKey, limit: &RateLimit, now: Timestamp) -> Bucket {
+        self.0
+            .get(&key.clone().to_stable())
+            .map(|b| b.into_inner())
+            .unwrap_or_else(|| Bucket::full(limit, now))
+    }
+
+    /// check whether the caller still has tokens left for the method, without taking one
+    pub fn check(
+        &self,
+        caller: Principal,
+        method: &str,
+        limit: &RateLimit,
+        now: Timestamp
+    ) -> RateLimitResult {
+        let key = RateLimitKey::new(caller, method)?;
+        self.bucket(&key, limit, now).take(limit, method, now)
+    }
+
+    /// take a token for the method, errors if the caller ran out of tokens
+    pub fn consume(
+        &mut self,
+        caller: Principal,
+        method: &str,
+        limit: &RateLimit,
+        now: Timestamp
+    ) -> RateLimitResult {
+        let key = RateLimitKey::new(caller, method)?;
+
+        let mut bucket = self.bucket(&key, limit, now);
+        bucket.take(limit, method, now)?;
+
+        self.0.insert(key.to_stable(), bucket.to_stable());
+
+        Ok(())
+    }
+
+    /// remove buckets that are already back to full capacity, returns the amount of buckets removed
+    pub fn prune(&mut self, now: Timestamp) -> usize {
+        let stale = self.0
+            .iter()
+            .filter(|(_, bucket)| bucket.full_at <= now)
+            .map(|(key, _)| key)
+            .take(MAX_PRUNE_PER_ROUND)
+            .collect::<Vec<_>>();
+
+        for key in stale.iter() {
+            self.0.remove(key);
+        }
+
+        stale.len()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_manager;
+
+    use super::*;
+
+    struct TestMemory;
+    crate::generate_memory_id!(TestMemory);
+
+    #[test]
+    fn test_token_bucket() {
+        let memory_manager = memory_manager!();
+        let mut limiter = RateLimiter::init::<TestMemory>(&memory_manager);
+
+        let limit = RateLimit::new(2, Duration::from_secs(60));
+        let caller = Principal::anonymous();
+        let now = Timestamp::new();
+
+        limiter.consume(caller, "register", &limit, now).unwrap();
+        limiter.consume(caller, "register", &limit, now).unwrap();
+
+        assert!(
+            matches!(
+                limiter.check(caller, "register", &limit, now),
+                Err(RateLimitError::Exceeded { retry_after_secs: 60, .. })
+            )
+        );
+        assert!(limiter.consume(caller, "register", &limit, now).is_err());
+
+        // other methods and callers have their own bucket
+        limiter.consume(caller, "claim", &limit, now).unwrap();
+        limiter.consume(Principal::management_canister(), "register", &limit, now).unwrap();
+
+        // one token is given back per interval
+        let later = now.after(Duration::from_secs(61));
+        limiter.consume(caller, "register", &limit, later).unwrap();
+        assert!(limiter.consume(caller, "register", &limit, later).is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let memory_manager = memory_manager!();
+        let mut limiter = RateLimiter::init::<TestMemory>(&memory_manager);
+
+        let limit = RateLimit::new(2, Duration::from_secs(60));
+        let now = Timestamp::new();
+
+        limiter.consume(Principal::anonymous(), "register", &limit, now).unwrap();
+        limiter.consume(Principal::anonymous(), "register", &limit, now).unwrap();
+
+        assert_eq!(limiter.prune(now.after(Duration::from_secs(60))), 0);
+        assert_eq!(limiter.prune(now.after(Duration::from_secs(120))), 1);
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let mut limits = RateLimits::new([("register", RateLimit::new(1, Duration::from_secs(1)))]);
+
+        limits.set("register".to_string(), Some(RateLimit::new(5, Duration::from_secs(1))));
+        assert_eq!(limits.get("register").unwrap().capacity, 5);
+
+        limits.set("register".to_string(), None);
+        assert!(limits.get("register").is_none());
+    }
+}
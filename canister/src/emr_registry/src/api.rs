@@ -1,8 +1,15 @@
 use candid::{ CandidType, Principal };
-use canister_common::{ common::{ EmrBody, EmrHeaderWithBody, EmrId, ProviderId, UserId }, from };
+use canister_common::{
+    common::{ EmrBody, EmrHeader, EmrHeaderWithBody, EmrId, ProviderId, UserId },
+    from,
+};
 use serde::Deserialize;
 
-use crate::registry::key;
+use crate::{
+    link::{ EmrLinkEdge, EmrLinkGraph, LinkType },
+    registry::key,
+    retention::{ PurgeEntry, RetentionPolicy, RetentionTarget },
+};
 
 pub use crate::header;
 
@@ -102,3 +109,111 @@ from!(RemoveEmrResponse: bool as status {
 pub struct AuthorizedCallerRequest {
     pub caller: Principal,
 }
+
+#[derive(CandidType, Deserialize)]
+pub struct SetRetentionPolicyRequest {
+    pub target: RetentionTarget,
+    pub retain_for_days: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RemoveRetentionPolicyRequest {
+    pub target: RetentionTarget,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RetentionPoliciesResponse {
+    pub policies: Vec<RetentionPolicy>,
+}
+
+from!(RetentionPoliciesResponse: Vec<RetentionPolicy> as policies {
+    policies : policies
+});
+
+#[derive(CandidType, Deserialize)]
+pub struct RetentionDryRunRequest {
+    /// header of the emr to start examining from, start from the beginning of the registry if empty
+    pub from: Option<Header>,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RetentionDryRunResponse {
+    pub candidates: Vec<PurgeEntry>,
+    /// pass this as `from` to continue the report, empty once the end of the registry is reached
+    pub next: Option<Header>,
+}
+
+impl RetentionDryRunResponse {
+    pub fn new(candidates: Vec<PurgeEntry>, next: Option<Header>) -> Self {
+        Self { candidates, next }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PurgeLogRequest {
+    pub page: u64,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PurgeLogResponse {
+    pub entries: Vec<PurgeEntry>,
+    pub total: u64,
+}
+
+impl PurgeLogResponse {
+    pub fn new(entries: Vec<PurgeEntry>, total: u64) -> Self {
+        Self { entries, total }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PurgedEmrsRequest {
+    /// index of the purge log entry to start from, pass the `next` of the previous response
+    pub from: u64,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PurgedEmrsResponse {
+    pub headers: Vec<EmrHeader>,
+    pub next: u64,
+}
+
+impl PurgedEmrsResponse {
+    pub fn new(headers: Vec<EmrHeader>, next: u64) -> Self {
+        Self { headers, next }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LinkEmrRequest {
+    /// the emr the link starts from, e.g. the follow up
+    pub from: Header,
+    /// the emr the link points to, e.g. the visit being followed up
+    pub to: Header,
+    pub link_type: LinkType,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReadEmrLinksRequest {
+    pub header: Header,
+    /// how many hops away from `header` to traverse, capped at 3
+    pub depth: u8,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReadEmrLinksResponse {
+    pub nodes: Vec<EmrHeader>,
+    pub edges: Vec<EmrLinkEdge>,
+}
+
+impl From<EmrLinkGraph> for ReadEmrLinksResponse {
+    fn from(graph: EmrLinkGraph) -> Self {
+        Self {
+            nodes: graph.nodes,
+            edges: graph.edges,
+        }
+    }
+}
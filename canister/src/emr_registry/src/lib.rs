@@ -1,10 +1,13 @@
 use api::{
-    AuthorizedCallerRequest, CreateEmrRequest, CreateEmrResponse, ReadEmrByIdRequest,
-    ReadEmrByIdResponse, RemoveEmrRequest, RemoveEmrResponse, UpdateEmrRequest, UpdateEmrResponse,
+    AuthorizedCallerRequest, CreateEmrRequest, CreateEmrResponse, LinkEmrRequest, PurgeLogRequest,
+    PurgeLogResponse, PurgedEmrsRequest, PurgedEmrsResponse, ReadEmrByIdRequest, ReadEmrByIdResponse, RemoveEmrRequest,
+    ReadEmrLinksRequest, ReadEmrLinksResponse, RemoveEmrResponse, RemoveRetentionPolicyRequest, RetentionDryRunRequest,
+    RetentionDryRunResponse, RetentionPoliciesResponse, SetRetentionPolicyRequest,
+    UpdateEmrRequest, UpdateEmrResponse,
 };
 use candid::{Decode, Encode};
 use canister_common::{
-    common::{self, guard::verified_caller},
+    common::{self, guard::verified_caller, Timestamp},
     id_generator::IdGenerator,
     log,
     mmgr::MemoryManager,
@@ -20,14 +23,18 @@ use core::time::Duration;
 use ic_cdk::{init, query, update};
 use ic_stable_structures::Cell;
 use memory::UpgradeMemory;
+use retention::{Retention, RetentionPolicy, RETENTION_BATCH_SIZE};
 use std::cell::RefCell;
 
 pub mod api;
 mod config;
 pub mod header;
 mod key;
+mod link;
 mod memory;
 mod registry;
+mod retention;
+mod stats;
 
 type State =
     common::State<registry::CoreEmrRegistry, Cell<Stable<CanisterConfig, Candid>, Memory>, ()>;
@@ -38,10 +45,17 @@ thread_local! {
     static ID_GENERATOR: RefCell<Option<IdGenerator<CanisterRandomSource>>> = const {
         RefCell::new(None)
     };
+    static RETENTION: RefCell<Option<Retention>> = const { RefCell::new(None) };
 }
 // change this if you want to change the interval of the metrics collection
 const METRICS_INTERVAL: Duration = Duration::from_secs(60 * 5); // 5 minutes
 
+// change this if you want to change the interval of the retention job
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+// change this if you want to change the amount of emrs counted on every statistics backfill tick
+const STATS_BACKFILL_BATCH_SIZE: usize = 500;
+
 /// A helper method to read the state.
 ///
 /// Precondition: the state is already initialized.
@@ -68,6 +82,20 @@ pub fn with_state_mut<R>(f: impl FnOnce(&mut State) -> R) -> R {
     STATE.with(|cell| f(cell.borrow_mut().as_mut().expect("state not initialized")))
 }
 
+/// A helper method to read the retention policies.
+///
+/// Precondition: the retention is already initialized.
+pub fn with_retention<R>(f: impl FnOnce(&Retention) -> R) -> R {
+    RETENTION.with(|cell| f(cell.borrow().as_ref().expect("retention not initialized")))
+}
+
+/// A helper method to mutate the retention policies.
+///
+/// Precondition: the retention is already initialized.
+pub fn with_retention_mut<R>(f: impl FnOnce(&mut Retention) -> R) -> R {
+    RETENTION.with(|cell| f(cell.borrow_mut().as_mut().expect("retention not initialized")))
+}
+
 // TODO : add init method
 
 fn initialize_id_generator() {
@@ -135,8 +163,16 @@ fn initialize() {
     let state = init_state();
     STATE.replace(Some(state));
     log!("state initialized");
+    let retention = with_state(|s| Retention::init(&s.memory_manager));
+    RETENTION.replace(Some(retention));
+    log!("retention initialized");
     initialize_id_generator();
     start_collect_metrics_job();
+    start_retention_job();
+
+    if !with_state(|s| s.registry.is_stats_backfilled()) {
+        start_stats_backfill_job();
+    }
 }
 
 #[ic_cdk::pre_upgrade]
@@ -199,6 +235,31 @@ fn start_collect_metrics_job() {
     });
 }
 
+fn start_retention_job() {
+    ic_cdk_timers::set_timer_interval(RETENTION_INTERVAL, || {
+        let purged = with_state_mut(|s| {
+            with_retention_mut(|r| r.run_batch(&mut s.registry, RETENTION_BATCH_SIZE))
+        });
+
+        if !purged.is_empty() {
+            log!("retention job purged {} entries", purged.len());
+        }
+    });
+}
+
+/// count emrs created before the statistics existed, a batch per tick so upgrades stay within the instruction limit
+fn start_stats_backfill_job() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let done = with_state_mut(|s| s.registry.backfill_stats(STATS_BACKFILL_BATCH_SIZE));
+
+        if done {
+            log!("emr statistics backfilled");
+        } else {
+            start_stats_backfill_job();
+        }
+    });
+}
+
 fn deserialize_canister_metrics() {
     let mem = with_state(|s| s.memory_manager.get_memory::<_, UpgradeMemory>(|mem| mem));
 
@@ -345,17 +406,76 @@ fn remove_emr(req: RemoveEmrRequest) -> RemoveEmrResponse {
     })
 }
 
+#[ic_cdk::update(guard = "only_authorized_caller")]
+fn link_emr(req: LinkEmrRequest) {
+    with_state_mut(|s| s.registry.link(req.from, req.to, req.link_type)).unwrap()
+}
+
+#[ic_cdk::update(guard = "only_authorized_caller")]
+fn unlink_emr(req: LinkEmrRequest) {
+    with_state_mut(|s| s.registry.unlink(req.from, req.to, req.link_type)).unwrap()
+}
+
+#[ic_cdk::query(guard = "only_authorized_caller")]
+fn read_emr_links(req: ReadEmrLinksRequest) -> ReadEmrLinksResponse {
+    with_state(|s| s.registry.link_graph(req.header, req.depth))
+        .unwrap()
+        .into()
+}
+
 // this will serve as an synchronization function in the future, for now it's only for testing inter-canister calls successfully
 #[ic_cdk::query(guard = "only_authorized_caller")]
 fn ping() {
     // no-op
 }
 
+#[ic_cdk::update(guard = "only_canister_owner")]
+fn set_retention_policy(req: SetRetentionPolicyRequest) {
+    with_retention_mut(|r| r.set_policy(RetentionPolicy::new(req.target, req.retain_for_days)));
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+fn remove_retention_policy(req: RemoveRetentionPolicyRequest) {
+    with_retention_mut(|r| r.remove_policy(&req.target));
+}
+
+#[ic_cdk::query(guard = "only_canister_owner")]
+fn retention_policies() -> RetentionPoliciesResponse {
+    with_retention(|r| r.policies()).into()
+}
+
+/// report what the retention job would purge without purging anything, bounded by the same batch size as the job itself
+#[ic_cdk::query(guard = "only_canister_owner")]
+fn retention_dry_run(req: RetentionDryRunRequest) -> RetentionDryRunResponse {
+    let limit = (req.limit as usize).min(RETENTION_BATCH_SIZE);
+    let (emrs, next) =
+        with_state(|s| s.registry.emr_batch(req.from.map(|h| h.into_inner()), limit));
+
+    let candidates = with_retention(|r| r.evaluate(emrs, &Timestamp::new()));
+
+    RetentionDryRunResponse::new(candidates, next.map(Into::into))
+}
+
+#[ic_cdk::query(guard = "only_canister_owner")]
+fn purge_log(req: PurgeLogRequest) -> PurgeLogResponse {
+    with_retention(|r| PurgeLogResponse::new(r.purge_log(req.page, req.limit), r.total_purged()))
+}
+
+/// whole emrs purged by the retention job, for the registries that keep headers of their own
+#[ic_cdk::query(guard = "only_authorized_caller")]
+fn purged_emrs(req: PurgedEmrsRequest) -> PurgedEmrsResponse {
+    let limit = req.limit.min(RETENTION_BATCH_SIZE as u64);
+    let (headers, next) = with_retention(|r| r.purged_since(req.from, limit));
+
+    PurgedEmrsResponse::new(headers, next)
+}
+
 #[ic_cdk::query(guard = "only_authorized_metrics_collector")]
 fn metrics() -> String {
     with_state(|s| {
         [
             opaque_metrics!(s.registry),
+            with_retention(|r| opaque_metrics!(r)),
             statistics::canister::BlockchainMetrics::measure(),
             statistics::canister::MemoryStatistics::measure(),
             OpaqueMetrics::measure(s.config.get().as_ref()),
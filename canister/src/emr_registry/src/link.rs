@@ -0,0 +1,283 @@
+use std::collections::{ BTreeSet, VecDeque };
+
+use candid::CandidType;
+use canister_common::{
+    common::EmrHeader,
+    impl_max_size,
+    impl_mem_bound,
+    impl_range_bound,
+    mmgr::MemoryManager,
+    stable::{ Stable, StableSet, ToStable },
+};
+use parity_scale_codec::{ Decode, Encode };
+use serde::Deserialize;
+
+/// max depth allowed when traversing the link graph
+pub const MAX_LINK_DEPTH: u8 = 3;
+
+/// max amount of emrs returned in a single link graph
+pub const MAX_LINK_GRAPH_NODES: usize = 100;
+
+#[derive(
+    CandidType,
+    Deserialize,
+    Encode,
+    Decode,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord
+)]
+pub enum LinkType {
+    /// the source emr is a follow-up visit of the target emr
+    #[default]
+    FollowsUp,
+    /// the source emr corrects the target emr
+    Amends,
+    /// the source emr refers the patient to whatever the target emr records
+    RefersTo,
+    /// the source emr is the result of the target emr, e.g. a lab result of a referral
+    ResultOf,
+}
+
+/// one side of a link, the header is the emr on the other end of the link
+#[derive(
+    CandidType,
+    Deserialize,
+    Encode,
+    Decode,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord
+)]
+pub struct EmrLink {
+    pub header: EmrHeader,
+    pub link_type: LinkType,
+}
+
+impl_max_size!(for EmrLink: EmrHeader, LinkType);
+impl_mem_bound!(for EmrLink: bounded; fixed_size: false);
+impl_range_bound!(EmrLink);
+
+impl EmrLink {
+    pub fn new(header: EmrHeader, link_type: LinkType) -> Self {
+        Self { header, link_type }
+    }
+}
+
+/// a directed link between two emrs, `from` [LinkType] `to`. e.g. `from` follows up `to`
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EmrLinkEdge {
+    pub from: EmrHeader,
+    pub to: EmrHeader,
+    pub link_type: LinkType,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct EmrLinkGraph {
+    pub nodes: Vec<EmrHeader>,
+    pub edges: Vec<EmrLinkEdge>,
+}
+
+/// outgoing links, source emr -> (target emr, link type)
+pub struct LinkMap(StableSet<Stable<EmrHeader>, Stable<EmrLink>>);
+
+/// incoming links, target emr -> (source emr, link type). used for reverse lookup
+pub struct BacklinkMap(StableSet<Stable<EmrHeader>, Stable<EmrLink>>);
+
+pub struct EmrLinks {
+    links: LinkMap,
+    backlinks: BacklinkMap,
+}
+
+impl EmrLinks {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            links: LinkMap(StableSet::init::<LinkMap>(memory_manager)),
+            backlinks: BacklinkMap(StableSet::init::<BacklinkMap>(memory_manager)),
+        }
+    }
+
+    pub fn is_linked(&self, from: &EmrHeader, to: &EmrHeader, link_type: LinkType) -> bool {
+        let key = (from.clone().to_stable(), EmrLink::new(to.clone(), link_type).to_stable());
+        self.links.0.inner().contains_key(&key)
+    }
+
+    /// returns false if the link already exists
+    pub fn link(&mut self, from: EmrHeader, to: EmrHeader, link_type: LinkType) -> bool {
+        if self.is_linked(&from, &to, link_type) {
+            return false;
+        }
+
+        self.links.0.insert(from.clone().to_stable(), EmrLink::new(to.clone(), link_type).to_stable());
+        self.backlinks.0.insert(to.to_stable(), EmrLink::new(from, link_type).to_stable());
+
+        true
+    }
+
+    /// returns false if the link does not exist
+    pub fn unlink(&mut self, from: &EmrHeader, to: &EmrHeader, link_type: LinkType) -> bool {
+        let removed = self.links.0
+            .inner_mut()
+            .remove(&(from.clone().to_stable(), EmrLink::new(to.clone(), link_type).to_stable()))
+            .is_some();
+
+        self.backlinks.0
+            .inner_mut()
+            .remove(&(to.clone().to_stable(), EmrLink::new(from.clone(), link_type).to_stable()));
+
+        removed
+    }
+
+    /// links going out of the given emr
+    pub fn links_of(&self, header: &EmrHeader) -> Vec<EmrLink> {
+        Self::associated(&self.links.0, header)
+    }
+
+    /// links coming into the given emr
+    pub fn backlinks_of(&self, header: &EmrHeader) -> Vec<EmrLink> {
+        Self::associated(&self.backlinks.0, header)
+    }
+
+    fn associated(
+        set: &StableSet<Stable<EmrHeader>, Stable<EmrLink>>,
+        header: &EmrHeader
+    ) -> Vec<EmrLink> {
+        set.get_set_associated_by_key(header.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|link| link.into_inner())
+            .collect()
+    }
+
+    /// remove every link from and to the given emr, used when the emr itself is removed
+    pub fn remove_all(&mut self, header: &EmrHeader) {
+        for link in self.links_of(header) {
+            self.unlink(header, &link.header, link.link_type);
+        }
+
+        for link in self.backlinks_of(header) {
+            self.unlink(&link.header, header, link.link_type);
+        }
+    }
+
+    /// breadth first traversal of the links around `root` in both directions, up to `depth` hops away.
+    /// stops adding emrs once [MAX_LINK_GRAPH_NODES] is reached.
+    pub fn graph(&self, root: EmrHeader, depth: u8) -> EmrLinkGraph {
+        let depth = depth.min(MAX_LINK_DEPTH);
+
+        let mut visited = BTreeSet::from([root.clone()]);
+        let mut edges = BTreeSet::new();
+        let mut queue = VecDeque::from([(root.clone(), 0)]);
+        let mut nodes = vec![root];
+
+        while let Some((current, hops)) = queue.pop_front() {
+            if hops >= depth {
+                continue;
+            }
+
+            let outgoing = self
+                .links_of(&current)
+                .into_iter()
+                .map(|link| (current.clone(), link.header, link.link_type));
+
+            let incoming = self
+                .backlinks_of(&current)
+                .into_iter()
+                .map(|link| (link.header, current.clone(), link.link_type));
+
+            for (from, to, link_type) in outgoing.chain(incoming) {
+                let neighbour = if from == current { to.clone() } else { from.clone() };
+
+                if !visited.contains(&neighbour) {
+                    if nodes.len() >= MAX_LINK_GRAPH_NODES {
+                        continue;
+                    }
+
+                    visited.insert(neighbour.clone());
+                    nodes.push(neighbour.clone());
+                    queue.push_back((neighbour, hops + 1));
+                }
+
+                edges.insert(EmrLinkEdge { from, to, link_type });
+            }
+        }
+
+        EmrLinkGraph {
+            nodes,
+            edges: edges.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::{ common::Id, test_utils::hash };
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn header(i: u8) -> EmrHeader {
+        EmrHeader {
+            emr_id: Id::from(Uuid::from_bytes([i; 16])),
+            provider_id: Id::from(Uuid::from_bytes([0; 16])),
+            user_id: hash(b"user").into(),
+            registry_id: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_link_and_reverse_lookup() {
+        let memory_manager = MemoryManager::init();
+        let mut links = EmrLinks::init(&memory_manager);
+
+        let (visit, follow_up) = (header(1), header(2));
+
+        assert!(links.link(follow_up.clone(), visit.clone(), LinkType::FollowsUp));
+        assert!(!links.link(follow_up.clone(), visit.clone(), LinkType::FollowsUp));
+
+        assert_eq!(links.links_of(&follow_up), vec![
+            EmrLink::new(visit.clone(), LinkType::FollowsUp)
+        ]);
+        assert_eq!(links.backlinks_of(&visit), vec![
+            EmrLink::new(follow_up.clone(), LinkType::FollowsUp)
+        ]);
+
+        assert!(links.unlink(&follow_up, &visit, LinkType::FollowsUp));
+        assert!(!links.unlink(&follow_up, &visit, LinkType::FollowsUp));
+        assert!(links.backlinks_of(&visit).is_empty());
+    }
+
+    #[test]
+    fn test_graph() {
+        let memory_manager = MemoryManager::init();
+        let mut links = EmrLinks::init(&memory_manager);
+
+        // referral <- result <- follow up <- amendment, plus an unrelated pair
+        links.link(header(2), header(1), LinkType::ResultOf);
+        links.link(header(3), header(2), LinkType::FollowsUp);
+        links.link(header(4), header(3), LinkType::Amends);
+        links.link(header(6), header(5), LinkType::RefersTo);
+
+        let graph = links.graph(header(2), 1);
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.edges.len(), 2);
+
+        let graph = links.graph(header(1), MAX_LINK_DEPTH);
+        assert_eq!(graph.nodes, vec![header(1), header(2), header(3), header(4)]);
+        assert_eq!(graph.edges.len(), 3);
+
+        links.remove_all(&header(3));
+        let graph = links.graph(header(1), MAX_LINK_DEPTH);
+        assert_eq!(graph.nodes, vec![header(1), header(2)]);
+        assert!(links.backlinks_of(&header(3)).is_empty());
+    }
+}
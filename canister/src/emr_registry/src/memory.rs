@@ -1,6 +1,24 @@
 use canister_common::generate_memory_id;
 
-use crate::{ config::CanisterConfig, registry::CoreEmrRegistry };
+use crate::{
+    config::CanisterConfig,
+    link::{ BacklinkMap, LinkMap },
+    registry::CoreEmrRegistry,
+    retention::{ PurgeLogEntryMemory, PurgeLogIndexMemory, RetentionPolicyMap },
+    stats::{ EmrCountByPatientMemory, EmrCountByProviderMemory, EmrTotalsMemory },
+};
 
 pub struct UpgradeMemory;
-generate_memory_id!(UpgradeMemory, CoreEmrRegistry, CanisterConfig);
+generate_memory_id!(
+    UpgradeMemory,
+    CoreEmrRegistry,
+    CanisterConfig,
+    RetentionPolicyMap,
+    PurgeLogIndexMemory,
+    PurgeLogEntryMemory,
+    EmrCountByProviderMemory,
+    EmrCountByPatientMemory,
+    EmrTotalsMemory,
+    LinkMap,
+    BacklinkMap
+);
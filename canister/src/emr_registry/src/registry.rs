@@ -7,6 +7,7 @@ use canister_common::{
         canister_id,
         ArbitraryEmrValue,
         EmrBody,
+        EmrHeader,
         EmrHeaderWithBody,
         EmrId,
         Id,
@@ -15,11 +16,16 @@ use canister_common::{
     },
     metrics,
     mmgr::MemoryManager,
+    opaque_metrics,
     stable::{ Memory, Stable, ToStable },
     statistics::traits::Metrics,
 };
 
-use crate::header::Header;
+use crate::{
+    header::Header,
+    link::{ EmrLinkGraph, EmrLinks, LinkType },
+    stats::{ EmrSize, EmrStats },
+};
 
 use self::key::*;
 
@@ -46,6 +52,18 @@ pub enum CoreRegistryError {
 
     #[error("The EMR already exists")]
     AlreadyExists,
+
+    #[error("An EMR can't be linked to itself")]
+    SelfLink,
+
+    #[error("EMRs of different patients can't be linked")]
+    CrossPatientLink,
+
+    #[error("The EMRs are already linked")]
+    LinkAlreadyExists,
+
+    #[error("The EMRs are not linked")]
+    LinkNotExist,
 }
 
 pub type RegistryResult<T> = Result<T, CoreRegistryError>;
@@ -101,8 +119,12 @@ pub mod key {
         Known<ProviderId>
     >;
 }
-pub struct CoreEmrRegistry(BTreeMap<Stable<CompositeKey>, ArbitraryEmrValue, Memory>);
-metrics!(CoreEmrRegistry: TotalKeys);
+pub struct CoreEmrRegistry {
+    inner: BTreeMap<Stable<CompositeKey>, ArbitraryEmrValue, Memory>,
+    stats: EmrStats,
+    links: EmrLinks,
+}
+metrics!(CoreEmrRegistry: TotalKeys, Statistics);
 
 impl Metrics<TotalKeys> for CoreEmrRegistry {
     fn metrics_name() -> &'static str {
@@ -118,14 +140,107 @@ impl Metrics<TotalKeys> for CoreEmrRegistry {
     }
 
     fn get_measurements(&self) -> String {
-        self.0.len().to_string()
+        self.inner.len().to_string()
+    }
+}
+
+impl Metrics<Statistics> for CoreEmrRegistry {
+    fn metrics_name() -> &'static str {
+        "emr_statistics"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        ""
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        opaque_metrics!(self.stats)
+    }
+
+    /// the statistics are already prometheus formatted, so return them as is
+    fn measure(&self) -> String {
+        <Self as Metrics<Statistics>>::get_measurements(self)
     }
 }
 
 impl CoreEmrRegistry {
     pub fn init(memory_manager: &MemoryManager) -> Self {
         let tree = memory_manager.get_memory::<_, Self>(BTreeMap::init);
-        Self(tree)
+        let stats = EmrStats::init(memory_manager);
+        let links = EmrLinks::init(memory_manager);
+
+        let mut registry = Self { inner: tree, stats, links };
+
+        // nothing to count in a new registry, existing ones are counted by [CoreEmrRegistry::backfill_stats]
+        if !registry.stats.is_initialized() && registry.inner.is_empty() {
+            registry.stats.mark_initialized();
+        }
+
+        registry
+    }
+
+    pub fn is_stats_backfilled(&self) -> bool {
+        self.stats.is_initialized()
+    }
+
+    /// count the next `limit` emrs created before the statistics existed, continuing from where the last call
+    /// left off. returns true once every emr is counted.
+    pub fn backfill_stats(&mut self, limit: usize) -> bool {
+        if self.stats.is_initialized() {
+            return true;
+        }
+
+        let (emrs, next) = self.emr_batch(self.stats.backfill_from(), limit);
+
+        for emr in emrs {
+            let header = emr.header.clone();
+
+            let mut size = EmrSize::default();
+            for fragment in emr.into_inner_body().into_inner() {
+                size.add(&fragment.value);
+            }
+
+            self.stats.emr_created(&header.provider_id, &header.user_id, size, false);
+        }
+
+        match next {
+            Some(next) => self.stats.set_backfill_from(next),
+            None => self.stats.mark_initialized(),
+        }
+
+        self.stats.is_initialized()
+    }
+
+    fn is_counted(&self, key: &CompositeKey) -> bool {
+        self.stats.is_counted(key.user_id(), key.provider_id(), key.emr_id())
+    }
+
+    fn is_same_emr(a: &CompositeKey, b: &CompositeKey) -> bool {
+        a.user_id() == b.user_id() && a.provider_id() == b.provider_id() && a.emr_id() == b.emr_id()
+    }
+
+    /// fragment count and value size of the emr the key belongs to, any records key of the key is ignored
+    fn emr_size(&self, key: &CompositeKey) -> EmrSize {
+        let start = CompositeKey::new(
+            key.user_id().clone(),
+            key.provider_id().clone(),
+            key.emr_id().clone(),
+            RecordsKey::default()
+        );
+
+        let mut size = EmrSize::default();
+
+        self.inner
+            .range(start.to_stable()..)
+            .take_while(|(k, _)| Self::is_same_emr(k.as_inner(), key))
+            .filter(|(k, _)| k.record_key().ne(&MAGIC_RECORDS_KEY))
+            .for_each(|(_, v)| size.add(&v));
+
+        size
     }
 }
 
@@ -133,7 +248,7 @@ impl Debug for CoreEmrRegistry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut result = f.debug_map();
 
-        for (key, value) in self.0.iter() {
+        for (key, value) in self.inner.iter() {
             let key = format!("{key} => ");
             let value = value.to_string();
             result.entry(&key, &value);
@@ -164,7 +279,7 @@ impl CoreEmrRegistry {
         );
 
         // insert magic key
-        self.0.insert(magic_key, MAGIC_RECORDS_KEY_VALUE.into());
+        self.inner.insert(magic_key.clone(), MAGIC_RECORDS_KEY_VALUE.into());
 
         for fragment in emr.into_iter() {
             let (k, v) = (fragment.key, fragment.value);
@@ -174,7 +289,12 @@ impl CoreEmrRegistry {
             }
 
             let emr_key = key.clone().with_records_key(k).build();
-            self.0.insert(emr_key.into(), v);
+            self.inner.insert(emr_key.into(), v);
+        }
+
+        if self.is_counted(&magic_key) {
+            let size = self.emr_size(&magic_key);
+            self.stats.emr_created(&header.provider_id, &header.user_id, size, true);
         }
 
         Ok(header)
@@ -183,7 +303,7 @@ impl CoreEmrRegistry {
     pub fn is_emr_exists(&self, key: EmrKey) -> RegistryResult<()> {
         let key = key.to_magic().build().to_stable();
 
-        self.0.contains_key(&key).then_some(()).ok_or(CoreRegistryError::NotExist)
+        self.inner.contains_key(&key).then_some(()).ok_or(CoreRegistryError::NotExist)
     }
 
     pub fn update(
@@ -191,8 +311,15 @@ impl CoreEmrRegistry {
         key: UpdateKey,
         value: ArbitraryEmrValue
     ) -> Option<ArbitraryEmrValue> {
-        let key = key.build().into();
-        self.0.insert(key, value)
+        let key: Stable<CompositeKey> = key.build().into();
+
+        let before = self.emr_size(&key);
+        let old = self.inner.insert(key.clone(), value);
+        if self.is_counted(&key) {
+            self.stats.emr_updated(before, self.emr_size(&key));
+        }
+
+        old
     }
 
     /// update given emr, will upsert if the the field does not exists.
@@ -207,7 +334,9 @@ impl CoreEmrRegistry {
             .with_emr_id(key.emr_id.clone().into_inner());
 
         // ensure emr exists
+        let magic_key = check_key.clone().to_magic().build();
         self.is_emr_exists(check_key)?;
+        let before = self.emr_size(&magic_key);
 
         let header = Header::new(
             key.user_id.clone().into_inner(),
@@ -223,8 +352,12 @@ impl CoreEmrRegistry {
                 continue;
             }
 
-            let records_key = key.clone().with_records_key(k);
-            self.update(records_key, v);
+            let records_key = key.clone().with_records_key(k).build();
+            self.inner.insert(records_key.into(), v);
+        }
+
+        if self.is_counted(&magic_key) {
+            self.stats.emr_updated(before, self.emr_size(&magic_key));
         }
 
         Ok(header)
@@ -233,7 +366,7 @@ impl CoreEmrRegistry {
     pub fn remove_record(&mut self, key: EmrKey) -> RegistryResult<()> {
         let key = key.build().to_stable();
 
-        let keys_to_remove: Vec<_> = self.0
+        let keys_to_remove: Vec<_> = self.inner
             .range(key.clone()..)
             .take_while(|(k, _)| k.emr_id() == key.emr_id())
             .map(|(k, _)| k.clone())
@@ -243,13 +376,137 @@ impl CoreEmrRegistry {
             return Err(CoreRegistryError::NotExist);
         }
 
+        if self.is_counted(&key) {
+            let size = self.emr_size(&key);
+            self.stats.emr_removed(key.provider_id(), key.user_id(), size);
+        }
+        self.links.remove_all(&Header::from(key.clone().into_inner()).into_inner());
+
         for key in keys_to_remove {
-            self.0.remove(&key);
+            self.inner.remove(&key);
         }
 
         Ok(())
     }
 
+    /// remove a single fragment from an emr, returns the removed value if it exists.
+    /// the magic records key can't be removed this way, use [CoreEmrRegistry::remove_record] instead.
+    pub fn remove_fragment(&mut self, key: UpdateKey) -> Option<ArbitraryEmrValue> {
+        let key = key.build().to_stable();
+
+        if key.record_key().eq(&MAGIC_RECORDS_KEY) {
+            return None;
+        }
+
+        let before = self.emr_size(&key);
+        let removed = self.inner.remove(&key)?;
+        if self.is_counted(&key) {
+            self.stats.emr_updated(before, self.emr_size(&key));
+        }
+
+        Some(removed)
+    }
+
+    /// iterate emrs in key order starting from `from` (inclusive), returning at most `limit` emrs along with their fragments
+    /// and the header of the next emr to continue from. the returned cursor is [None] once the end of the registry is reached.
+    pub fn emr_batch(
+        &self,
+        from: Option<EmrHeader>,
+        limit: usize
+    ) -> (Vec<EmrHeaderWithBody>, Option<EmrHeader>) {
+        let start = match from {
+            Some(header) =>
+                CompositeKey::new(
+                    header.user_id,
+                    header.provider_id,
+                    header.emr_id,
+                    RecordsKey::default()
+                ),
+            None => CompositeKey::default(),
+        };
+
+        let mut result: Vec<(CompositeKey, Vec<(RecordsKey, ArbitraryEmrValue)>)> = vec![];
+
+        for (k, v) in self.inner.range(start.to_stable()..) {
+            let k = k.into_inner();
+
+            let is_same_emr = result
+                .last()
+                .map(|(last, _)| Self::is_same_emr(last, &k))
+                .unwrap_or(false);
+
+            if !is_same_emr {
+                // the first key of the next emr becomes the cursor
+                if result.len() >= limit {
+                    return (Self::to_emr_batch(result), Some(Header::from(k).into_inner()));
+                }
+
+                result.push((k.clone(), vec![]));
+            }
+
+            if k.record_key().ne(&MAGIC_RECORDS_KEY) {
+                let (_, fragments) = result.last_mut().expect("emr must be pushed first");
+                fragments.push((k.record_key().to_owned(), v));
+            }
+        }
+
+        (Self::to_emr_batch(result), None)
+    }
+
+    fn to_emr_batch(
+        batch: Vec<(CompositeKey, Vec<(RecordsKey, ArbitraryEmrValue)>)>
+    ) -> Vec<EmrHeaderWithBody> {
+        batch
+            .into_iter()
+            .map(|(key, fragments)| {
+                EmrHeaderWithBody::new(Header::from(key).into_inner(), EmrBody::from(fragments))
+            })
+            .collect()
+    }
+
+    /// the header as stored in the link maps, regardless of the registry id supplied by the caller
+    fn link_header(header: EmrHeader) -> EmrHeader {
+        Header::new(header.user_id, header.provider_id, header.emr_id, canister_id()).into_inner()
+    }
+
+    /// link `from` to `to`, e.g. `from` follows up `to`. both emrs must exist and belong to the same patient.
+    pub fn link(&mut self, from: Header, to: Header, link_type: LinkType) -> RegistryResult<()> {
+        let (from, to) = (Self::link_header(from.into_inner()), Self::link_header(to.into_inner()));
+
+        if from.eq(&to) {
+            return Err(CoreRegistryError::SelfLink);
+        }
+
+        if from.user_id != to.user_id {
+            return Err(CoreRegistryError::CrossPatientLink);
+        }
+
+        self.is_emr_exists(Header::from(from.clone()).to_emr_key())?;
+        self.is_emr_exists(Header::from(to.clone()).to_emr_key())?;
+
+        self.links
+            .link(from, to, link_type)
+            .then_some(())
+            .ok_or(CoreRegistryError::LinkAlreadyExists)
+    }
+
+    pub fn unlink(&mut self, from: Header, to: Header, link_type: LinkType) -> RegistryResult<()> {
+        let (from, to) = (Self::link_header(from.into_inner()), Self::link_header(to.into_inner()));
+
+        self.links
+            .unlink(&from, &to, link_type)
+            .then_some(())
+            .ok_or(CoreRegistryError::LinkNotExist)
+    }
+
+    /// the emrs linked to `root` in both directions, up to `depth` hops away
+    pub fn link_graph(&self, root: Header, depth: u8) -> RegistryResult<EmrLinkGraph> {
+        let root = Self::link_header(root.into_inner());
+        self.is_emr_exists(Header::from(root.clone()).to_emr_key())?;
+
+        Ok(self.links.graph(root, depth))
+    }
+
     /// Get the list of EMRs for a user, this will not filter by provider
     pub fn get_user_list_batch(&self, page: u64, limit: u64, key: UserBatchKey) -> Vec<Header> {
         let key = key.build().to_stable();
@@ -274,7 +531,7 @@ impl CoreEmrRegistry {
         let mut last_id = Id::default();
         let mut index = 0;
 
-        let iter = self.0.range(key..);
+        let iter = self.inner.range(key..);
 
         let mut result = vec![];
 
@@ -317,7 +574,7 @@ impl CoreEmrRegistry {
     pub fn read_by_id(&self, key: EmrKey) -> RegistryResult<EmrHeaderWithBody> {
         let key = key.build().to_stable();
 
-        let records = self.0
+        let records = self.inner
             .range(key.clone()..)
             .take_while(|(k, _)| k.emr_id() == key.emr_id())
             .filter(|(k, _)| k.record_key().ne(&MAGIC_RECORDS_KEY))
@@ -560,4 +817,163 @@ mod tests {
             assert!(total_fields.contains(&fragment));
         }
     }
+
+    #[test]
+    fn test_emr_statistics() {
+        use canister_common::statistics::traits::OpaqueMetrics;
+
+        let memory_manager = MemoryManager::init();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+
+        let user = canister_common::test_utils::hash(b"user");
+        let provider_a = id!("b0e6abc0-5b4f-49b8-b1cf-9f4a452ff22d");
+        let provider_b = id!("be06a4e7-bc46-4740-8397-ea00d9933cc1");
+
+        let mut add = |provider: &ProviderId, emr_id: EmrId, records: &[(&str, &str)]| {
+            let key = AddEmrKey::new()
+                .with_user(user.into())
+                .with_provider(provider.clone())
+                .with_emr_id(emr_id);
+
+            let records = records
+                .iter()
+                .map(|(k, v)| (AsciiRecordsKey::new(*k).unwrap(), ArbitraryEmrValue::from(*v)))
+                .collect::<Vec<_>>();
+
+            registry.add(key, EmrBody::from(records)).unwrap()
+        };
+
+        let first = add(&provider_a, id!("6c5dd2ec-0fe0-40dc-ae33-234252be26ed"), &[("key1", "aaaa")]).into_inner();
+        add(&provider_a, id!("5d5dd2ec-0fe0-40dc-ae33-234252be26ed"), &[("key1", "aa"), ("key2", "aa")]);
+        add(&provider_b, id!("4e5dd2ec-0fe0-40dc-ae33-234252be26ed"), &[("key1", "aaaaaa")]);
+
+        let metrics = OpaqueMetrics::measure(&registry);
+        assert!(metrics.contains(&format!("emrs_by_provider_len{{provider=\"{provider_a}\"}} 2")));
+        assert!(metrics.contains(&format!("emrs_by_provider_len{{provider=\"{provider_b}\"}} 1")));
+        assert!(metrics.contains("emrs_by_provider_len{provider=\"other\"} 0"));
+        assert!(metrics.contains("average_value_size_bytes 3"));
+        assert!(metrics.contains("fragments_per_emr_bucket{le=\"1\"} 2"));
+        assert!(metrics.contains("fragments_per_emr_bucket{le=\"2\"} 3"));
+        assert!(metrics.contains("emrs_per_patient_bucket{le=\"4\"} 1"));
+
+        // two more fragments moves the first emr into the 3-4 fragments bucket
+        let fields = vec![
+            EmrFragment::new("key2".try_into().unwrap(), "a".to_string()),
+            EmrFragment::new("key3".try_into().unwrap(), "a".to_string())
+        ];
+        registry.update_batch(Header::from(first.clone()).to_partial_update_key(), fields.into()).unwrap();
+
+        let metrics = OpaqueMetrics::measure(&registry);
+        assert!(metrics.contains("fragments_per_emr_bucket{le=\"1\"} 1"));
+        assert!(metrics.contains("fragments_per_emr_bucket{le=\"2\"} 2"));
+        assert!(metrics.contains("fragments_per_emr_bucket{le=\"4\"} 3"));
+
+        registry.remove_record(Header::from(first).to_emr_key()).unwrap();
+
+        let metrics = OpaqueMetrics::measure(&registry);
+        assert!(metrics.contains(&format!("emrs_by_provider_len{{provider=\"{provider_a}\"}} 1")));
+        assert!(metrics.contains("fragments_per_emr_bucket{le=\"4\"} 2"));
+        assert!(metrics.contains("emrs_per_patient_bucket{le=\"2\"} 1"));
+        assert!(metrics.contains("average_value_size_bytes 3"));
+
+        // statistics must survive re-initialization without being recounted
+        let registry = CoreEmrRegistry::init(&memory_manager);
+        assert_eq!(OpaqueMetrics::measure(&registry), metrics);
+    }
+
+    #[test]
+    fn test_stats_backfill() {
+        use canister_common::statistics::traits::OpaqueMetrics;
+
+        let memory_manager = MemoryManager::init();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+
+        let user = canister_common::test_utils::hash(b"user");
+        let provider = id!("b0e6abc0-5b4f-49b8-b1cf-9f4a452ff22d");
+
+        let add = |registry: &mut CoreEmrRegistry, emr_id: EmrId| {
+            let key = AddEmrKey::new()
+                .with_user(user.into())
+                .with_provider(provider.clone())
+                .with_emr_id(emr_id);
+            let records = vec![(AsciiRecordsKey::new("key1").unwrap(), ArbitraryEmrValue::from("aa"))];
+
+            registry.add(key, EmrBody::from(records)).unwrap().into_inner()
+        };
+
+        let first = add(&mut registry, Id::new(&[1u8; 10]));
+        for i in 2..5u8 {
+            add(&mut registry, Id::new(&[i; 10]));
+        }
+
+        // statistics of a registry created before they existed
+        registry.stats = EmrStats::init(&MemoryManager::init());
+        assert!(!registry.is_stats_backfilled());
+
+        assert!(!registry.backfill_stats(2));
+
+        // counted emrs are tracked as usual, the rest is left for the backfill
+        registry.remove_record(Header::from(first).to_emr_key()).unwrap();
+        add(&mut registry, Id::new(&[9u8; 10]));
+
+        assert!(!registry.backfill_stats(2));
+        assert!(registry.backfill_stats(2));
+        assert!(registry.is_stats_backfilled());
+
+        let metrics = OpaqueMetrics::measure(&registry);
+        assert!(metrics.contains(&format!("emrs_by_provider_len{{provider=\"{provider}\"}} 4")));
+        assert!(metrics.contains("fragments_per_emr_bucket{le=\"1\"} 4"));
+        assert!(metrics.contains("emrs_per_patient_bucket{le=\"4\"} 1"));
+    }
+
+    #[test]
+    fn test_link_emr() {
+        let memory_manager = MemoryManager::init();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+
+        let user = canister_common::test_utils::hash(b"user");
+        let provider = id!("b0e6abc0-5b4f-49b8-b1cf-9f4a452ff22d");
+
+        let mut add = |user: [u8; 32], emr_id: EmrId| {
+            let key = AddEmrKey::new()
+                .with_user(user.into())
+                .with_provider(provider.clone())
+                .with_emr_id(emr_id);
+
+            let records = vec![(AsciiRecordsKey::new("key1").unwrap(), ArbitraryEmrValue::from("value1"))];
+            registry.add(key, EmrBody::from(records)).unwrap().into_inner()
+        };
+
+        let visit = add(user, id!("6c5dd2ec-0fe0-40dc-ae33-234252be26ed"));
+        let follow_up = add(user, id!("5d5dd2ec-0fe0-40dc-ae33-234252be26ed"));
+        let other_patient = add(
+            canister_common::test_utils::hash(b"other user"),
+            id!("3f5dd2ec-0fe0-40dc-ae33-234252be26ed"),
+        );
+
+        let mut missing = visit.clone();
+        missing.emr_id = id!("4e5dd2ec-0fe0-40dc-ae33-234252be26ed");
+
+        let link = |registry: &mut CoreEmrRegistry, from: &EmrHeader, to: &EmrHeader| {
+            registry.link(from.clone().into(), to.clone().into(), LinkType::FollowsUp)
+        };
+
+        assert!(matches!(link(&mut registry, &follow_up, &missing), Err(CoreRegistryError::NotExist)));
+        assert!(matches!(link(&mut registry, &visit, &visit), Err(CoreRegistryError::SelfLink)));
+        assert!(
+            matches!(link(&mut registry, &follow_up, &other_patient), Err(CoreRegistryError::CrossPatientLink))
+        );
+        assert!(link(&mut registry, &follow_up, &visit).is_ok());
+        assert!(
+            matches!(link(&mut registry, &follow_up, &visit), Err(CoreRegistryError::LinkAlreadyExists))
+        );
+
+        let graph = registry.link_graph(visit.clone().into(), 1).unwrap();
+        assert_eq!(graph.nodes, vec![visit.clone(), follow_up.clone()]);
+
+        // removing an emr drops every link to it
+        registry.remove_record(Header::from(follow_up).to_emr_key()).unwrap();
+        let graph = registry.link_graph(visit.into(), 1).unwrap();
+        assert!(graph.edges.is_empty());
+    }
 }
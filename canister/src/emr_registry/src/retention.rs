@@ -0,0 +1,513 @@
+use candid::CandidType;
+use canister_common::{
+    common::{ AsciiRecordsKey, EmrHeader, EmrHeaderWithBody, Timestamp },
+    impl_max_size,
+    impl_mem_bound,
+    impl_range_bound,
+    metrics,
+    mmgr::MemoryManager,
+    stable::{ Candid, Memory, Stable, ToStable },
+    statistics::traits::Metrics,
+};
+use ic_stable_structures::{ BTreeMap, Log };
+use serde::Deserialize;
+
+use crate::{ header::Header, registry::{ key::UpdateKey, CoreEmrRegistry } };
+
+/// fragment key used to determine the type of an emr, matched against [RetentionTarget::RecordType] policies.
+pub const RECORD_TYPE_KEY: &str = "record_type";
+
+// change this if you want to change the amount of emrs examined on every retention job tick
+pub const RETENTION_BATCH_SIZE: usize = 100;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RetentionTarget {
+    /// purge whole emrs whose [RECORD_TYPE_KEY] fragment equals the given value
+    RecordType(AsciiRecordsKey),
+    /// purge a single fragment with the given records key from every emr
+    RecordsKey(AsciiRecordsKey),
+}
+
+// ~40 bytes benchmarked for candid encoding
+impl_max_size!(for RetentionTarget: 64);
+impl_mem_bound!(for RetentionTarget: bounded; fixed_size: false);
+impl_range_bound!(RetentionTarget);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RetentionPolicy {
+    pub target: RetentionTarget,
+    /// emrs are retained for this many days since they were created
+    pub retain_for_days: u32,
+    pub created_at: Timestamp,
+}
+
+impl_max_size!(for RetentionPolicy: 128);
+impl_mem_bound!(for RetentionPolicy: bounded; fixed_size: false);
+
+impl RetentionPolicy {
+    pub fn new(target: RetentionTarget, retain_for_days: u32) -> Self {
+        Self {
+            target,
+            retain_for_days,
+            created_at: Timestamp::new(),
+        }
+    }
+
+    /// emr creation time is derived from the emr id, emrs with id that does not carry timestamp are never expired.
+    pub fn is_expired(&self, header: &EmrHeader, now: &Timestamp) -> bool {
+        let Some(created_at) = header.emr_id.timestamp() else {
+            return false;
+        };
+
+        let retain_for = u64::from(self.retain_for_days).saturating_mul(NANOS_PER_DAY);
+
+        now.inner().saturating_sub(created_at.inner()) >= retain_for
+    }
+}
+
+#[cfg(test)]
+mod encode_test_retention {
+    use super::*;
+
+    #[test]
+    fn test_len_encoded() {
+        use candid::{ Decode, Encode };
+
+        let policy = RetentionPolicy::new(
+            RetentionTarget::RecordType(AsciiRecordsKey::new("a".repeat(32)).unwrap()),
+            u32::MAX
+        );
+
+        let encoded = Encode!(&policy.target).unwrap();
+        println!("encoded target: {:?}", encoded.len());
+        assert!(encoded.len() <= RetentionTarget::max_size());
+
+        let encoded = Encode!(&policy).unwrap();
+        println!("encoded policy: {:?}", encoded.len());
+        assert!(encoded.len() <= RetentionPolicy::max_size());
+
+        let decoded = Decode!(&encoded, RetentionPolicy).unwrap();
+        assert_eq!(policy, decoded);
+    }
+}
+
+/// a single purge, either of a whole emr or a single fragment of it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PurgeEntry {
+    pub header: EmrHeader,
+    /// [None] if the whole emr is purged
+    pub records_key: Option<AsciiRecordsKey>,
+    pub target: RetentionTarget,
+    pub purged_at: Timestamp,
+}
+
+impl_max_size!(for PurgeEntry: 512);
+impl_mem_bound!(for PurgeEntry: bounded; fixed_size: false);
+
+impl PurgeEntry {
+    fn new(header: EmrHeader, records_key: Option<AsciiRecordsKey>, target: RetentionTarget) -> Self {
+        Self {
+            header,
+            records_key,
+            target,
+            purged_at: Timestamp::new(),
+        }
+    }
+}
+
+pub struct RetentionPolicyMap(
+    BTreeMap<Stable<RetentionTarget, Candid>, Stable<RetentionPolicy, Candid>, Memory>,
+);
+
+impl RetentionPolicyMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(BTreeMap::init))
+    }
+}
+
+pub struct PurgeLogIndexMemory;
+pub struct PurgeLogEntryMemory;
+
+/// append only log of every purge done by the retention job
+pub struct PurgeLog(Log<Stable<PurgeEntry, Candid>, Memory, Memory>);
+
+impl PurgeLog {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let index_mem = memory_manager.get_memory::<_, PurgeLogIndexMemory>(|mem| mem);
+        let data_mem = memory_manager.get_memory::<_, PurgeLogEntryMemory>(|mem| mem);
+
+        Self(Log::init(index_mem, data_mem).expect("purge log memory is corrupted"))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    pub fn add(&mut self, entry: &PurgeEntry) -> u64 {
+        self.0.append(entry.to_stable_ref()).expect("OOM")
+    }
+
+    /// oldest entries first, starting from the entry at index `from`
+    pub fn get_from(&self, from: u64, limit: u64) -> Vec<PurgeEntry> {
+        (from..from.saturating_add(limit).min(self.0.len()))
+            .filter_map(|i| self.0.get(i))
+            .map(|entry| entry.into_inner())
+            .collect()
+    }
+
+    /// newest entries first
+    pub fn get_paged(&self, page: u64, limit: u64) -> Vec<PurgeEntry> {
+        let len = self.0.len();
+        let start = page.saturating_mul(limit);
+
+        (start..start.saturating_add(limit).min(len))
+            .filter_map(|i| self.0.get(len - 1 - i))
+            .map(|entry| entry.into_inner())
+            .collect()
+    }
+}
+
+pub struct Retention {
+    policies: RetentionPolicyMap,
+    purge_log: PurgeLog,
+    /// header of the next emr to be examined by the retention job, kept in heap memory
+    /// as losing it on upgrade only means the job starts over from the beginning of the registry.
+    cursor: Option<EmrHeader>,
+}
+
+metrics!(Retention: Policies, Purged);
+
+impl Metrics<Policies> for Retention {
+    fn metrics_name() -> &'static str {
+        "retention"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "policies"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.policies.0.len().to_string()
+    }
+}
+
+impl Metrics<Purged> for Retention {
+    fn metrics_name() -> &'static str {
+        "retention"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "purged"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.purge_log.len().to_string()
+    }
+}
+
+impl Retention {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            policies: RetentionPolicyMap::init(memory_manager),
+            purge_log: PurgeLog::init(memory_manager),
+            cursor: None,
+        }
+    }
+
+    /// add or replace the policy for the given target
+    pub fn set_policy(&mut self, policy: RetentionPolicy) -> Option<RetentionPolicy> {
+        self.policies.0
+            .insert(policy.target.clone().to_stable(), policy.to_stable())
+            .map(|p| p.into_inner())
+    }
+
+    pub fn remove_policy(&mut self, target: &RetentionTarget) -> Option<RetentionPolicy> {
+        self.policies.0.remove(target.to_stable_ref()).map(|p| p.into_inner())
+    }
+
+    pub fn policies(&self) -> Vec<RetentionPolicy> {
+        self.policies.0
+            .iter()
+            .map(|(_, policy)| policy.into_inner())
+            .collect()
+    }
+
+    pub fn purge_log(&self, page: u64, limit: u64) -> Vec<PurgeEntry> {
+        self.purge_log.get_paged(page, limit)
+    }
+
+    pub fn total_purged(&self) -> u64 {
+        self.purge_log.len()
+    }
+
+    /// headers of whole emrs purged since the purge log entry at index `from`, oldest first.
+    /// returns the index to continue from, registries holding headers of their own use this to drop purged emrs.
+    pub fn purged_since(&self, from: u64, limit: u64) -> (Vec<EmrHeader>, u64) {
+        let next = from.saturating_add(limit).min(self.purge_log.len()).max(from);
+
+        let headers = self.purge_log
+            .get_from(from, limit)
+            .into_iter()
+            .filter(|entry| entry.records_key.is_none())
+            .map(|entry| entry.header)
+            .collect();
+
+        (headers, next)
+    }
+
+    /// evaluate every policy against the given emrs, returning what should be purged.
+    /// a whole emr purge takes precedence over fragment purges of the same emr.
+    pub fn evaluate(&self, emrs: Vec<EmrHeaderWithBody>, now: &Timestamp) -> Vec<PurgeEntry> {
+        let policies = self.policies();
+
+        if policies.is_empty() {
+            return vec![];
+        }
+
+        let mut result = vec![];
+
+        for emr in emrs {
+            let header = emr.header.clone();
+            let fragments = emr.into_inner_body().into_inner();
+
+            let record_type = fragments
+                .iter()
+                .find(|f| f.key.to_ascii_str() == RECORD_TYPE_KEY)
+                .map(|f| f.value.as_str());
+
+            let whole = policies.iter().find(|p| {
+                matches!(
+                    (&p.target, record_type),
+                    (RetentionTarget::RecordType(t), Some(r)) if t.to_ascii_str() == r
+                ) && p.is_expired(&header, now)
+            });
+
+            if let Some(policy) = whole {
+                result.push(PurgeEntry::new(header, None, policy.target.clone()));
+                continue;
+            }
+
+            for fragment in fragments {
+                let expired = policies.iter().find(|p| {
+                    matches!(&p.target, RetentionTarget::RecordsKey(k) if k == &fragment.key) &&
+                        p.is_expired(&header, now)
+                });
+
+                if let Some(policy) = expired {
+                    result.push(
+                        PurgeEntry::new(header.clone(), Some(fragment.key), policy.target.clone())
+                    );
+                }
+            }
+        }
+
+        result
+    }
+
+    /// examine the next batch of emrs and purge every expired one, continuing from where the last call left off.
+    /// returns the purged entries.
+    pub fn run_batch(&mut self, registry: &mut CoreEmrRegistry, limit: usize) -> Vec<PurgeEntry> {
+        let (emrs, next) = registry.emr_batch(self.cursor.take(), limit);
+        self.cursor = next;
+
+        let entries = self.evaluate(emrs, &Timestamp::new());
+
+        for entry in entries.iter() {
+            let header = Header::from(entry.header.clone());
+
+            match entry.records_key {
+                Some(ref records_key) => {
+                    let key = UpdateKey::new()
+                        .with_user(header.0.user_id)
+                        .with_provider(header.0.provider_id)
+                        .with_emr_id(header.0.emr_id)
+                        .with_records_key(records_key.clone());
+
+                    // no-op if the fragment has been removed in the meantime
+                    registry.remove_fragment(key);
+                }
+                None => {
+                    // no-op if the emr has been removed in the meantime
+                    let _ = registry.remove_record(header.to_emr_key());
+                }
+            }
+
+            self.purge_log.add(entry);
+        }
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::{
+        common::{ ArbitraryEmrValue, EmrBody, Id },
+        memory_manager,
+    };
+
+    use crate::{ key::{ CompositeKeyBuilder, UnknownUsage }, registry::key::EmrKey };
+
+    use super::*;
+
+    fn add_emr(registry: &mut CoreEmrRegistry, emr_id: Id, records: Vec<(&str, &str)>) -> Header {
+        let user = canister_common::test_utils::hash(b"user");
+        let provider = canister_common::id!("b0e6abc0-5b4f-49b8-b1cf-9f4a452ff22d");
+
+        let key = CompositeKeyBuilder::<UnknownUsage>
+            ::new()
+            .records_key()
+            .with_user(user.into())
+            .with_provider(provider)
+            .with_emr_id(emr_id);
+
+        let records = records
+            .into_iter()
+            .map(|(k, v)| (AsciiRecordsKey::new(k).unwrap(), ArbitraryEmrValue::from(v)))
+            .collect::<Vec<_>>();
+
+        registry.add(key, EmrBody::from(records)).unwrap()
+    }
+
+    fn emr_key(header: &Header) -> EmrKey {
+        EmrKey::new()
+            .with_user(header.user_id.clone())
+            .with_provider(header.provider_id.clone())
+            .with_emr_id(header.emr_id.clone())
+    }
+
+    #[test]
+    fn test_purge_by_record_type() {
+        let memory_manager = memory_manager!();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+        let mut retention = Retention::init(&memory_manager);
+
+        let lab = add_emr(&mut registry, Id::new(&[1u8; 10]), vec![(RECORD_TYPE_KEY, "lab")]);
+        let visit = add_emr(&mut registry, Id::new(&[2u8; 10]), vec![(RECORD_TYPE_KEY, "visit")]);
+
+        retention.set_policy(
+            RetentionPolicy::new(RetentionTarget::RecordType(AsciiRecordsKey::new("lab").unwrap()), 0)
+        );
+        retention.set_policy(
+            RetentionPolicy::new(
+                RetentionTarget::RecordType(AsciiRecordsKey::new("visit").unwrap()),
+                30
+            )
+        );
+
+        let purged = retention.run_batch(&mut registry, RETENTION_BATCH_SIZE);
+
+        assert_eq!(purged.len(), 1);
+        assert_eq!(purged[0].header, lab.0);
+        assert!(registry.is_emr_exists(emr_key(&lab)).is_err());
+        assert!(registry.is_emr_exists(emr_key(&visit)).is_ok());
+        assert_eq!(retention.purge_log(0, 10), purged);
+    }
+
+    #[test]
+    fn test_purge_by_records_key() {
+        let memory_manager = memory_manager!();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+        let mut retention = Retention::init(&memory_manager);
+
+        let header = add_emr(
+            &mut registry,
+            Id::new(&[1u8; 10]),
+            vec![("photo", "base64"), ("diagnosis", "flu")]
+        );
+
+        retention.set_policy(
+            RetentionPolicy::new(RetentionTarget::RecordsKey(AsciiRecordsKey::new("photo").unwrap()), 0)
+        );
+
+        let (emrs, _) = registry.emr_batch(None, RETENTION_BATCH_SIZE);
+        let report = retention.evaluate(emrs, &Timestamp::new());
+        assert_eq!(report.len(), 1);
+
+        // dry run does not touch the registry
+        assert_eq!(registry.read_by_id(emr_key(&header)).unwrap().body.into_inner().len(), 2);
+
+        retention.run_batch(&mut registry, RETENTION_BATCH_SIZE);
+
+        let body = registry.read_by_id(emr_key(&header)).unwrap().into_inner_body().into_inner();
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0].key.to_ascii_str(), "diagnosis");
+    }
+
+    #[test]
+    fn test_batch_cursor() {
+        let memory_manager = memory_manager!();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+        let mut retention = Retention::init(&memory_manager);
+
+        for i in 0..5u8 {
+            add_emr(&mut registry, Id::new(&[i; 10]), vec![(RECORD_TYPE_KEY, "lab")]);
+        }
+
+        retention.set_policy(
+            RetentionPolicy::new(RetentionTarget::RecordType(AsciiRecordsKey::new("lab").unwrap()), 0)
+        );
+
+        assert_eq!(retention.run_batch(&mut registry, 2).len(), 2);
+        assert_eq!(retention.run_batch(&mut registry, 2).len(), 2);
+        assert_eq!(retention.run_batch(&mut registry, 2).len(), 1);
+        assert!(retention.cursor.is_none());
+        assert_eq!(retention.total_purged(), 5);
+    }
+
+    #[test]
+    fn test_purged_since() {
+        let memory_manager = memory_manager!();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+        let mut retention = Retention::init(&memory_manager);
+
+        let lab = add_emr(&mut registry, Id::new(&[1u8; 10]), vec![(RECORD_TYPE_KEY, "lab")]);
+        add_emr(&mut registry, Id::new(&[2u8; 10]), vec![("photo", "base64"), ("diagnosis", "flu")]);
+
+        retention.set_policy(
+            RetentionPolicy::new(RetentionTarget::RecordType(AsciiRecordsKey::new("lab").unwrap()), 0)
+        );
+        retention.set_policy(
+            RetentionPolicy::new(RetentionTarget::RecordsKey(AsciiRecordsKey::new("photo").unwrap()), 0)
+        );
+        assert_eq!(retention.run_batch(&mut registry, RETENTION_BATCH_SIZE).len(), 2);
+
+        // fragment purges keep the emr around, so only the whole emr purge is reported
+        let (headers, next) = retention.purged_since(0, 10);
+        assert_eq!(headers, vec![lab.0]);
+        assert_eq!(next, 2);
+
+        let (headers, next) = retention.purged_since(next, 10);
+        assert!(headers.is_empty());
+        assert_eq!(next, 2);
+    }
+
+    #[test]
+    fn test_v4_id_never_expires() {
+        let memory_manager = memory_manager!();
+        let mut registry = CoreEmrRegistry::init(&memory_manager);
+        let mut retention = Retention::init(&memory_manager);
+
+        add_emr(
+            &mut registry,
+            canister_common::id!("6c5dd2ec-0fe0-40dc-ae33-234252be26ed"),
+            vec![(RECORD_TYPE_KEY, "lab")]
+        );
+
+        retention.set_policy(
+            RetentionPolicy::new(RetentionTarget::RecordType(AsciiRecordsKey::new("lab").unwrap()), 0)
+        );
+
+        assert!(retention.run_batch(&mut registry, RETENTION_BATCH_SIZE).is_empty());
+    }
+}
@@ -0,0 +1,557 @@
+use candid::CandidType;
+use canister_common::{
+    common::{ EmrHeader, EmrId, ProviderId, Timestamp, UserId },
+    impl_max_size,
+    impl_mem_bound,
+    metrics,
+    mmgr::MemoryManager,
+    stable::{ Candid, Memory, Stable, ToStable },
+    statistics::traits::Metrics,
+};
+use ic_stable_structures::{ BTreeMap, Cell };
+use serde::Deserialize;
+
+use crate::METRICS_INTERVAL;
+
+/// upper bounds (inclusive) of the histogram buckets, the last bucket catches everything above
+pub const BUCKETS: [u64; 8] = [1, 2, 4, 8, 16, 32, 64, u64::MAX];
+
+/// providers reported by id in [EmrsByProvider], the rest is summed so the series stay bounded
+pub const TOP_PROVIDERS: usize = 10;
+
+/// the `n` largest counts, largest first, along with the sum of every other count
+fn top_n<K>(counts: impl Iterator<Item = (K, u64)>, n: usize) -> (Vec<(K, u64)>, u64) {
+    let mut top: Vec<(K, u64)> = Vec::with_capacity(n + 1);
+    let mut other = 0;
+
+    for (key, count) in counts {
+        let position = top.iter().position(|(_, top)| count > *top).unwrap_or(top.len());
+        top.insert(position, (key, count));
+
+        if top.len() > n {
+            other += top.pop().map(|(_, count)| count).unwrap_or(0);
+        }
+    }
+
+    (top, other)
+}
+
+fn bucket_of(count: u64) -> usize {
+    BUCKETS.iter()
+        .position(|bound| count <= *bound)
+        .unwrap_or(BUCKETS.len() - 1)
+}
+
+/// amount of fragments and the total size of their values of a single emr, excluding the magic fragment
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EmrSize {
+    pub fragments: u64,
+    pub value_bytes: u64,
+}
+
+impl EmrSize {
+    pub fn add(&mut self, value: &str) {
+        self.fragments += 1;
+        self.value_bytes += value.len() as u64;
+    }
+}
+
+/// emr mutations done in a single interval
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntervalActivity {
+    pub created: u64,
+    pub updated: u64,
+    pub removed: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct EmrTotals {
+    /// false until the statistics are computed from the existing registry, see [EmrStats::is_initialized]
+    initialized: bool,
+    emrs: u64,
+    fragments: u64,
+    value_bytes: u64,
+    /// emr count bucketed by the amount of fragments it has, indexed the same as [BUCKETS]
+    fragments_histogram: Vec<u64>,
+    /// patient count bucketed by the amount of emrs they have, indexed the same as [BUCKETS]
+    patients_histogram: Vec<u64>,
+    /// index of the interval `current` belongs to, i.e. time / [METRICS_INTERVAL]
+    interval: u64,
+    current: IntervalActivity,
+    previous: IntervalActivity,
+    /// the next emr to be counted by the backfill, emrs before it are already counted
+    backfill_from: Option<EmrHeader>,
+}
+
+// ~300 bytes benchmarked for candid encoding with fully populated histograms and a backfill cursor
+impl_max_size!(for EmrTotals: 512);
+impl_mem_bound!(for EmrTotals: bounded; fixed_size: false);
+
+impl EmrTotals {
+    fn histogram_mut(histogram: &mut Vec<u64>) -> &mut Vec<u64> {
+        histogram.resize(BUCKETS.len(), 0);
+        histogram
+    }
+
+    fn move_bucket(histogram: &mut Vec<u64>, from: Option<u64>, to: Option<u64>) {
+        let histogram = Self::histogram_mut(histogram);
+
+        if let Some(from) = from {
+            let bucket = &mut histogram[bucket_of(from)];
+            *bucket = bucket.saturating_sub(1);
+        }
+
+        if let Some(to) = to {
+            histogram[bucket_of(to)] += 1;
+        }
+    }
+
+    fn interval_of(now: &Timestamp) -> u64 {
+        now.inner() / (METRICS_INTERVAL.as_nanos() as u64)
+    }
+
+    /// roll the activity counters forward so that `current` always belongs to the given interval
+    fn roll(&mut self, interval: u64) {
+        if interval == self.interval {
+            return;
+        }
+
+        self.previous = if interval == self.interval + 1 {
+            std::mem::take(&mut self.current)
+        } else {
+            IntervalActivity::default()
+        };
+
+        self.current = IntervalActivity::default();
+        self.interval = interval;
+    }
+
+    /// activity of the last complete interval relative to the given interval
+    fn last_interval(&self, interval: u64) -> IntervalActivity {
+        if interval == self.interval {
+            self.previous.clone()
+        } else if interval == self.interval + 1 {
+            self.current.clone()
+        } else {
+            IntervalActivity::default()
+        }
+    }
+}
+
+pub struct EmrCountByProviderMemory;
+pub struct EmrCountByPatientMemory;
+pub struct EmrTotalsMemory;
+
+/// emr statistics used for capacity planning, kept in sync by [CoreEmrRegistry](crate::registry::CoreEmrRegistry) on every mutation.
+pub struct EmrStats {
+    providers: BTreeMap<Stable<ProviderId>, u64, Memory>,
+    patients: BTreeMap<Stable<UserId>, u64, Memory>,
+    totals: Cell<Stable<EmrTotals, Candid>, Memory>,
+}
+
+impl EmrStats {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            providers: memory_manager.get_memory::<_, EmrCountByProviderMemory>(BTreeMap::init),
+            patients: memory_manager.get_memory::<_, EmrCountByPatientMemory>(BTreeMap::init),
+            totals: memory_manager
+                .get_memory::<_, EmrTotalsMemory>(|m| Cell::init(m, EmrTotals::default().to_stable()))
+                .expect("emr totals memory is corrupted"),
+        }
+    }
+
+    /// whether the statistics already account for every emr in the registry. registries created before the
+    /// statistics existed are counted in batches, moving [EmrStats::backfill_from] forward until
+    /// [EmrStats::mark_initialized] is called.
+    pub fn is_initialized(&self) -> bool {
+        self.totals.get().initialized
+    }
+
+    pub fn mark_initialized(&mut self) {
+        self.update_totals(|totals| {
+            totals.initialized = true;
+            totals.backfill_from = None;
+        });
+    }
+
+    pub fn backfill_from(&self) -> Option<EmrHeader> {
+        self.totals.get().backfill_from.clone()
+    }
+
+    pub fn set_backfill_from(&mut self, from: EmrHeader) {
+        self.update_totals(|totals| {
+            totals.backfill_from = Some(from);
+        });
+    }
+
+    /// whether the emr is already accounted for, mutations of emrs not reached by the backfill yet must not be
+    /// recorded as the backfill counts them as they are once it gets there
+    pub fn is_counted(&self, user: &UserId, provider: &ProviderId, emr_id: &EmrId) -> bool {
+        let totals = self.totals.get();
+
+        totals.initialized ||
+            totals.backfill_from
+                .as_ref()
+                .is_some_and(|from| {
+                    (user, provider, emr_id) < (&from.user_id, &from.provider_id, &from.emr_id)
+                })
+    }
+
+    fn update_totals(&mut self, f: impl FnOnce(&mut EmrTotals)) {
+        let mut totals = self.totals.get().clone().into_inner();
+        f(&mut totals);
+        self.totals.set(totals.to_stable()).expect("emr totals exceed max size");
+    }
+
+    /// record a newly created emr. `count_activity` is false when recounting existing emrs
+    pub fn emr_created(
+        &mut self,
+        provider: &ProviderId,
+        user: &UserId,
+        size: EmrSize,
+        count_activity: bool
+    ) {
+        let provider_count = self.providers.get(provider.to_stable_ref()).unwrap_or(0);
+        self.providers.insert(provider.clone().to_stable(), provider_count + 1);
+
+        let patient_count = self.patients.get(user.to_stable_ref()).unwrap_or(0);
+        self.patients.insert(user.clone().to_stable(), patient_count + 1);
+
+        let now = EmrTotals::interval_of(&Timestamp::new());
+        self.update_totals(|totals| {
+            totals.emrs += 1;
+            totals.fragments += size.fragments;
+            totals.value_bytes += size.value_bytes;
+
+            EmrTotals::move_bucket(&mut totals.fragments_histogram, None, Some(size.fragments));
+            EmrTotals::move_bucket(
+                &mut totals.patients_histogram,
+                (patient_count > 0).then_some(patient_count),
+                Some(patient_count + 1)
+            );
+
+            if count_activity {
+                totals.roll(now);
+                totals.current.created += 1;
+            }
+        });
+    }
+
+    /// record a change to the fragments of an existing emr
+    pub fn emr_updated(&mut self, before: EmrSize, after: EmrSize) {
+        let now = EmrTotals::interval_of(&Timestamp::new());
+        self.update_totals(|totals| {
+            totals.fragments = totals.fragments.saturating_sub(before.fragments) + after.fragments;
+            totals.value_bytes =
+                totals.value_bytes.saturating_sub(before.value_bytes) + after.value_bytes;
+
+            EmrTotals::move_bucket(
+                &mut totals.fragments_histogram,
+                Some(before.fragments),
+                Some(after.fragments)
+            );
+
+            totals.roll(now);
+            totals.current.updated += 1;
+        });
+    }
+
+    /// record a removed emr, `size` being its size right before removal
+    pub fn emr_removed(&mut self, provider: &ProviderId, user: &UserId, size: EmrSize) {
+        let provider_count = self.providers.get(provider.to_stable_ref()).unwrap_or(0);
+        match provider_count {
+            0 | 1 => self.providers.remove(provider.to_stable_ref()),
+            count => self.providers.insert(provider.clone().to_stable(), count - 1),
+        };
+
+        let patient_count = self.patients.get(user.to_stable_ref()).unwrap_or(0);
+        match patient_count {
+            0 | 1 => self.patients.remove(user.to_stable_ref()),
+            count => self.patients.insert(user.clone().to_stable(), count - 1),
+        };
+
+        let now = EmrTotals::interval_of(&Timestamp::new());
+        self.update_totals(|totals| {
+            totals.emrs = totals.emrs.saturating_sub(1);
+            totals.fragments = totals.fragments.saturating_sub(size.fragments);
+            totals.value_bytes = totals.value_bytes.saturating_sub(size.value_bytes);
+
+            EmrTotals::move_bucket(&mut totals.fragments_histogram, Some(size.fragments), None);
+            EmrTotals::move_bucket(
+                &mut totals.patients_histogram,
+                (patient_count > 0).then_some(patient_count),
+                (patient_count > 1).then_some(patient_count - 1)
+            );
+
+            totals.roll(now);
+            totals.current.removed += 1;
+        });
+    }
+
+    /// average size of a single fragment value in bytes, 0 if there's no fragment at all
+    pub fn average_value_size(&self) -> u64 {
+        let totals = self.totals.get();
+        totals.value_bytes.checked_div(totals.fragments).unwrap_or(0)
+    }
+
+    pub fn last_interval_activity(&self) -> IntervalActivity {
+        self.totals.get().last_interval(EmrTotals::interval_of(&Timestamp::new()))
+    }
+
+    /// cumulative histogram in prometheus format, e.g. `name{le="2"} 10`
+    fn histogram(name: &str, histogram: &[u64]) -> String {
+        let mut cumulative = 0;
+
+        BUCKETS.iter()
+            .enumerate()
+            .map(|(i, bound)| {
+                cumulative += histogram.get(i).copied().unwrap_or(0);
+
+                let bound = match *bound {
+                    u64::MAX => String::from("+Inf"),
+                    bound => bound.to_string(),
+                };
+
+                format!("{name}{{le=\"{bound}\"}} {cumulative}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+metrics!(
+    EmrStats: EmrsByProvider,
+    FragmentsPerEmr,
+    EmrsPerPatient,
+    AverageValueSize,
+    EmrsCreated,
+    EmrsUpdated,
+    EmrsRemoved
+);
+
+impl Metrics<EmrsByProvider> for EmrStats {
+    fn metrics_name() -> &'static str {
+        "emrs_by_provider"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "len"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.providers.len().to_string()
+    }
+
+    /// one line for each of the [TOP_PROVIDERS] largest providers, labeled with the provider id,
+    /// and one line labeled `other` for every other provider
+    fn measure(&self) -> String {
+        let id = <Self as Metrics<EmrsByProvider>>::prometheus_id();
+        let (top, other) = top_n(self.providers.iter(), TOP_PROVIDERS);
+
+        top.into_iter()
+            .map(|(provider, count)| format!("{id}{{provider=\"{}\"}} {count}", provider.as_inner()))
+            .chain(std::iter::once(format!("{id}{{provider=\"other\"}} {other}")))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Metrics<FragmentsPerEmr> for EmrStats {
+    fn metrics_name() -> &'static str {
+        "fragments_per_emr"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "bucket"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.totals.get().fragments.to_string()
+    }
+
+    fn measure(&self) -> String {
+        Self::histogram(
+            &<Self as Metrics<FragmentsPerEmr>>::prometheus_id(),
+            &self.totals.get().fragments_histogram
+        )
+    }
+}
+
+impl Metrics<EmrsPerPatient> for EmrStats {
+    fn metrics_name() -> &'static str {
+        "emrs_per_patient"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "bucket"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.patients.len().to_string()
+    }
+
+    fn measure(&self) -> String {
+        Self::histogram(
+            &<Self as Metrics<EmrsPerPatient>>::prometheus_id(),
+            &self.totals.get().patients_histogram
+        )
+    }
+}
+
+impl Metrics<AverageValueSize> for EmrStats {
+    fn metrics_name() -> &'static str {
+        "average_value_size"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "bytes"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.average_value_size().to_string()
+    }
+}
+
+impl Metrics<EmrsCreated> for EmrStats {
+    fn metrics_name() -> &'static str {
+        "emrs_created"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "last_interval"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.last_interval_activity().created.to_string()
+    }
+}
+
+impl Metrics<EmrsUpdated> for EmrStats {
+    fn metrics_name() -> &'static str {
+        "emrs_updated"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "last_interval"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.last_interval_activity().updated.to_string()
+    }
+}
+
+impl Metrics<EmrsRemoved> for EmrStats {
+    fn metrics_name() -> &'static str {
+        "emrs_removed"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "last_interval"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.last_interval_activity().removed.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_of() {
+        assert_eq!(bucket_of(0), 0);
+        assert_eq!(bucket_of(1), 0);
+        assert_eq!(bucket_of(3), 2);
+        assert_eq!(bucket_of(64), 6);
+        assert_eq!(bucket_of(65), 7);
+    }
+
+    #[test]
+    fn test_top_n() {
+        let counts = [("a", 3), ("b", 7), ("c", 1), ("d", 7), ("e", 5)];
+
+        let (top, other) = top_n(counts.into_iter(), 3);
+        assert_eq!(top, vec![("b", 7), ("d", 7), ("e", 5)]);
+        assert_eq!(other, 4);
+
+        let (top, other) = top_n(counts.into_iter(), 10);
+        assert_eq!(top.len(), 5);
+        assert_eq!(other, 0);
+    }
+
+    #[test]
+    fn test_roll_interval() {
+        let mut totals = EmrTotals::default();
+
+        totals.roll(10);
+        totals.current.created += 2;
+
+        assert_eq!(totals.last_interval(10).created, 0);
+        assert_eq!(totals.last_interval(11).created, 2);
+        assert_eq!(totals.last_interval(12).created, 0);
+
+        totals.roll(11);
+        totals.current.created += 1;
+        assert_eq!(totals.last_interval(11).created, 2);
+
+        // skipping an interval drops everything
+        totals.roll(13);
+        assert_eq!(totals.last_interval(13), IntervalActivity::default());
+    }
+
+    #[test]
+    fn test_totals_size() {
+        let id = canister_common::id!("6c5dd2ec-0fe0-40dc-ae33-234252be26ed");
+        let totals = EmrTotals {
+            initialized: true,
+            emrs: u64::MAX,
+            fragments: u64::MAX,
+            value_bytes: u64::MAX,
+            fragments_histogram: vec![u64::MAX; BUCKETS.len()],
+            patients_histogram: vec![u64::MAX; BUCKETS.len()],
+            interval: u64::MAX,
+            current: IntervalActivity { created: u64::MAX, updated: u64::MAX, removed: u64::MAX },
+            previous: IntervalActivity { created: u64::MAX, updated: u64::MAX, removed: u64::MAX },
+            backfill_from: Some(
+                EmrHeader::new(
+                    UserId::from([u8::MAX; 32]),
+                    id.clone(),
+                    id,
+                    candid::Principal::from_slice(&[u8::MAX; 29])
+                )
+            ),
+        };
+
+        let encoded = candid::encode_one(totals).unwrap();
+        assert!(encoded.len() <= 512);
+    }
+}
@@ -1,15 +1,26 @@
 use candid::{CandidType, Principal};
 use canister_common::{
-    common::{AsciiRecordsKey, EmrHeader, EmrId, ProviderId, UserId, H256},
+    common::{AsciiRecordsKey, EmrHeader, EmrId, Id, ProviderId, Timestamp, UserId, H256},
     from,
+    rate_limit::RateLimit,
     stable::{EncodingMarker, Stable},
 };
 use serde::Deserialize;
 
 use crate::{
-    consent::{Consent, ConsentCode, SessionId},
+    consent::{
+        ActiveSession, ClaimApproval, Consent, ConsentAccess, ConsentCode, ConsentScope,
+        ProviderSession, Purpose, SessionId, StandingTerms,
+    },
+    device::{Device, LinkCode},
+    emergency::{EmergencyAccess, ReviewOutcome},
     encryption::vetkd::{HexEncodedPublicKey, HexEncodedSecretKey},
-    log::Activity,
+    kyc::{KycCase, KycCaseStatus, KycDecision, KycDocument, KycTransition},
+    log::{Activity, ActivityType, ChainHead, ChainVerification},
+    notification::Notification,
+    profile::{PatientVersion, V2},
+    receipt::ConsentReceipt,
+    recovery::RecoveryRequest,
     registry::{
         Group, GroupConsentCode, GroupId, HeaderStatus, KycStatus, Patient, Relation, NIK, V1,
     },
@@ -111,6 +122,52 @@ pub struct IssueRequest {
 }
 pub type UpdateRequest = IssueRequest;
 
+#[derive(CandidType, Deserialize)]
+pub struct NotifyPurgedRequest {
+    pub headers: Vec<EmrHeader>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HasSessionRequest {
+    pub provider_id: ProviderId,
+    pub nik: H256,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReadableEmrsRequest {
+    pub provider_id: ProviderId,
+    pub headers: Vec<EmrHeader>,
+    /// read only if not set
+    pub access: Option<ConsentAccess>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReadableEmrsResponse {
+    pub headers: Vec<EmrHeader>,
+}
+
+impl ReadableEmrsResponse {
+    pub fn new(headers: Vec<EmrHeader>) -> Self {
+        Self { headers }
+    }
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct CreateConsentRequest {
+    /// share everything if not set
+    pub scope: Option<ConsentScope>,
+    /// grant the consent directly to a provider, the provider accepts it from their pending consent list
+    pub provider: Option<ProviderId>,
+    /// use a 10 character alphanumeric code instead of the default 6 digit code
+    pub long_code: Option<bool>,
+    /// keep the consent valid for multiple visits to the same provider
+    pub standing: Option<StandingTerms>,
+    /// claims wait for the patient to confirm them, see `request_consent_claim`
+    pub require_approval: Option<bool>,
+    /// purposes of use providers may claim the consent for, treatment only if not set
+    pub purposes: Option<Vec<Purpose>>,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct CreateConsentResponse {
     code: ConsentCode,
@@ -148,6 +205,49 @@ impl EmrListConsentResponse {
     }
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct EmrListEncounterRequest {
+    pub encounter_id: Id,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmrListEncounterSessionRequest {
+    pub session_id: SessionId,
+    pub encounter_id: Id,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterListRequest {
+    pub page: u64,
+    pub limit: u8,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterListResponse {
+    // the encounter is owned by the provider registry, so we pass it along as is
+    pub encounters: Vec<crate::declarations::provider_registry::Encounter>,
+}
+
+impl From<crate::declarations::provider_registry::EncounterListResponse> for EncounterListResponse {
+    fn from(response: crate::declarations::provider_registry::EncounterListResponse) -> Self {
+        Self {
+            encounters: response.encounters,
+        }
+    }
+}
+
+impl From<crate::declarations::provider_registry::EmrHeader> for EmrHeader {
+    fn from(header: crate::declarations::provider_registry::EmrHeader) -> Self {
+        // safe to unwrap as the header is produced by the provider registry from valid ids
+        EmrHeader {
+            emr_id: header.emr_id.parse().unwrap(),
+            provider_id: header.provider_id.parse().unwrap(),
+            user_id: header.user_id.parse().unwrap(),
+            registry_id: header.registry_id.into(),
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct DeriveVerificationKeyRequest {
     pub session_id: SessionId,
@@ -180,6 +280,8 @@ from!(DeriveSecretKeyResponse: HexEncodedSecretKey as value {
 #[derive(CandidType, Deserialize)]
 pub struct ClaimConsentRequest {
     pub code: ConsentCode,
+    /// defaults to treatment
+    pub purpose: Option<Purpose>,
 }
 #[derive(CandidType, Deserialize)]
 pub struct ClaimConsentResponse {
@@ -193,6 +295,19 @@ impl ClaimConsentResponse {
     }
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct ConsentClaimStatusResponse {
+    pub approval: ClaimApproval,
+    /// set once the patient confirmed the claim
+    pub session_id: Option<SessionId>,
+    pub name: Option<AsciiRecordsKey<64>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConsentClaimDecisionRequest {
+    pub code: ConsentCode,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct RevokeConsentRequest {
     pub codes: Vec<ConsentCode>,
@@ -223,6 +338,19 @@ pub struct UpdatePatientInfoRequest {
     pub info: V1,
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct UpdatePatientInfoV2Request {
+    /// the raw 16 digit NIK, only used to check the date of birth and never stored
+    pub nik: String,
+    pub info: V2,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct GetPatientInfoRequest {
+    /// V1 if not set
+    pub version: Option<PatientVersion>,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct GetPatientInfoResponse {
     pub patient: Patient,
@@ -238,29 +366,77 @@ impl GetPatientInfoResponse {
 #[derive(CandidType, Deserialize)]
 pub struct GetPatientInfoBySessionRequest {
     pub session_id: SessionId,
+    /// V1 if not set
+    pub version: Option<PatientVersion>,
+}
+
+/// the session to continue a patient list from, patients are listed in the order their sessions were claimed
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PatientListCursor {
+    pub claimed_at: Timestamp,
+    pub session_id: SessionId,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct PatientListRequest {
+    /// `next_cursor` of the previous page, the first page if not set
+    pub cursor: Option<PatientListCursor>,
+    /// 100 if not set, capped at 100
+    pub limit: Option<u64>,
+    pub kyc_status: Option<KycStatus>,
+    /// registration time in nanoseconds, inclusive
+    pub registered_from: Option<u64>,
+    /// registration time in nanoseconds, inclusive
+    pub registered_to: Option<u64>,
+    /// V1 if not set
+    pub version: Option<PatientVersion>,
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct PatientListResponse {
     pub patients: Vec<PatientWithNikAndSession>,
+    /// [None] on the last page
+    pub next_cursor: Option<PatientListCursor>,
+    /// sessions the provider has open across every page, the filters aren't applied
+    pub total: u64,
 }
 
-from!(PatientListResponse: Vec<PatientWithNikAndSession> as value {
-    patients: value
-});
+/// the patient to continue an admin patient list from, patients are listed in registration order
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PatientListAdminCursor {
+    /// nanoseconds, 0 for patients registered before registration times were recorded
+    pub registered_at: u64,
+    pub nik: NIK,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct PatientListAdminRequest {
+    /// `next_cursor` of the previous page, the first page if not set
+    pub cursor: Option<PatientListAdminCursor>,
+    /// 100 if not set, capped at 100
+    pub limit: Option<u64>,
+    pub kyc_status: Option<KycStatus>,
+    /// registration time in nanoseconds, inclusive
+    pub registered_from: Option<u64>,
+    /// registration time in nanoseconds, inclusive
+    pub registered_to: Option<u64>,
+    /// V1 if not set
+    pub version: Option<PatientVersion>,
+}
 
 /// Response type for admin-only patient list requests.
-/// Contains a list of all patients with their basic information and NIK.
+/// Contains a page of patients with their basic information and NIK.
 /// This is specifically for backoffice UI administrative purposes.
 #[derive(CandidType, Deserialize)]
 pub struct PatientListAdminResponse {
     pub patients: Vec<PatientWithNik>,
+    /// [None] on the last page
+    pub next_cursor: Option<PatientListAdminCursor>,
+    /// patients with the kyc status, or every patient, across every page.
+    /// the registration window isn't applied
+    pub total: u64,
 }
 
-from!(PatientListAdminResponse: Vec<PatientWithNik> as value {
-    patients: value
-});
-
 #[derive(CandidType, Deserialize)]
 pub struct PatientWithNikAndSession {
     pub info: Patient,
@@ -316,11 +492,279 @@ from!(ConsentListResponse: Vec<Consent> as value {
     consents: value
 });
 
+#[derive(CandidType, Deserialize)]
+pub struct ConsentReceiptEntry {
+    pub id: u64,
+    pub receipt: ConsentReceipt,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConsentReceiptListResponse {
+    pub receipts: Vec<ConsentReceiptEntry>,
+}
+
+from!(ConsentReceiptListResponse: Vec<ConsentReceiptEntry> as value {
+    receipts: value
+});
+
+#[derive(CandidType, Deserialize)]
+pub struct DownloadConsentReceiptRequest {
+    pub id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConsentReceiptDocument {
+    pub receipt_id: String,
+    /// kantara consent receipt v1.1 json document
+    pub json: String,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct RequestDeviceLinkRequest {
+    /// shown in the device list, e.g. "phone"
+    pub label: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DeviceLinkCodeResponse {
+    /// enter this code on a device that is already linked to approve the link
+    pub code: LinkCode,
+    pub expires_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ApproveDeviceLinkRequest {
+    pub code: LinkCode,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DeviceListResponse {
+    /// the device the patient registered with, it can't be revoked
+    pub registered: Option<Principal>,
+    pub devices: Vec<Device>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RevokeDeviceRequest {
+    pub principal: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RequestAccountRecoveryRequest {
+    pub nik: NIK,
+    /// how the patient proves they own the NIK, checked by an admin
+    pub evidence: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AccountRecoveryIdRequest {
+    pub id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AccountRecoveryEntry {
+    pub id: u64,
+    pub request: RecoveryRequest,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AccountRecoveryQueueRequest {
+    pub page: u64,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AccountRecoveryQueueResponse {
+    pub requests: Vec<AccountRecoveryEntry>,
+    pub total_pending: u64,
+    pub total_pages: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReviewAccountRecoveryRequest {
+    pub id: u64,
+    pub approve: bool,
+    pub note: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SubmitKycRequest {
+    pub documents: Vec<KycDocument>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct KycCaseRequest {
+    pub nik: NIK,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct KycCaseResponse {
+    pub case: KycCase,
+    /// oldest first
+    pub history: Vec<KycTransition>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct KycCaseListRequest {
+    pub status: Option<KycCaseStatus>,
+    pub reviewer: Option<Principal>,
+    pub page: u64,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct KycCaseListResponse {
+    pub cases: Vec<KycCase>,
+    pub total: u64,
+    pub total_pages: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AssignKycCaseRequest {
+    pub nik: NIK,
+    /// defaults to the caller
+    pub reviewer: Option<Principal>,
+    pub reason: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DecideKycCaseRequest {
+    pub nik: NIK,
+    pub decision: KycDecision,
+    pub reason: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmergencyAccessRequest {
+    pub nik: NIK,
+    /// why the patient consent could not be obtained, shown to the patient and the reviewing admin
+    pub justification: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmergencyAccessResponse {
+    pub id: u64,
+    pub session_id: SessionId,
+    pub expires_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmergencyAccessEntry {
+    pub id: u64,
+    pub access: EmergencyAccess,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmergencyAccessListResponse {
+    pub accesses: Vec<EmergencyAccessEntry>,
+}
+
+from!(EmergencyAccessListResponse: Vec<EmergencyAccessEntry> as value {
+    accesses: value
+});
+
+#[derive(CandidType, Deserialize)]
+pub struct EmergencyReviewQueueRequest {
+    pub page: u64,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmergencyReviewQueueResponse {
+    pub accesses: Vec<EmergencyAccessEntry>,
+    pub total_pending: u64,
+    pub total_pages: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReviewEmergencyAccessRequest {
+    pub id: u64,
+    pub outcome: ReviewOutcome,
+    pub note: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PatientSession {
+    pub session: ActiveSession,
+    /// [None] if the provider could not be resolved
+    pub provider_name: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ActiveSessionListResponse {
+    pub sessions: Vec<PatientSession>,
+}
+
+from!(ActiveSessionListResponse: Vec<PatientSession> as value {
+    sessions: value
+});
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProviderSessionSort {
+    /// most recently claimed first
+    #[default]
+    Newest,
+    Oldest,
+    PatientName,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ProviderSessionListRequest {
+    pub page: u64,
+    pub limit: u64,
+    pub sort: Option<ProviderSessionSort>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ProviderPatientSession {
+    pub session: ProviderSession,
+    pub patient_name: AsciiRecordsKey<64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ProviderSessionListResponse {
+    pub sessions: Vec<ProviderPatientSession>,
+    pub total_sessions: u64,
+    pub total_pages: u64,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct SearchPatientRequest {
     pub nik: H256,
     #[serde(default)]
     pub _type: Option<String>,
+    /// V1 if not set
+    pub version: Option<PatientVersion>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SearchPatientsAdminRequest {
+    /// matched against the start of the normalized name, case and extra whitespace are ignored
+    pub name: Option<String>,
+    /// YYYY-MM-DD
+    pub date_of_birth: Option<String>,
+    pub kyc_status: Option<KycStatus>,
+    /// `next_cursor` of the previous page, the first page if not set
+    pub cursor: Option<SearchPatientsAdminCursor>,
+    /// 100 if not set, capped at 100
+    pub limit: Option<u64>,
+    /// V1 if not set
+    pub version: Option<PatientVersion>,
+}
+
+/// the index entry to continue an admin patient search from
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SearchPatientsAdminCursor {
+    /// normalized name or date of birth of the patient, empty when searching by kyc status only
+    pub key: String,
+    pub nik: NIK,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SearchPatientsAdminResponse {
+    pub patients: Vec<PatientWithNik>,
+    /// [None] on the last page
+    pub next_cursor: Option<SearchPatientsAdminCursor>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -347,25 +791,83 @@ from!(SearchPatientAdminResponse: PatientWithNik as value {
     patient_info: value
 });
 
+#[derive(CandidType, Deserialize, Default)]
+pub struct GetLogsRequest {
+    /// only return activities recorded for this purpose of use
+    pub purpose: Option<Purpose>,
+    /// only return activities of these types, every type if not set
+    pub activity_types: Option<Vec<ActivityType>>,
+    /// inclusive
+    pub from: Option<Timestamp>,
+    /// inclusive
+    pub to: Option<Timestamp>,
+    /// the `next_cursor` of the previous page, newest activities first
+    pub cursor: Option<u64>,
+    /// 100 if not set, capped at 100
+    pub limit: Option<u64>,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct LogResponse {
     logs: Vec<Activity>,
+    /// none once every matching activity was returned
+    next_cursor: Option<u64>,
 }
 
 impl LogResponse {
-    pub fn new(logs: Vec<Activity>) -> Self {
-        Self { logs }
+    pub fn new(logs: Vec<Activity>, next_cursor: Option<u64>) -> Self {
+        Self { logs, next_cursor }
     }
 }
 
-from!(LogResponse: Vec<Activity> as value {
-    logs: value
-});
+#[derive(CandidType, Deserialize, Default)]
+pub struct VerifyLogChainRequest {
+    /// a head of the caller chain seen earlier, e.g. from a previous verification
+    pub known_head: Option<ChainHead>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyLogChainResponse {
+    pub verification: ChainVerification,
+    /// head of the chain over every activity
+    pub root: ChainHead,
+    /// certificate over the canister certified data
+    pub certificate: Option<Vec<u8>>,
+    /// CBOR encoded hash tree with the caller chain head under `chain_heads/<nik>` and `root` under
+    /// `chain_root`, its root hash is the certified data
+    pub witness: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct NotificationListRequest {
+    /// starting from 0, newest notifications first
+    pub page: Option<u64>,
+    /// 100 if not set, capped at 100
+    pub limit: Option<u64>,
+    /// only return notifications that were not read yet
+    pub unread_only: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct NotificationListResponse {
+    pub notifications: Vec<Notification>,
+    /// notifications matching the request across every page
+    pub total: u64,
+    /// unread notifications in the inbox
+    pub unread: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MarkNotificationsReadRequest {
+    pub ids: Vec<u64>,
+}
 
 #[derive(CandidType, Deserialize)]
 pub struct UpdateKycStatusRequest {
     pub nik: H256,
     pub kyc_status: KycStatus,
+    /// kept in the kyc case history, [crate::kyc::DEFAULT_FORCE_REASON] if not set
+    pub reason: Option<String>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -518,6 +1020,8 @@ pub struct GetGroupDetailsNoPaginatedRequest {
 #[derive(CandidType, Deserialize)]
 pub struct CreateConsentForGroupRequest {
     pub nik: NIK,
+    /// use a 10 character alphanumeric code instead of the default 6 digit code
+    pub long_code: Option<bool>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -526,3 +1030,10 @@ pub struct CreateConsentForGroupResponse {
 }
 
 // End of API response and request structs for group consent functionality.
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateRateLimitRequest {
+    pub method: String,
+    /// remove the limit for the method if not set
+    pub limit: Option<RateLimit>,
+}
@@ -0,0 +1,106 @@
+//! shared format of consent codes and group consent codes.
+//!
+//! a code is either the default 6 digit numeric code, or the optional longer alphanumeric code
+//! which is a lot harder to guess. both are stored in the same fixed size buffer, padded with zeroes.
+
+// change this if you want to change the length of the numeric code
+pub const CODE_LEN: usize = 6;
+
+// change this if you want to change the length of the alphanumeric code
+pub const LONG_CODE_LEN: usize = 10;
+
+/// amount of leading characters used to group codes when tracking failed claim attempts
+pub const PREFIX_LEN: usize = 3;
+
+const NUMERIC: &[u8] = b"0123456789";
+
+// no 0, 1, I and O as they are easily misread when the code is read out loud
+const ALPHANUMERIC: &[u8; 32] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+pub type RawCode = [u8; LONG_CODE_LEN];
+
+/// the last 6 digits of the given random number
+pub fn numeric(u: u64) -> RawCode {
+    let digits = format!(
+        "{:0width$}",
+        u % 10u64.pow(CODE_LEN as u32),
+        width = CODE_LEN
+    );
+
+    let mut code = [0; LONG_CODE_LEN];
+    code[..CODE_LEN].copy_from_slice(digits.as_bytes());
+    code
+}
+
+/// 10 alphanumeric characters taken from the given random number, 5 bits per character
+pub fn alphanumeric(mut u: u64) -> RawCode {
+    let mut code = [0; LONG_CODE_LEN];
+
+    for c in code.iter_mut() {
+        *c = ALPHANUMERIC[(u % 32) as usize];
+        u /= 32;
+    }
+
+    code
+}
+
+/// parse either code format, alphanumeric codes are case insensitive
+pub fn parse(s: &str) -> Result<RawCode, String> {
+    let allowed: &[u8] = match s.len() {
+        CODE_LEN => NUMERIC,
+        LONG_CODE_LEN => ALPHANUMERIC,
+        _ => return Err("invalid length".to_string()),
+    };
+
+    let mut code = [0; LONG_CODE_LEN];
+
+    for (i, c) in s.bytes().enumerate() {
+        let c = c.to_ascii_uppercase();
+
+        if !allowed.contains(&c) {
+            return Err("invalid character".to_string());
+        }
+
+        code[i] = c;
+    }
+
+    Ok(code)
+}
+
+pub fn as_str(code: &RawCode) -> &str {
+    let len = code.iter().position(|c| *c == 0).unwrap_or(LONG_CODE_LEN);
+    std::str::from_utf8(&code[..len]).unwrap()
+}
+
+pub fn prefix(code: &RawCode) -> [u8; PREFIX_LEN] {
+    let mut prefix = [0; PREFIX_LEN];
+    prefix.copy_from_slice(&code[..PREFIX_LEN]);
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numeric() {
+        assert_eq!(as_str(&numeric(u64::MAX)), "551615");
+        assert_eq!(as_str(&numeric(42)), "000042");
+        assert_eq!(parse("551615").unwrap(), numeric(u64::MAX));
+    }
+
+    #[test]
+    fn test_alphanumeric() {
+        let code = alphanumeric(u64::MAX);
+        let str = as_str(&code);
+
+        assert_eq!(str.len(), LONG_CODE_LEN);
+        assert_eq!(parse(str).unwrap(), code);
+        assert_eq!(parse(&str.to_lowercase()).unwrap(), code);
+
+        // short codes are numeric only, long codes never contain ambiguous characters
+        assert!(parse("12345A").is_err());
+        assert!(parse("ABCDEFGHJ0").is_err());
+        assert!(parse("1234567").is_err());
+    }
+}
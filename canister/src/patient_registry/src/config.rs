@@ -1,9 +1,12 @@
+use std::time::Duration;
+
 use candid::{ CandidType, Principal };
 use canister_common::{
     impl_max_size,
     impl_mem_bound,
     metrics,
     mmgr::MemoryManager,
+    rate_limit::{ MethodRateLimit, RateLimit, RateLimits },
     stable::{ Candid, Memory, Stable, ToStable },
     statistics::traits::Metrics,
 };
@@ -20,6 +23,9 @@ pub struct CanisterConfig {
     emr_registries: Vec<Principal>,
     authorized_metrics_collectors: Vec<Principal>,
     provider_registry: Principal,
+
+    // optional so configs stored before rate limits existed still decode, `None` means the default limits
+    rate_limits: Option<RateLimits>,
 }
 
 metrics!(CanisterConfig: Owners, MaxItem);
@@ -80,17 +86,7 @@ impl Default for CanisterConfig {
     /// will panic if called outside canister execution environment. don't call this in test,
     /// use `CanisterConfig::new` instead.
     fn default() -> Self {
-        Self {
-            max_item_per_response: Self::INITIAL_MAX_EMR_RESPONSE,
-
-            owner: ic_cdk::caller(),
-            // intentionally anonymous so that we can change and test it later, because if not then only the local
-            // deployments would guaranteed to work properly
-            default_emr_registry: Principal::anonymous(),
-            emr_registries: vec![Principal::anonymous()],
-            authorized_metrics_collectors: vec![],
-            provider_registry: Principal::anonymous(),
-        }
+        Self::new(ic_cdk::caller())
     }
 }
 
@@ -103,8 +99,16 @@ impl CanisterConfig {
 
     pub fn new(owner: Principal) -> Self {
         Self {
+            max_item_per_response: Self::INITIAL_MAX_EMR_RESPONSE,
+
             owner,
-            ..Default::default()
+            // intentionally anonymous so that we can change and test it later, because if not then only the local
+            // deployments would guaranteed to work properly
+            default_emr_registry: Principal::anonymous(),
+            emr_registries: vec![Principal::anonymous()],
+            authorized_metrics_collectors: vec![],
+            provider_registry: Principal::anonymous(),
+            rate_limits: None,
         }
     }
 
@@ -167,4 +171,83 @@ impl CanisterConfig {
     pub fn is_provider_registry(&self, principal: &Principal) -> bool {
         self.provider_registry.eq(principal)
     }
+
+    /// limit for the method, `None` if the method is not rate limited
+    pub fn rate_limit(&self, method: &str) -> Option<RateLimit> {
+        match self.rate_limits {
+            Some(ref limits) => limits.get(method),
+            None => Self::default_rate_limits().get(method),
+        }
+    }
+
+    pub fn rate_limits(&self) -> Vec<MethodRateLimit> {
+        self.rate_limits.clone().unwrap_or_else(Self::default_rate_limits).list().to_vec()
+    }
+
+    /// set the limit for the method, `None` removes the limit entirely
+    pub fn update_rate_limit(&mut self, method: String, limit: Option<RateLimit>) {
+        let limits = self.rate_limits.get_or_insert_with(Self::default_rate_limits);
+        limits.set(method, limit);
+    }
+
+    /// expensive methods callable by anyone are limited by default
+    fn default_rate_limits() -> RateLimits {
+        RateLimits::new([
+            ("register_patient", RateLimit::new(5, Duration::from_secs(60 * 10))),
+            ("claim_consent", RateLimit::new(20, Duration::from_secs(30))),
+            ("emergency_access", RateLimit::new(10, Duration::from_secs(60 * 60))),
+            ("request_device_link", RateLimit::new(5, Duration::from_secs(60 * 10))),
+            ("request_account_recovery", RateLimit::new(3, Duration::from_secs(60 * 60))),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::{
+        common::Timestamp,
+        memory_manager,
+        rate_limit::{ RateLimitError, RateLimiter },
+    };
+
+    use super::*;
+    use crate::memory::RateLimiterMemory;
+
+    fn exhaust(method: &str) {
+        let memory_manager = memory_manager!();
+        let mut limiter = RateLimiter::init::<RateLimiterMemory>(&memory_manager);
+
+        // fresh install, no limits were ever configured
+        let config = CanisterConfig::new(Principal::anonymous());
+        let limit = config.rate_limit(method).expect("method should be limited by default");
+
+        let caller = Principal::from_slice(&[1; 29]);
+        let now = Timestamp::new();
+
+        for _ in 0..limit.capacity {
+            limiter.consume(caller, method, &limit, now).unwrap();
+        }
+
+        assert!(
+            matches!(
+                limiter.consume(caller, method, &limit, now),
+                Err(RateLimitError::Exceeded { .. })
+            )
+        );
+    }
+
+    #[test]
+    fn test_emergency_access_limited_by_default() {
+        exhaust("emergency_access");
+    }
+
+    #[test]
+    fn test_request_device_link_limited_by_default() {
+        exhaust("request_device_link");
+    }
+
+    #[test]
+    fn test_request_account_recovery_limited_by_default() {
+        exhaust("request_account_recovery");
+    }
 }
@@ -3,7 +3,7 @@ use std::{str::FromStr, time::Duration};
 
 use candid::{CandidType, Principal};
 use canister_common::{
-    common::{Id, ProviderId},
+    common::{AsciiRecordsKey, EmrHeader, EmrId, Id, ProviderId, Timestamp},
     deref,
     id_generator::IdGenerator,
     impl_max_size, impl_mem_bound, impl_range_bound, log, metrics,
@@ -13,9 +13,12 @@ use canister_common::{
     stable::{Candid, Memory, Stable, StableSet, ToStable},
     statistics::traits::Metrics,
 };
+use ic_stable_structures::Cell;
+use parity_scale_codec::{Decode, Encode};
 use serde::Deserialize;
 
 use crate::{
+    code::{self, RawCode},
     registry::{PatientRegistry, NIK},
     with_state,
 };
@@ -49,17 +52,31 @@ pub fn with_consent<R>(f: impl FnOnce(&ConsentMap) -> R) -> R {
 // change this if you want to change the expiry time of the consent code
 const EXPIRY: Duration = Duration::from_secs(60 * 60); // 1 hour
 
-// change this if you want to change the length of the consent code
-const CODE_LEN: usize = 6;
+// change this if you want to change how long a session lives after the consent is claimed
+const SESSION_EXPIRY: Duration = Duration::from_secs(60 * 60); // 1 hour
 
-// change this if you want to change the allowed characters in the consent code
-const ALLOWED_CHAR: [char; 10] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+// change this if you want to change how long a break-glass emergency session lives
+pub const EMERGENCY_SESSION_EXPIRY: Duration = Duration::from_secs(60 * 30); // 30 minutes
 
-/// A consent code is a 6 digit code that is used to identify a consent
-#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct ConsentCode([u8; CODE_LEN]);
-// benchmarked for candid encoding
-impl_max_size!(for ConsentCode: 14);
+// change this if you want to change how often expired consents are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 5); // 5 minutes
+
+// max amount of expired consents removed in a single sweep, the rest is picked up by the next sweep
+const MAX_SWEEP_PER_ROUND: usize = 500;
+
+// max amount of emr ids, providers and record keys a consent scope can hold
+const MAX_SCOPE_ITEMS: usize = 16;
+
+// change this if you want to change how long a standing consent can stay valid
+const MAX_STANDING_DAYS: u16 = 365;
+
+const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// A consent code is a 6 digit code, or optionally a 10 character alphanumeric code, that is used to identify a consent
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Encode, Decode)]
+pub struct ConsentCode(RawCode);
+// benchmarked for candid encoding, 14 bytes for numeric and 18 bytes for alphanumeric codes
+impl_max_size!(for ConsentCode: 18);
 impl_mem_bound!(for ConsentCode: bounded; fixed_size:false);
 impl_range_bound!(ConsentCode);
 
@@ -70,12 +87,16 @@ mod encode_test_consent_code {
     #[test]
     fn test_len_encoded() {
         use candid::{Decode, Encode};
-        let code = ConsentCode([b'1', b'2', b'3', b'4', b'5', b'6']);
+        let code = ConsentCode::from_str("123456").unwrap();
         let encoded = Encode!(&code).unwrap();
         println!("encoded: {:?}", encoded.len());
         let decoded: ConsentCode = Decode!(&encoded, ConsentCode).unwrap();
 
         assert_eq!(code, decoded);
+
+        let code = ConsentCode::alphanumeric_from_u64(u64::MAX);
+        let encoded = Encode!(&code).unwrap();
+        assert!(encoded.len() <= 18);
     }
 }
 
@@ -83,15 +104,17 @@ impl ConsentCode {
     /// the u32 is assumed to be random and unique
     // for now, we are using the last 6 digits of the u32
     pub fn from_u64(u: u64) -> Self {
-        let str = u.to_string();
-        let str = &str[str.len() - CODE_LEN..];
-        let mut code = [0; CODE_LEN];
+        ConsentCode(code::numeric(u))
+    }
 
-        for (i, c) in str.chars().enumerate() {
-            code[i] = c as u8;
-        }
+    /// the longer alphanumeric code, harder to guess than the numeric one
+    pub fn alphanumeric_from_u64(u: u64) -> Self {
+        ConsentCode(code::alphanumeric(u))
+    }
 
-        ConsentCode(code)
+    /// leading characters of the code, used to track failed claim attempts
+    pub fn prefix(&self) -> [u8; code::PREFIX_LEN] {
+        code::prefix(&self.0)
     }
 }
 
@@ -99,27 +122,13 @@ impl FromStr for ConsentCode {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() != CODE_LEN {
-            return Err("invalid length".to_string());
-        }
-
-        let mut code = [0; CODE_LEN];
-
-        for (i, c) in s.chars().enumerate() {
-            if !ALLOWED_CHAR.contains(&c) {
-                return Err("invalid character".to_string());
-            }
-
-            code[i] = c as u8;
-        }
-
-        Ok(ConsentCode(code))
+        code::parse(s).map(ConsentCode)
     }
 }
 
 impl ConsentCode {
     pub fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.0).unwrap()
+        code::as_str(&self.0)
     }
 
     pub fn from_text(text: &str) -> Result<Self, String> {
@@ -168,7 +177,7 @@ mod tests_code {
 
     #[test]
     fn test_consent_code() {
-        let code = ConsentCode([b'1', b'2', b'3', b'4', b'5', b'6']);
+        let code = ConsentCode::from_u64(123456);
         assert_eq!(code.as_str(), "123456");
     }
 
@@ -232,36 +241,76 @@ impl ConsentsApi {
         with_consent(|consents| consents.list_consent_with_patient(user))
     }
 
-    // we dont have expiry for now
-    // fn remove_consent_after_expiry(code: ConsentCode) {
-    //     ensure_initialized();
+    /// sessions currently open under the consent
+    pub fn sessions_of(code: &ConsentCode) -> Vec<SessionId> {
+        ensure_initialized();
+        with_consent(|consents| consents.sessions_of(code))
+    }
+
+    /// the consent a session was opened under along with the session bookkeeping
+    pub fn session_snapshot(session_id: &SessionId) -> Option<SessionSnapshot> {
+        ensure_initialized();
+        with_consent(|consents| consents.session_snapshot(session_id))
+    }
+
+    /// sessions currently open on the patient data
+    pub fn active_sessions(patient: &NIK) -> Vec<ActiveSession> {
+        ensure_initialized();
+        with_consent(|consents| consents.active_sessions(patient, &Timestamp::new()))
+    }
+
+    /// end a session opened on the patient data, returns the consent the session was opened with
+    pub fn terminate_session(session_id: &SessionId, patient: &NIK) -> Result<Consent, String> {
+        ensure_initialized();
+        with_consent_mut(|consents| consents.terminate_session(session_id, patient))
+    }
+
+    /// periodically remove expired consents and their sessions. the expiry is kept in stable memory
+    /// so this only needs to be restarted on every canister initialization, including upgrades.
+    fn start_expiry_sweep() {
+        ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || {
+            // consents are initialized asynchronously, skip until they are ready
+            if !INIT_FLAG.with(|cell| cell.get()) {
+                return;
+            }
+
+            let removed = with_consent_mut(|consents| consents.remove_expired(&Timestamp::new()));
 
-    //     ic_cdk_timers::set_timer(EXPIRY, move || {
-    //         let Some(consent) = with_consent_mut(|consent| consent.remove_consent(&code)) else {
-    //             return;
-    //         };
+            if removed > 0 {
+                log!("removed {} expired consents", removed);
+            }
 
-    //         match consent.session_id {
-    //             Some(ref session) =>
-    //                 with_consent_mut(|consent| consent.finish_session_unchecked(session)),
-    //             None => (),
-    //         }
-    //     });
-    // }
+            // piggyback on the sweep to forget stale failed claim attempts
+            crate::with_state_mut(|s| s.claim_throttle.prune(&Timestamp::new()));
+        });
+    }
 
-    pub fn generate_consent(nik: NIK) -> ConsentCode {
+    pub fn generate_consent(
+        nik: NIK,
+        scope: Option<ConsentScope>,
+        target: Option<ProviderId>,
+        standing: Option<StandingTerms>,
+        long_code: bool,
+        require_approval: bool,
+        purposes: Option<Vec<Purpose>>,
+    ) -> ConsentCode {
         ensure_initialized();
 
-        let partial = PartialConsent::new(nik);
+        let partial = PartialConsent::new(nik)
+            .with_scope(scope)
+            .with_target(target)
+            .with_standing(standing)
+            .with_long_code(long_code)
+            .with_approval(require_approval)
+            .with_purposes(purposes);
 
-        // we dont have expiry for now
-        // Self::remove_consent_after_expiry(code);
         with_consent_mut(|consents: &mut ConsentMap| consents.add_consent(partial))
     }
 
+    /// remove the consent along with every session opened under it
     pub fn revoke_consent(code: &ConsentCode) {
         ensure_initialized();
-        with_consent_mut(|consents| consents.remove_consent(code));
+        with_consent_mut(|consents| consents.expire(code));
     }
 
     pub fn is_session_allowed(code: &ConsentCode, session_id: &Id) -> bool {
@@ -274,19 +323,102 @@ impl ConsentsApi {
         with_consent(|consents| consents.is_claimed(code, patient))
     }
 
-    // TODO: temporary function to get patient list
     pub fn user_list_with_consent(user: &ProviderId) -> Vec<Consent> {
         ensure_initialized();
-        with_consent(|consents| consents.consent_list_with_user(user))
+        with_consent(|consents| consents.consent_list_with_user(user, &Timestamp::new()))
+    }
+
+    /// sessions the provider currently has open, oldest claim first
+    pub fn provider_sessions(provider: &ProviderId) -> Vec<ProviderSession> {
+        ensure_initialized();
+        with_consent(|consents| consents.provider_sessions(provider, &Timestamp::new()))
+    }
+
+    /// up to `limit` sessions of the provider kept by `filter_map`, starting at the given session
+    pub fn provider_sessions_page<T>(
+        provider: &ProviderId,
+        from: Option<(&Timestamp, &SessionId)>,
+        limit: usize,
+        filter_map: impl FnMut(ProviderSession) -> Option<T>,
+    ) -> Vec<T> {
+        ensure_initialized();
+        with_consent(|consents| {
+            consents
+                .provider_sessions_from(provider, from, &Timestamp::new())
+                .filter_map(filter_map)
+                .take(limit)
+                .collect()
+        })
+    }
+
+    /// number of sessions the provider has open, including expired ones not cleaned up yet
+    pub fn provider_session_count(provider: &ProviderId) -> u64 {
+        ensure_initialized();
+        with_consent(|consents| consents.provider_sessions.count(provider))
+    }
+
+    /// whether the provider currently has a session open with the patient
+    pub fn has_session(provider: &ProviderId, nik: &NIK) -> bool {
+        ensure_initialized();
+        with_consent(|consents| consents.has_session(provider, nik, &Timestamp::new()))
+    }
+
+    /// the subset of `headers` the provider can currently access through one of its sessions
+    pub fn readable_emrs(
+        provider: &ProviderId,
+        headers: Vec<EmrHeader>,
+        access: ConsentAccess,
+    ) -> Vec<EmrHeader> {
+        ensure_initialized();
+        with_consent(|consents| {
+            consents.readable_emrs(provider, headers, access, &Timestamp::new())
+        })
     }
 
     pub fn claim_consent(
         code: &ConsentCode,
         session_user: ProviderId,
+        purpose: Purpose,
     ) -> Option<(Id, canister_common::common::H256)> {
         ensure_initialized();
 
-        with_consent_mut(|consents| consents.claim_consent(code, session_user))
+        with_consent_mut(|consents| consents.claim_consent(code, session_user, purpose))
+    }
+
+    pub fn request_claim(
+        code: &ConsentCode,
+        provider: ProviderId,
+        purpose: Purpose,
+    ) -> Result<ClaimApproval, String> {
+        ensure_initialized();
+        with_consent_mut(|consents| {
+            consents.request_claim(code, provider, purpose, &Timestamp::new())
+        })
+    }
+
+    /// see [ConsentMap::open_emergency_session]
+    pub fn open_emergency_session(nik: NIK, provider: ProviderId) -> (SessionId, Timestamp) {
+        ensure_initialized();
+        with_consent_mut(|consents| {
+            consents.open_emergency_session(nik, provider, &Timestamp::new())
+        })
+    }
+
+    pub fn decide_claim(
+        code: &ConsentCode,
+        patient: &NIK,
+        approve: bool,
+    ) -> Result<(ProviderId, Option<SessionId>), String> {
+        ensure_initialized();
+        with_consent_mut(|consents| {
+            consents.decide_claim(code, patient, approve, &Timestamp::new())
+        })
+    }
+
+    /// consents granted directly to the provider that have not been accepted yet
+    pub fn pending_consents(provider: &ProviderId) -> Vec<Consent> {
+        ensure_initialized();
+        with_consent(|consents| consents.pending_consents(provider, &Timestamp::new()))
     }
 
     pub fn finish_sesion(session_id: &Id, session_user: &ProviderId) {
@@ -304,17 +436,28 @@ impl ConsentsApi {
         let consent =
             with_consent_mut(|consents| consents.resolve_session(session_id, session_user));
 
-        match consent {
-            Some(consent) => {
-                Ok(PatientRegistry::do_call_read_emr(req.to_args(consent.nik), registry).await)
-            }
-            None => Err("invalid session".to_string()),
+        let Some(consent) = consent else {
+            return Err("invalid session".to_string());
+        };
+
+        if !consent.allows_emr(&req.emr_id, &req.provider_id) {
+            return Err("emr is not covered by the consent scope".to_string());
         }
+
+        let mut response =
+            PatientRegistry::do_call_read_emr(req.to_args(consent.nik.clone()), registry).await;
+        response
+            .emr
+            .body
+            .retain(|fragment| consent.allows_key(&fragment.key));
+
+        Ok(response)
     }
 
     /// call this function in the init method of the canister
     pub fn init() {
         ConsentMap::init();
+        Self::start_expiry_sweep();
     }
 
     /// claims a consent code specifically for group membership
@@ -337,6 +480,231 @@ impl ConsentsApi {
 
 pub type SessionId = Id;
 
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+/// sessions are read only unless the consent scope grants write access, which lets the provider
+/// update the emrs within the scope even if it didn't issue them
+pub enum ConsentAccess {
+    #[default]
+    ReadOnly,
+    ReadWrite,
+}
+
+/// limits what a session created from a consent can see. empty lists mean no restriction
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConsentScope {
+    /// only these emrs can be listed and read
+    pub emr_ids: Vec<EmrId>,
+    /// only emrs issued by these providers can be listed and read
+    pub providers: Vec<ProviderId>,
+    /// only these record keys are returned when reading an emr
+    pub keys: Vec<AsciiRecordsKey>,
+    pub access: ConsentAccess,
+}
+
+impl ConsentScope {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.emr_ids.len() > MAX_SCOPE_ITEMS
+            || self.providers.len() > MAX_SCOPE_ITEMS
+            || self.keys.len() > MAX_SCOPE_ITEMS
+        {
+            return Err(format!(
+                "consent scope can hold at most {} emrs, providers and keys each",
+                MAX_SCOPE_ITEMS
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// whether the scope limits which emrs are visible, as opposed to only limiting the records
+    pub fn restricts_emrs(&self) -> bool {
+        !self.emr_ids.is_empty() || !self.providers.is_empty()
+    }
+
+    pub fn allows_emr(&self, emr_id: &EmrId, provider_id: &ProviderId) -> bool {
+        (self.emr_ids.is_empty() || self.emr_ids.contains(emr_id))
+            && (self.providers.is_empty() || self.providers.contains(provider_id))
+    }
+
+    pub fn allows_key(&self, key: &str) -> bool {
+        self.keys.is_empty() || self.keys.iter().any(|k| k.to_ascii_str() == key)
+    }
+
+    pub fn allows_access(&self, access: ConsentAccess) -> bool {
+        access <= self.access
+    }
+}
+
+/// terms requested by the patient when creating a standing consent
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StandingTerms {
+    /// the consent stays valid for this many days
+    pub days: u16,
+    /// [None] means the provider can open as many sessions as they want until the consent expires
+    pub max_sessions: Option<u32>,
+}
+
+impl StandingTerms {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.days == 0 || self.days > MAX_STANDING_DAYS {
+            return Err(format!(
+                "standing consent must be valid for 1 to {} days",
+                MAX_STANDING_DAYS
+            ));
+        }
+
+        if self.max_sessions == Some(0) {
+            return Err("standing consent must allow at least 1 session".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn valid_for(&self) -> Duration {
+        DAY * self.days as u32
+    }
+}
+
+/// a standing consent can be claimed repeatedly by the same provider, every claim opens a new session
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StandingConsent {
+    pub max_sessions: Option<u32>,
+    pub sessions_opened: u32,
+}
+
+impl StandingConsent {
+    pub fn has_sessions_left(&self) -> bool {
+        self.max_sessions
+            .is_none_or(|max| self.sessions_opened < max)
+    }
+}
+
+/// what the patient data is accessed for
+#[derive(
+    CandidType,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Encode,
+    Decode,
+)]
+pub enum Purpose {
+    #[default]
+    Treatment,
+    Emergency,
+    Insurance,
+    Research,
+    Legal,
+}
+
+impl_max_size!(for Purpose: Purpose);
+impl_mem_bound!(for Purpose: bounded; fixed_size: true);
+
+impl Purpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Treatment => "treatment",
+            Self::Emergency => "emergency",
+            Self::Insurance => "insurance",
+            Self::Research => "research",
+            Self::Legal => "legal",
+        }
+    }
+
+    /// the purposes a consent allows, must not be empty
+    pub fn validate_list(purposes: &[Purpose]) -> Result<(), String> {
+        if purposes.is_empty() {
+            return Err("consent must allow at least one purpose".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// state of a consent that needs the patient to approve claims
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClaimApproval {
+    /// nobody asked to claim the consent yet
+    Required,
+    Pending {
+        provider_id: ProviderId,
+        requested_at: Timestamp,
+        /// optional only to be able to decode claims requested before purposes existed
+        purpose: Option<Purpose>,
+    },
+    /// the session is opened as soon as the patient confirms
+    Approved {
+        provider_id: ProviderId,
+        decided_at: Timestamp,
+    },
+    /// the consent can't be claimed anymore, it's kept until it expires so the provider can see the rejection
+    Rejected {
+        provider_id: ProviderId,
+        decided_at: Timestamp,
+    },
+}
+
+impl ClaimApproval {
+    /// the provider that asked to claim the consent, [None] if nobody asked yet
+    pub fn provider_id(&self) -> Option<&ProviderId> {
+        match self {
+            Self::Required => None,
+            Self::Pending { provider_id, .. }
+            | Self::Approved { provider_id, .. }
+            | Self::Rejected { provider_id, .. } => Some(provider_id),
+        }
+    }
+}
+
+/// bookkeeping for a single session, kept apart from the consent as a standing consent can have many sessions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct SessionInfo {
+    pub claimed_at: Timestamp,
+    pub last_access: Timestamp,
+}
+
+impl_max_size!(for SessionInfo: Timestamp, Timestamp);
+impl_mem_bound!(for SessionInfo: bounded; fixed_size: true);
+
+/// a session as seen by the provider
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProviderSession {
+    pub session_id: SessionId,
+    pub code: ConsentCode,
+    pub nik: NIK,
+    pub claimed_at: Timestamp,
+    pub last_access: Timestamp,
+    pub expires_at: Option<Timestamp>,
+    pub purpose: Purpose,
+}
+
+/// a session as seen by the patient
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ActiveSession {
+    pub session_id: SessionId,
+    pub code: ConsentCode,
+    pub provider_id: ProviderId,
+    pub claimed_at: Timestamp,
+    /// last time the provider used the session to read the patient data
+    pub last_access: Timestamp,
+    pub expires_at: Option<Timestamp>,
+    pub purpose: Purpose,
+}
+
+/// a session along with the consent it was opened under, taken before the session is finished
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    pub consent: Consent,
+    pub info: SessionInfo,
+    pub purpose: Purpose,
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Consent {
     pub code: ConsentCode,
@@ -345,6 +713,19 @@ pub struct Consent {
     pub session_id: Option<SessionId>,
     pub session_user: Option<ProviderId>,
     pub group_claimer: Option<Principal>,
+    /// the code can't be claimed after this, once claimed the session can't be used after this.
+    /// optional only to be able to decode consents stored before expiry existed
+    pub expires_at: Option<Timestamp>,
+    /// [None] means the session can access every emr of the patient
+    pub scope: Option<ConsentScope>,
+    /// consent granted directly to a provider, only that provider can claim it
+    pub target: Option<ProviderId>,
+    /// [None] for one off consents, `session_id` then points to the latest session opened under it
+    pub standing: Option<StandingConsent>,
+    /// [None] if claims don't need the patient approval
+    pub approval: Option<ClaimApproval>,
+    /// what sessions opened under the consent may be used for, [None] only allows treatment
+    pub purposes: Option<Vec<Purpose>>,
 }
 #[cfg(test)]
 mod encode_test_consent {
@@ -356,16 +737,40 @@ mod encode_test_consent {
     fn test_len_encoded() {
         use candid::{Decode, Encode};
         let code = Consent {
-            code: ConsentCode([b'1', b'2', b'3', b'4', b'5', b'6']),
+            code: ConsentCode::alphanumeric_from_u64(u64::MAX),
             nik: NIK::from_str("3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709")
                 .unwrap(),
             claimed: false,
             session_id: Some(id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3")),
             session_user: Some(id!("60673662-792a-4e50-b7aa-eccf7e4146a3")),
             group_claimer: None,
+            expires_at: Some(Timestamp::new()),
+            scope: Some(ConsentScope {
+                emr_ids: vec![id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3"); MAX_SCOPE_ITEMS],
+                providers: vec![id!("60673662-792a-4e50-b7aa-eccf7e4146a3"); MAX_SCOPE_ITEMS],
+                keys: vec![AsciiRecordsKey::new("a".repeat(32)).unwrap(); MAX_SCOPE_ITEMS],
+                access: ConsentAccess::ReadWrite,
+            }),
+            target: Some(id!("60673662-792a-4e50-b7aa-eccf7e4146a3")),
+            standing: Some(StandingConsent {
+                max_sessions: Some(u32::MAX),
+                sessions_opened: u32::MAX,
+            }),
+            approval: Some(ClaimApproval::Rejected {
+                provider_id: id!("60673662-792a-4e50-b7aa-eccf7e4146a3"),
+                decided_at: Timestamp::new(),
+            }),
+            purposes: Some(vec![
+                Purpose::Treatment,
+                Purpose::Emergency,
+                Purpose::Insurance,
+                Purpose::Research,
+                Purpose::Legal,
+            ]),
         };
         let encoded = Encode!(&code).unwrap();
         println!("encoded: {:?}", encoded.len());
+        assert!(encoded.len() <= 2304);
         let decoded: Consent = Decode!(&encoded, Consent).unwrap();
 
         assert_eq!(code, decoded);
@@ -374,12 +779,22 @@ mod encode_test_consent {
 
 // ~189 bytes benchmarked for candid encoding - @zian
 // increased to 256 bytes to accommodate new field "group_claimer" - @mylo
-impl_max_size!(for Consent: 256);
+// increased to 2048 bytes to accommodate a full consent scope, ~2kb benchmarked
+// increased to 2112 bytes to accommodate the consent target
+// increased to 2176 bytes to accommodate standing consents
+// increased to 2240 bytes to accommodate the claim approval
+// increased to 2304 bytes to accommodate the purposes
+impl_max_size!(for Consent: 2304);
 impl_mem_bound!(for Consent: bounded; fixed_size:false);
 impl_range_bound!(Consent);
 
 impl Consent {
     pub fn from_partial(partial: PartialConsent, code: ConsentCode) -> Self {
+        let valid_for = partial
+            .standing
+            .as_ref()
+            .map_or(EXPIRY, StandingTerms::valid_for);
+
         Consent {
             session_id: None,
             claimed: false,
@@ -387,6 +802,95 @@ impl Consent {
             nik: partial.nik,
             session_user: None,
             group_claimer: None,
+            expires_at: Some(Timestamp::new().after(valid_for)),
+            scope: partial.scope,
+            target: partial.target,
+            standing: partial.standing.map(|terms| StandingConsent {
+                max_sessions: terms.max_sessions,
+                sessions_opened: 0,
+            }),
+            approval: partial.require_approval.then_some(ClaimApproval::Required),
+            purposes: partial.purposes,
+        }
+    }
+
+    /// whether the given provider can open a new session with this consent right now.
+    /// a standing consent stays bound to the provider that claimed it first.
+    pub fn can_open_session(&self, provider: &ProviderId, now: &Timestamp) -> bool {
+        if self.is_expired(now)
+            || !self.is_claimable_by(provider)
+            || !self.is_approved_for(provider)
+        {
+            return false;
+        }
+
+        match self.standing {
+            Some(ref standing) => {
+                standing.has_sessions_left()
+                    && self
+                        .session_user
+                        .as_ref()
+                        .is_none_or(|user| user == provider)
+            }
+            None => !self.claimed,
+        }
+    }
+
+    /// a finished session only removes a standing consent once no more sessions can be opened
+    fn is_used_up(&self) -> bool {
+        self.standing
+            .as_ref()
+            .is_none_or(|standing| !standing.has_sessions_left())
+    }
+
+    /// whether the patient approved the provider claim, always true if the consent doesn't need approval
+    pub fn is_approved_for(&self, provider: &ProviderId) -> bool {
+        match self.approval {
+            None => true,
+            Some(ClaimApproval::Approved {
+                ref provider_id, ..
+            }) => provider_id == provider,
+            Some(_) => false,
+        }
+    }
+
+    pub fn allows_purpose(&self, purpose: &Purpose) -> bool {
+        match self.purposes {
+            Some(ref purposes) => purposes.contains(purpose),
+            None => purpose == &Purpose::Treatment,
+        }
+    }
+
+    /// whether the given provider is allowed to claim this consent
+    pub fn is_claimable_by(&self, provider: &ProviderId) -> bool {
+        self.target.as_ref().is_none_or(|target| target == provider)
+    }
+
+    pub fn allows_emr(&self, emr_id: &EmrId, provider_id: &ProviderId) -> bool {
+        self.scope
+            .as_ref()
+            .is_none_or(|scope| scope.allows_emr(emr_id, provider_id))
+    }
+
+    pub fn allows_key(&self, key: &str) -> bool {
+        self.scope
+            .as_ref()
+            .is_none_or(|scope| scope.allows_key(key))
+    }
+
+    /// consents without a scope are read only
+    pub fn allows_access(&self, access: ConsentAccess) -> bool {
+        match self.scope {
+            Some(ref scope) => scope.allows_access(access),
+            None => access == ConsentAccess::ReadOnly,
+        }
+    }
+
+    /// consents without an expiry predate expiry support and are treated as expired
+    pub fn is_expired(&self, now: &Timestamp) -> bool {
+        match self.expires_at {
+            Some(expires_at) => now >= &expires_at,
+            None => true,
         }
     }
 }
@@ -394,11 +898,57 @@ impl Consent {
 #[derive(CandidType, Debug, Deserialize, Clone)]
 pub struct PartialConsent {
     nik: NIK,
+    scope: Option<ConsentScope>,
+    target: Option<ProviderId>,
+    standing: Option<StandingTerms>,
+    long_code: bool,
+    require_approval: bool,
+    purposes: Option<Vec<Purpose>>,
 }
 
 impl PartialConsent {
     pub fn new(nik: NIK) -> Self {
-        Self { nik }
+        Self {
+            nik,
+            scope: None,
+            target: None,
+            standing: None,
+            long_code: false,
+            require_approval: false,
+            purposes: None,
+        }
+    }
+
+    pub fn with_purposes(mut self, purposes: Option<Vec<Purpose>>) -> Self {
+        self.purposes = purposes;
+        self
+    }
+
+    /// claims wait for the patient to confirm them before a session is opened
+    pub fn with_approval(mut self, require_approval: bool) -> Self {
+        self.require_approval = require_approval;
+        self
+    }
+
+    pub fn with_standing(mut self, standing: Option<StandingTerms>) -> Self {
+        self.standing = standing;
+        self
+    }
+
+    /// use the longer alphanumeric code instead of the 6 digit one
+    pub fn with_long_code(mut self, long_code: bool) -> Self {
+        self.long_code = long_code;
+        self
+    }
+
+    pub fn with_target(mut self, target: Option<ProviderId>) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn with_scope(mut self, scope: Option<ConsentScope>) -> Self {
+        self.scope = scope;
+        self
     }
 }
 
@@ -419,13 +969,23 @@ impl ProviderConsentSet {
 }
 deref!(mut ProviderConsentSet: StableSet<Stable<ProviderId>, Stable<SessionId>>);
 
+/// consents granted directly to a provider, waiting for the provider to accept them
+pub struct PendingConsentSet(StableSet<Stable<ProviderId>, Stable<ConsentCode>>);
+
+impl PendingConsentSet {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        PendingConsentSet(StableSet::init::<Self>(memory_manager))
+    }
+}
+deref!(mut PendingConsentSet: StableSet<Stable<ProviderId>, Stable<ConsentCode>>);
+
 pub struct InnerConsentMap(
     ic_stable_structures::BTreeMap<Stable<ConsentCode, Candid>, Stable<Consent, Candid>, Memory>,
 );
 
 impl InnerConsentMap {
     pub fn init(memory_manager: &MemoryManager) -> Self {
-        let map = memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::new);
+        let map = memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init);
 
         InnerConsentMap(map)
     }
@@ -439,7 +999,7 @@ pub struct SessionMap(
 
 impl SessionMap {
     pub fn init(memory_manager: &MemoryManager) -> Self {
-        let map = memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::new);
+        let map = memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init);
 
         SessionMap(map)
     }
@@ -447,82 +1007,533 @@ impl SessionMap {
 
 deref!(mut SessionMap: ic_stable_structures::BTreeMap<Stable<SessionId>, Stable<ConsentCode,Candid>, Memory>);
 
-// TODO: move all maps to stable memory
-pub struct ConsentMap {
-    provider_set: ProviderConsentSet,
-    inner: InnerConsentMap,
-    sessions: SessionMap,
-    // TODO: remove this after demo, move all of the structure into stable memory
-    // and then move the consent related functions to provider registry either all of them or part of it
-    rng: CanisterRandomSource,
+/// sessions opened under a consent, a standing consent can have more than one
+pub struct ConsentSessionSet(StableSet<Stable<ConsentCode>, Stable<SessionId>>);
+
+impl ConsentSessionSet {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        ConsentSessionSet(StableSet::init::<Self>(memory_manager))
+    }
 }
+deref!(mut ConsentSessionSet: StableSet<Stable<ConsentCode>, Stable<SessionId>>);
 
-mod metrics {
-    use super::*;
+pub struct SessionInfoMap(
+    ic_stable_structures::BTreeMap<Stable<SessionId>, Stable<SessionInfo>, Memory>,
+);
 
-    metrics!(ConsentMap: OngoingSession);
+impl SessionInfoMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let map = memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init);
 
-    impl Metrics<OngoingSession> for ConsentMap {
-        fn metrics_name() -> &'static str {
-            "consent_map"
-        }
+        SessionInfoMap(map)
+    }
+}
 
-        fn metrics_measurements() -> &'static str {
-            "ongoing_session"
-        }
+deref!(mut SessionInfoMap: ic_stable_structures::BTreeMap<Stable<SessionId>, Stable<SessionInfo>, Memory>);
 
-        fn update_measurements(&self) {
-            // no-op
-        }
+/// what each session was opened for, sessions opened before purposes existed are treated as treatment
+pub struct SessionPurposeMap(
+    ic_stable_structures::BTreeMap<Stable<SessionId>, Stable<Purpose>, Memory>,
+);
 
-        fn get_measurements(&self) -> String {
-            self.sessions.len().to_string()
-        }
+impl SessionPurposeMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let map = memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init);
+
+        SessionPurposeMap(map)
     }
 }
 
-impl ConsentMap {
-    pub fn is_session_user(&self, user: &Stable<ProviderId>) -> bool {
-        self.provider_set.is_session_user(user)
+deref!(mut SessionPurposeMap: ic_stable_structures::BTreeMap<Stable<SessionId>, Stable<Purpose>, Memory>);
+
+/// sessions of a provider are ordered by the time they were claimed
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub struct ProviderSessionKey {
+    /// nanoseconds, kept as a plain integer so the default key sorts before every session
+    claimed_at: u64,
+    session_id: SessionId,
+}
+
+impl_max_size!(for ProviderSessionKey: u64, SessionId);
+impl_mem_bound!(for ProviderSessionKey: bounded; fixed_size: true);
+impl_range_bound!(ProviderSessionKey);
+
+/// active sessions per provider, so listing the patients of a provider does not need to scan every consent
+pub struct ProviderSessionIndex(StableSet<Stable<ProviderId>, Stable<ProviderSessionKey>>);
+
+impl ProviderSessionIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        ProviderSessionIndex(StableSet::init::<Self>(memory_manager))
     }
 
-    pub fn new_with_seed(seed: u64, memory_manager: &MemoryManager) -> Self {
-        ConsentMap {
-            provider_set: ProviderConsentSet::init(memory_manager),
-            sessions: SessionMap::init(memory_manager),
-            inner: InnerConsentMap::init(memory_manager),
-            rng: CanisterRandomSource::new_with_seed(seed),
+    fn key(claimed_at: &Timestamp, session_id: &SessionId) -> Stable<ProviderSessionKey> {
+        ProviderSessionKey {
+            claimed_at: claimed_at.inner(),
+            session_id: session_id.clone(),
         }
+        .to_stable()
     }
 
-    //TODO: temporary function to get patient list
-    pub fn consent_list_with_user(&self, user: &ProviderId) -> Vec<Consent> {
-        self.inner
-            .iter()
-            .filter(|(_, consent)| consent.session_user.as_ref().eq(&Some(user)))
-            .map(|(_, consent)| consent.into_inner())
-            .collect()
+    pub fn add(&mut self, provider: &ProviderId, claimed_at: &Timestamp, session_id: &SessionId) {
+        self.0.insert(
+            provider.clone().to_stable(),
+            Self::key(claimed_at, session_id),
+        );
     }
 
-    pub fn new(rng: CanisterRandomSource, memory_manager: &MemoryManager) -> Self {
-        ConsentMap {
-            provider_set: ProviderConsentSet::init(memory_manager),
-            sessions: SessionMap::init(memory_manager),
-            inner: InnerConsentMap::init(memory_manager),
-            rng,
-        }
+    pub fn remove(
+        &mut self,
+        provider: &ProviderId,
+        claimed_at: &Timestamp,
+        session_id: &SessionId,
+    ) {
+        self.0.inner_mut().remove(&(
+            provider.clone().to_stable(),
+            Self::key(claimed_at, session_id),
+        ));
     }
 
-    /// call this every canister initialization
-    pub fn init() {
-        ic_cdk_timers::set_timer(Duration::from_secs(3), || {
-            ic_cdk::spawn(async move {
-                let rng = CanisterRandomSource::new().await;
-
-                let new_cell = with_state(|s| {
-                    let memory_manager = &s.memory_manager;
-                    ConsentMap::new(rng, memory_manager)
-                });
+    /// sessions of the provider claimed from the given session onwards, the given session included
+    pub fn sessions_from<'a>(
+        &'a self,
+        provider: &'a ProviderId,
+        from: Option<(&Timestamp, &SessionId)>,
+    ) -> impl Iterator<Item = SessionId> + 'a {
+        let start = from
+            .map(|(claimed_at, session_id)| Self::key(claimed_at, session_id))
+            .unwrap_or_default();
+
+        self.0
+            .inner()
+            .range((provider.clone().to_stable(), start)..)
+            .take_while(move |((key, _), _)| key.as_inner() == provider)
+            .map(|((_, key), _)| key.into_inner().session_id)
+    }
+
+    pub fn count(&self, provider: &ProviderId) -> u64 {
+        self.sessions_from(provider, None).count() as u64
+    }
+}
+
+/// active sessions per patient, so listing the sessions open on a patient does not need to scan every consent
+pub struct PatientSessionIndex(StableSet<Stable<NIK>, Stable<ProviderSessionKey>>);
+
+impl PatientSessionIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        PatientSessionIndex(StableSet::init::<Self>(memory_manager))
+    }
+
+    pub fn add(&mut self, nik: &NIK, claimed_at: &Timestamp, session_id: &SessionId) {
+        self.0.insert(
+            nik.clone().to_stable(),
+            ProviderSessionIndex::key(claimed_at, session_id),
+        );
+    }
+
+    pub fn remove(&mut self, nik: &NIK, claimed_at: &Timestamp, session_id: &SessionId) {
+        self.0.inner_mut().remove(&(
+            nik.clone().to_stable(),
+            ProviderSessionIndex::key(claimed_at, session_id),
+        ));
+    }
+
+    /// sessions opened on the patient data, oldest claim first
+    pub fn sessions(&self, nik: &NIK) -> Vec<SessionId> {
+        self.0
+            .get_set_associated_by_key(nik.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|key| key.into_inner().session_id)
+            .collect()
+    }
+}
+
+/// consent codes ordered by their expiry time, used to sweep expired consents without scanning every consent
+pub struct ConsentExpiryIndex(
+    ic_stable_structures::BTreeMap<(Stable<Timestamp>, Stable<ConsentCode, Candid>), (), Memory>,
+);
+
+impl ConsentExpiryIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let map = memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init);
+
+        ConsentExpiryIndex(map)
+    }
+
+    pub fn add(&mut self, consent: &Consent) {
+        if let Some(expires_at) = consent.expires_at {
+            self.0
+                .insert((expires_at.to_stable(), consent.code.to_stable()), ());
+        }
+    }
+
+    pub fn remove(&mut self, consent: &Consent) {
+        if let Some(expires_at) = consent.expires_at {
+            self.0
+                .remove(&(expires_at.to_stable(), consent.code.to_stable()));
+        }
+    }
+
+    /// codes expiring at or before `now`, oldest first
+    pub fn expired(&self, now: &Timestamp, limit: usize) -> Vec<ConsentCode> {
+        self.0
+            .iter()
+            .take_while(|((expires_at, _), _)| expires_at.as_inner() <= now)
+            .take(limit)
+            .map(|((_, code), _)| code.into_inner())
+            .collect()
+    }
+}
+
+/// whether the consents stored before expiry existed were removed, so the scan only runs once
+pub struct LegacyConsentFlag(Cell<bool, Memory>);
+
+impl LegacyConsentFlag {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(
+            memory_manager
+                .get_memory::<_, Self>(|m| Cell::init(m, false))
+                .unwrap(),
+        )
+    }
+}
+
+deref!(mut LegacyConsentFlag: Cell<bool, Memory>);
+
+// TODO: move all maps to stable memory
+pub struct ConsentMap {
+    provider_set: ProviderConsentSet,
+    inner: InnerConsentMap,
+    sessions: SessionMap,
+    expiry: ConsentExpiryIndex,
+    pending: PendingConsentSet,
+    consent_sessions: ConsentSessionSet,
+    session_info: SessionInfoMap,
+    provider_sessions: ProviderSessionIndex,
+    patient_sessions: PatientSessionIndex,
+    session_purpose: SessionPurposeMap,
+    legacy_removed: LegacyConsentFlag,
+    // TODO: remove this after demo, move all of the structure into stable memory
+    // and then move the consent related functions to provider registry either all of them or part of it
+    rng: CanisterRandomSource,
+}
+
+mod metrics {
+    use super::*;
+
+    metrics!(ConsentMap: OngoingSession);
+
+    impl Metrics<OngoingSession> for ConsentMap {
+        fn metrics_name() -> &'static str {
+            "consent_map"
+        }
+
+        fn metrics_measurements() -> &'static str {
+            "ongoing_session"
+        }
+
+        fn update_measurements(&self) {
+            // no-op
+        }
+
+        fn get_measurements(&self) -> String {
+            self.sessions.len().to_string()
+        }
+    }
+}
+
+impl ConsentMap {
+    pub fn is_session_user(&self, user: &Stable<ProviderId>) -> bool {
+        self.provider_set.is_session_user(user)
+    }
+
+    pub fn new_with_seed(seed: u64, memory_manager: &MemoryManager) -> Self {
+        ConsentMap {
+            provider_set: ProviderConsentSet::init(memory_manager),
+            sessions: SessionMap::init(memory_manager),
+            inner: InnerConsentMap::init(memory_manager),
+            expiry: ConsentExpiryIndex::init(memory_manager),
+            pending: PendingConsentSet::init(memory_manager),
+            consent_sessions: ConsentSessionSet::init(memory_manager),
+            session_info: SessionInfoMap::init(memory_manager),
+            provider_sessions: ProviderSessionIndex::init(memory_manager),
+            patient_sessions: PatientSessionIndex::init(memory_manager),
+            session_purpose: SessionPurposeMap::init(memory_manager),
+            legacy_removed: LegacyConsentFlag::init(memory_manager),
+            rng: CanisterRandomSource::new_with_seed(seed),
+        }
+    }
+
+    /// consents the provider currently has a session with, one entry per consent
+    pub fn consent_list_with_user(&self, user: &ProviderId, now: &Timestamp) -> Vec<Consent> {
+        let mut consents = self
+            .provider_sessions(user, now)
+            .into_iter()
+            .filter_map(|session| self.consent(&session.code))
+            .collect::<Vec<_>>();
+
+        consents.sort_by_key(|consent| consent.code);
+        consents.dedup_by(|a, b| a.code == b.code);
+        consents
+    }
+
+    /// sessions the provider currently has open, oldest claim first
+    pub fn provider_sessions(
+        &self,
+        provider: &ProviderId,
+        now: &Timestamp,
+    ) -> Vec<ProviderSession> {
+        self.provider_sessions_from(provider, None, now).collect()
+    }
+
+    /// sessions of the provider claimed from the given session onwards, oldest claim first
+    pub fn provider_sessions_from<'a>(
+        &'a self,
+        provider: &'a ProviderId,
+        from: Option<(&Timestamp, &SessionId)>,
+        now: &'a Timestamp,
+    ) -> impl Iterator<Item = ProviderSession> + 'a {
+        self.provider_sessions
+            .sessions_from(provider, from)
+            .filter_map(move |session_id| {
+                let code = self.sessions.get(session_id.to_stable_ref())?.into_inner();
+                let consent = self.consent(&code).filter(|c| !c.is_expired(now))?;
+                let info = self.session_info.get(session_id.to_stable_ref())?;
+
+                Some(ProviderSession {
+                    purpose: self.session_purpose(&session_id),
+                    session_id,
+                    code,
+                    nik: consent.nik,
+                    claimed_at: info.claimed_at,
+                    last_access: info.last_access,
+                    expires_at: consent.expires_at,
+                })
+            })
+    }
+
+    pub fn has_session(&self, provider: &ProviderId, nik: &NIK, now: &Timestamp) -> bool {
+        self.provider_sessions(provider, now)
+            .iter()
+            .any(|session| session.nik.eq(nik))
+    }
+
+    /// keep only the emrs covered by a session the provider has open with the given access,
+    /// honoring the consent scope
+    pub fn readable_emrs(
+        &self,
+        provider: &ProviderId,
+        headers: Vec<EmrHeader>,
+        access: ConsentAccess,
+        now: &Timestamp,
+    ) -> Vec<EmrHeader> {
+        let consents = self
+            .provider_sessions(provider, now)
+            .into_iter()
+            .filter_map(|session| self.consent(&session.code))
+            .filter(|consent| consent.allows_access(access))
+            .collect::<Vec<_>>();
+
+        headers
+            .into_iter()
+            .filter(|header| {
+                consents.iter().any(|consent| {
+                    consent.nik == header.user_id
+                        && consent.allows_emr(&header.emr_id, &header.provider_id)
+                })
+            })
+            .collect()
+    }
+
+    pub fn new(rng: CanisterRandomSource, memory_manager: &MemoryManager) -> Self {
+        let mut consents = ConsentMap {
+            provider_set: ProviderConsentSet::init(memory_manager),
+            sessions: SessionMap::init(memory_manager),
+            inner: InnerConsentMap::init(memory_manager),
+            expiry: ConsentExpiryIndex::init(memory_manager),
+            pending: PendingConsentSet::init(memory_manager),
+            consent_sessions: ConsentSessionSet::init(memory_manager),
+            session_info: SessionInfoMap::init(memory_manager),
+            provider_sessions: ProviderSessionIndex::init(memory_manager),
+            patient_sessions: PatientSessionIndex::init(memory_manager),
+            session_purpose: SessionPurposeMap::init(memory_manager),
+            legacy_removed: LegacyConsentFlag::init(memory_manager),
+            rng,
+        };
+
+        consents.remove_legacy();
+        consents.index_provider_sessions();
+        consents.index_patient_sessions();
+        consents
+    }
+
+    /// sessions opened before the provider session index existed are not indexed yet, add them once on initialization.
+    /// sessions without bookkeeping are treated as claimed now.
+    fn index_provider_sessions(&mut self) {
+        if self.provider_sessions.0.len() > 0 {
+            return;
+        }
+
+        let now = Timestamp::new();
+        let sessions = self
+            .sessions
+            .iter()
+            .filter_map(|(session_id, code)| {
+                let provider = self.consent(code.as_inner())?.session_user?;
+                Some((session_id.into_inner(), provider))
+            })
+            .collect::<Vec<_>>();
+
+        for (session_id, provider) in sessions {
+            let info = match self.session_info.get(session_id.to_stable_ref()) {
+                Some(info) => info.into_inner(),
+                None => {
+                    let info = SessionInfo {
+                        claimed_at: now,
+                        last_access: now,
+                    };
+                    self.session_info
+                        .insert(session_id.clone().to_stable(), info.to_stable());
+                    info
+                }
+            };
+
+            self.provider_sessions
+                .add(&provider, &info.claimed_at, &session_id);
+        }
+    }
+
+    /// sessions opened before the patient session index existed are not indexed yet, add them once on initialization.
+    /// both indexes are kept in lockstep, so an empty patient index next to a filled provider index means it was never built.
+    fn index_patient_sessions(&mut self) {
+        if self.patient_sessions.0.len() > 0 || self.provider_sessions.0.len() == 0 {
+            return;
+        }
+
+        let sessions = self
+            .sessions
+            .iter()
+            .filter_map(|(session_id, code)| {
+                let nik = self.consent(code.as_inner())?.nik;
+                let info = self.session_info.get(&session_id)?;
+                Some((session_id.into_inner(), nik, info.claimed_at))
+            })
+            .collect::<Vec<_>>();
+
+        for (session_id, nik, claimed_at) in sessions {
+            self.patient_sessions.add(&nik, &claimed_at, &session_id);
+        }
+    }
+
+    /// consents stored before expiry existed are not in the expiry index, so remove them once on initialization.
+    /// they never survived an upgrade before anyway.
+    fn remove_legacy(&mut self) {
+        if *self.legacy_removed.get() {
+            return;
+        }
+
+        let legacy = self
+            .inner
+            .iter()
+            .filter(|(_, consent)| consent.expires_at.is_none())
+            .map(|(code, _)| code.into_inner())
+            .collect::<Vec<_>>();
+
+        for code in legacy {
+            self.expire(&code);
+        }
+
+        self.legacy_removed.set(true).unwrap();
+    }
+
+    /// remove the consent along with every session opened under it
+    fn expire(&mut self, code: &ConsentCode) {
+        let Some(consent) = self.remove_consent(code) else {
+            return;
+        };
+
+        // sessions opened before the session index existed
+        if let Some(ref session_id) = consent.session_id {
+            self.sessions.remove(session_id.to_stable_ref());
+        }
+
+        for session_id in self.sessions_of(code) {
+            self.sessions.remove(session_id.to_stable_ref());
+            self.forget_session(&consent, &session_id);
+        }
+    }
+
+    /// remove the bookkeeping of a session opened under the consent
+    fn forget_session(&mut self, consent: &Consent, session_id: &SessionId) {
+        let info = self.session_info.remove(session_id.to_stable_ref());
+        self.session_purpose.remove(session_id.to_stable_ref());
+
+        if let Some(ref info) = info {
+            self.patient_sessions
+                .remove(&consent.nik, &info.claimed_at, session_id);
+        }
+
+        if let (Some(info), Some(provider)) = (info, consent.session_user.as_ref()) {
+            self.provider_sessions
+                .remove(provider, &info.claimed_at, session_id);
+        }
+
+        self.consent_sessions
+            .inner_mut()
+            .remove(&(consent.code.to_stable(), session_id.clone().to_stable()));
+    }
+
+    /// the consent a session was opened under along with the session bookkeeping
+    pub fn session_snapshot(&self, session_id: &SessionId) -> Option<SessionSnapshot> {
+        let code = self.sessions.get(session_id.to_stable_ref())?;
+        let consent = self.consent(code.as_inner())?;
+        let info = self.session_info.get(session_id.to_stable_ref())?;
+
+        Some(SessionSnapshot {
+            consent,
+            info: info.into_inner(),
+            purpose: self.session_purpose(session_id),
+        })
+    }
+
+    pub fn session_purpose(&self, session_id: &SessionId) -> Purpose {
+        self.session_purpose
+            .get(session_id.to_stable_ref())
+            .map(Stable::into_inner)
+            .unwrap_or_default()
+    }
+
+    /// sessions currently open under the consent
+    pub fn sessions_of(&self, code: &ConsentCode) -> Vec<SessionId> {
+        self.consent_sessions
+            .get_set_associated_by_key(code.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(Stable::into_inner)
+            .collect()
+    }
+
+    /// remove consents that expired at or before `now`, returns the amount of consents removed
+    pub fn remove_expired(&mut self, now: &Timestamp) -> usize {
+        let expired = self.expiry.expired(now, MAX_SWEEP_PER_ROUND);
+
+        for code in expired.iter() {
+            self.expire(code);
+        }
+
+        expired.len()
+    }
+
+    /// call this every canister initialization
+    pub fn init() {
+        ic_cdk_timers::set_timer(Duration::from_secs(3), || {
+            ic_cdk::spawn(async move {
+                let rng = CanisterRandomSource::new().await;
+
+                let new_cell = with_state(|s| {
+                    let memory_manager = &s.memory_manager;
+                    ConsentMap::new(rng, memory_manager)
+                });
 
                 CONSENTS.replace(Some(new_cell));
 
@@ -547,9 +1558,19 @@ impl ConsentMap {
     pub fn add_consent(&mut self, partial: PartialConsent) -> ConsentCode {
         let random = self.rng.raw_random_u64();
 
-        let code = ConsentCode::from_u64(random);
+        let code = match partial.long_code {
+            true => ConsentCode::alphanumeric_from_u64(random),
+            false => ConsentCode::from_u64(random),
+        };
         let consent = Consent::from_partial(partial, code);
 
+        self.expiry.add(&consent);
+
+        if let Some(ref target) = consent.target {
+            self.pending
+                .insert(target.clone().to_stable(), code.to_stable());
+        }
+
         assert!(self
             .inner
             .insert(code.to_stable(), consent.to_stable())
@@ -558,9 +1579,68 @@ impl ConsentMap {
         code
     }
 
+    /// open a read only emergency session on the patient without their consent code.
+    /// backed by a consent targeted at the provider so the patient can see and terminate it like any other session
+    pub fn open_emergency_session(
+        &mut self,
+        nik: NIK,
+        provider: ProviderId,
+        now: &Timestamp,
+    ) -> (SessionId, Timestamp) {
+        let partial = PartialConsent::new(nik)
+            .with_target(Some(provider.clone()))
+            .with_scope(Some(ConsentScope {
+                emr_ids: vec![],
+                providers: vec![],
+                keys: vec![],
+                access: ConsentAccess::ReadOnly,
+            }))
+            .with_purposes(Some(vec![Purpose::Emergency]));
+        let code = self.add_consent(partial);
+
+        let (session_id, _) = self
+            .claim_consent(&code, provider, Purpose::Emergency)
+            .expect("a fresh consent can always be claimed by its target");
+
+        let mut consent = self.consent(&code).expect("consent was just added");
+        let expires_at = now.after(EMERGENCY_SESSION_EXPIRY);
+        self.expiry.remove(&consent);
+        consent.expires_at = Some(expires_at);
+        self.expiry.add(&consent);
+        self.inner.insert(code.to_stable(), consent.to_stable());
+
+        (session_id, expires_at)
+    }
+
+    fn remove_pending(&mut self, consent: &Consent) {
+        if let Some(ref target) = consent.target {
+            self.pending
+                .inner_mut()
+                .remove(&(target.clone().to_stable(), consent.code.to_stable()));
+        }
+    }
+
+    /// unclaimed, unexpired consents granted directly to the given provider
+    pub fn pending_consents(&self, provider: &ProviderId, now: &Timestamp) -> Vec<Consent> {
+        self.pending
+            .get_set_associated_by_key(provider.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|code| self.consent(code.as_inner()))
+            .filter(|consent| !consent.claimed && !consent.is_expired(now))
+            .collect()
+    }
+
     pub fn ensure_session_allowed(&self, code: &ConsentCode, session_id: &Id) -> bool {
+        let is_consent_session = self
+            .sessions
+            .get(session_id.to_stable_ref())
+            .is_some_and(|session_code| session_code.as_inner() == code);
+
         match self.inner.get(code.to_stable_ref()) {
-            Some(consent) => consent.claimed && consent.session_id.as_ref().eq(&Some(session_id)),
+            Some(consent) => {
+                consent.claimed && is_consent_session && !consent.is_expired(&Timestamp::new())
+            }
             None => false,
         }
     }
@@ -589,9 +1669,17 @@ impl ConsentMap {
     }
 
     pub fn remove_consent(&mut self, code: &ConsentCode) -> Option<Consent> {
-        self.inner
+        let consent = self
+            .inner
             .remove(code.to_stable_ref())
-            .map(|c| c.into_inner())
+            .map(|c| c.into_inner());
+
+        if let Some(ref consent) = consent {
+            self.expiry.remove(consent);
+            self.remove_pending(consent);
+        }
+
+        consent
     }
 
     pub fn ensure_correct_session_owner(&self, session_id: &Id, session_user: &ProviderId) -> bool {
@@ -614,11 +1702,22 @@ impl ConsentMap {
     }
 
     pub fn finish_session_unchecked(&mut self, session_id: &SessionId) {
-        let code = self.sessions.remove(session_id.to_stable_ref());
+        let Some(code) = self.sessions.remove(session_id.to_stable_ref()) else {
+            return;
+        };
+        let code = code.into_inner();
+
+        // no-op if the consent is already removed, its sessions were forgotten along with it
+        let Some(consent) = self.consent(&code) else {
+            return;
+        };
+
+        self.forget_session(&consent, session_id);
 
-        // remove the consent if the session is finished, no-op if the consent is already removed
-        if let Some(ref code) = code {
-            self.remove_consent(code);
+        // remove the consent if the session is finished.
+        // a standing consent is kept as long as the provider can still open sessions with it
+        if consent.is_used_up() && self.sessions_of(&code).is_empty() {
+            self.remove_consent(&code);
         }
     }
 
@@ -630,17 +1729,20 @@ impl ConsentMap {
             .collect()
     }
 
-    /// wil return none if consent is already claimed or does not exist
+    /// wil return none if consent is already claimed, expired or does not exist
     pub fn claim_consent(
         &mut self,
         code: &ConsentCode,
         session_user: ProviderId,
+        purpose: Purpose,
     ) -> Option<(SessionId, NIK)> {
         let Some(mut consent) = self.inner.get(code.to_stable_ref()) else {
             return None;
         };
 
-        if consent.claimed {
+        let now = Timestamp::new();
+
+        if !consent.can_open_session(&session_user, &now) || !consent.allows_purpose(&purpose) {
             return None;
         }
 
@@ -648,6 +1750,20 @@ impl ConsentMap {
         let session_id =
             IdGenerator::<CanisterRandomSource>::generate_id_with_different_source(rng);
 
+        self.remove_pending(&consent);
+
+        match consent.standing {
+            // standing consents keep their own expiry, sessions live as long as the consent
+            Some(ref mut standing) => standing.sessions_opened += 1,
+
+            // the session gets its own lifetime starting from the claim
+            None => {
+                self.expiry.remove(&consent);
+                consent.expires_at = Some(now.after(SESSION_EXPIRY));
+                self.expiry.add(&consent);
+            }
+        }
+
         consent.claimed = true;
         consent.session_id = Some(session_id.clone());
         consent.session_user = Some(session_user.clone());
@@ -665,14 +1781,119 @@ impl ConsentMap {
 
         self.inner.insert(code.to_stable(), consent);
 
+        self.consent_sessions
+            .insert(code.to_stable(), session_id.clone().to_stable());
+
+        let info = SessionInfo {
+            claimed_at: now,
+            last_access: now,
+        };
+        self.session_info
+            .insert(session_id.clone().to_stable(), info.to_stable());
+        self.provider_sessions.add(&session_user, &now, &session_id);
+        self.patient_sessions.add(&nik, &now, &session_id);
+        self.session_purpose
+            .insert(session_id.clone().to_stable(), purpose.to_stable());
+
         self.provider_set
             .insert(session_user.to_stable(), session_id.clone().into());
 
         Some((session_id, nik))
     }
 
+    /// ask the patient to approve the provider claim on the consent
+    pub fn request_claim(
+        &mut self,
+        code: &ConsentCode,
+        provider: ProviderId,
+        purpose: Purpose,
+        now: &Timestamp,
+    ) -> Result<ClaimApproval, String> {
+        let mut consent = self.consent(code).ok_or("consent does not exist")?;
+
+        match consent.approval {
+            None => return Err("consent does not require approval, claim it directly".to_string()),
+            Some(ClaimApproval::Required) => (),
+            // asking again is a no-op
+            Some(
+                ref approval @ ClaimApproval::Pending {
+                    ref provider_id, ..
+                },
+            ) if provider_id == &provider => return Ok(approval.clone()),
+            Some(_) => return Err("consent already has a claim request".to_string()),
+        }
+
+        if consent.is_expired(now) || !consent.is_claimable_by(&provider) {
+            return Err("consent can not be claimed".to_string());
+        }
+
+        if !consent.allows_purpose(&purpose) {
+            return Err("purpose is not allowed by the consent".to_string());
+        }
+
+        let approval = ClaimApproval::Pending {
+            provider_id: provider,
+            requested_at: *now,
+            purpose: Some(purpose),
+        };
+        consent.approval = Some(approval.clone());
+        self.inner.insert(code.to_stable(), consent.to_stable());
+
+        Ok(approval)
+    }
+
+    /// confirm or reject the pending claim on the patient consent, confirming opens the session right away.
+    /// returns the provider that asked for the claim and the opened session
+    pub fn decide_claim(
+        &mut self,
+        code: &ConsentCode,
+        patient: &NIK,
+        approve: bool,
+        now: &Timestamp,
+    ) -> Result<(ProviderId, Option<SessionId>), String> {
+        let mut consent = self
+            .consent(code)
+            .filter(|consent| consent.nik.eq(patient))
+            .ok_or("consent does not exist")?;
+
+        let Some(ClaimApproval::Pending {
+            provider_id,
+            purpose,
+            ..
+        }) = consent.approval.clone()
+        else {
+            return Err("consent has no pending claim".to_string());
+        };
+
+        if consent.is_expired(now) {
+            return Err("consent already expired".to_string());
+        }
+
+        consent.approval = Some(match approve {
+            true => ClaimApproval::Approved {
+                provider_id: provider_id.clone(),
+                decided_at: *now,
+            },
+            false => ClaimApproval::Rejected {
+                provider_id: provider_id.clone(),
+                decided_at: *now,
+            },
+        });
+        self.inner.insert(code.to_stable(), consent.to_stable());
+
+        if !approve {
+            return Ok((provider_id, None));
+        }
+
+        let (session_id, _) = self
+            .claim_consent(code, provider_id.clone(), purpose.unwrap_or_default())
+            .ok_or("consent can not be claimed")?;
+
+        Ok((provider_id, Some(session_id)))
+    }
+
     /// resolve a given session id to consent if it has been claimed,
-    /// will return [None] the session if the consent is already removed.
+    /// will return [None] the session if the consent is already removed or expired.
     pub fn resolve_session(
         &mut self,
         session_id: &SessionId,
@@ -684,8 +1905,67 @@ impl ConsentMap {
             return None;
         };
 
-        // return the consent if it exists
-        self.safe_get_consent_for(code.as_inner(), session_user)
+        let now = Timestamp::new();
+
+        // return the consent if it exists and has not expired yet, the sweep removes it eventually
+        let consent = self
+            .safe_get_consent_for(code.as_inner(), session_user)
+            .filter(|consent| !consent.is_expired(&now))?;
+
+        // only sticks when resolved from an update call, queries discard it
+        if let Some(info) = self.session_info.get(session_id.to_stable_ref()) {
+            let info = SessionInfo {
+                last_access: now,
+                ..info.into_inner()
+            };
+            self.session_info
+                .insert(session_id.clone().to_stable(), info.to_stable());
+        }
+
+        Some(consent)
+    }
+
+    /// sessions open on the patient data, oldest claim first
+    pub fn active_sessions(&self, patient: &NIK, now: &Timestamp) -> Vec<ActiveSession> {
+        self.patient_sessions
+            .sessions(patient)
+            .into_iter()
+            .filter_map(|session_id| {
+                let code = self.sessions.get(session_id.to_stable_ref())?.into_inner();
+                let consent = self
+                    .consent(&code)
+                    .filter(|c| c.claimed && !c.is_expired(now))?;
+                let info = self.session_info.get(session_id.to_stable_ref())?;
+
+                Some(ActiveSession {
+                    purpose: self.session_purpose(&session_id),
+                    code,
+                    provider_id: consent.session_user?,
+                    claimed_at: info.claimed_at,
+                    last_access: info.last_access,
+                    expires_at: consent.expires_at,
+                    session_id,
+                })
+            })
+            .collect()
+    }
+
+    /// end a session on behalf of the patient, errors if the session is not opened on the patient data
+    pub fn terminate_session(
+        &mut self,
+        session_id: &SessionId,
+        patient: &NIK,
+    ) -> Result<Consent, String> {
+        let consent = self
+            .sessions
+            .get(session_id.to_stable_ref())
+            .and_then(|code| self.consent(code.as_inner()))
+            .filter(|consent| consent.nik.eq(patient))
+            .ok_or("session not found")?;
+
+        self.finish_session_unchecked(session_id);
+
+        Ok(consent)
     }
 
     pub fn resolve_session_with_code(&self, code: &ConsentCode, patient: &NIK) -> Option<Consent> {
@@ -743,7 +2023,9 @@ mod tests {
         let code = consents.add_consent(partial.clone());
         let provider_id = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
 
-        let (session_id, _) = consents.claim_consent(&code, provider_id.clone()).unwrap();
+        let (session_id, _) = consents
+            .claim_consent(&code, provider_id.clone(), Purpose::Treatment)
+            .unwrap();
 
         assert_eq!(
             consents
@@ -755,7 +2037,9 @@ mod tests {
             &session_id
         );
 
-        assert!(consents.claim_consent(&code, provider_id).is_none());
+        assert!(consents
+            .claim_consent(&code, provider_id, Purpose::Treatment)
+            .is_none());
     }
 
     #[test]
@@ -771,7 +2055,9 @@ mod tests {
         let partial = PartialConsent::new(nik.clone());
         let code = consents.add_consent(partial.clone());
 
-        let (session_id, _) = consents.claim_consent(&code, provider_id.clone()).unwrap();
+        let (session_id, _) = consents
+            .claim_consent(&code, provider_id.clone(), Purpose::Treatment)
+            .unwrap();
 
         assert!(consents.ensure_session_allowed(&code, &session_id));
 
@@ -787,6 +2073,548 @@ mod tests {
             .contains_key(provider_id.to_stable(), session_id.to_stable::<Scale>()))
     }
 
+    #[test]
+    fn test_consent_expiry() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let provider_id = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+
+        let unclaimed = consents.add_consent(PartialConsent::new(nik.clone()));
+        let claimed = consents.add_consent(PartialConsent::new(nik.clone()));
+        let (session_id, _) = consents
+            .claim_consent(&claimed, provider_id.clone(), Purpose::Treatment)
+            .unwrap();
+
+        let consent = consents.get_consent_uncheked(&claimed).unwrap();
+        assert!(!consent.is_expired(&Timestamp::new()));
+        assert!(consent.is_expired(&Timestamp::new().after(SESSION_EXPIRY * 2)));
+
+        // nothing is expired yet
+        assert_eq!(consents.remove_expired(&Timestamp::new()), 0);
+        assert!(consents
+            .resolve_session(&session_id, &provider_id)
+            .is_some());
+
+        assert_eq!(
+            consents.remove_expired(&Timestamp::new().after(EXPIRY + SESSION_EXPIRY)),
+            2
+        );
+        assert!(consents.get_consent_uncheked(&unclaimed).is_none());
+        assert!(consents.get_consent_uncheked(&claimed).is_none());
+        assert!(consents.sessions.get(session_id.to_stable_ref()).is_none());
+        assert!(consents.expiry.0.is_empty());
+    }
+
+    #[test]
+    fn test_consent_scope() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let lab = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let clinic = id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3");
+        let lab_result = id!("5d5dd2ec-0fe0-40dc-ae33-234252be26ed");
+        let other_result = id!("4e5dd2ec-0fe0-40dc-ae33-234252be26ed");
+
+        let scope = ConsentScope {
+            emr_ids: vec![lab_result.clone()],
+            providers: vec![lab.clone()],
+            keys: vec![AsciiRecordsKey::new("result").unwrap()],
+            access: ConsentAccess::ReadOnly,
+        };
+        assert!(scope.validate().is_ok());
+        assert!(scope.restricts_emrs());
+
+        let code = consents.add_consent(PartialConsent::new(nik.clone()).with_scope(Some(scope)));
+        let consent = consents.get_consent_uncheked(&code).unwrap();
+
+        assert!(consent.allows_emr(&lab_result, &lab));
+        assert!(!consent.allows_emr(&other_result, &lab));
+        assert!(!consent.allows_emr(&lab_result, &clinic));
+        assert!(consent.allows_key("result"));
+        assert!(!consent.allows_key("diagnosis"));
+
+        let code = consents.add_consent(PartialConsent::new(nik));
+        let consent = consents.get_consent_uncheked(&code).unwrap();
+        assert!(consent.allows_emr(&other_result, &clinic));
+        assert!(consent.allows_key("diagnosis"));
+
+        let too_wide = ConsentScope {
+            emr_ids: vec![lab_result; MAX_SCOPE_ITEMS + 1],
+            ..Default::default()
+        };
+        assert!(too_wide.validate().is_err());
+    }
+
+    #[test]
+    fn test_targeted_consent() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let pharmacy = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let stranger = id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3");
+        let now = Timestamp::new();
+
+        let code = consents
+            .add_consent(PartialConsent::new(nik.clone()).with_target(Some(pharmacy.clone())));
+
+        assert_eq!(consents.pending_consents(&pharmacy, &now).len(), 1);
+        assert!(consents.pending_consents(&stranger, &now).is_empty());
+
+        // only the target can claim it
+        assert!(consents
+            .claim_consent(&code, stranger, Purpose::Treatment)
+            .is_none());
+        assert!(consents
+            .claim_consent(&code, pharmacy.clone(), Purpose::Treatment)
+            .is_some());
+
+        assert!(consents.pending_consents(&pharmacy, &now).is_empty());
+
+        // revoking a pending consent removes it from the pending list
+        let code =
+            consents.add_consent(PartialConsent::new(nik).with_target(Some(pharmacy.clone())));
+        consents.remove_consent(&code);
+        assert!(consents
+            .pending
+            .get_set_associated_by_key(pharmacy.to_stable_ref())
+            .is_none());
+    }
+
+    #[test]
+    fn test_standing_consent() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let clinic = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let stranger = id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3");
+
+        let terms = StandingTerms {
+            days: 30,
+            max_sessions: Some(2),
+        };
+        assert!(terms.validate().is_ok());
+        assert!(StandingTerms { days: 0, ..terms }.validate().is_err());
+        assert!(StandingTerms {
+            max_sessions: Some(0),
+            ..terms
+        }
+        .validate()
+        .is_err());
+
+        let code =
+            consents.add_consent(PartialConsent::new(nik.clone()).with_standing(Some(terms)));
+
+        // still valid long after a one off consent would have expired
+        let consent = consents.get_consent_uncheked(&code).unwrap();
+        assert!(!consent.is_expired(&Timestamp::new().after(DAY * 29)));
+        assert!(consent.is_expired(&Timestamp::new().after(DAY * 31)));
+
+        // the first claimer is the only one that can open sessions
+        let (first, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+        assert!(consents
+            .claim_consent(&code, stranger, Purpose::Treatment)
+            .is_none());
+
+        // finishing a session keeps the consent around for the next visit
+        consents.finish_session_unchecked(&first);
+        assert!(consents.get_consent_uncheked(&code).is_some());
+
+        let (second, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+        assert!(consents.ensure_session_allowed(&code, &second));
+        assert!(!consents.ensure_session_allowed(&code, &first));
+
+        // out of sessions
+        assert!(consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .is_none());
+        let standing = consents
+            .get_consent_uncheked(&code)
+            .unwrap()
+            .standing
+            .unwrap();
+        assert_eq!(standing.sessions_opened, 2);
+
+        consents.finish_session_unchecked(&second);
+        assert!(consents.get_consent_uncheked(&code).is_none());
+
+        // revoking removes every open session
+        let terms = StandingTerms {
+            days: 1,
+            max_sessions: None,
+        };
+        let code = consents.add_consent(PartialConsent::new(nik).with_standing(Some(terms)));
+        let (first, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+        let (second, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+        assert_eq!(consents.sessions_of(&code).len(), 2);
+
+        consents.expire(&code);
+        assert!(consents.resolve_session(&first, &clinic).is_none());
+        assert!(consents.resolve_session(&second, &clinic).is_none());
+        assert!(consents.sessions_of(&code).is_empty());
+    }
+
+    #[test]
+    fn test_active_sessions() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let other =
+            NIK::from_str("3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709")
+                .unwrap();
+        let clinic = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let now = Timestamp::new();
+
+        let unclaimed = consents.add_consent(PartialConsent::new(nik.clone()));
+        let code = consents.add_consent(PartialConsent::new(nik.clone()));
+        let (session_id, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+
+        let sessions = consents.active_sessions(&nik, &now);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, session_id);
+        assert_eq!(sessions[0].provider_id, clinic);
+        assert!(sessions.iter().all(|s| s.code != unclaimed));
+
+        // using the session bumps the last access
+        let claimed_at = sessions[0].claimed_at;
+        consents.resolve_session(&session_id, &clinic).unwrap();
+        let sessions = consents.active_sessions(&nik, &now);
+        assert_eq!(sessions[0].claimed_at, claimed_at);
+        assert!(sessions[0].last_access >= claimed_at);
+
+        // only the patient the session was opened on can terminate it
+        assert!(consents.terminate_session(&session_id, &other).is_err());
+        assert!(consents.terminate_session(&session_id, &nik).is_ok());
+
+        assert!(consents.active_sessions(&nik, &now).is_empty());
+        assert!(consents.resolve_session(&session_id, &clinic).is_none());
+        assert!(consents.session_info.is_empty());
+        assert!(consents.patient_sessions.sessions(&nik).is_empty());
+    }
+
+    #[test]
+    fn test_provider_sessions() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let clinic = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let other_clinic = id!("4bd1a9e6-5d0e-4a8b-9a43-0c8e1e7a2f11");
+        let now = Timestamp::new();
+
+        let first = consents.add_consent(PartialConsent::new(nik.clone()));
+        let (first_session, _) = consents
+            .claim_consent(&first, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+        let second = consents.add_consent(PartialConsent::new(nik.clone()));
+        let (second_session, _) = consents
+            .claim_consent(&second, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+        let third = consents.add_consent(PartialConsent::new(nik.clone()));
+        consents
+            .claim_consent(&third, other_clinic.clone(), Purpose::Treatment)
+            .unwrap();
+
+        // ordered by claim time, oldest first
+        let sessions = consents.provider_sessions(&clinic, &now);
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|s| s.session_id.clone())
+                .collect::<Vec<_>>(),
+            vec![first_session.clone(), second_session.clone()]
+        );
+        assert!(sessions.iter().all(|s| s.nik == nik));
+        assert!(consents.has_session(&clinic, &nik, &now));
+        assert_eq!(consents.provider_sessions.count(&clinic), 2);
+
+        // a page starts at the given session
+        let from = &sessions[1];
+        assert_eq!(
+            consents
+                .provider_sessions_from(&clinic, Some((&from.claimed_at, &from.session_id)), &now)
+                .map(|s| s.session_id)
+                .collect::<Vec<_>>(),
+            vec![second_session.clone()]
+        );
+        assert_eq!(consents.consent_list_with_user(&clinic, &now).len(), 2);
+
+        // finished and revoked sessions are dropped from the index
+        consents.finish_session(&first_session, &clinic);
+        consents.expire(&second);
+        assert!(consents.provider_sessions(&clinic, &now).is_empty());
+        assert_eq!(consents.provider_sessions.count(&clinic), 0);
+        assert!(!consents.has_session(&clinic, &nik, &now));
+        assert_eq!(consents.provider_sessions(&other_clinic, &now).len(), 1);
+    }
+
+    #[test]
+    fn test_remove_legacy_once() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let add_legacy = |consents: &mut ConsentMap| {
+            let code = consents.add_consent(PartialConsent::new(nik.clone()));
+            let mut consent = consents.consent(&code).unwrap();
+            consent.expires_at = None;
+            consents.inner.insert(code.to_stable(), consent.to_stable());
+            code
+        };
+
+        let code = add_legacy(&mut consents);
+        consents.remove_legacy();
+        assert!(consents.consent(&code).is_none());
+
+        // the flag is persisted, so later initializations skip the scan
+        let code = add_legacy(&mut consents);
+        let consents = ConsentMap::new(CanisterRandomSource::new_with_seed(0), &memory_manager);
+        assert!(consents.consent(&code).is_some());
+    }
+
+    #[test]
+    fn test_readable_emrs() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let other_nik =
+            NIK::from_str("8b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+                .unwrap();
+        let clinic = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let lab = id!("4bd1a9e6-5d0e-4a8b-9a43-0c8e1e7a2f11");
+        let now = Timestamp::new();
+
+        let header = |nik: &NIK, emr_id: EmrId| {
+            EmrHeader::new(nik.clone(), emr_id, lab.clone(), Principal::anonymous())
+        };
+        let lab_result = header(&nik, id!("5d5dd2ec-0fe0-40dc-ae33-234252be26ed"));
+        let other_result = header(&nik, id!("4e5dd2ec-0fe0-40dc-ae33-234252be26ed"));
+        let other_patient = header(&other_nik, id!("3f5dd2ec-0fe0-40dc-ae33-234252be26ed"));
+        let headers = vec![
+            lab_result.clone(),
+            other_result.clone(),
+            other_patient.clone(),
+        ];
+
+        // no session, nothing is readable
+        assert!(consents
+            .readable_emrs(&clinic, headers.clone(), ConsentAccess::ReadOnly, &now)
+            .is_empty());
+
+        let scope = ConsentScope {
+            emr_ids: vec![lab_result.emr_id.clone()],
+            ..Default::default()
+        };
+        let code = consents.add_consent(PartialConsent::new(nik.clone()).with_scope(Some(scope)));
+        let (session, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+
+        // only the emrs of the session patient within the consent scope
+        assert_eq!(
+            consents.readable_emrs(&clinic, headers.clone(), ConsentAccess::ReadOnly, &now),
+            vec![lab_result.clone()]
+        );
+
+        // sessions are read only unless the scope says otherwise
+        assert!(consents
+            .readable_emrs(&clinic, headers.clone(), ConsentAccess::ReadWrite, &now)
+            .is_empty());
+
+        consents.finish_session(&session, &clinic);
+        assert!(consents
+            .readable_emrs(&clinic, headers.clone(), ConsentAccess::ReadOnly, &now)
+            .is_empty());
+
+        let scope = ConsentScope {
+            providers: vec![lab.clone()],
+            access: ConsentAccess::ReadWrite,
+            ..Default::default()
+        };
+        let code = consents.add_consent(PartialConsent::new(nik.clone()).with_scope(Some(scope)));
+        consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+
+        assert_eq!(
+            consents.readable_emrs(&clinic, headers, ConsentAccess::ReadWrite, &now),
+            vec![lab_result, other_result]
+        );
+    }
+
+    #[test]
+    fn test_claim_approval() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let other =
+            NIK::from_str("3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709")
+                .unwrap();
+        let clinic = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let other_clinic = id!("4bd1a9e6-5d0e-4a8b-9a43-0c8e1e7a2f11");
+        let now = Timestamp::new();
+
+        // consents without approval are claimed directly
+        let direct = consents.add_consent(PartialConsent::new(nik.clone()));
+        assert!(consents
+            .request_claim(&direct, clinic.clone(), Purpose::Treatment, &now)
+            .is_err());
+
+        let code = consents.add_consent(PartialConsent::new(nik.clone()).with_approval(true));
+        assert!(consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .is_none());
+
+        consents
+            .request_claim(&code, clinic.clone(), Purpose::Treatment, &now)
+            .unwrap();
+        assert!(consents
+            .request_claim(&code, clinic.clone(), Purpose::Treatment, &now)
+            .is_ok());
+        assert!(consents
+            .request_claim(&code, other_clinic.clone(), Purpose::Treatment, &now)
+            .is_err());
+        assert!(consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .is_none());
+
+        // only the patient the consent belongs to can decide
+        assert!(consents.decide_claim(&code, &other, true, &now).is_err());
+
+        let (provider, session_id) = consents.decide_claim(&code, &nik, true, &now).unwrap();
+        assert_eq!(provider, clinic);
+        let session_id = session_id.unwrap();
+        assert!(consents.resolve_session(&session_id, &clinic).is_some());
+        assert!(consents.decide_claim(&code, &nik, false, &now).is_err());
+
+        // a rejected claim never opens a session
+        let rejected = consents.add_consent(PartialConsent::new(nik.clone()).with_approval(true));
+        consents
+            .request_claim(&rejected, clinic.clone(), Purpose::Treatment, &now)
+            .unwrap();
+        let (_, session_id) = consents.decide_claim(&rejected, &nik, false, &now).unwrap();
+        assert!(session_id.is_none());
+        assert!(consents
+            .claim_consent(&rejected, clinic.clone(), Purpose::Treatment)
+            .is_none());
+        assert!(matches!(
+            consents.consent(&rejected).unwrap().approval,
+            Some(ClaimApproval::Rejected { .. })
+        ));
+    }
+
+    #[test]
+    fn test_consent_purposes() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let clinic = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let now = Timestamp::new();
+
+        // consents without purposes only allow treatment
+        let code = consents.add_consent(PartialConsent::new(nik.clone()));
+        assert!(consents
+            .claim_consent(&code, clinic.clone(), Purpose::Research)
+            .is_none());
+        let (session_id, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .unwrap();
+        assert_eq!(consents.session_purpose(&session_id), Purpose::Treatment);
+
+        let code = consents.add_consent(
+            PartialConsent::new(nik.clone())
+                .with_purposes(Some(vec![Purpose::Insurance, Purpose::Legal])),
+        );
+        assert!(consents
+            .claim_consent(&code, clinic.clone(), Purpose::Treatment)
+            .is_none());
+        let (session_id, _) = consents
+            .claim_consent(&code, clinic.clone(), Purpose::Insurance)
+            .unwrap();
+        assert_eq!(
+            consents.session_snapshot(&session_id).unwrap().purpose,
+            Purpose::Insurance
+        );
+
+        // forgotten along with the session
+        consents.finish_session(&session_id, &clinic);
+        assert_eq!(consents.session_purpose(&session_id), Purpose::Treatment);
+
+        let code = consents.add_consent(
+            PartialConsent::new(nik)
+                .with_purposes(Some(vec![Purpose::Emergency]))
+                .with_approval(true),
+        );
+        assert!(consents
+            .request_claim(&code, clinic.clone(), Purpose::Treatment, &now)
+            .is_err());
+        consents
+            .request_claim(&code, clinic.clone(), Purpose::Emergency, &now)
+            .unwrap();
+
+        assert!(Purpose::validate_list(&[]).is_err());
+        assert!(Purpose::validate_list(&[Purpose::Research]).is_ok());
+    }
+
+    #[test]
+    fn test_emergency_session() {
+        let memory_manager = memory_manager!();
+        let mut consents = ConsentMap::new_with_seed(0, &memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let clinic = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let now = Timestamp::new();
+
+        let (session_id, expires_at) =
+            consents.open_emergency_session(nik.clone(), clinic.clone(), &now);
+        assert_eq!(expires_at, now.after(EMERGENCY_SESSION_EXPIRY));
+
+        let snapshot = consents.session_snapshot(&session_id).unwrap();
+        assert_eq!(snapshot.purpose, Purpose::Emergency);
+        assert_eq!(snapshot.consent.expires_at, Some(expires_at));
+        assert!(matches!(
+            snapshot.consent.scope,
+            Some(ConsentScope {
+                access: ConsentAccess::ReadOnly,
+                ..
+            })
+        ));
+        assert!(consents.resolve_session(&session_id, &clinic).is_some());
+        assert!(consents.pending_consents(&clinic, &now).is_empty());
+
+        // the patient can end it like any other session
+        assert!(consents.terminate_session(&session_id, &nik).is_ok());
+    }
+
     #[test]
     #[should_panic]
     fn panic_wrong_session_user() {
@@ -801,7 +2629,9 @@ mod tests {
         let partial = PartialConsent::new(nik.clone());
         let code = consents.add_consent(partial.clone());
 
-        let (session_id, _) = consents.claim_consent(&code, provider_id).unwrap();
+        let (session_id, _) = consents
+            .claim_consent(&code, provider_id, Purpose::Treatment)
+            .unwrap();
 
         consents.finish_session(&session_id, &id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3"));
     }
@@ -0,0 +1,456 @@
+//! linking more devices to a patient, e.g. a phone and a web app that use different internet identity anchors.
+//!
+//! the new device asks for a one-time link code, the patient then approves the code from a device that is already linked.
+//! linked devices are bound in the [OwnerMap] just like the device the patient registered with,
+//! so every patient guard accepts them. the device the patient registered with can't be revoked.
+use std::{str::FromStr, time::Duration};
+
+use candid::{CandidType, Principal};
+use canister_common::{
+    common::Timestamp,
+    deref, impl_max_size, impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{Candid, Memory, Stable, ToStable},
+};
+use parity_scale_codec::{Decode, Encode};
+use serde::Deserialize;
+
+use crate::{
+    code::{self, RawCode},
+    registry::{OwnerMap, NIK},
+};
+
+// change this if you want to change how long a link code can be approved for
+pub const LINK_CODE_EXPIRY: Duration = Duration::from_secs(60 * 10); // 10 minutes
+
+// max amount of devices linked to a patient, not counting the one they registered with
+pub const MAX_LINKED_DEVICES: usize = 8;
+
+const MAX_LABEL_LEN: usize = 64;
+
+// max amount of expired link codes removed in a single request
+const MAX_PRUNE_PER_REQUEST: usize = 50;
+
+/// one-time code shown on the new device, always the 10 character alphanumeric format
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Encode, Decode)]
+pub struct LinkCode(RawCode);
+impl_max_size!(for LinkCode: 10);
+impl_mem_bound!(for LinkCode: bounded; fixed_size: true);
+
+impl LinkCode {
+    pub fn from_u64(u: u64) -> Self {
+        LinkCode(code::alphanumeric(u))
+    }
+
+    pub fn as_str(&self) -> &str {
+        code::as_str(&self.0)
+    }
+}
+
+impl FromStr for LinkCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != code::LONG_CODE_LEN {
+            return Err("invalid length".to_string());
+        }
+
+        code::parse(s).map(LinkCode)
+    }
+}
+
+impl<'de> Deserialize<'de> for LinkCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        LinkCode::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl CandidType for LinkCode {
+    fn _ty() -> candid::types::Type {
+        candid::types::TypeInner::Text.into()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        self.as_str().idl_serialize(serializer)
+    }
+}
+
+impl std::fmt::Debug for LinkCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    pub principal: Principal,
+    pub label: Option<String>,
+    pub linked_at: Timestamp,
+    /// the device that approved the link
+    pub linked_by: Principal,
+}
+
+impl_max_size!(for Device: 256);
+impl_mem_bound!(for Device: bounded; fixed_size: false);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingLink {
+    pub principal: Principal,
+    pub label: Option<String>,
+    pub expires_at: Timestamp,
+}
+
+impl_max_size!(for PendingLink: 192);
+impl_mem_bound!(for PendingLink: bounded; fixed_size: false);
+
+pub fn validate_label(label: &Option<String>) -> Result<(), String> {
+    match label {
+        Some(label) if label.len() > MAX_LABEL_LEN => Err(format!(
+            "device label can be at most {} characters long",
+            MAX_LABEL_LEN
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub struct Devices {
+    linked: DeviceMap,
+    pending: PendingLinkMap,
+    expiry: PendingLinkExpiryIndex,
+    requested: PendingLinkRequesterMap,
+}
+
+impl Devices {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let mut devices = Self {
+            linked: DeviceMap::init(memory_manager),
+            pending: PendingLinkMap::init(memory_manager),
+            expiry: PendingLinkExpiryIndex::init(memory_manager),
+            requested: PendingLinkRequesterMap::init(memory_manager),
+        };
+
+        // index codes issued before the indexes existed, codes only live for minutes so there's few
+        if devices.expiry.is_empty() && !devices.pending.is_empty() {
+            let pending = devices
+                .pending
+                .iter()
+                .map(|(code, link)| (code.into_inner(), link.into_inner()))
+                .collect::<Vec<_>>();
+
+            for (code, link) in pending {
+                devices.index(&code, &link);
+            }
+        }
+
+        devices
+    }
+
+    fn index(&mut self, code: &LinkCode, link: &PendingLink) {
+        self.expiry
+            .insert((link.expires_at.to_stable(), code.to_stable()), ());
+        self.requested.insert(link.principal, code.to_stable());
+    }
+
+    /// remove a pending code along with its index entries
+    fn remove_pending(&mut self, code: &LinkCode) -> Option<PendingLink> {
+        let link = self.pending.remove(&code.to_stable())?.into_inner();
+
+        self.expiry
+            .remove(&(link.expires_at.to_stable(), code.to_stable()));
+        if self
+            .requested
+            .get(&link.principal)
+            .is_some_and(|requested| requested.as_inner() == code)
+        {
+            self.requested.remove(&link.principal);
+        }
+
+        Some(link)
+    }
+
+    /// remove codes that expired at or before `now`, returns the amount of codes removed
+    pub fn remove_expired(&mut self, now: &Timestamp, limit: usize) -> usize {
+        let expired = self
+            .expiry
+            .iter()
+            .take_while(|((expires_at, _), _)| expires_at.as_inner() <= now)
+            .take(limit)
+            .map(|((_, code), _)| code.into_inner())
+            .collect::<Vec<_>>();
+
+        for code in expired.iter() {
+            self.remove_pending(code);
+        }
+
+        expired.len()
+    }
+
+    /// issue a link code for the calling device, replacing any code it asked for before
+    pub fn request_link(
+        &mut self,
+        principal: Principal,
+        label: Option<String>,
+        random: u64,
+        now: &Timestamp,
+    ) -> (LinkCode, Timestamp) {
+        if let Some(previous) = self.requested.get(&principal) {
+            self.remove_pending(previous.as_inner());
+        }
+        self.remove_expired(now, MAX_PRUNE_PER_REQUEST);
+
+        let code = LinkCode::from_u64(random);
+        // a colliding code would otherwise leave stale index entries of the code it replaced
+        self.remove_pending(&code);
+
+        let link = PendingLink {
+            principal,
+            label,
+            expires_at: now.after(LINK_CODE_EXPIRY),
+        };
+        let expires_at = link.expires_at;
+        self.index(&code, &link);
+        self.pending.insert(code.to_stable(), link.to_stable());
+
+        (code, expires_at)
+    }
+
+    /// link the device that asked for the code to the patient of the approving device
+    pub fn approve_link(
+        &mut self,
+        owner_map: &mut OwnerMap,
+        code: &LinkCode,
+        approver: Principal,
+        now: &Timestamp,
+    ) -> Result<Device, String> {
+        let nik = owner_map
+            .get_nik(&approver)
+            .map_err(|e| e.to_string())?
+            .into_inner();
+
+        let link = self
+            .remove_pending(code)
+            .filter(|link| now < &link.expires_at)
+            .ok_or("link code does not exist or already expired")?;
+
+        if self.list(&nik).len() >= MAX_LINKED_DEVICES {
+            return Err(format!(
+                "at most {} devices can be linked",
+                MAX_LINKED_DEVICES
+            ));
+        }
+
+        owner_map
+            .link(link.principal, nik.clone())
+            .map_err(|e| e.to_string())?;
+
+        let device = Device {
+            principal: link.principal,
+            label: link.label,
+            linked_at: *now,
+            linked_by: approver,
+        };
+        self.linked.insert(
+            (nik.to_stable(), device.principal),
+            device.clone().to_stable(),
+        );
+
+        Ok(device)
+    }
+
+    /// devices linked to the patient, not including the one they registered with
+    pub fn list(&self, nik: &NIK) -> Vec<Device> {
+        let start = (nik.clone().to_stable(), Principal::management_canister());
+
+        self.linked
+            .range(start..)
+            .take_while(|((key, _), _)| key.as_inner() == nik)
+            .map(|(_, device)| device.into_inner())
+            .collect()
+    }
+
+    pub fn is_linked(&self, nik: &NIK, principal: &Principal) -> bool {
+        self.linked
+            .contains_key(&(nik.clone().to_stable(), *principal))
+    }
+
+    /// drop every linked device of the patient, their owner map bindings are left to the caller
+    pub fn forget(&mut self, nik: &NIK) {
+        let principals = self
+            .list(nik)
+            .into_iter()
+            .map(|device| device.principal)
+            .collect::<Vec<_>>();
+
+        for principal in principals {
+            self.linked.remove(&(nik.clone().to_stable(), principal));
+        }
+    }
+
+    /// unlink a device from the patient, the device loses access right away
+    pub fn revoke(
+        &mut self,
+        owner_map: &mut OwnerMap,
+        nik: &NIK,
+        principal: &Principal,
+    ) -> Result<(), String> {
+        self.linked
+            .remove(&(nik.clone().to_stable(), *principal))
+            .ok_or("device is not linked to the patient")?;
+
+        owner_map.revoke(principal).map_err(|e| e.to_string())
+    }
+}
+
+/// devices linked to a patient, keyed by the patient so they can be listed with a range scan
+pub struct DeviceMap(
+    ic_stable_structures::BTreeMap<(Stable<NIK>, Principal), Stable<Device, Candid>, Memory>,
+);
+
+impl DeviceMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut DeviceMap: ic_stable_structures::BTreeMap<(Stable<NIK>, Principal), Stable<Device, Candid>, Memory>);
+
+/// link codes waiting for an approval
+pub struct PendingLinkMap(
+    ic_stable_structures::BTreeMap<Stable<LinkCode>, Stable<PendingLink, Candid>, Memory>,
+);
+
+impl PendingLinkMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PendingLinkMap: ic_stable_structures::BTreeMap<Stable<LinkCode>, Stable<PendingLink, Candid>, Memory>);
+
+/// pending link codes ordered by their expiry time, to prune expired codes without a full scan
+pub struct PendingLinkExpiryIndex(
+    ic_stable_structures::BTreeMap<(Stable<Timestamp>, Stable<LinkCode>), (), Memory>,
+);
+
+impl PendingLinkExpiryIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PendingLinkExpiryIndex: ic_stable_structures::BTreeMap<(Stable<Timestamp>, Stable<LinkCode>), (), Memory>);
+
+/// the pending link code of each device, so asking again replaces the previous code
+pub struct PendingLinkRequesterMap(
+    ic_stable_structures::BTreeMap<Principal, Stable<LinkCode>, Memory>,
+);
+
+impl PendingLinkRequesterMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PendingLinkRequesterMap: ic_stable_structures::BTreeMap<Principal, Stable<LinkCode>, Memory>);
+
+#[cfg(test)]
+mod tests {
+    use canister_common::memory_manager;
+
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn test_link_device() {
+        let memory_manager = memory_manager!();
+        let mut owner_map = OwnerMap::init(&memory_manager);
+        let mut devices = Devices::init(&memory_manager);
+
+        let nik = NIK::from([1u8; 32]);
+        let (phone, laptop, stranger) = (principal(1), principal(2), principal(3));
+        owner_map.bind(phone, nik.clone()).unwrap();
+        let now = Timestamp::new();
+
+        let (code, _) = devices.request_link(laptop, Some("laptop".to_string()), 42, &now);
+
+        // only an already linked device can approve
+        assert!(devices
+            .approve_link(&mut owner_map, &code, stranger, &now)
+            .is_err());
+        // expired codes are rejected
+        assert!(devices
+            .approve_link(&mut owner_map, &code, phone, &now.after(LINK_CODE_EXPIRY))
+            .is_err());
+
+        let (code, _) = devices.request_link(laptop, Some("laptop".to_string()), 42, &now);
+        let device = devices
+            .approve_link(&mut owner_map, &code, phone, &now)
+            .unwrap();
+        assert_eq!(device.linked_by, phone);
+        assert_eq!(owner_map.get_nik(&laptop).unwrap().into_inner(), nik);
+        assert_eq!(devices.list(&nik), vec![device]);
+        assert!(devices.is_linked(&nik, &laptop));
+
+        // codes are single use
+        assert!(devices
+            .approve_link(&mut owner_map, &code, phone, &now)
+            .is_err());
+
+        // the registered device can't be revoked, linked ones can
+        assert!(devices.revoke(&mut owner_map, &nik, &phone).is_err());
+        devices.revoke(&mut owner_map, &nik, &laptop).unwrap();
+        assert!(!owner_map.is_valid_owner(&laptop));
+        assert!(owner_map.is_valid_owner(&phone));
+        assert!(devices.list(&nik).is_empty());
+    }
+
+    #[test]
+    fn test_request_link_replaces_code() {
+        let memory_manager = memory_manager!();
+        let mut devices = Devices::init(&memory_manager);
+        let now = Timestamp::new();
+
+        let (first, _) = devices.request_link(principal(1), None, 1, &now);
+        let (second, _) = devices.request_link(principal(1), None, 2, &now);
+        assert!(devices.pending.get(&first.to_stable()).is_none());
+        assert!(devices.pending.get(&second.to_stable()).is_some());
+        assert_eq!(devices.expiry.len(), 1);
+
+        assert!(validate_label(&Some("a".repeat(65))).is_err());
+        assert!(validate_label(&None).is_ok());
+    }
+
+    #[test]
+    fn test_expired_codes_pruned() {
+        let memory_manager = memory_manager!();
+        let mut devices = Devices::init(&memory_manager);
+        let now = Timestamp::new();
+
+        for id in 0..3 {
+            devices.request_link(principal(id), None, id as u64, &now);
+        }
+        assert_eq!(devices.pending.len(), 3);
+
+        // nothing expired yet
+        let later = now.after(Duration::from_secs(1));
+        assert_eq!(devices.remove_expired(&later, MAX_PRUNE_PER_REQUEST), 0);
+
+        // a request after the codes expired prunes them through the expiry index
+        let later = now.after(LINK_CODE_EXPIRY);
+        let (code, _) = devices.request_link(principal(10), None, 10, &later);
+        assert_eq!(devices.pending.len(), 1);
+        assert_eq!(devices.expiry.len(), 1);
+        assert_eq!(devices.requested.len(), 1);
+        assert!(devices.pending.get(&code.to_stable()).is_some());
+    }
+}
@@ -0,0 +1,264 @@
+//! break-glass access, lets a provider open a read only session on a patient that can't share a consent code,
+//! e.g. an unconscious patient in the emergency room.
+//!
+//! every access is kept along with the provider justification and queued until an admin reviews it.
+use candid::{CandidType, Principal};
+use canister_common::{
+    common::{ProviderId, Timestamp},
+    deref, impl_max_size, impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{Candid, Memory, Stable, StableSet, ToStable},
+};
+use serde::Deserialize;
+
+use crate::{consent::SessionId, log::U64, registry::NIK};
+
+const MIN_JUSTIFICATION_LEN: usize = 20;
+const MAX_JUSTIFICATION_LEN: usize = 1000;
+const MAX_REVIEW_NOTE_LEN: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReviewOutcome {
+    Justified,
+    Unjustified,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EmergencyReview {
+    pub reviewer: Principal,
+    pub outcome: ReviewOutcome,
+    pub note: String,
+    pub reviewed_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EmergencyAccess {
+    pub nik: NIK,
+    pub provider_id: ProviderId,
+    pub session_id: SessionId,
+    pub justification: String,
+    pub opened_at: Timestamp,
+    pub expires_at: Timestamp,
+    /// [None] while the access waits in the review queue
+    pub review: Option<EmergencyReview>,
+}
+
+// justification and review note are capped at 1000 bytes each, the rest is well under 512 bytes
+impl_max_size!(for EmergencyAccess: 2560);
+impl_mem_bound!(for EmergencyAccess: bounded; fixed_size: false);
+
+impl EmergencyAccess {
+    pub fn validate_justification(justification: &str) -> Result<(), String> {
+        let len = justification.trim().len();
+        if !(MIN_JUSTIFICATION_LEN..=MAX_JUSTIFICATION_LEN).contains(&len) {
+            return Err(format!(
+                "justification must be {} to {} characters long",
+                MIN_JUSTIFICATION_LEN, MAX_JUSTIFICATION_LEN
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct EmergencyAccessLog {
+    accesses: EmergencyAccessMap,
+    review_queue: EmergencyReviewQueue,
+    index: EmergencyAccessIndex,
+}
+
+impl EmergencyAccessLog {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            accesses: EmergencyAccessMap::init(memory_manager),
+            review_queue: EmergencyReviewQueue::init(memory_manager),
+            index: EmergencyAccessIndex::init(memory_manager),
+        }
+    }
+
+    /// store the access and queue it for review, returns its id
+    pub fn record(&mut self, access: EmergencyAccess) -> u64 {
+        let id = self
+            .accesses
+            .last_key_value()
+            .map_or(0, |(id, _)| u64::from(id.into_inner()) + 1);
+        let key = U64::from(id).to_stable();
+
+        self.index
+            .insert(access.nik.clone().to_stable(), key.clone());
+        self.review_queue.insert(key.clone(), ());
+        self.accesses.insert(key, access.to_stable());
+
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<EmergencyAccess> {
+        self.accesses
+            .get(&U64::from(id).to_stable())
+            .map(Stable::into_inner)
+    }
+
+    /// accesses waiting for a review, oldest first
+    pub fn pending(&self) -> Vec<(u64, EmergencyAccess)> {
+        self.review_queue
+            .iter()
+            .filter_map(|(id, _)| {
+                let id = u64::from(id.into_inner());
+                self.get(id).map(|access| (id, access))
+            })
+            .collect()
+    }
+
+    pub fn pending_len(&self) -> u64 {
+        self.review_queue.len()
+    }
+
+    /// accesses on the patient along with their id, oldest first
+    pub fn list(&self, nik: &NIK) -> Vec<(u64, EmergencyAccess)> {
+        self.index
+            .get_set_associated_by_key(nik.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| {
+                let id = u64::from(id.into_inner());
+                self.get(id).map(|access| (id, access))
+            })
+            .collect()
+    }
+
+    /// review a queued access and take it off the queue, an access can only be reviewed once
+    pub fn review(&mut self, id: u64, review: EmergencyReview) -> Result<EmergencyAccess, String> {
+        if review.note.len() > MAX_REVIEW_NOTE_LEN {
+            return Err(format!(
+                "review note can be at most {} characters long",
+                MAX_REVIEW_NOTE_LEN
+            ));
+        }
+
+        let key = U64::from(id).to_stable();
+        let mut access = self
+            .accesses
+            .get(&key)
+            .ok_or("emergency access does not exist")?
+            .into_inner();
+
+        if access.review.is_some() {
+            return Err("emergency access already reviewed".to_string());
+        }
+
+        access.review = Some(review);
+        self.accesses
+            .insert(key.clone(), access.clone().to_stable());
+        self.review_queue.remove(&key);
+
+        Ok(access)
+    }
+}
+
+pub struct EmergencyAccessMap(
+    ic_stable_structures::BTreeMap<Stable<U64>, Stable<EmergencyAccess, Candid>, Memory>,
+);
+
+impl EmergencyAccessMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut EmergencyAccessMap: ic_stable_structures::BTreeMap<Stable<U64>, Stable<EmergencyAccess, Candid>, Memory>);
+
+/// ids of accesses not reviewed yet
+pub struct EmergencyReviewQueue(ic_stable_structures::BTreeMap<Stable<U64>, (), Memory>);
+
+impl EmergencyReviewQueue {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut EmergencyReviewQueue: ic_stable_structures::BTreeMap<Stable<U64>, (), Memory>);
+
+/// access ids per patient
+pub struct EmergencyAccessIndex(StableSet<Stable<NIK>, Stable<U64>>);
+
+impl EmergencyAccessIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(StableSet::init::<Self>(memory_manager))
+    }
+}
+
+deref!(mut EmergencyAccessIndex: StableSet<Stable<NIK>, Stable<U64>>);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use canister_common::{id, memory_manager};
+
+    use super::*;
+
+    fn access(nik: &NIK) -> EmergencyAccess {
+        let now = Timestamp::new();
+
+        EmergencyAccess {
+            nik: nik.clone(),
+            provider_id: id!("60673662-792a-4e50-b7aa-eccf7e4146a3"),
+            session_id: id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3"),
+            justification: "unconscious patient brought in after a car accident".to_string(),
+            opened_at: now,
+            expires_at: now,
+            review: None,
+        }
+    }
+
+    fn review(outcome: ReviewOutcome) -> EmergencyReview {
+        EmergencyReview {
+            reviewer: Principal::anonymous(),
+            outcome,
+            note: String::new(),
+            reviewed_at: Timestamp::new(),
+        }
+    }
+
+    #[test]
+    fn test_review_queue() {
+        let memory_manager = memory_manager!();
+        let mut log = EmergencyAccessLog::init(&memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let other =
+            NIK::from_str("3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709")
+                .unwrap();
+
+        let first = log.record(access(&nik));
+        let second = log.record(access(&other));
+        assert_eq!((first, second), (0, 1));
+        assert_eq!(log.pending_len(), 2);
+        assert_eq!(log.list(&nik).len(), 1);
+
+        let reviewed = log.review(first, review(ReviewOutcome::Justified)).unwrap();
+        assert!(reviewed.review.is_some());
+        assert!(log
+            .review(first, review(ReviewOutcome::Unjustified))
+            .is_err());
+        assert!(log.review(42, review(ReviewOutcome::Justified)).is_err());
+
+        let pending = log.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, second);
+
+        // reviewed accesses stay visible to the patient
+        assert!(log.list(&nik)[0].1.review.is_some());
+    }
+
+    #[test]
+    fn test_justification() {
+        assert!(EmergencyAccess::validate_justification("   too short   ").is_err());
+        assert!(EmergencyAccess::validate_justification(&"a".repeat(1001)).is_err());
+        assert!(EmergencyAccess::validate_justification(
+            "unconscious patient brought in after a car accident"
+        )
+        .is_ok());
+    }
+}
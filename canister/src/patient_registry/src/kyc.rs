@@ -0,0 +1,528 @@
+//! kyc cases, the review workflow behind [KycStatus].
+//!
+//! a patient submits their documents, an admin gets assigned to review them and moves the case along,
+//! every transition is kept in the case history together with who made it and why.
+//! the flat [KycStatus] on the patient info is derived from the case and kept in sync on every transition.
+//!
+//! ```text
+//! Submitted -> InReview -> Approved
+//!                |    \-> Denied -> Submitted
+//!                 \-> NeedsMoreInfo -> Submitted
+//! ```
+use candid::{CandidType, Principal};
+use canister_common::{
+    common::Timestamp,
+    deref, impl_max_size, impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{Candid, Memory, Scale, Stable, StableSet, ToStable},
+};
+use ic_stable_structures::Log;
+use serde::Deserialize;
+
+use crate::{
+    log::U64,
+    registry::{KycStatus, NIK},
+};
+
+const MAX_DOCUMENTS: usize = 5;
+const MAX_REFERENCE_LEN: usize = 256;
+const MAX_REASON_LEN: usize = 500;
+
+/// recorded for admin status updates that don't give a reason, callers from before reasons existed
+pub const DEFAULT_FORCE_REASON: &str = "status set through update_kyc_status";
+
+pub struct KycHistoryEntryMemory;
+pub struct KycHistoryIndexMemory;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KycCaseStatus {
+    /// waiting for an admin to pick it up
+    Submitted,
+    InReview,
+    /// the patient has to submit their documents again
+    NeedsMoreInfo,
+    Approved,
+    Denied,
+}
+
+impl KycCaseStatus {
+    pub fn kyc_status(&self) -> KycStatus {
+        match self {
+            Self::Approved => KycStatus::Approved,
+            Self::Denied => KycStatus::Denied,
+            Self::Submitted | Self::InReview | Self::NeedsMoreInfo => KycStatus::Pending,
+        }
+    }
+
+    /// whether the patient can (re)submit their documents
+    fn accepts_submission(&self) -> bool {
+        matches!(self, Self::NeedsMoreInfo | Self::Denied)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KycDocumentKind {
+    IdCard,
+    FamilyCard,
+    Selfie,
+    Other,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KycDocument {
+    pub kind: KycDocumentKind,
+    /// where the document is stored off chain, e.g. an object storage key
+    pub reference: String,
+    /// hex encoded sha256 of the document, used by reviewers to check it was not swapped
+    pub sha256: Option<String>,
+}
+
+impl KycDocument {
+    pub fn validate_list(documents: &[KycDocument]) -> Result<(), String> {
+        if documents.is_empty() || documents.len() > MAX_DOCUMENTS {
+            return Err(format!("submit 1 to {} documents", MAX_DOCUMENTS));
+        }
+
+        for document in documents {
+            if document.reference.is_empty() || document.reference.len() > MAX_REFERENCE_LEN {
+                return Err(format!(
+                    "document reference must be 1 to {} characters long",
+                    MAX_REFERENCE_LEN
+                ));
+            }
+
+            if let Some(ref sha256) = document.sha256 {
+                if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err("document sha256 must be 64 hex characters".to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KycCase {
+    pub nik: NIK,
+    pub status: KycCaseStatus,
+    pub documents: Vec<KycDocument>,
+    /// the admin reviewing the case
+    pub reviewer: Option<Principal>,
+    pub submitted_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+// 5 documents with a 256 bytes reference and a 64 bytes hash each, ~1.7kb plus the candid type table
+impl_max_size!(for KycCase: 2560);
+impl_mem_bound!(for KycCase: bounded; fixed_size: false);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KycTransition {
+    pub nik: NIK,
+    /// [None] for the first submission
+    pub from: Option<KycCaseStatus>,
+    pub to: KycCaseStatus,
+    pub by: Principal,
+    pub reviewer: Option<Principal>,
+    /// required for every transition made by an admin
+    pub reason: Option<String>,
+    pub at: Timestamp,
+}
+
+// the reason is capped at 500 bytes
+impl_max_size!(for KycTransition: 1024);
+impl_mem_bound!(for KycTransition: bounded; fixed_size: false);
+
+/// what the reviewer decided on a case in review
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KycDecision {
+    Approve,
+    Deny,
+    RequestMoreInfo,
+}
+
+impl From<KycDecision> for KycCaseStatus {
+    fn from(decision: KycDecision) -> Self {
+        match decision {
+            KycDecision::Approve => Self::Approved,
+            KycDecision::Deny => Self::Denied,
+            KycDecision::RequestMoreInfo => Self::NeedsMoreInfo,
+        }
+    }
+}
+
+fn validate_reason(reason: &str) -> Result<String, String> {
+    let reason = reason.trim();
+    if reason.is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err(format!(
+            "reason must be 1 to {} characters long",
+            MAX_REASON_LEN
+        ));
+    }
+
+    Ok(reason.to_string())
+}
+
+pub struct KycCases {
+    cases: KycCaseMap,
+    history: KycHistoryLog,
+    history_index: KycHistoryIndex,
+}
+
+impl KycCases {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            cases: KycCaseMap::init(memory_manager),
+            history: KycHistoryLog::init(memory_manager),
+            history_index: KycHistoryIndex::init(memory_manager),
+        }
+    }
+
+    pub fn get(&self, nik: &NIK) -> Option<KycCase> {
+        self.cases.get(nik.to_stable_ref()).map(Stable::into_inner)
+    }
+
+    /// every transition of the case, oldest first
+    pub fn history(&self, nik: &NIK) -> Vec<KycTransition> {
+        self.history_index
+            .get(nik)
+            .into_iter()
+            .filter_map(|id| self.history.0.get(id))
+            .map(Stable::into_inner)
+            .collect()
+    }
+
+    /// every case, optionally only the ones in the given status or assigned to the given reviewer.
+    /// oldest update first so the longest waiting cases come up first
+    pub fn list(&self, status: Option<KycCaseStatus>, reviewer: Option<Principal>) -> Vec<KycCase> {
+        let mut cases = self
+            .cases
+            .iter()
+            .map(|(_, case)| case.into_inner())
+            .filter(|case| status.is_none_or(|status| case.status == status))
+            .filter(|case| reviewer.is_none_or(|reviewer| case.reviewer == Some(reviewer)))
+            .collect::<Vec<_>>();
+        cases.sort_by_key(|case| case.updated_at);
+        cases
+    }
+
+    /// open a case for the patient or resubmit documents on a case that needs them
+    pub fn submit(
+        &mut self,
+        nik: NIK,
+        documents: Vec<KycDocument>,
+        by: Principal,
+        now: Timestamp,
+    ) -> Result<KycCase, String> {
+        KycDocument::validate_list(&documents)?;
+
+        let from = match self.get(&nik) {
+            Some(case) if !case.status.accepts_submission() => {
+                return Err(format!("kyc case is {:?}", case.status))
+            }
+            Some(case) => Some(case.status),
+            None => None,
+        };
+
+        let case = KycCase {
+            nik,
+            status: KycCaseStatus::Submitted,
+            documents,
+            reviewer: None,
+            submitted_at: now,
+            updated_at: now,
+        };
+
+        self.transition(from, case, by, None)
+    }
+
+    /// assign the case to a reviewer, a case already in review can be reassigned
+    pub fn assign(
+        &mut self,
+        nik: &NIK,
+        reviewer: Principal,
+        by: Principal,
+        reason: &str,
+        now: Timestamp,
+    ) -> Result<KycCase, String> {
+        let reason = validate_reason(reason)?;
+        let mut case = self.get(nik).ok_or("kyc case does not exist")?;
+        let from = case.status;
+
+        if !matches!(from, KycCaseStatus::Submitted | KycCaseStatus::InReview) {
+            return Err(format!("kyc case is {:?}", from));
+        }
+
+        case.status = KycCaseStatus::InReview;
+        case.reviewer = Some(reviewer);
+        case.updated_at = now;
+
+        self.transition(Some(from), case, by, Some(reason))
+    }
+
+    /// decide on a case in review, only the assigned reviewer can
+    pub fn decide(
+        &mut self,
+        nik: &NIK,
+        decision: KycDecision,
+        by: Principal,
+        reason: &str,
+        now: Timestamp,
+    ) -> Result<KycCase, String> {
+        let reason = validate_reason(reason)?;
+        let mut case = self.get(nik).ok_or("kyc case does not exist")?;
+        let from = case.status;
+
+        if from != KycCaseStatus::InReview {
+            return Err(format!("kyc case is {:?}", from));
+        }
+
+        if case.reviewer != Some(by) {
+            return Err("kyc case is assigned to another reviewer".to_string());
+        }
+
+        case.status = decision.into();
+        case.updated_at = now;
+
+        self.transition(Some(from), case, by, Some(reason))
+    }
+
+    /// set the status directly, bypassing the workflow. kept for the admin [KycStatus] update
+    pub fn force(
+        &mut self,
+        nik: NIK,
+        status: KycStatus,
+        by: Principal,
+        reason: &str,
+        now: Timestamp,
+    ) -> Result<KycCase, String> {
+        let reason = validate_reason(reason)?;
+        let existing = self.get(&nik);
+        let from = existing.as_ref().map(|case| case.status);
+
+        let mut case = existing.unwrap_or(KycCase {
+            nik,
+            status: KycCaseStatus::Submitted,
+            documents: vec![],
+            reviewer: None,
+            submitted_at: now,
+            updated_at: now,
+        });
+        case.status = match status {
+            KycStatus::Approved => KycCaseStatus::Approved,
+            KycStatus::Denied => KycCaseStatus::Denied,
+            KycStatus::Pending => KycCaseStatus::Submitted,
+        };
+        case.reviewer = Some(by);
+        case.updated_at = now;
+
+        self.transition(from, case, by, Some(reason))
+    }
+
+    fn transition(
+        &mut self,
+        from: Option<KycCaseStatus>,
+        case: KycCase,
+        by: Principal,
+        reason: Option<String>,
+    ) -> Result<KycCase, String> {
+        let transition = KycTransition {
+            nik: case.nik.clone(),
+            from,
+            to: case.status,
+            by,
+            reviewer: case.reviewer,
+            reason,
+            at: case.updated_at,
+        };
+
+        let id = self
+            .history
+            .0
+            .append(transition.to_stable_ref())
+            .expect("OOM");
+        self.history_index.add(case.nik.clone(), id);
+        self.cases
+            .insert(case.nik.clone().to_stable(), case.clone().to_stable());
+
+        Ok(case)
+    }
+}
+
+pub struct KycCaseMap(ic_stable_structures::BTreeMap<Stable<NIK>, Stable<KycCase, Candid>, Memory>);
+
+impl KycCaseMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut KycCaseMap: ic_stable_structures::BTreeMap<Stable<NIK>, Stable<KycCase, Candid>, Memory>);
+
+pub struct KycHistoryLog(Log<Stable<KycTransition, Candid>, Memory, Memory>);
+
+impl KycHistoryLog {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let index_mem = memory_manager.get_memory::<_, KycHistoryIndexMemory>(|mem| mem);
+        let data_mem = memory_manager.get_memory::<_, KycHistoryEntryMemory>(|mem| mem);
+
+        Self(Log::init(index_mem, data_mem).unwrap())
+    }
+}
+
+/// transition ids per patient
+pub struct KycHistoryIndex(StableSet<Stable<NIK>, Stable<U64>>);
+
+impl KycHistoryIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(StableSet::init::<Self>(memory_manager))
+    }
+
+    pub fn add(&mut self, nik: NIK, id: u64) {
+        let id: Stable<U64, Scale> = U64::from(id).to_stable();
+        self.0.insert(nik.to_stable(), id)
+    }
+
+    pub fn get(&self, nik: &NIK) -> Vec<u64> {
+        self.0
+            .get_set_associated_by_key(nik.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| id.into_inner().into())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::memory_manager;
+
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn documents() -> Vec<KycDocument> {
+        vec![KycDocument {
+            kind: KycDocumentKind::IdCard,
+            reference: "kyc/ktp.jpg".to_string(),
+            sha256: Some("ab".repeat(32)),
+        }]
+    }
+
+    #[test]
+    fn test_kyc_workflow() {
+        let memory_manager = memory_manager!();
+        let mut cases = KycCases::init(&memory_manager);
+
+        let nik = NIK::from([1u8; 32]);
+        let (patient, admin, other_admin) = (principal(1), principal(2), principal(3));
+        let now = Timestamp::new();
+
+        assert!(cases.submit(nik.clone(), vec![], patient, now).is_err());
+        cases
+            .submit(nik.clone(), documents(), patient, now)
+            .unwrap();
+        // can't resubmit while the case waits for a review
+        assert!(cases
+            .submit(nik.clone(), documents(), patient, now)
+            .is_err());
+
+        // a reason is required and only the assigned reviewer can decide
+        assert!(cases.assign(&nik, admin, admin, " ", now).is_err());
+        assert!(cases
+            .decide(&nik, KycDecision::Approve, admin, "looks good", now)
+            .is_err());
+        cases.assign(&nik, admin, admin, "picked up", now).unwrap();
+        assert!(cases
+            .decide(&nik, KycDecision::Approve, other_admin, "looks good", now)
+            .is_err());
+
+        let case = cases
+            .decide(
+                &nik,
+                KycDecision::RequestMoreInfo,
+                admin,
+                "id card is blurry",
+                now,
+            )
+            .unwrap();
+        assert_eq!(case.status.kyc_status(), KycStatus::Pending);
+
+        cases
+            .submit(nik.clone(), documents(), patient, now)
+            .unwrap();
+        cases
+            .assign(&nik, other_admin, admin, "handing over", now)
+            .unwrap();
+        let case = cases
+            .decide(
+                &nik,
+                KycDecision::Deny,
+                other_admin,
+                "nik does not match",
+                now,
+            )
+            .unwrap();
+        assert_eq!(case.status.kyc_status(), KycStatus::Denied);
+
+        let history = cases.history(&nik);
+        assert_eq!(
+            history.iter().map(|t| t.to).collect::<Vec<_>>(),
+            vec![
+                KycCaseStatus::Submitted,
+                KycCaseStatus::InReview,
+                KycCaseStatus::NeedsMoreInfo,
+                KycCaseStatus::Submitted,
+                KycCaseStatus::InReview,
+                KycCaseStatus::Denied,
+            ]
+        );
+        assert_eq!(history[0].from, None);
+        assert_eq!(history[5].reason.as_deref(), Some("nik does not match"));
+        assert_eq!(history[5].by, other_admin);
+
+        assert_eq!(
+            cases
+                .list(Some(KycCaseStatus::Denied), Some(other_admin))
+                .len(),
+            1
+        );
+        assert!(cases.list(Some(KycCaseStatus::Approved), None).is_empty());
+    }
+
+    #[test]
+    fn test_force_status() {
+        let memory_manager = memory_manager!();
+        let mut cases = KycCases::init(&memory_manager);
+
+        let nik = NIK::from([1u8; 32]);
+        let admin = principal(2);
+        let now = Timestamp::new();
+
+        // patients registered before kyc cases existed don't have one yet
+        let case = cases
+            .force(
+                nik.clone(),
+                KycStatus::Approved,
+                admin,
+                "verified in person",
+                now,
+            )
+            .unwrap();
+        assert_eq!(case.status, KycCaseStatus::Approved);
+        assert_eq!(cases.history(&nik).len(), 1);
+        assert!(cases.force(nik.clone(), KycStatus::Denied, admin, "", now).is_err());
+
+        let case = cases
+            .force(nik.clone(), KycStatus::Denied, admin, DEFAULT_FORCE_REASON, now)
+            .unwrap();
+        assert_eq!(case.status, KycCaseStatus::Denied);
+        assert_eq!(
+            cases.history(&nik).last().unwrap().reason.as_deref(),
+            Some(DEFAULT_FORCE_REASON)
+        );
+    }
+}
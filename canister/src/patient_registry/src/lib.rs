@@ -1,16 +1,17 @@
 use std::{borrow::BorrowMut, cell::RefCell, str::FromStr, time::Duration};
 
 use api::{
-    AddGroupMemberRequest, AuthorizedCallerRequest, BindAdminRequest, CheckNikRequest, ClaimConsentRequest, ClaimConsentResponse, ConsentListResponse, CreateConsentForGroupRequest, CreateConsentForGroupResponse, CreateConsentResponse, CreateGroupRequest, CreateGroupResponse, EmrHeaderWithStatus, EmrListConsentRequest, EmrListConsentResponse, EmrListPatientRequest, EmrListPatientResponse, FinishSessionRequest, GetGroupDetailsNoPaginatedRequest, GetGroupDetailsRequest, GetGroupDetailsResponse, GetPatientInfoBySessionRequest, GetPatientInfoResponse, GetUserGroupsResponse, GrantGroupAccessRequest, GroupDetail, IsConsentClaimedRequest, IsConsentClaimedResponse, IssueRequest, LeaveGroupRequest, LogResponse, PatientListAdminResponse, PatientListResponse, PatientWithNik, PatientWithNikAndSession, PingResult, ReadEmrByIdRequest, ReadEmrSessionRequest, ReadGroupMembersEmrInfoRequest, RegisterPatientRequest, RegisterPatientResponse, RegisterPatientStatus, RevokeConsentRequest, RevokeGroupAccessRequest, SearchPatientAdminResponse, SearchPatientRequest, SearchPatientResponse, UpdateEmrRegistryRequest, UpdateInitialPatientInfoRequest, UpdateKycStatusRequest, UpdateKycStatusResponse, UpdatePatientInfoRequest, UpdateRequest, ViewGroupMemberEmrInformationRequest
+    AccountRecoveryEntry, AccountRecoveryIdRequest, AccountRecoveryQueueRequest, AccountRecoveryQueueResponse, ActiveSessionListResponse, AddGroupMemberRequest, ApproveDeviceLinkRequest, AssignKycCaseRequest, AuthorizedCallerRequest, BindAdminRequest, CheckNikRequest, ClaimConsentRequest, ClaimConsentResponse, ConsentClaimDecisionRequest, ConsentClaimStatusResponse, ConsentListResponse, ConsentReceiptDocument, ConsentReceiptEntry, ConsentReceiptListResponse, CreateConsentForGroupRequest, CreateConsentForGroupResponse, CreateConsentRequest, CreateConsentResponse, CreateGroupRequest, CreateGroupResponse, DecideKycCaseRequest, DeviceLinkCodeResponse, DeviceListResponse, DownloadConsentReceiptRequest, EmergencyAccessEntry, EmergencyAccessListResponse, EmergencyAccessRequest, EmergencyAccessResponse, EmergencyReviewQueueRequest, EmergencyReviewQueueResponse, EmrHeaderWithStatus, EmrListConsentRequest, EmrListConsentResponse, EmrListEncounterRequest, EmrListEncounterSessionRequest, EmrListPatientRequest, EncounterListRequest, EncounterListResponse, EmrListPatientResponse, FinishSessionRequest, GetGroupDetailsNoPaginatedRequest, GetGroupDetailsRequest, GetGroupDetailsResponse, GetPatientInfoBySessionRequest, GetPatientInfoRequest, GetPatientInfoResponse, GetUserGroupsResponse, GrantGroupAccessRequest, GroupDetail, IsConsentClaimedRequest, IsConsentClaimedResponse, KycCaseListRequest, KycCaseListResponse, KycCaseRequest, KycCaseResponse, GetLogsRequest, IssueRequest, LeaveGroupRequest, LogResponse, MarkNotificationsReadRequest, NotificationListRequest, NotificationListResponse, HasSessionRequest, NotifyPurgedRequest, ReadableEmrsRequest, ReadableEmrsResponse, PatientListAdminCursor, PatientListAdminRequest, PatientListAdminResponse, PatientListCursor, PatientListRequest, PatientListResponse, PatientSession, PatientWithNik, PatientWithNikAndSession, PingResult, ProviderPatientSession, ProviderSessionListRequest, ProviderSessionListResponse, ProviderSessionSort, ReadEmrByIdRequest, ReadEmrSessionRequest, ReadGroupMembersEmrInfoRequest, RegisterPatientRequest, RegisterPatientResponse, RegisterPatientStatus, RequestAccountRecoveryRequest, RequestDeviceLinkRequest, ReviewAccountRecoveryRequest, ReviewEmergencyAccessRequest, RevokeConsentRequest, RevokeDeviceRequest, RevokeGroupAccessRequest, SearchPatientAdminResponse, SearchPatientRequest, SearchPatientResponse, SearchPatientsAdminCursor, SearchPatientsAdminRequest, SearchPatientsAdminResponse, SubmitKycRequest, UpdateEmrRegistryRequest, UpdateInitialPatientInfoRequest, UpdateKycStatusRequest, UpdateKycStatusResponse, UpdatePatientInfoRequest, UpdatePatientInfoV2Request, UpdateRateLimitRequest, UpdateRequest, VerifyLogChainRequest, VerifyLogChainResponse, ViewGroupMemberEmrInformationRequest
 };
 use candid::{Decode, Encode, Principal};
 use canister_common::{
-    common::{guard::verified_caller, AsciiRecordsKey, EmrId, ProviderId},
+    common::{guard::verified_caller, AsciiRecordsKey, EmrHeader, EmrId, ProviderId, Timestamp},
     id_generator::IdGenerator,
     log,
     mmgr::MemoryManager,
     opaque_metrics,
-    random::CanisterRandomSource,
+    random::{CanisterRandomSource, RandomSource},
+    rate_limit::{MethodRateLimit, RateLimiter},
     register_log,
     stable::{Candid, Memory, Stable, ToStable},
     statistics::{self, traits::OpaqueMetrics},
@@ -19,28 +20,57 @@ use config::CanisterConfig;
 use declarations::{emr_registry::ReadEmrByIdResponse, provider_registry::GetProviderBatchRequest};
 
 use ic_stable_structures::Cell;
-use log::PatientLog;
-use memory::UpgradeMemory;
-use registry::{Group, GroupConsentCode, GroupId, Patient, PatientRegistry, Relation, NIK};
-
+use log::{Activity, LogFilter, PatientLog};
+use memory::{RateLimiterMemory, UpgradeMemory};
+use notification::{Inbox, NotificationEvent, NotificationPreferences};
+use registry::{Group, GroupConsentCode, GroupId, PatientRegistry, Relation, NIK};
+use device::{Device, Devices};
+use emergency::{EmergencyAccess, EmergencyAccessLog, EmergencyReview, ReviewOutcome};
+use kyc::{KycCase, KycCases, DEFAULT_FORCE_REASON};
+use profile::V2;
+use receipt::{ConsentReceipt, ConsentReceipts, ReceiptEvent};
+use recovery::{Recoveries, RecoveryRequest};
+use throttle::ClaimThrottle;
+
+use crate::consent::ClaimApproval;
 use crate::consent::ConsentCode;
+use crate::consent::Consent;
 use crate::consent::ConsentsApi;
+use crate::consent::{Purpose, SessionId, SessionSnapshot};
 use crate::registry::{KycStatus, PatientRegistryError, V1};
 
 mod api;
+mod code;
 mod config;
 mod consent;
 mod declarations;
+mod device;
+mod emergency;
 mod encryption;
+mod kyc;
 mod log;
 mod memory;
+mod notification;
+mod profile;
+mod receipt;
+mod recovery;
 mod registry;
+mod search;
+mod throttle;
 
 pub struct State {
     pub registry: registry::PatientRegistry,
     pub config: Cell<Stable<CanisterConfig, Candid>, Memory>,
     pub memory_manager: MemoryManager,
     pub patient_log: PatientLog,
+    pub claim_throttle: ClaimThrottle,
+    pub rate_limiter: RateLimiter,
+    pub consent_receipts: ConsentReceipts,
+    pub emergency_access: EmergencyAccessLog,
+    pub devices: Devices,
+    pub recoveries: Recoveries,
+    pub kyc: KycCases,
+    pub inbox: Inbox,
 }
 
 register_log!("patient");
@@ -48,6 +78,18 @@ register_log!("patient");
 // change this if you want to change the interval of the metrics collection
 const METRICS_INTERVAL: Duration = Duration::from_secs(60 * 5); // 5 minutes
 
+// change this if you want to change how often full rate limit buckets are removed
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+// change this if you want to change how many older activities are chained per timer call
+const LOG_CHAIN_BATCH: u64 = 1_000;
+
+// change this if you want to change how many patients are indexed per round of an index rebuild
+const INDEX_REBUILD_BATCH: u64 = 1_000;
+
+// default and largest page size of the patient lists and activity log
+const PAGE_LIMIT: u64 = 100;
+
 thread_local! {
     pub static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
     static ID_GENERATOR: RefCell<Option<IdGenerator<CanisterRandomSource>>> = const {
@@ -189,6 +231,72 @@ fn only_admin_or_controller_or_patient() -> Result<(), String> {
     }
 }
 
+// guard function
+fn rate_limit_register_patient() -> Result<(), String> {
+    consume_rate_limit("register_patient")
+}
+
+// guard function
+fn rate_limit_claim_consent() -> Result<(), String> {
+    consume_rate_limit("claim_consent")
+}
+
+// guard function
+fn rate_limit_request_device_link() -> Result<(), String> {
+    consume_rate_limit("request_device_link")
+}
+
+// guard function
+fn rate_limit_request_account_recovery() -> Result<(), String> {
+    consume_rate_limit("request_account_recovery")
+}
+
+// guard function
+fn rate_limit_emergency_access() -> Result<(), String> {
+    consume_rate_limit("emergency_access")
+}
+
+// guard function
+fn rate_limit_submit_kyc() -> Result<(), String> {
+    consume_rate_limit("submit_kyc")
+}
+
+/// take a token from the caller bucket for the method, no-op if the method is not rate limited
+fn consume_rate_limit(method: &str) -> Result<(), String> {
+    let caller = verified_caller()?;
+
+    with_state_mut(|s| {
+        let Some(limit) = s.config.get().rate_limit(method) else {
+            return Ok(());
+        };
+
+        s.rate_limiter
+            .consume(caller, method, &limit, Timestamp::new())
+            .map_err(|e| format!("[PATIENT_REGISTRY_LIB] {}", e))
+    })
+}
+
+/// drop ingress messages from callers that already ran out of tokens, before they cost us any cycles.
+/// the token itself is taken in the guard function as state changes made here are discarded.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let caller = ic_cdk::caller();
+
+    let result = with_state(|s| {
+        s.config
+            .get()
+            .rate_limit(&method)
+            .map(|limit| s.rate_limiter.check(caller, &method, &limit, Timestamp::new()))
+    });
+
+    if let Some(Err(e)) = result {
+        ic_cdk::trap(&format!("[PATIENT_REGISTRY_LIB] {}", e));
+    }
+
+    ic_cdk::api::call::accept_message();
+}
+
 // guard functionn for only providers access (just check with providers registry and the principals existence)
 // todo! awaiting declarations from providers registry for the is_valid_provider function
 // fn only_providers() -> Result<(), String> {
@@ -211,6 +319,14 @@ fn init_state() -> State {
         registry: PatientRegistry::init(&memory_manager),
         config: CanisterConfig::init(&memory_manager),
         patient_log: PatientLog::init(&memory_manager),
+        claim_throttle: ClaimThrottle::init(&memory_manager),
+        rate_limiter: RateLimiter::init::<RateLimiterMemory>(&memory_manager),
+        consent_receipts: ConsentReceipts::init(&memory_manager),
+        emergency_access: EmergencyAccessLog::init(&memory_manager),
+        devices: Devices::init(&memory_manager),
+        recoveries: Recoveries::init(&memory_manager),
+        kyc: KycCases::init(&memory_manager),
+        inbox: Inbox::init(&memory_manager),
         memory_manager,
     }
 }
@@ -277,6 +393,43 @@ fn start_collect_metrics_job() {
     });
 }
 
+fn start_rate_limit_prune_job() {
+    ic_cdk_timers::set_timer_interval(RATE_LIMIT_PRUNE_INTERVAL, || {
+        let removed = with_state_mut(|s| s.rate_limiter.prune(Timestamp::new()));
+        log!("removed {} full rate limit buckets", removed);
+    });
+}
+
+/// chain the activities recorded before the log chain existed.
+/// a batch per call, so an upgrade doesn't hash the whole log at once
+fn start_log_chain_job() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let pending = with_state_mut(|s| s.patient_log.chain_older_entries(LOG_CHAIN_BATCH));
+
+        if pending {
+            start_log_chain_job();
+        } else {
+            log!("activity log chain is up to date");
+        }
+    });
+}
+
+/// rebuild the patient search indexes after they got out of sync, see
+/// [registry::InfoMap::rebuild_index]. a batch per call, so an upgrade doesn't index every
+/// patient at once
+fn start_index_rebuild_job() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let pending =
+            with_state_mut(|s| s.registry.info_map.rebuild_index(INDEX_REBUILD_BATCH));
+
+        if pending {
+            start_index_rebuild_job();
+        } else {
+            log!("patient search indexes are up to date");
+        }
+    });
+}
+
 fn deserialize_canister_metrics() {
     let mem = with_state(|s| s.memory_manager.get_memory::<_, UpgradeMemory>(|mem| mem));
 
@@ -328,6 +481,9 @@ fn initialize() {
     initialize_id_generator();
     ConsentsApi::init();
     start_collect_metrics_job();
+    start_rate_limit_prune_job();
+    start_log_chain_job();
+    start_index_rebuild_job();
 }
 
 #[ic_cdk::update]
@@ -397,18 +553,8 @@ async fn read_emr_by_id(req: ReadEmrByIdRequest) -> ReadEmrByIdResponse {
     PatientRegistry::do_call_read_emr(args, registry).await
 }
 
-#[ic_cdk::query(guard = "only_patient", composite = true)]
-async fn emr_list_patient(req: EmrListPatientRequest) -> EmrListPatientResponse {
-    let caller = verified_caller().unwrap();
-    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
-
-    let emrs = with_state(move |s| {
-        s.registry
-            .emr_binding_map
-            .emr_list(&nik, req.page, req.limit)
-    })
-    .unwrap();
-
+/// attach the emr status and the issuing provider name to each header
+async fn emr_headers_with_status(emrs: Vec<Stable<EmrHeader>>) -> Vec<EmrHeaderWithStatus> {
     let provider_registry = with_state(|s| s.config.get().provider_registry());
 
     let providers = emrs
@@ -442,38 +588,162 @@ async fn emr_list_patient(req: EmrListPatientRequest) -> EmrListPatientResponse
             EmrHeaderWithStatus::new(header, status, providers)
         })
         .collect::<Vec<_>>()
+}
+
+/// fetch the encounter along with its emrs, making sure the encounter belongs to the given patient.
+/// only emrs allowed by `consent` are returned if given
+async fn encounter_emrs_of(
+    nik: &NIK,
+    encounter_id: String,
+    consent: Option<&Consent>,
+) -> Vec<EmrHeaderWithStatus> {
+    let provider_registry = with_state(|s| s.config.get().provider_registry());
+    let response = PatientRegistry::do_call_get_encounter_emrs(encounter_id, provider_registry).await;
+
+    if response.encounter.patient != nik.to_string() {
+        ic_cdk::trap("encounter does not belong to the patient");
+    }
+
+    let emrs = response
+        .emrs
+        .into_iter()
+        .map(EmrHeader::from)
+        .filter(|header| {
+            consent.is_none_or(|consent| consent.allows_emr(&header.emr_id, &header.provider_id))
+        })
+        .map(|header| header.to_stable())
+        .collect::<Vec<_>>();
+
+    emr_headers_with_status(emrs).await
+}
+
+#[ic_cdk::query(guard = "only_patient", composite = true)]
+async fn emr_list_patient(req: EmrListPatientRequest) -> EmrListPatientResponse {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    let emrs = with_state(move |s| {
+        s.registry
+            .emr_binding_map
+            .emr_list(&nik, req.page, req.limit)
+    })
+    .unwrap();
+
+    emr_headers_with_status(emrs).await.into()
+}
+
+#[ic_cdk::query(guard = "only_patient", composite = true)]
+async fn encounter_list(req: EncounterListRequest) -> EncounterListResponse {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    let provider_registry = with_state(|s| s.config.get().provider_registry());
+    PatientRegistry::do_call_encounter_list_patient(
+        declarations::provider_registry::EncounterListPatientRequest {
+            user_id: nik.to_string(),
+            page: req.page,
+            limit: req.limit,
+        },
+        provider_registry,
+    )
+    .await
+    .into()
+}
+
+#[ic_cdk::query(guard = "only_patient", composite = true)]
+async fn emr_list_by_encounter(req: EmrListEncounterRequest) -> EmrListPatientResponse {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    encounter_emrs_of(&nik, req.encounter_id.to_string(), None)
+        .await
         .into()
 }
 
 #[ic_cdk::update(guard = "only_provider_registry")]
 fn notify_issued(req: IssueRequest) {
+    with_state_mut(|s| {
+        s.patient_log.record_activity(
+            Activity::new(
+                log::ActivityType::EmrCreated,
+                req.header.provider_id.clone(),
+                req.header.user_id.clone(),
+            )
+            .with_emr(req.header.emr_id.clone()),
+        );
+        s.inbox.notify(
+            &req.header.user_id,
+            NotificationEvent::EmrIssued {
+                emr_id: req.header.emr_id.clone(),
+                provider_id: req.header.provider_id.clone(),
+            },
+            Timestamp::new(),
+        );
+    });
     with_state_mut(|s| s.registry.issue_for(req.header.clone().user_id, req.header)).unwrap();
 }
 
 #[ic_cdk::update(guard = "only_provider_registry")]
 fn notify_updated(req: UpdateRequest) {
     with_state_mut(|s| {
-        s.patient_log.record(
-            log::ActivityType::Updated,
-            req.header.provider_id.clone(),
-            req.header.user_id.clone(),
-        )
+        s.patient_log.record_activity(
+            Activity::new(
+                log::ActivityType::Updated,
+                req.header.provider_id.clone(),
+                req.header.user_id.clone(),
+            )
+            .with_emr(req.header.emr_id.clone()),
+        );
+        s.inbox.notify(
+            &req.header.user_id,
+            NotificationEvent::EmrUpdated {
+                emr_id: req.header.emr_id.clone(),
+                provider_id: req.header.provider_id.clone(),
+            },
+            Timestamp::new(),
+        );
     });
     with_state_mut(|s| s.registry.header_status_map.update(req.header)).unwrap();
 }
 
+/// drop emrs purged by the emr registry retention job, relayed by the provider registry
+#[ic_cdk::update(guard = "only_provider_registry")]
+fn notify_purged(req: NotifyPurgedRequest) {
+    with_state_mut(|s| {
+        for header in req.headers {
+            s.registry.purge(header);
+        }
+    });
+}
+
+/// whether the patient is registered and the provider has a session open with them, used by the
+/// provider registry before starting an encounter
+#[ic_cdk::query(guard = "only_provider_registry")]
+fn has_session(req: HasSessionRequest) -> bool {
+    let registered = with_state(|s| s.registry.get_patient_info(req.nik.clone()).is_ok());
+    registered && ConsentsApi::has_session(&req.provider_id, &req.nik)
+}
+
+/// the emrs the provider can access through one of its open sessions, used by the provider registry
+/// to hide emrs of other patients from the link graph and to authorize updates
+#[ic_cdk::query(guard = "only_provider_registry")]
+fn readable_emrs(req: ReadableEmrsRequest) -> ReadableEmrsResponse {
+    let access = req.access.unwrap_or_default();
+    ReadableEmrsResponse::new(ConsentsApi::readable_emrs(&req.provider_id, req.headers, access))
+}
+
 // TODO : unsafe, anybody can register as a patient and bind to any NIK, should discuss how do we gate this properly.
 // probably best to only allow this be called from the frontend canister(todo)
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "rate_limit_register_patient")]
 fn register_patient(req: RegisterPatientRequest) -> RegisterPatientResponse {
     let caller = verified_caller().unwrap();
     let nik = NIK::from_str(&req.nik.to_string()).unwrap();
 
     // check if the NIK exists
     with_state_mut(|s| {
-        if let Ok(existing_owner) = s.registry.owner_map.get_principal(&nik) {
+        if s.registry.owner_map.is_nik_in_use(&nik) {
             // if the NIK exists but belongs to a different owner, return error
-            if existing_owner != caller {
+            if !s.registry.owner_map.get_principals(&nik).contains(&caller) {
                 return RegisterPatientResponse {
                     result: RegisterPatientStatus::Error(
                         "[REGISTER_PATIENT] This NIK is already registered to another user. Each NIK can only be registered to one user account. If you believe this is an error, please contact support.".to_string(),
@@ -485,17 +755,13 @@ fn register_patient(req: RegisterPatientRequest) -> RegisterPatientResponse {
             // if the NIK belongs to the same owner, check KYC status
             // this is the case when the user wants to resubmit their form for kyc
             if let Ok(patient) = s.registry.info_map.get(nik.clone()) {
-                match patient.clone() {
-                    Patient::V1(v1) => {
-                        // allow re-registration only if KYC status is denied
-                        if matches!(v1.kyc_status, KycStatus::Denied) {
-                            s.registry.owner_map.rebind(caller, nik.clone()).unwrap();
-                            return RegisterPatientResponse {
-                                result: RegisterPatientStatus::Success,
-                                nik: nik.clone(),
-                            };
-                        }
-                    }
+                // allow re-registration only if KYC status is denied
+                if matches!(patient.kyc_status(), KycStatus::Denied) {
+                    s.registry.owner_map.rebind(caller, nik.clone()).unwrap();
+                    return RegisterPatientResponse {
+                        result: RegisterPatientStatus::Success,
+                        nik: nik.clone(),
+                    };
                 }
             }
             return RegisterPatientResponse {
@@ -526,143 +792,561 @@ fn register_patient(req: RegisterPatientRequest) -> RegisterPatientResponse {
     })
 }
 
-// TODO : optimize this, this is a very expensive operation
-#[ic_cdk::query(composite = true)]
-async fn patient_list() -> PatientListResponse {
-    let caller = verified_caller().unwrap();
-    let provider_registry = with_state(|s| s.config.get().provider_registry());
-    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
-    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
-    let provider: ProviderId = match provider.providers.first().unwrap() {
-        declarations::provider_registry::Provider::V1(provider) => {
-            provider.internal_id.clone().try_into().unwrap()
-        }
-    };
+/// called from the new device, returns a one-time code the patient approves from a device that is already linked
+#[ic_cdk::update(guard = "rate_limit_request_device_link")]
+async fn request_device_link(req: Option<RequestDeviceLinkRequest>) -> DeviceLinkCodeResponse {
+    let req = req.unwrap_or_default();
+    device::validate_label(&req.label).unwrap_or_else(|e| ic_cdk::trap(&e));
 
-    if !ConsentsApi::is_session_user(provider.to_stable_ref()) {
-        ic_cdk::trap("only session user can call this method");
+    let caller = verified_caller().unwrap();
+    if with_state(|s| s.registry.owner_map.is_valid_owner(&caller)) {
+        ic_cdk::trap("caller is already linked to a patient");
     }
 
-    let consents = ConsentsApi::user_list_with_consent(&provider);
+    let random = CanisterRandomSource::new().await.raw_random_u64();
+    let (code, expires_at) = with_state_mut(|s| {
+        s.devices
+            .request_link(caller, req.label, random, &Timestamp::new())
+    });
 
-    consents
-        .into_iter()
-        .map(|c| {
-            let nik = c.nik;
-            let session_id = c.session_id.expect("claimed session must have session id");
-            let patient = with_state(|s| {
-                s.registry
-                    .get_patient_info(nik.clone())
-                    .expect("patient not found")
-            });
+    DeviceLinkCodeResponse { code, expires_at }
+}
 
-            PatientWithNikAndSession::new(patient, nik, session_id)
-        })
-        .collect::<Vec<_>>()
-        .into()
+/// link the device that asked for the code to the caller, the device can call every patient method right after
+#[ic_cdk::update(guard = "only_patient")]
+fn approve_device_link(req: ApproveDeviceLinkRequest) -> Result<Device, String> {
+    let caller = verified_caller().unwrap();
+
+    with_state_mut(|s| {
+        s.devices.approve_link(
+            &mut s.registry.owner_map,
+            &req.code,
+            caller,
+            &Timestamp::new(),
+        )
+    })
 }
 
-// a patient list function for admins only
-#[ic_cdk::query(guard = "only_admin_or_controller")]
-async fn get_patient_list_admin() -> PatientListAdminResponse {
-    // get all NIKs from the owner map
-    let patients = with_state(|s| {
-        s.registry
+#[ic_cdk::query(guard = "only_patient")]
+fn device_list() -> DeviceListResponse {
+    let caller = verified_caller().unwrap();
+
+    with_state(|s| {
+        let nik = s.registry.owner_map.get_nik(&caller).unwrap().into_inner();
+        let registered = s
+            .registry
             .owner_map
-            .get_all_nik()
-            .iter()
-            .map(|nik| {
-                let nik = nik.clone().into_inner();
-                let patient = s
-                    .registry
-                    .get_patient_info(nik.clone())
-                    .expect("patient not found");
+            .get_principals(&nik)
+            .into_iter()
+            .find(|principal| !s.devices.is_linked(&nik, principal));
 
-                PatientWithNik::new(patient, nik)
-            })
-            .collect::<Vec<_>>()
-    });
+        DeviceListResponse {
+            registered,
+            devices: s.devices.list(&nik),
+        }
+    })
+}
 
-    PatientListAdminResponse::from(patients)
+/// unlink one of the caller devices, the device the patient registered with can't be revoked
+#[ic_cdk::update(guard = "only_patient")]
+fn revoke_device(req: RevokeDeviceRequest) -> Result<(), String> {
+    let caller = verified_caller().unwrap();
+
+    with_state_mut(|s| {
+        let nik = s.registry.owner_map.get_nik(&caller).unwrap().into_inner();
+        s.devices
+            .revoke(&mut s.registry.owner_map, &nik, &req.principal)
+    })
 }
 
-#[ic_cdk::query(composite = true)]
-async fn search_patient(req: SearchPatientRequest) -> SearchPatientResponse {
+/// called from a new principal by a patient that lost access to every linked device.
+/// an admin reviews the evidence, see [review_account_recovery]. requests for a NIK that is not
+/// registered are queued like any other, so the response doesn't tell whether the NIK is in use
+#[ic_cdk::update(guard = "rate_limit_request_account_recovery")]
+fn request_account_recovery(req: RequestAccountRecoveryRequest) -> Result<u64, String> {
     let caller = verified_caller().unwrap();
-    let provider_registry = with_state(|s| s.config.get().provider_registry());
-    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
-    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
-    let provider: ProviderId = match provider.providers.first().unwrap() {
-        declarations::provider_registry::Provider::V1(provider) => {
-            provider.internal_id.clone().try_into().unwrap()
+
+    with_state_mut(|s| {
+        if s.registry.owner_map.is_valid_owner(&caller) {
+            return Err("caller is already linked to a patient".to_string());
         }
-    };
-    if !ConsentsApi::is_session_user(provider.to_stable_ref()) {
-        ic_cdk::trap("only session user can call this method");
-    }
 
-    let consents = ConsentsApi::user_list_with_consent(&provider);
+        let id = s
+            .recoveries
+            .submit(req.nik, caller, req.evidence, Timestamp::new())?;
+        let request = s.recoveries.get(id).expect("request was just submitted");
+        record_recovery_step(s, id, &request, None);
+        log!("account recovery {} requested by {}", id, caller);
 
-    consents
-        .into_iter()
-        .find(|c| c.nik == req.nik)
-        .map(|c| {
-            let nik = c.nik;
-            let session_id = c.session_id.expect("claimed session must have session id");
-            let patient = with_state(|s| {
-                s.registry
-                    .get_patient_info(nik.clone())
-                    .expect("patient not found")
-            });
+        Ok(id)
+    })
+}
 
-            PatientWithNikAndSession::new(patient, nik, session_id)
-        })
-        .expect("patient not found")
-        .into()
+/// status of a recovery, only for the principal that requested it
+#[ic_cdk::query]
+fn account_recovery_status(req: AccountRecoveryIdRequest) -> Result<RecoveryRequest, String> {
+    let caller = verified_caller().unwrap();
+
+    with_state(|s| s.recoveries.get(req.id))
+        .filter(|request| request.principal == caller)
+        .ok_or("recovery request does not exist".to_string())
 }
 
-/// Search Patient for Admin UI
-///
-/// Description: This function is used to search for a patient by their NIK.
-///
-/// Parameters:
-/// - req: SearchPatientRequest
-///
-/// Returns:
-/// - SearchPatientAdminResponse
-#[ic_cdk::query(guard = "only_admin_or_controller")]
-fn search_patient_admin(req: SearchPatientRequest) -> SearchPatientAdminResponse {
-    let patient = with_state(|s| s.registry.get_patient_info(req.nik.clone())).unwrap();
+/// bind the NIK to the caller once the recovery is approved and its cooling-off period is over.
+/// every device previously linked to the patient loses access
+#[ic_cdk::update]
+fn complete_account_recovery(req: AccountRecoveryIdRequest) -> Result<(), String> {
+    let caller = verified_caller().unwrap();
 
-    let patient = PatientWithNik::new(patient, req.nik);
+    with_state_mut(|s| {
+        let request = s.recoveries.complete(
+            &mut s.registry.owner_map,
+            &mut s.devices,
+            req.id,
+            caller,
+            Timestamp::new(),
+        )?;
+        record_recovery_step(s, req.id, &request, None);
+
+        Ok::<_, String>(())
+    })?;
+    log!("account recovery {} completed by {}", req.id, caller);
 
-    SearchPatientAdminResponse::new(patient)
+    Ok(())
 }
 
+/// the recoveries in progress for the caller NIK, oldest first. shown on the remaining devices so the
+/// patient can cancel the recoveries they didn't ask for
 #[ic_cdk::query(guard = "only_patient")]
-fn is_consent_claimed(req: IsConsentClaimedRequest) -> IsConsentClaimedResponse {
+fn pending_account_recovery() -> Vec<AccountRecoveryEntry> {
     let caller = verified_caller().unwrap();
-    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
 
-    if let Some(consent) = ConsentsApi::resolve_session_with_code(&req.code, &patient) {
-        IsConsentClaimedResponse {
-            claimed: consent.claimed,
-            info: Some(consent),
-        }
-    } else {
-        Default::default()
-    }
+    with_state(|s| s.recoveries.open(&nik))
+        .into_iter()
+        .map(|(id, request)| AccountRecoveryEntry { id, request })
+        .collect()
 }
 
-#[ic_cdk::query(composite = true)]
-async fn ping() -> PingResult {
-    let emr_registry = with_state(|s| s.config.get().emr_registry());
-    let emr_registry_status = emr_registry.ping().await.is_ok();
-
-    PingResult {
-        emr_registry_status,
-    }
-}
+#[ic_cdk::update(guard = "only_patient")]
+fn cancel_account_recovery() -> Result<(), String> {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state_mut(|s| {
+        for (id, request) in s.recoveries.cancel(&nik, caller, Timestamp::new())? {
+            record_recovery_step(s, id, &request, None);
+        }
+
+        Ok::<_, String>(())
+    })?;
+    log!("account recovery for {} cancelled by {}", nik, caller);
+
+    Ok(())
+}
+
+/// recovery requests waiting for a review, oldest first
+#[ic_cdk::query(guard = "only_admin")]
+fn account_recovery_queue(
+    req: AccountRecoveryQueueRequest,
+) -> Result<AccountRecoveryQueueResponse, String> {
+    if req.limit == 0 {
+        return Err("limit must be greater than 0".to_string());
+    }
+
+    let (requests, total_pending) = with_state(|s| {
+        let requests = s
+            .recoveries
+            .pending()
+            .into_iter()
+            .skip(req.page.saturating_mul(req.limit) as usize)
+            .take(req.limit as usize)
+            .map(|(id, request)| AccountRecoveryEntry { id, request })
+            .collect::<Vec<_>>();
+
+        (requests, s.recoveries.pending_len())
+    });
+
+    Ok(AccountRecoveryQueueResponse {
+        requests,
+        total_pending,
+        total_pages: total_pending.div_ceil(req.limit),
+    })
+}
+
+/// approve or reject a recovery request, an approved recovery can be completed after the cooling-off period.
+/// requests for a NIK that is not registered can only be rejected
+#[ic_cdk::update(guard = "only_admin")]
+fn review_account_recovery(req: ReviewAccountRecoveryRequest) -> Result<(), String> {
+    let reviewer = verified_caller().unwrap();
+
+    with_state_mut(|s| {
+        let request = s
+            .recoveries
+            .get(req.id)
+            .ok_or("recovery request does not exist")?;
+        if req.approve && !s.registry.owner_map.is_nik_in_use(&request.nik) {
+            return Err("NIK is not registered".to_string());
+        }
+
+        let request = s.recoveries.review(
+            req.id,
+            reviewer,
+            req.approve,
+            req.note,
+            Timestamp::new(),
+        )?;
+        record_recovery_step(s, req.id, &request, Some(reviewer));
+
+        Ok(())
+    })?;
+    log!(
+        "account recovery {} {} by {}",
+        req.id,
+        if req.approve { "approved" } else { "rejected" },
+        reviewer
+    );
+
+    Ok(())
+}
+
+/// Patient List
+///
+/// Description:
+/// - List the patients the calling provider has an open session with, in the order the sessions were claimed.
+/// - Backed by the provider session index, pass `next_cursor` of a page to get the next one.
+///
+/// Parameters:
+/// - req: optional, the first 100 patients if not set
+#[ic_cdk::query(composite = true)]
+async fn patient_list(req: Option<PatientListRequest>) -> PatientListResponse {
+    let req = req.unwrap_or_default();
+    let limit = page_limit(req.limit);
+    let provider = caller_provider_id().await;
+
+    if !ConsentsApi::is_session_user(provider.to_stable_ref()) {
+        ic_cdk::trap("only session user can call this method");
+    }
+
+    let registered =
+        req.registered_from.unwrap_or_default()..=req.registered_to.unwrap_or(u64::MAX);
+
+    with_state(|s| {
+        // the scan starts at the cursor claim order, so it stays valid after its session is closed
+        let mut page = ConsentsApi::provider_sessions_page(
+            &provider,
+            req.cursor
+                .as_ref()
+                .map(|cursor| (&cursor.claimed_at, &cursor.session_id)),
+            limit + 1,
+            |session| {
+                let patient = s.registry.get_patient_info(session.nik.clone()).ok()?;
+                let registered_at = s.registry.info_map.registered_key(&session.nik);
+
+                let matches = req
+                    .kyc_status
+                    .as_ref()
+                    .is_none_or(|status| patient.kyc_status() == status)
+                    && registered.contains(&registered_at);
+
+                matches.then_some((session, patient))
+            },
+        );
+
+        let next_cursor = if page.len() > limit {
+            page.pop().map(|(session, _)| PatientListCursor {
+                claimed_at: session.claimed_at,
+                session_id: session.session_id,
+            })
+        } else {
+            None
+        };
+
+        PatientListResponse {
+            patients: page
+                .into_iter()
+                .map(|(session, patient)| {
+                    PatientWithNikAndSession::new(
+                        patient.versioned(req.version),
+                        session.nik,
+                        session.session_id,
+                    )
+                })
+                .collect(),
+            next_cursor,
+            total: ConsentsApi::provider_session_count(&provider),
+        }
+    })
+}
+
+/// Provider Session List
+///
+/// Description:
+/// - List the sessions the calling provider currently has open, along with the patient names.
+/// - Backed by the provider session index, so it does not scan every consent.
+///
+/// Parameters:
+/// - page: The page number, starting from 0.
+/// - limit: The number of sessions per page.
+/// - sort: Newest claim first by default.
+#[ic_cdk::query(composite = true)]
+async fn provider_session_list(
+    req: ProviderSessionListRequest,
+) -> Result<ProviderSessionListResponse, String> {
+    if req.limit == 0 {
+        return Err("limit must be greater than 0".to_string());
+    }
+
+    let provider = caller_provider_id().await;
+
+    let mut sessions = with_state(|s| {
+        ConsentsApi::provider_sessions(&provider)
+            .into_iter()
+            .filter_map(|session| {
+                let patient = s.registry.get_patient_info(session.nik.clone()).ok()?;
+
+                Some(ProviderPatientSession {
+                    patient_name: patient.name(),
+                    session,
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    // the index is ordered by claim time, oldest first
+    match req.sort.unwrap_or_default() {
+        ProviderSessionSort::Newest => sessions.reverse(),
+        ProviderSessionSort::Oldest => (),
+        ProviderSessionSort::PatientName => {
+            sessions.sort_by(|a, b| a.patient_name.cmp(&b.patient_name))
+        }
+    }
+
+    let total_sessions = sessions.len() as u64;
+
+    Ok(ProviderSessionListResponse {
+        sessions: sessions
+            .into_iter()
+            .skip(req.page.saturating_mul(req.limit) as usize)
+            .take(req.limit as usize)
+            .collect(),
+        total_sessions,
+        total_pages: total_sessions.div_ceil(req.limit),
+    })
+}
+
+// a patient list function for admins only, backed by the registration index instead of scanning every patient
+#[ic_cdk::query(guard = "only_admin_or_controller")]
+async fn get_patient_list_admin(req: Option<PatientListAdminRequest>) -> PatientListAdminResponse {
+    patient_list_admin(req)
+}
+
+/// same as [get_patient_list_admin], every listed patient gets an
+/// [log::ActivityType::AdminRead] entry
+#[ic_cdk::update(guard = "only_admin_or_controller")]
+fn get_patient_list_admin_logged(req: Option<PatientListAdminRequest>) -> PatientListAdminResponse {
+    let response = patient_list_admin(req);
+    record_admin_reads(response.patients.iter().map(|patient| patient.nik.clone()));
+
+    response
+}
+
+fn patient_list_admin(req: Option<PatientListAdminRequest>) -> PatientListAdminResponse {
+    let req = req.unwrap_or_default();
+    let limit = page_limit(req.limit);
+
+    with_state(|s| {
+        let page = s.registry.info_map.index().registered(
+            req.kyc_status.as_ref(),
+            req.registered_from,
+            req.registered_to,
+            req.cursor.map(|cursor| (cursor.registered_at, cursor.nik)),
+            limit,
+        );
+
+        let patients = page
+            .niks
+            .into_iter()
+            .filter_map(|nik| {
+                let patient = s.registry.info_map.get(nik.clone()).ok()?;
+                Some(PatientWithNik::new(patient.versioned(req.version), nik))
+            })
+            .collect();
+
+        PatientListAdminResponse {
+            patients,
+            next_cursor: page
+                .next
+                .map(|(registered_at, nik)| PatientListAdminCursor { registered_at, nik }),
+            total: page.total,
+        }
+    })
+}
+
+/// keep the admin lookup in the activity log of every patient
+fn record_admin_reads(niks: impl IntoIterator<Item = NIK>) {
+    let admin = verified_caller().unwrap();
+
+    with_state_mut(|s| {
+        for nik in niks {
+            s.patient_log.record_activity(
+                Activity::by_patient(log::ActivityType::AdminRead, nik).with_admin(admin),
+            );
+        }
+    });
+}
+
+/// page size of the patient lists and activity log, traps on a zero limit
+fn page_limit(limit: Option<u64>) -> usize {
+    let limit = limit.unwrap_or(PAGE_LIMIT).min(PAGE_LIMIT);
+    if limit == 0 {
+        ic_cdk::trap("limit must be greater than 0");
+    }
+
+    limit as usize
+}
+
+#[ic_cdk::query(composite = true)]
+async fn search_patient(req: SearchPatientRequest) -> SearchPatientResponse {
+    let caller = verified_caller().unwrap();
+    let provider_registry = with_state(|s| s.config.get().provider_registry());
+    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
+    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
+    let provider: ProviderId = match provider.providers.first().unwrap() {
+        declarations::provider_registry::Provider::V1(provider) => {
+            provider.internal_id.clone().try_into().unwrap()
+        }
+    };
+    if !ConsentsApi::is_session_user(provider.to_stable_ref()) {
+        ic_cdk::trap("only session user can call this method");
+    }
+
+    let consents = ConsentsApi::user_list_with_consent(&provider);
+
+    consents
+        .into_iter()
+        .find(|c| c.nik == req.nik)
+        .map(|c| {
+            let nik = c.nik;
+            let session_id = c.session_id.expect("claimed session must have session id");
+            let patient = with_state(|s| {
+                s.registry
+                    .get_patient_info(nik.clone())
+                    .expect("patient not found")
+            });
+
+            PatientWithNikAndSession::new(patient.versioned(req.version), nik, session_id)
+        })
+        .expect("patient not found")
+        .into()
+}
+
+/// Search Patient for Admin UI
+///
+/// Description: This function is used to search for a patient by their NIK.
+///
+/// Parameters:
+/// - req: SearchPatientRequest
+///
+/// Returns:
+/// - SearchPatientAdminResponse
+#[ic_cdk::query(guard = "only_admin_or_controller")]
+fn search_patient_admin(req: SearchPatientRequest) -> SearchPatientAdminResponse {
+    let patient = with_state(|s| s.registry.get_patient_info(req.nik.clone())).unwrap();
+
+    let patient = PatientWithNik::new(patient.versioned(req.version), req.nik);
+
+    SearchPatientAdminResponse::new(patient)
+}
+
+/// same as [search_patient_admin], the lookup is kept in the patient activity log
+#[ic_cdk::update(guard = "only_admin_or_controller")]
+fn search_patient_admin_logged(req: SearchPatientRequest) -> SearchPatientAdminResponse {
+    let response = search_patient_admin(req);
+    record_admin_reads([response.patient_info.nik.clone()]);
+
+    response
+}
+
+/// Search Patients for Admin UI
+///
+/// Description: search patients by name prefix, date of birth and KYC status, backed by the patient indexes.
+/// at least one filter is required, patients have to match every given filter. name prefixes need
+/// at least [search::MIN_NAME_PREFIX_LEN] characters, pages continue from `next_cursor` and may
+/// come back short on sparse matches.
+///
+/// Parameters:
+/// - req: SearchPatientsAdminRequest
+///
+/// Returns:
+/// - SearchPatientsAdminResponse, ordered by name when searching by name
+///
+/// an update so every patient found is kept in their activity log
+#[ic_cdk::update(guard = "only_admin_or_controller")]
+fn search_patients_admin(
+    req: SearchPatientsAdminRequest,
+) -> Result<SearchPatientsAdminResponse, String> {
+    let limit = page_limit(req.limit);
+
+    let response = with_state(|s| -> Result<_, String> {
+        let page = s
+            .registry
+            .info_map
+            .index()
+            .search(
+                req.name.as_deref(),
+                req.date_of_birth.as_deref(),
+                req.kyc_status.as_ref(),
+                req.cursor.map(|cursor| (cursor.key, cursor.nik)),
+                limit,
+            )
+            .map_err(|e| e.to_string())?;
+
+        let patients = page
+            .niks
+            .into_iter()
+            .filter_map(|nik| {
+                let patient = s.registry.info_map.get(nik.clone()).ok()?;
+                Some(PatientWithNik::new(patient.versioned(req.version), nik))
+            })
+            .collect();
+
+        Ok(SearchPatientsAdminResponse {
+            patients,
+            next_cursor: page
+                .next
+                .map(|(key, nik)| SearchPatientsAdminCursor { key, nik }),
+        })
+    })?;
+    record_admin_reads(response.patients.iter().map(|patient| patient.nik.clone()));
+
+    Ok(response)
+}
+
+#[ic_cdk::query(guard = "only_patient")]
+fn is_consent_claimed(req: IsConsentClaimedRequest) -> IsConsentClaimedResponse {
+    let caller = verified_caller().unwrap();
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    if let Some(consent) = ConsentsApi::resolve_session_with_code(&req.code, &patient) {
+        IsConsentClaimedResponse {
+            claimed: consent.claimed,
+            info: Some(consent),
+        }
+    } else {
+        Default::default()
+    }
+}
+
+#[ic_cdk::query(composite = true)]
+async fn ping() -> PingResult {
+    let emr_registry = with_state(|s| s.config.get().emr_registry());
+    let emr_registry_status = emr_registry.ping().await.is_ok();
+
+    PingResult {
+        emr_registry_status,
+    }
+}
 
 #[ic_cdk::query(guard = "only_authorized_metrics_collector")]
 fn metrics() -> String {
@@ -670,6 +1354,7 @@ fn metrics() -> String {
         [
             ConsentsApi::metrics(),
             opaque_metrics!(s.registry),
+            opaque_metrics!(s.claim_throttle),
             OpaqueMetrics::measure(&**s.config.get()),
             statistics::canister::BlockchainMetrics::measure(),
             statistics::canister::MemoryStatistics::measure(),
@@ -700,18 +1385,50 @@ pub async fn update_canistergeek_information(
 
 // TODO : make all emr list is available and user does not have to choose what emr to share, share everything by default
 #[ic_cdk::update(guard = "only_patient")]
-async fn create_consent() -> CreateConsentResponse {
+async fn create_consent(req: Option<CreateConsentRequest>) -> CreateConsentResponse {
     let owner = verified_caller().unwrap();
     let owner = with_state(|s| s.registry.owner_map.get_nik(&owner))
         .unwrap()
         .into_inner();
 
-    ConsentsApi::generate_consent(owner).into()
+    let req = req.unwrap_or_default();
+    if let Some(ref scope) = req.scope {
+        scope.validate().unwrap();
+    }
+    if let Some(ref standing) = req.standing {
+        standing.validate().unwrap();
+    }
+    if let Some(ref purposes) = req.purposes {
+        Purpose::validate_list(purposes).unwrap();
+    }
+
+    // make sure the provider exists, traps otherwise
+    if let Some(ref target) = req.provider {
+        let provider_registry = with_state(|s| s.config.get().provider_registry());
+        provider_registry
+            .get_provider_batch(GetProviderBatchRequest {
+                ids: vec![target.to_string()],
+            })
+            .await
+            .expect("provider does not exist");
+    }
+
+    ConsentsApi::generate_consent(
+        owner,
+        req.scope,
+        req.provider,
+        req.standing,
+        req.long_code.unwrap_or(false),
+        req.require_approval.unwrap_or(false),
+        req.purposes,
+    )
+    .into()
 }
 
 #[ic_cdk::update(guard = "only_patient")]
 fn create_consent_for_group(req: CreateConsentForGroupRequest) -> CreateConsentForGroupResponse {
-    let code = with_state_mut(|s| s.registry.group_consent_map.generate_code());
+    let long_code = req.long_code.unwrap_or(false);
+    let code = with_state_mut(|s| s.registry.group_consent_map.generate_code(long_code));
 
     // bind it to a nik
     with_state_mut(|s| s.registry.group_consent_map.bind(code, req.nik)).unwrap();
@@ -741,6 +1458,34 @@ async fn read_emr_with_session(
         .unwrap()
 }
 
+/// same as [read_emr_with_session], the read is kept in the patient activity log
+#[ic_cdk::update]
+async fn read_emr_with_session_logged(
+    req: ReadEmrSessionRequest,
+) -> crate::declarations::emr_registry::ReadEmrByIdResponse {
+    let provider = caller_provider_id().await;
+
+    let registry = with_state(|s| s.config.get().emr_registry());
+    let emr_id = req.args.emr_id.clone();
+    let response =
+        ConsentsApi::read_emr_with_session(&req.session_id, req.args, registry, &provider)
+            .await
+            .unwrap();
+
+    if let Some(snapshot) = ConsentsApi::session_snapshot(&req.session_id) {
+        with_state_mut(|s| {
+            s.patient_log.record_activity(
+                Activity::new(log::ActivityType::EmrRead, provider, snapshot.consent.nik)
+                    .with_purpose(Some(snapshot.purpose))
+                    .with_emr(emr_id)
+                    .with_session(req.session_id),
+            )
+        });
+    }
+
+    response
+}
+
 #[ic_cdk::query(composite = true)]
 async fn emr_list_with_session(req: EmrListConsentRequest) -> EmrListConsentResponse {
     let caller = verified_caller().unwrap();
@@ -755,58 +1500,59 @@ async fn emr_list_with_session(req: EmrListConsentRequest) -> EmrListConsentResp
 
     let consent =
         ConsentsApi::resolve_session(&req.session_id, &provider).expect("invalid session");
-    let nik = consent.nik;
+    let nik = consent.nik.clone();
     let info = with_state(|s| s.registry.info_map.get(nik.clone())).unwrap();
 
-    let emrs = with_state(|s| {
-        s.registry
-            .emr_binding_map
-            .emr_list(&nik, req.page, req.limit)
-    })
-    .unwrap();
-
-    let provider_registry = with_state(|s| s.config.get().provider_registry());
-
-    let providers = emrs
-        .iter()
-        .map(|header| header.provider_id.to_string())
-        .collect::<Vec<_>>();
+    let emrs = match consent.scope.as_ref().filter(|scope| scope.restricts_emrs()) {
+        // the scope filters the emr list, so paginate after filtering to keep pages full
+        Some(scope) => with_state(|s| s.registry.emr_binding_map.emr_list_all(&nik))
+            .unwrap()
+            .into_iter()
+            .filter(|header| scope.allows_emr(&header.emr_id, &header.provider_id))
+            .skip(req.page as usize * req.limit as usize)
+            .take(req.limit as usize)
+            .collect(),
 
-    let providers = provider_registry
-        .get_provider_batch(GetProviderBatchRequest { ids: providers })
-        .await
-        .expect("failed to get providers info")
-        .0
-        .providers
-        .into_iter()
-        .map(|provider| match provider {
-            declarations::provider_registry::Provider::V1(provider) => {
-                provider.display_name.try_into().unwrap()
-            }
+        None => with_state(|s| {
+            s.registry
+                .emr_binding_map
+                .emr_list(&nik, req.page, req.limit)
         })
-        .collect::<Vec<_>>();
-
-    let emrs = emrs
-        .into_iter()
-        .zip(providers.into_iter())
-        .map(move |(header, providers)| {
-            let status = with_state(|s| {
-                s.registry
-                    .header_status_map
-                    .get(&header)
-                    .expect("issued emr must have valid status")
-            });
+        .unwrap(),
+    };
 
-            EmrHeaderWithStatus::new(header, status, providers)
-        })
-        .collect::<Vec<_>>();
+    let emrs = emr_headers_with_status(emrs).await;
 
-    EmrListConsentResponse::new(emrs, info.name().to_owned())
+    EmrListConsentResponse::new(emrs, info.name())
 }
 
-#[cfg(feature = "vetkd")]
-#[ic_cdk::update]
-/// Derive the encryption key with the session id securely transported by encrypting the decryption key, used to decrypt emr
+#[ic_cdk::query(composite = true)]
+async fn emr_list_by_encounter_with_session(
+    req: EmrListEncounterSessionRequest,
+) -> EmrListConsentResponse {
+    let caller = verified_caller().unwrap();
+    let provider_registry = with_state(|s| s.config.get().provider_registry());
+    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
+    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
+    let provider = match provider.providers.first().unwrap() {
+        declarations::provider_registry::Provider::V1(provider) => {
+            provider.internal_id.clone().try_into().unwrap()
+        }
+    };
+
+    let consent =
+        ConsentsApi::resolve_session(&req.session_id, &provider).expect("invalid session");
+    let nik = consent.nik.clone();
+    let info = with_state(|s| s.registry.info_map.get(nik.clone())).unwrap();
+
+    let emrs = encounter_emrs_of(&nik, req.encounter_id.to_string(), Some(&consent)).await;
+
+    EmrListConsentResponse::new(emrs, info.name())
+}
+
+#[cfg(feature = "vetkd")]
+#[ic_cdk::update]
+/// Derive the encryption key with the session id securely transported by encrypting the decryption key, used to decrypt emr
 async fn derive_encryption_key_with_session(
     req: DeriveSecretKeyRequest,
 ) -> DeriveSecretKeyResponse {
@@ -822,186 +1568,1027 @@ async fn derive_encryption_key_for_self() {
     todo!()
 }
 
-#[cfg(feature = "vetkd")]
-#[ic_cdk::update]
-/// Derive the encryption verification key with the session id, used to verify the encrypted emr decryption key
-async fn derive_encryption_verification_key_with_session(
-    req: DeriveVerificationKeyRequest,
-) -> DeriveVerificationKeyResponse {
-    let consent = ConsentsApi::resolve_session(&req.session_id).expect("session not found");
-    vetkd::EncryptionApi::verification_key_for(&consent.nik)
-        .await
+#[cfg(feature = "vetkd")]
+#[ic_cdk::update]
+/// Derive the encryption verification key with the session id, used to verify the encrypted emr decryption key
+async fn derive_encryption_verification_key_with_session(
+    req: DeriveVerificationKeyRequest,
+) -> DeriveVerificationKeyResponse {
+    let consent = ConsentsApi::resolve_session(&req.session_id).expect("session not found");
+    vetkd::EncryptionApi::verification_key_for(&consent.nik)
+        .await
+        .into()
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+fn update_emr_registry_principal(req: UpdateEmrRegistryRequest) {
+    with_state_mut(|s| {
+        let mut config = s.config.get().to_owned();
+
+        config.update_default_emr_registry_principal(req.principal);
+
+        match s.config.set(config) {
+            Ok(_) => (),
+            Err(e) => ic_cdk::trap(&format!("failed to update emr registry principal: {:?}", e)),
+        }
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+fn update_rate_limit(req: UpdateRateLimitRequest) {
+    with_state_mut(|s| {
+        let mut config = s.config.get().to_owned();
+
+        config.update_rate_limit(req.method, req.limit);
+
+        match s.config.set(config) {
+            Ok(_) => (),
+            Err(e) => ic_cdk::trap(&format!("failed to update rate limit: {:?}", e)),
+        }
+    })
+}
+
+#[ic_cdk::query(guard = "only_canister_owner")]
+fn get_rate_limits() -> Vec<MethodRateLimit> {
+    with_state(|s| s.config.get().rate_limits())
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+fn update_provider_registry_principal(req: UpdateEmrRegistryRequest) {
+    with_state_mut(|s| {
+        let mut config = s.config.get().to_owned();
+
+        config.update_provider_registry_principal(req.principal);
+
+        match s.config.set(config) {
+            Ok(_) => (),
+            Err(e) => ic_cdk::trap(&format!("failed to update emr registry principal: {:?}", e)),
+        }
+    })
+}
+
+#[ic_cdk::update(guard = "only_patient")]
+fn update_patient_info(req: UpdatePatientInfoRequest) {
+    let caller = verified_caller().unwrap();
+    with_state_mut(|s| {
+        let nik = s.registry.owner_map.get_nik(&caller)?.into_inner();
+
+        // stored as V2 from now on, V2 only fields are kept as is
+        let mut patient = match s.registry.get_patient_info(nik.clone()) {
+            Ok(existing) => existing.into_v2(),
+            Err(_) => V2::default(),
+        };
+        patient.apply_v1(req.info);
+
+        // V1 clients can't send the raw NIK, the date of birth is only locked once kyc is approved
+        if let Err(e) = patient.validate() {
+            ic_cdk::trap(&e.to_string());
+        }
+
+        if let Ok(current) = s.registry.get_patient_info(nik.clone()) {
+            if let Err(e) = patient.validate_update(&current) {
+                ic_cdk::trap(&e.to_string());
+            }
+        }
+
+        // the kyc status only changes through the kyc case, see [submit_kyc]
+        patient.kyc_status = derived_kyc_status(s, &nik);
+
+        s.registry.update_patient_info(caller, patient.into())
+    })
+    .unwrap()
+}
+
+/// update the caller info with the V2 profile. the date of birth has to match the one encoded in
+/// the NIK and is locked once kyc is approved, see [V2::validate_update]
+#[ic_cdk::update(guard = "only_patient")]
+fn update_patient_info_v2(req: UpdatePatientInfoV2Request) -> Result<(), String> {
+    let caller = verified_caller().unwrap();
+    let mut patient = req.info;
+    patient.validate().map_err(|e| e.to_string())?;
+
+    with_state_mut(|s| {
+        let nik = s
+            .registry
+            .owner_map
+            .get_nik(&caller)
+            .map_err(|e| e.to_string())?
+            .into_inner();
+        patient
+            .validate_against_nik(&req.nik, &nik)
+            .map_err(|e| e.to_string())?;
+        if let Ok(current) = s.registry.get_patient_info(nik.clone()) {
+            patient.validate_update(&current).map_err(|e| e.to_string())?;
+        }
+
+        // the kyc status only changes through the kyc case, see [submit_kyc]
+        patient.kyc_status = derived_kyc_status(s, &nik);
+
+        s.registry
+            .update_patient_info(caller, patient.into())
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// the status of the patient kyc case, patients without a case keep the status they already have
+fn derived_kyc_status(s: &State, nik: &NIK) -> KycStatus {
+    match s.kyc.get(nik) {
+        Some(case) => case.status.kyc_status(),
+        None => s
+            .registry
+            .get_patient_info(nik.clone())
+            .map(|patient| patient.kyc_status().clone())
+            .unwrap_or_default(),
+    }
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_patient_info_with_consent(
+    req: GetPatientInfoBySessionRequest,
+) -> GetPatientInfoResponse {
+    let caller = verified_caller().unwrap();
+    let provider_registry = with_state(|s| s.config.get().provider_registry());
+    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
+    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
+    let provider = match provider.providers.first().unwrap() {
+        declarations::provider_registry::Provider::V1(provider) => {
+            provider.internal_id.clone().try_into().unwrap()
+        }
+    };
+
+    let consent =
+        ConsentsApi::resolve_session(&req.session_id, &provider).expect("invalid session");
+    let patient = with_state(|s| s.registry.get_patient_info(consent.nik.clone())).unwrap();
+    GetPatientInfoResponse::new(patient.versioned(req.version), consent.nik)
+}
+
+#[ic_cdk::query(guard = "only_admin_or_controller_or_patient")]
+fn get_patient_info(req: Option<GetPatientInfoRequest>) -> GetPatientInfoResponse {
+    let caller = verified_caller().unwrap();
+    let req = req.unwrap_or_default();
+    let (patient, nik) =
+        with_state(|s| s.registry.get_patient_info_with_principal(caller)).unwrap();
+
+    GetPatientInfoResponse::new(patient.versioned(req.version), nik)
+}
+
+#[ic_cdk::update(guard = "only_patient")]
+fn revoke_consent(req: RevokeConsentRequest) {
+    for code in req.codes {
+        let user_consent = ConsentsApi::consent(&code).expect("consent not found");
+        let snapshots = ConsentsApi::sessions_of(&code)
+            .into_iter()
+            .map(|session_id| {
+                let snapshot = ConsentsApi::session_snapshot(&session_id);
+                (session_id, snapshot)
+            })
+            .collect::<Vec<_>>();
+
+        ConsentsApi::revoke_consent(&code);
+
+        for (session_id, snapshot) in snapshots {
+            issue_receipt(ReceiptEvent::Finished, &session_id, snapshot);
+        }
+
+        if !user_consent.claimed && user_consent.session_user.is_none() {
+            continue;
+        }
+
+        with_state_mut(|s| {
+            s.patient_log.record(
+                log::ActivityType::Revoked,
+                user_consent.session_user.unwrap(),
+                user_consent.nik,
+            )
+        });
+    }
+}
+
+#[ic_cdk::query(guard = "only_patient")]
+fn get_logs(req: Option<GetLogsRequest>) -> LogResponse {
+    let req = req.unwrap_or_default();
+    let limit = page_limit(req.limit);
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    let filter = LogFilter {
+        purpose: req.purpose,
+        activity_types: req.activity_types.unwrap_or_default(),
+        from: req.from,
+        to: req.to,
+    };
+    let (logs, next_cursor) =
+        with_state(|s| s.patient_log.find_logs(&nik, &filter, req.cursor, limit));
+
+    LogResponse::new(logs, next_cursor)
+}
+
+/// recompute the hash chain over the caller activity log, a mismatch means the log was rewritten.
+/// the caller chain head and the chain root are certified, see [VerifyLogChainResponse::witness]
+#[ic_cdk::query(guard = "only_patient")]
+fn verify_log_chain(req: Option<VerifyLogChainRequest>) -> VerifyLogChainResponse {
+    let req = req.unwrap_or_default();
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state(|s| VerifyLogChainResponse {
+        verification: s.patient_log.verify_chain(&nik, req.known_head.as_ref()),
+        root: s.patient_log.chain_root(),
+        certificate: ic_cdk::api::data_certificate(),
+        witness: s.patient_log.chain_witness(&nik),
+    })
+}
+
+/// notifications in the caller inbox, newest first
+#[ic_cdk::query(guard = "only_patient")]
+fn notification_list(req: Option<NotificationListRequest>) -> NotificationListResponse {
+    let req = req.unwrap_or_default();
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state(|s| {
+        let (notifications, total) = s.inbox.list(
+            &nik,
+            req.unread_only.unwrap_or(false),
+            req.page.unwrap_or(0),
+            page_limit(req.limit),
+        );
+
+        NotificationListResponse {
+            notifications,
+            total,
+            unread: s.inbox.unread(&nik),
+        }
+    })
+}
+
+#[ic_cdk::query(guard = "only_patient")]
+fn unread_notification_count() -> u64 {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state(|s| s.inbox.unread(&nik))
+}
+
+/// returns how many notifications were marked, unknown and already read ids are skipped
+#[ic_cdk::update(guard = "only_patient")]
+fn mark_notifications_read(req: MarkNotificationsReadRequest) -> u64 {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state_mut(|s| s.inbox.mark_read(&nik, &req.ids, Timestamp::new()))
+}
+
+#[ic_cdk::update(guard = "only_patient")]
+fn mark_all_notifications_read() -> u64 {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state_mut(|s| s.inbox.mark_all_read(&nik, Timestamp::new()))
+}
+
+#[ic_cdk::query(guard = "only_patient")]
+fn notification_preferences() -> NotificationPreferences {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state(|s| s.inbox.preferences(&nik))
+}
+
+/// muted kinds are not stored in the inbox from now on, notifications already stored are kept
+#[ic_cdk::update(guard = "only_patient")]
+fn update_notification_preferences(req: NotificationPreferences) {
+    let caller = verified_caller().unwrap();
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state_mut(|s| s.inbox.set_preferences(&nik, req))
+}
+
+#[ic_cdk::update]
+async fn finish_session(req: FinishSessionRequest) {
+    let caller = verified_caller().unwrap();
+    let provider_registry = with_state(|s| s.config.get().provider_registry());
+    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
+    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
+    let provider = match provider.providers.first().unwrap() {
+        declarations::provider_registry::Provider::V1(provider) => {
+            provider.internal_id.clone().try_into().unwrap()
+        }
+    };
+
+    let snapshot = ConsentsApi::session_snapshot(&req.session_id);
+    ConsentsApi::finish_sesion(&req.session_id, &provider);
+
+    // only sessions of the caller are finished
+    if let Some(snapshot) = snapshot
+        .as_ref()
+        .filter(|snapshot| snapshot.consent.session_user.as_ref() == Some(&provider))
+    {
+        with_state_mut(|s| {
+            s.patient_log.record_activity(
+                Activity::new(
+                    log::ActivityType::SessionFinished,
+                    provider.clone(),
+                    snapshot.consent.nik.clone(),
+                )
+                .with_purpose(Some(snapshot.purpose))
+                .with_session(req.session_id.clone()),
+            );
+            s.inbox.notify(
+                &snapshot.consent.nik,
+                NotificationEvent::SessionFinished {
+                    session_id: req.session_id.clone(),
+                    provider_id: provider.clone(),
+                },
+                Timestamp::new(),
+            );
+        });
+    }
+
+    issue_receipt(ReceiptEvent::Finished, &req.session_id, snapshot);
+}
+
+// TODO : move this into provider registry
+// #[ic_cdk::update(guard = "only_provider")] // see the implementation for this up there for notes
+#[ic_cdk::update(guard = "rate_limit_claim_consent")]
+async fn claim_consent(req: ClaimConsentRequest) -> ClaimConsentResponse {
+    throttle_claim(req.code.prefix());
+
+    let provider = caller_provider_id().await;
+    let response = do_claim_consent(&req.code, provider, req.purpose.unwrap_or_default());
+
+    throttle_succeed(req.code.prefix());
+    response
+}
+
+/// accept a consent granted directly to the calling provider, listed in [pending_consent_list]
+#[ic_cdk::update]
+async fn accept_consent(req: ClaimConsentRequest) -> ClaimConsentResponse {
+    throttle_claim(req.code.prefix());
+
+    let provider = caller_provider_id().await;
+
+    let consent = ConsentsApi::consent(&req.code).expect("consent does not exists");
+    if consent.target.as_ref() != Some(&provider) {
+        ic_cdk::trap("consent is not granted to the caller");
+    }
+
+    let response = do_claim_consent(&req.code, provider, req.purpose.unwrap_or_default());
+
+    throttle_succeed(req.code.prefix());
+    response
+}
+
+#[ic_cdk::query(composite = true)]
+async fn pending_consent_list() -> ConsentListResponse {
+    let provider = caller_provider_id().await;
+    ConsentsApi::pending_consents(&provider).into()
+}
+
+/// ask the patient to approve a claim on a consent created with `require_approval`.
+/// the session is opened once the patient confirms, poll [consent_claim_status] to get it
+#[ic_cdk::update(guard = "rate_limit_claim_consent")]
+async fn request_consent_claim(req: ClaimConsentRequest) -> ConsentClaimStatusResponse {
+    throttle_claim(req.code.prefix());
+
+    let provider = caller_provider_id().await;
+    let approval =
+        ConsentsApi::request_claim(&req.code, provider, req.purpose.unwrap_or_default())
+            .unwrap_or_else(|e| ic_cdk::trap(&e));
+
+    throttle_succeed(req.code.prefix());
+
+    ConsentClaimStatusResponse {
+        approval,
+        session_id: None,
+        name: None,
+    }
+}
+
+/// status of a claim the calling provider requested with [request_consent_claim]
+#[ic_cdk::query(composite = true)]
+async fn consent_claim_status(
+    req: ClaimConsentRequest,
+) -> Result<ConsentClaimStatusResponse, String> {
+    let provider = caller_provider_id().await;
+
+    let consent = ConsentsApi::consent(&req.code)
+        .filter(|consent| {
+            consent
+                .approval
+                .as_ref()
+                .and_then(ClaimApproval::provider_id)
+                == Some(&provider)
+        })
+        .ok_or("no claim requested by the caller")?;
+
+    let session_id = match consent.approval {
+        Some(ClaimApproval::Approved { .. }) => consent.session_id,
+        _ => None,
+    };
+    let name = match session_id {
+        Some(_) => with_state(|s| s.registry.get_patient_info(consent.nik.clone()))
+            .ok()
+            .map(|patient| patient.name()),
+        None => None,
+    };
+
+    Ok(ConsentClaimStatusResponse {
+        approval: consent.approval.expect("checked above"),
+        session_id,
+        name,
+    })
+}
+
+/// confirm a pending claim on one of the caller consents, the session is opened for the provider right away
+#[ic_cdk::update(guard = "only_patient")]
+fn confirm_consent_claim(req: ConsentClaimDecisionRequest) -> Result<(), String> {
+    decide_consent_claim(&req.code, true)
+}
+
+/// reject a pending claim on one of the caller consents, the consent can't be claimed anymore
+#[ic_cdk::update(guard = "only_patient")]
+fn reject_consent_claim(req: ConsentClaimDecisionRequest) -> Result<(), String> {
+    decide_consent_claim(&req.code, false)
+}
+
+fn decide_consent_claim(code: &ConsentCode, approve: bool) -> Result<(), String> {
+    let caller = verified_caller().unwrap();
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    let (provider, session_id) = ConsentsApi::decide_claim(code, &patient, approve)?;
+
+    let snapshot = session_id
+        .as_ref()
+        .and_then(ConsentsApi::session_snapshot);
+    let purpose = snapshot.as_ref().map(|snapshot| snapshot.purpose);
+    if let Some(session_id) = &session_id {
+        issue_receipt(ReceiptEvent::Claimed, session_id, snapshot);
+    }
+
+    let (activity, opened) =
+        claim_decision(approve, provider, patient.clone(), session_id, purpose);
+    with_state_mut(|s| {
+        s.patient_log.record_activity(activity);
+        if let Some(event) = opened {
+            s.inbox.notify(&patient, event, Timestamp::new());
+        }
+    });
+
+    Ok(())
+}
+
+/// the activity a claim decision is logged as, plus a session opened notification once confirmed
+fn claim_decision(
+    approve: bool,
+    provider: ProviderId,
+    patient: NIK,
+    session_id: Option<SessionId>,
+    purpose: Option<Purpose>,
+) -> (Activity, Option<NotificationEvent>) {
+    let opened = match (&session_id, purpose) {
+        (Some(session_id), Some(purpose)) if approve => Some(NotificationEvent::SessionOpened {
+            session_id: session_id.clone(),
+            provider_id: provider.clone(),
+            purpose,
+        }),
+        _ => None,
+    };
+
+    let activity = match approve {
+        true => log::ActivityType::ClaimConfirmed,
+        false => log::ActivityType::ClaimRejected,
+    };
+    let mut activity = Activity::new(activity, provider, patient).with_purpose(purpose);
+    if let Some(session_id) = session_id {
+        activity = activity.with_session(session_id);
+    }
+
+    (activity, opened)
+}
+
+/// resolve the calling provider principal into its internal id, traps if the caller is not a provider
+async fn caller_provider_id() -> ProviderId {
+    let caller = verified_caller().unwrap();
+    let provider_registry = with_state(|s| s.config.get().provider_registry());
+    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
+    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
+
+    match provider.providers.first().unwrap() {
+        declarations::provider_registry::Provider::V1(provider) => {
+            provider.internal_id.clone().try_into().unwrap()
+        }
+    }
+}
+
+/// record the claim attempt as failed up front, traps if the caller or code is locked out.
+/// this must run before any await, otherwise a trapping claim would roll the failure back.
+fn throttle_claim(prefix: [u8; code::PREFIX_LEN]) {
+    let caller = verified_caller().unwrap();
+
+    with_state_mut(|s| {
+        s.claim_throttle
+            .attempt(caller, prefix.into(), &Timestamp::new())
+    })
+    .unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+}
+
+fn throttle_succeed(prefix: [u8; code::PREFIX_LEN]) {
+    let caller = verified_caller().unwrap();
+    with_state_mut(|s| s.claim_throttle.succeed(caller, prefix.into()));
+}
+
+fn do_claim_consent(
+    code: &ConsentCode,
+    provider: ProviderId,
+    purpose: Purpose,
+) -> ClaimConsentResponse {
+    let (session_id, nik) = ConsentsApi::claim_consent(code, provider.clone(), purpose)
+        .expect("consent already claimed, does not exists, needs the patient approval or does not allow the purpose");
+
+    with_state_mut(|s| {
+        s.patient_log.record_activity(
+            Activity::new(log::ActivityType::Accessed, provider.clone(), nik.clone())
+                .with_purpose(Some(purpose))
+                .with_session(session_id.clone()),
+        );
+        s.inbox.notify(
+            &nik,
+            NotificationEvent::SessionOpened {
+                session_id: session_id.clone(),
+                provider_id: provider,
+                purpose,
+            },
+            Timestamp::new(),
+        );
+    });
+    issue_receipt(ReceiptEvent::Claimed, &session_id, ConsentsApi::session_snapshot(&session_id));
+
+    let patient = with_state(|s| s.registry.get_patient_info(nik).unwrap()).name();
+
+    ClaimConsentResponse::new(session_id, patient)
+}
+
+/// store a consent receipt for the session, the snapshot is taken before a finished session is removed.
+/// no-op if the session did not exist
+fn issue_receipt(
+    event: ReceiptEvent,
+    session_id: &SessionId,
+    snapshot: Option<SessionSnapshot>,
+) {
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+    let Some(provider) = snapshot.consent.session_user.clone() else {
+        return;
+    };
+
+    let receipt = ConsentReceipt::new(
+        event,
+        session_id.clone(),
+        provider,
+        &snapshot,
+        Timestamp::new(),
+    );
+    with_state_mut(|s| s.consent_receipts.issue(receipt));
+}
+
+/// consent receipts issued for the caller, oldest first
+#[ic_cdk::query(guard = "only_patient")]
+fn consent_receipt_list() -> ConsentReceiptListResponse {
+    let caller = verified_caller().unwrap();
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    with_state(|s| s.consent_receipts.list(&patient))
+        .into_iter()
+        .map(|(id, receipt)| ConsentReceiptEntry { id, receipt })
+        .collect::<Vec<_>>()
         .into()
 }
 
-#[ic_cdk::update(guard = "only_canister_owner")]
-fn update_emr_registry_principal(req: UpdateEmrRegistryRequest) {
-    with_state_mut(|s| {
-        let mut config = s.config.get().to_owned();
+/// download one of the caller consent receipts as a kantara consent receipt json document
+#[ic_cdk::query(guard = "only_patient")]
+fn download_consent_receipt(
+    req: DownloadConsentReceiptRequest,
+) -> Result<ConsentReceiptDocument, String> {
+    let caller = verified_caller().unwrap();
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
 
-        config.update_default_emr_registry_principal(req.principal);
+    let receipt = with_state(|s| s.consent_receipts.get(&patient, req.id))
+        .ok_or("receipt not found")?;
 
-        match s.config.set(config) {
-            Ok(_) => (),
-            Err(e) => ic_cdk::trap(&format!("failed to update emr registry principal: {:?}", e)),
-        }
+    Ok(ConsentReceiptDocument {
+        receipt_id: receipt.receipt_id(),
+        json: receipt.to_json(),
     })
 }
 
-#[ic_cdk::update(guard = "only_canister_owner")]
-fn update_provider_registry_principal(req: UpdateEmrRegistryRequest) {
-    with_state_mut(|s| {
-        let mut config = s.config.get().to_owned();
+#[ic_cdk::query(guard = "only_patient")]
+fn consent_list() -> ConsentListResponse {
+    let caller = verified_caller().unwrap();
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+    let consents = ConsentsApi::list_consent_with_patient(&patient);
 
-        config.update_provider_registry_principal(req.principal);
+    consents.into()
+}
 
-        match s.config.set(config) {
-            Ok(_) => (),
-            Err(e) => ic_cdk::trap(&format!("failed to update emr registry principal: {:?}", e)),
+/// sessions providers currently have open on the caller data
+#[ic_cdk::query(guard = "only_patient", composite = true)]
+async fn active_session_list() -> ActiveSessionListResponse {
+    let caller = verified_caller().unwrap();
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+    let sessions = ConsentsApi::active_sessions(&patient);
+
+    let mut ids = sessions
+        .iter()
+        .map(|session| session.provider_id.to_string())
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+
+    // names are best effort, the sessions are still listed if the provider registry can't resolve them
+    let providers = match ids.is_empty() {
+        true => vec![],
+        false => {
+            let provider_registry = with_state(|s| s.config.get().provider_registry());
+            provider_registry
+                .get_provider_batch(GetProviderBatchRequest { ids })
+                .await
+                .map(|(response,)| response.providers)
+                .unwrap_or_default()
         }
-    })
+    };
+
+    sessions
+        .into_iter()
+        .map(|session| {
+            let provider_name = providers.iter().find_map(|provider| match provider {
+                declarations::provider_registry::Provider::V1(provider) => (provider.internal_id
+                    == session.provider_id.to_string())
+                .then(|| provider.display_name.clone()),
+            });
+
+            PatientSession {
+                session,
+                provider_name,
+            }
+        })
+        .collect::<Vec<_>>()
+        .into()
 }
 
+/// end a session a provider has open on the caller data
 #[ic_cdk::update(guard = "only_patient")]
-fn update_patient_info(req: UpdatePatientInfoRequest) {
+fn terminate_session(req: FinishSessionRequest) {
     let caller = verified_caller().unwrap();
-    with_state_mut(|s| s.registry.update_patient_info(caller, req.info.into())).unwrap()
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
+
+    let snapshot = ConsentsApi::session_snapshot(&req.session_id);
+    let purpose = snapshot.as_ref().map(|snapshot| snapshot.purpose);
+    let consent = ConsentsApi::terminate_session(&req.session_id, &patient)
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    issue_receipt(ReceiptEvent::Finished, &req.session_id, snapshot);
+
+    if let Some(provider) = consent.session_user {
+        with_state_mut(|s| {
+            s.patient_log.record_activity(
+                Activity::new(log::ActivityType::Revoked, provider, patient)
+                    .with_purpose(purpose)
+                    .with_session(req.session_id),
+            )
+        });
+    }
 }
 
-#[ic_cdk::query(composite = true)]
-async fn get_patient_info_with_consent(
-    req: GetPatientInfoBySessionRequest,
-) -> GetPatientInfoResponse {
+/// break-glass access for emergencies where the patient can't share a consent code, e.g. an unconscious patient.
+/// opens a short read only session without the patient consent. only active providers can use it,
+/// the patient is notified through their activity log and the access is queued for an admin to review
+#[ic_cdk::update(guard = "rate_limit_emergency_access")]
+async fn emergency_access(req: EmergencyAccessRequest) -> EmergencyAccessResponse {
+    EmergencyAccess::validate_justification(&req.justification)
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+
     let caller = verified_caller().unwrap();
     let provider_registry = with_state(|s| s.config.get().provider_registry());
     let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
     let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
-    let provider = match provider.providers.first().unwrap() {
+    let provider: ProviderId = match provider.providers.first().expect("caller is not a provider") {
         declarations::provider_registry::Provider::V1(provider) => {
+            if !matches!(
+                provider.activation_status,
+                declarations::provider_registry::Status::Active
+            ) {
+                ic_cdk::trap("provider is not active");
+            }
+
             provider.internal_id.clone().try_into().unwrap()
         }
     };
 
-    let consent =
-        ConsentsApi::resolve_session(&req.session_id, &provider).expect("invalid session");
-    let patient = with_state(|s| s.registry.get_patient_info(consent.nik.clone())).unwrap();
-    GetPatientInfoResponse::new(patient, consent.nik)
+    with_state(|s| s.registry.get_patient_info(req.nik.clone())).expect("patient does not exist");
+
+    let now = Timestamp::new();
+    let (session_id, expires_at) =
+        ConsentsApi::open_emergency_session(req.nik.clone(), provider.clone());
+    issue_receipt(
+        ReceiptEvent::Claimed,
+        &session_id,
+        ConsentsApi::session_snapshot(&session_id),
+    );
+
+    let id = with_state_mut(|s| {
+        s.patient_log.record_activity(
+            Activity::new(
+                log::ActivityType::EmergencyAccess,
+                provider.clone(),
+                req.nik.clone(),
+            )
+            .with_purpose(Some(Purpose::Emergency))
+            .with_session(session_id.clone()),
+        );
+        s.inbox.notify(
+            &req.nik,
+            NotificationEvent::SessionOpened {
+                session_id: session_id.clone(),
+                provider_id: provider.clone(),
+                purpose: Purpose::Emergency,
+            },
+            now,
+        );
+
+        s.emergency_access.record(EmergencyAccess {
+            nik: req.nik,
+            provider_id: provider,
+            session_id: session_id.clone(),
+            justification: req.justification.trim().to_string(),
+            opened_at: now,
+            expires_at,
+            review: None,
+        })
+    });
+
+    EmergencyAccessResponse {
+        id,
+        session_id,
+        expires_at,
+    }
 }
 
-#[ic_cdk::query(guard = "only_admin_or_controller_or_patient")]
-fn get_patient_info() -> GetPatientInfoResponse {
+/// break-glass accesses on the caller data along with the provider justification and the admin review
+#[ic_cdk::query(guard = "only_patient")]
+fn emergency_access_list() -> EmergencyAccessListResponse {
     let caller = verified_caller().unwrap();
-    let (patient, nik) =
-        with_state(|s| s.registry.get_patient_info_with_principal(caller)).unwrap();
+    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
 
-    GetPatientInfoResponse::new(patient, nik)
+    with_state(|s| s.emergency_access.list(&patient))
+        .into_iter()
+        .map(|(id, access)| EmergencyAccessEntry { id, access })
+        .collect::<Vec<_>>()
+        .into()
 }
 
-#[ic_cdk::update(guard = "only_patient")]
-fn revoke_consent(req: RevokeConsentRequest) {
-    for code in req.codes {
-        let user_consent = ConsentsApi::consent(&code).expect("consent not found");
-        ConsentsApi::revoke_consent(&code);
+/// break-glass accesses waiting for a review, oldest first
+#[ic_cdk::query(guard = "only_admin")]
+fn emergency_review_queue(
+    req: EmergencyReviewQueueRequest,
+) -> Result<EmergencyReviewQueueResponse, String> {
+    if req.limit == 0 {
+        return Err("limit must be greater than 0".to_string());
+    }
 
-        if !user_consent.claimed && user_consent.session_user.is_none() {
-            continue;
-        }
+    let (accesses, total_pending) = with_state(|s| {
+        let accesses = s
+            .emergency_access
+            .pending()
+            .into_iter()
+            .skip(req.page.saturating_mul(req.limit) as usize)
+            .take(req.limit as usize)
+            .map(|(id, access)| EmergencyAccessEntry { id, access })
+            .collect::<Vec<_>>();
+
+        (accesses, s.emergency_access.pending_len())
+    });
 
-        with_state_mut(|s| {
-            s.patient_log.record(
-                log::ActivityType::Revoked,
-                user_consent.session_user.unwrap(),
-                user_consent.nik,
-            )
-        });
+    Ok(EmergencyReviewQueueResponse {
+        accesses,
+        total_pending,
+        total_pages: total_pending.div_ceil(req.limit),
+    })
+}
+
+/// review a queued break-glass access, an unjustified access has its session ended right away
+#[ic_cdk::update(guard = "only_admin")]
+fn review_emergency_access(req: ReviewEmergencyAccessRequest) -> Result<(), String> {
+    let reviewer = verified_caller().unwrap();
+
+    let access = with_state_mut(|s| {
+        s.emergency_access.review(
+            req.id,
+            EmergencyReview {
+                reviewer,
+                outcome: req.outcome,
+                note: req.note,
+                reviewed_at: Timestamp::new(),
+            },
+        )
+    })?;
+
+    if req.outcome == ReviewOutcome::Unjustified {
+        let snapshot = ConsentsApi::session_snapshot(&access.session_id);
+
+        // the session might have already been finished or expired
+        if ConsentsApi::terminate_session(&access.session_id, &access.nik).is_ok() {
+            issue_receipt(ReceiptEvent::Finished, &access.session_id, snapshot);
+            with_state_mut(|s| {
+                s.patient_log.record_activity(
+                    Activity::new(log::ActivityType::Revoked, access.provider_id, access.nik)
+                        .with_purpose(Some(Purpose::Emergency))
+                        .with_session(access.session_id),
+                )
+            });
+        }
     }
+
+    Ok(())
 }
 
-#[ic_cdk::query(guard = "only_patient")]
-fn get_logs() -> LogResponse {
+#[ic_cdk::update(guard = "only_admin")]
+fn update_kyc_status(req: UpdateKycStatusRequest) -> UpdateKycStatusResponse {
     let caller = verified_caller().unwrap();
-    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
-    let logs = match with_state(|s| s.patient_log.get_logs(&nik)) {
-        Some(logs) => logs.into_iter().map(|log| log.into_inner()).collect(),
-        None => vec![],
-    };
+    let reason = req.reason.unwrap_or_else(|| DEFAULT_FORCE_REASON.to_string());
+
+    // the status is set through the kyc case so the override shows up in its history
+    let updated_patient = with_state_mut(|s| {
+        s.registry
+            .get_patient_info(req.nik.clone())
+            .map_err(|e| e.to_string())?;
+        let case = s
+            .kyc
+            .force(req.nik.clone(), req.kyc_status.clone(), caller, &reason, Timestamp::new())?;
+        sync_kyc_case(s, &case);
+        notify_kyc_decision(s, &case);
+
+        s.registry
+            .get_patient_info(req.nik.clone())
+            .map_err(|e| e.to_string())
+    })
+    .unwrap_or_else(|e| ic_cdk::trap(&e));
+    log!("kyc status of {} set to {:?} by {}", req.nik, req.kyc_status, caller);
 
-    LogResponse::new(logs)
+    UpdateKycStatusResponse::new(updated_patient.versioned(None))
 }
 
-#[ic_cdk::update]
-async fn finish_session(req: FinishSessionRequest) {
-    let caller = verified_caller().unwrap();
-    let provider_registry = with_state(|s| s.config.get().provider_registry());
-    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
-    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
-    let provider = match provider.providers.first().unwrap() {
-        declarations::provider_registry::Provider::V1(provider) => {
-            provider.internal_id.clone().try_into().unwrap()
-        }
-    };
+fn sync_kyc_case(s: &mut State, case: &KycCase) {
+    s.registry
+        .sync_kyc_status(case.nik.clone(), case.status.kyc_status());
+}
 
-    ConsentsApi::finish_sesion(&req.session_id, &provider);
+/// record a step of an account recovery in the patient log and inbox so the remaining devices
+/// see it. requests for a NIK that is not registered have no patient to tell
+fn record_recovery_step(
+    s: &mut State,
+    id: u64,
+    request: &RecoveryRequest,
+    admin: Option<Principal>,
+) {
+    if !s.registry.owner_map.is_nik_in_use(&request.nik) {
+        return;
+    }
+
+    let mut activity = Activity::by_patient(request.status.activity_type(), request.nik.clone());
+    if let Some(admin) = admin {
+        activity = activity.with_admin(admin);
+    }
+    s.patient_log.record_activity(activity);
+
+    s.inbox.notify(
+        &request.nik,
+        NotificationEvent::AccountRecovery {
+            id,
+            status: request.status,
+        },
+        Timestamp::new(),
+    );
 }
 
-// TODO : move this into provider registry
-// #[ic_cdk::update(guard = "only_provider")] // see the implementation for this up there for notes
-#[ic_cdk::update]
-async fn claim_consent(req: ClaimConsentRequest) -> ClaimConsentResponse {
-    let caller = verified_caller().unwrap();
-    let provider_registry = with_state(|s| s.config.get().provider_registry());
-    let args = PatientRegistry::construct_get_provider_batch_args(vec![caller]);
-    let provider = PatientRegistry::do_call_get_provider_batch(args, provider_registry).await;
-    let provider: ProviderId = match provider.providers.first().unwrap() {
-        declarations::provider_registry::Provider::V1(provider) => {
-            provider.internal_id.clone().try_into().unwrap()
-        }
-    };
+fn notify_kyc_decision(s: &mut State, case: &KycCase) {
+    s.inbox.notify(
+        &case.nik,
+        NotificationEvent::KycDecision {
+            status: case.status,
+        },
+        Timestamp::new(),
+    );
+}
 
-    let (session_id, nik) = ConsentsApi::claim_consent(&req.code, provider.clone())
-        .expect("consent already claimed or does not exists");
+/// submit documents for kyc, also used to resubmit after an admin asked for more info or denied the case
+#[ic_cdk::update(guard = "rate_limit_submit_kyc")]
+fn submit_kyc(req: SubmitKycRequest) -> Result<KycCase, String> {
+    let caller = verified_caller().unwrap();
 
-    with_state_mut(|s| {
-        s.patient_log
-            .record(log::ActivityType::Accessed, provider, nik.clone())
-    });
+    let case = with_state_mut(|s| {
+        let nik = s
+            .registry
+            .owner_map
+            .get_nik(&caller)
+            .map_err(|e| e.to_string())?
+            .into_inner();
+        let case = s.kyc.submit(nik, req.documents, caller, Timestamp::new())?;
+        sync_kyc_case(s, &case);
 
-    let patient = with_state(|s| s.registry.get_patient_info(nik).unwrap())
-        .name()
-        .to_owned();
+        Ok::<_, String>(case)
+    })?;
+    log!("kyc case for {} submitted by {}", case.nik, caller);
 
-    ClaimConsentResponse::new(session_id, patient)
+    Ok(case)
 }
 
+/// the caller kyc case along with its history, [None] if they never submitted one
 #[ic_cdk::query(guard = "only_patient")]
-fn consent_list() -> ConsentListResponse {
+fn kyc_case() -> Option<KycCaseResponse> {
     let caller = verified_caller().unwrap();
-    let patient = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
-    let consents = ConsentsApi::list_consent_with_patient(&patient);
+    let nik = with_state(|s| s.registry.owner_map.get_nik(&caller).unwrap()).into_inner();
 
-    consents.into()
+    with_state(|s| {
+        s.kyc.get(&nik).map(|case| KycCaseResponse {
+            case,
+            history: s.kyc.history(&nik),
+        })
+    })
 }
 
-#[ic_cdk::update(guard = "only_admin")]
-fn update_kyc_status(req: UpdateKycStatusRequest) -> UpdateKycStatusResponse {
-    // get existing patient info
-    let patient = with_state(|s| s.registry.get_patient_info(req.nik.clone())).unwrap();
+#[ic_cdk::query(guard = "only_admin")]
+fn get_kyc_case(req: KycCaseRequest) -> Result<KycCaseResponse, String> {
+    with_state(|s| {
+        s.kyc.get(&req.nik).map(|case| KycCaseResponse {
+            case,
+            history: s.kyc.history(&req.nik),
+        })
+    })
+    .ok_or("kyc case does not exist".to_string())
+}
 
-    // create updated patient with new kyc status
-    let mut updated_patient = patient.clone();
-    updated_patient.update_kyc_status(req.kyc_status);
+/// kyc cases, longest waiting first
+#[ic_cdk::query(guard = "only_admin")]
+fn kyc_case_list(req: KycCaseListRequest) -> Result<KycCaseListResponse, String> {
+    if req.limit == 0 {
+        return Err("limit must be greater than 0".to_string());
+    }
 
-    // get the patient's principal to update their info
-    let patient_principal = with_state(|s| s.registry.owner_map.get_principal(&req.nik)).unwrap();
+    let cases = with_state(|s| s.kyc.list(req.status, req.reviewer));
+    let total = cases.len() as u64;
 
-    // update the patient info using their principal
-    with_state_mut(|s| {
-        s.registry
-            .update_patient_info(patient_principal, updated_patient.clone())
+    Ok(KycCaseListResponse {
+        cases: cases
+            .into_iter()
+            .skip(req.page.saturating_mul(req.limit) as usize)
+            .take(req.limit as usize)
+            .collect(),
+        total,
+        total_pages: total.div_ceil(req.limit),
     })
-    .unwrap();
+}
+
+/// assign a kyc case to an admin for review, a case already in review can be handed over
+#[ic_cdk::update(guard = "only_admin")]
+fn assign_kyc_case(req: AssignKycCaseRequest) -> Result<KycCase, String> {
+    let caller = verified_caller().unwrap();
+    let reviewer = req.reviewer.unwrap_or(caller);
+
+    let case = with_state_mut(|s| {
+        if !s.registry.admin_map.is_valid_admin(&reviewer) {
+            return Err("reviewer is not an admin".to_string());
+        }
+
+        let case = s
+            .kyc
+            .assign(&req.nik, reviewer, caller, &req.reason, Timestamp::new())?;
+        sync_kyc_case(s, &case);
+
+        Ok(case)
+    })?;
+    log!("kyc case for {} assigned to {} by {}", req.nik, reviewer, caller);
+
+    Ok(case)
+}
+
+/// approve, deny or ask for more documents on a kyc case, only the assigned reviewer can
+#[ic_cdk::update(guard = "only_admin")]
+fn decide_kyc_case(req: DecideKycCaseRequest) -> Result<KycCase, String> {
+    let caller = verified_caller().unwrap();
+
+    let case = with_state_mut(|s| {
+        let case = s
+            .kyc
+            .decide(&req.nik, req.decision, caller, &req.reason, Timestamp::new())?;
+        sync_kyc_case(s, &case);
+        notify_kyc_decision(s, &case);
 
-    UpdateKycStatusResponse::new(updated_patient)
+        Ok::<_, String>(case)
+    })?;
+    log!("kyc case for {} set to {:?} by {}", req.nik, case.status, caller);
+
+    Ok(case)
 }
 
 #[ic_cdk::update(guard = "only_canister_owner")]
@@ -1042,6 +2629,8 @@ fn create_group(req: CreateGroupRequest) -> Result<CreateGroupResponse, String>
 
 #[ic_cdk::update(guard = "only_patient")]
 async fn add_group_member(req: AddGroupMemberRequest) -> Result<(), String> {
+    throttle_claim(req.group_consent_code.prefix());
+
     // validate that the group consent code exists
     let is_group_consent_code_valid = with_state(|s| {
         s.registry
@@ -1056,6 +2645,8 @@ async fn add_group_member(req: AddGroupMemberRequest) -> Result<(), String> {
         );
     }
 
+    throttle_succeed(req.group_consent_code.prefix());
+
     // get the nik from the group consent code
     let nik_from_group_consent = with_state(|s| {
         s.registry
@@ -1066,11 +2657,22 @@ async fn add_group_member(req: AddGroupMemberRequest) -> Result<(), String> {
 
     // add the member to the group
     with_state_mut(|s| {
-        s.registry
-            .group_map
-            .add_member(req.group_id, nik_from_group_consent, req.relation)
+        s.registry.group_map.add_member(
+            req.group_id.clone(),
+            nik_from_group_consent.clone(),
+            req.relation,
+        )?;
+        s.inbox.notify(
+            &nik_from_group_consent,
+            NotificationEvent::GroupInvitation {
+                group_id: req.group_id,
+            },
+            Timestamp::new(),
+        );
+
+        Ok(())
     })
-    .map_err(|e| format!("Failed to add member: {:?}", e))
+    .map_err(|e: PatientRegistryError| format!("Failed to add member: {:?}", e))
 }
 
 #[ic_cdk::update(guard = "only_patient")]
@@ -1187,9 +2789,16 @@ fn grant_group_access(req: GrantGroupAccessRequest) -> Result<(), String> {
     with_state_mut(|s| {
         s.registry
             .group_access_map
-            .grant_access(granter_nik, grantee_nik, req.group_id.clone())
+            .grant_access(granter_nik.clone(), grantee_nik.clone(), req.group_id.clone())
+            .map_err(|e| format!("Failed to grant EMR access: {:?}", e))?;
+
+        s.patient_log.record_activity(
+            Activity::by_patient(log::ActivityType::GroupAccessGranted, granter_nik)
+                .with_group_member(grantee_nik),
+        );
+
+        Ok(())
     })
-    .map_err(|e| format!("Failed to grant EMR access: {:?}", e))
 }
 
 #[ic_cdk::update(guard = "only_patient")]
@@ -1228,9 +2837,16 @@ fn revoke_group_access(req: RevokeGroupAccessRequest) -> Result<(), String> {
     with_state_mut(|s| {
         s.registry
             .group_access_map
-            .revoke_access_for_group(granter_nik, revokee_nik, req.group_id)
+            .revoke_access_for_group(granter_nik.clone(), revokee_nik.clone(), req.group_id)
+            .map_err(|e| format!("[ERR_REVOKE_FAILED] Failed to revoke EMR access: {}", e))?;
+
+        s.patient_log.record_activity(
+            Activity::by_patient(log::ActivityType::GroupAccessRevoked, granter_nik)
+                .with_group_member(revokee_nik),
+        );
+
+        Ok(())
     })
-    .map_err(|e| format!("[ERR_REVOKE_FAILED] Failed to revoke EMR access: {}", e))
 }
 
 #[ic_cdk::query(composite = true, guard = "only_patient")]
@@ -1370,8 +2986,7 @@ async fn get_group_details_async_no_pagination(
 
     let leader_name = with_state(|s| s.registry.get_patient_info(group.leader.clone()))
         .map_err(|e| format!("Failed to get leader info: {:?}", e))?
-        .name()
-        .clone();
+        .name();
 
     Ok(GetGroupDetailsResponse::new(
         group
@@ -1379,25 +2994,21 @@ async fn get_group_details_async_no_pagination(
             .iter()
             .map(|(nik, relation)| {
                 let patient = with_state(|s| s.registry.get_patient_info(nik.clone())).unwrap();
-                let age = match patient.clone() {
-                    Patient::V1(v1) => {
-                        let dob = v1.date_of_birth.to_string();
-                        let year = dob
-                            .get(0..4)
-                            .and_then(|y| y.parse::<u16>().ok())
-                            .unwrap_or(0);
-                        let current_year = 2024; // todo: might want to get this dynamically
-                        (current_year - year) as u8
-                    }
+                let age = {
+                    let dob = patient.date_of_birth();
+                    let year = dob
+                        .get(0..4)
+                        .and_then(|y| y.parse::<u16>().ok())
+                        .unwrap_or(0);
+                    let current_year = 2024; // todo: might want to get this dynamically
+                    (current_year - year) as u8
                 };
 
-                let gender = match patient.clone() {
-                    Patient::V1(v1) => AsciiRecordsKey::<64>::new(v1.gender.to_string()).unwrap(),
-                };
+                let gender = AsciiRecordsKey::<64>::new(patient.gender()).unwrap();
 
                 GroupDetail {
                     nik: nik.clone(),
-                    name: patient.name().clone(),
+                    name: patient.name(),
                     gender,
                     age,
                     role: relation.clone(),
@@ -1441,8 +3052,7 @@ fn get_group_details(req: GetGroupDetailsRequest) -> Result<GetGroupDetailsRespo
     // get leader name
     let leader_name = with_state(|s| s.registry.get_patient_info(group.leader.clone()))
         .map_err(|e| format!("Failed to get leader info: {:?}", e))?
-        .name()
-        .clone();
+        .name();
 
     // get group name and member count
     let group_name = group.name.clone();
@@ -1464,28 +3074,24 @@ fn get_group_details(req: GetGroupDetailsRequest) -> Result<GetGroupDetailsRespo
             .unwrap_or(Relation::Other);
 
         // calculate age from date_of_birth
-        let age = match member {
-            Patient::V1(ref v1) => {
-                // parse date of birth string (assuming format YYYY-MM-DD)
-                let dob = v1.date_of_birth.to_string();
-                let year = dob
-                    .get(0..4)
-                    .and_then(|y| y.parse::<u16>().ok())
-                    .unwrap_or(0);
-                let current_year = 2024; // todo: might want to get this dynamically
-                (current_year - year) as u8
-            }
+        let age = {
+            // parse date of birth string (assuming format YYYY-MM-DD)
+            let dob = member.date_of_birth();
+            let year = dob
+                .get(0..4)
+                .and_then(|y| y.parse::<u16>().ok())
+                .unwrap_or(0);
+            let current_year = 2024; // todo: might want to get this dynamically
+            (current_year - year) as u8
         };
 
         // create a new AsciiRecordsKey<64> for gender
-        let gender = match member {
-            Patient::V1(ref v1) => AsciiRecordsKey::<64>::new(v1.gender.to_string())
-                .map_err(|_| "Failed to convert gender to AsciiRecordsKey<64>".to_string())?,
-        };
+        let gender = AsciiRecordsKey::<64>::new(member.gender())
+            .map_err(|_| "Failed to convert gender to AsciiRecordsKey<64>".to_string())?;
 
         let detail = GroupDetail {
             nik: member_nik.clone(),
-            name: member.name().clone(),
+            name: member.name(),
             gender,
             age,
             role,
@@ -1512,28 +3118,51 @@ fn get_group_details(req: GetGroupDetailsRequest) -> Result<GetGroupDetailsRespo
 
 #[ic_cdk::query(guard = "only_admin_or_controller")]
 fn get_group_details_admin(req: GetGroupDetailsRequest) -> Result<GetGroupDetailsResponse, String> {
+    group_details_admin(req).map(|(response, _)| response)
+}
+
+/// same as [get_group_details_admin], the leader and every listed member get an
+/// [log::ActivityType::AdminRead] entry
+#[ic_cdk::update(guard = "only_admin_or_controller")]
+fn get_group_details_admin_logged(
+    req: GetGroupDetailsRequest,
+) -> Result<GetGroupDetailsResponse, String> {
+    let (response, leader) = group_details_admin(req)?;
+
+    let mut niks = response
+        .group_details
+        .iter()
+        .map(|detail| detail.nik.clone())
+        .collect::<Vec<_>>();
+    if !niks.contains(&leader) {
+        niks.push(leader);
+    }
+    record_admin_reads(niks);
+
+    Ok(response)
+}
+
+/// the group details along with the leader NIK
+fn group_details_admin(
+    req: GetGroupDetailsRequest,
+) -> Result<(GetGroupDetailsResponse, NIK), String> {
     let group =
         with_state(|s| s.registry.group_map.get_group(req.group_id)).ok_or("Group not found")?;
 
     let leader_name = with_state(|s| s.registry.get_patient_info(group.leader.clone()))
         .map_err(|e| format!("Failed to get leader info: {:?}", e))?
-        .name()
-        .clone();
+        .name();
 
-    Ok(GetGroupDetailsResponse::new(
+    let response = GetGroupDetailsResponse::new(
         group
             .member_relations
             .iter()
             .map(|(nik, relation)| {
                 let patient = with_state(|s| s.registry.get_patient_info(nik.clone())).unwrap();
-                let gender = match patient {
-                    Patient::V1(ref v1) => {
-                        AsciiRecordsKey::<64>::new(v1.gender.to_string()).unwrap()
-                    }
-                };
+                let gender = AsciiRecordsKey::<64>::new(patient.gender()).unwrap();
                 GroupDetail {
                     nik: nik.clone(),
-                    name: patient.name().clone(),
+                    name: patient.name(),
                     gender,
                     age: 0,
                     role: relation.clone(),
@@ -1544,7 +3173,9 @@ fn get_group_details_admin(req: GetGroupDetailsRequest) -> Result<GetGroupDetail
         group.name,
         leader_name,
         (group.members.len() as u64 + req.limit - 1) / req.limit,
-    ))
+    );
+
+    Ok((response, group.leader))
 }
 
 /// Claim Consent for Group Membership
@@ -1636,3 +3267,46 @@ async fn read_group_members_emr_info(
 }
 
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use canister_common::id;
+
+    use super::*;
+
+    #[test]
+    fn test_claim_decision() {
+        let provider = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let session_id = id!("4bd1a9e6-5d0e-4a8b-9a43-0c8e1e7a2f11");
+        let nik = NIK::from([1u8; 32]);
+
+        let (activity, opened) = claim_decision(
+            true,
+            provider.clone(),
+            nik.clone(),
+            Some(session_id.clone()),
+            Some(Purpose::Treatment),
+        );
+        assert_eq!(activity.activity_type, log::ActivityType::ClaimConfirmed);
+        assert_eq!(activity.session_id, Some(session_id.clone()));
+        assert_eq!(
+            opened,
+            Some(NotificationEvent::SessionOpened {
+                session_id: session_id.clone(),
+                provider_id: provider.clone(),
+                purpose: Purpose::Treatment,
+            })
+        );
+
+        // a rejected claim doesn't open a session
+        let (activity, opened) = claim_decision(
+            false,
+            provider,
+            nik,
+            Some(session_id),
+            Some(Purpose::Treatment),
+        );
+        assert_eq!(activity.activity_type, log::ActivityType::ClaimRejected);
+        assert_eq!(opened, None);
+    }
+}
@@ -1,6 +1,6 @@
-use candid::CandidType;
+use candid::{ CandidType, Principal };
 use canister_common::{
-    common::{ ProviderId, Timestamp, UserId },
+    common::{ EmrId, ProviderId, Timestamp, UserId, H256 },
     deref,
     from,
     impl_max_size,
@@ -9,28 +9,228 @@ use canister_common::{
     mmgr::MemoryManager,
     stable::{ Candid, Memory, Scale, Stable, StableSet, ToStable },
 };
-use ic_stable_structures::Log;
+use ic_certified_map::{
+    fork,
+    fork_hash,
+    labeled,
+    labeled_hash,
+    leaf_hash,
+    AsHashTree,
+    Hash,
+    HashTree,
+    RbTree,
+};
+use ic_stable_structures::{ Cell, Log };
 use parity_scale_codec::{ Decode, Encode };
-use serde::Deserialize;
+use serde::{ Deserialize, Serialize };
+use tiny_keccak::Hasher;
 
-use crate::registry::NIK;
+use crate::{ consent::{ Purpose, SessionId }, registry::NIK };
 
 pub struct PatientLog {
     pub activity_log: ActivityLogEntry,
     pub log_map_index: LogMapIndex,
+    pub chain_heads: LogChainHeads,
+    pub chain_root: LogChainRoot,
+    /// the chain head of every user, certified along with the chain root
+    certified_heads: RbTree<NIK, Vec<u8>>,
 }
 
+// max amount of activities looked at for a single page of [PatientLog::find_logs]
+const MAX_LOG_SCAN: usize = 1_000;
+
+// labels of the certified hash tree, kept in label order
+const CHAIN_HEADS_LABEL: &[u8] = b"chain_heads";
+const CHAIN_ROOT_LABEL: &[u8] = b"chain_root";
+
 impl PatientLog {
     pub fn init(memory_manager: &MemoryManager) -> Self {
         let activity_log = ActivityLogEntry::init(memory_manager);
         let log_map_index = LogMapIndex::init(memory_manager);
-        Self { activity_log, log_map_index }
+        let chain_heads = LogChainHeads::init(memory_manager);
+        let chain_root = LogChainRoot::init(memory_manager);
+        // the certified tree lives on the heap, one entry per user rather than per activity
+        let mut certified_heads = RbTree::new();
+        for (user, head) in chain_heads.iter() {
+            certified_heads.insert(user.into_inner(), head.into_inner().encode());
+        }
+
+        let log = Self { activity_log, log_map_index, chain_heads, chain_root, certified_heads };
+
+        // certified data does not survive upgrades
+        log.certify();
+
+        log
+    }
+
+    /// chain up to `limit` activities recorded before the chain existed, in log order.
+    /// returns whether there are activities left to chain
+    pub fn chain_older_entries(&mut self, limit: u64) -> bool {
+        let start = self.chain_root.get().len;
+        let end = self.activity_log.0.len().min(start.saturating_add(limit));
+
+        for index in start..end {
+            if let Some(activity) = self.activity_log.get(index) {
+                self.chain(index, &activity.user_id);
+            }
+        }
+
+        self.certify();
+
+        end < self.activity_log.0.len()
     }
 
     pub fn record(&mut self, activity_type: ActivityType, provider: ProviderId, user: UserId) {
-        let activity = Activity::new(activity_type, provider, user.clone());
+        self.record_with_purpose(activity_type, provider, user, None)
+    }
+
+    pub fn record_with_purpose(
+        &mut self,
+        activity_type: ActivityType,
+        provider: ProviderId,
+        user: UserId,
+        purpose: Option<Purpose>
+    ) {
+        self.record_activity(Activity::new(activity_type, provider, user).with_purpose(purpose))
+    }
+
+    pub fn record_activity(&mut self, activity: Activity) {
         let index = self.activity_log.add(&activity);
-        self.log_map_index.add(user, index);
+        self.log_map_index.add(activity.user_id.clone(), index);
+
+        // while older activities are chained, see [PatientLog::chain_older_entries], this one waits
+        if self.chain_root.get().len == index {
+            self.chain(index, &activity.user_id);
+            self.certify();
+        }
+    }
+
+    // extend both the chain of the user and the chain over every activity with the stored entry
+    fn chain(&mut self, index: u64, user: &UserId) {
+        let Some(entry) = self.activity_log.get_bytes(index) else {
+            return;
+        };
+
+        let head = self.chain_head(user).next(index, &entry);
+        self.certified_heads.insert(user.clone(), head.encode());
+        self.chain_heads.insert(user.clone().to_stable(), head.to_stable());
+
+        let root = self.chain_root.get().next(index, &entry);
+        self.chain_root.set(root);
+    }
+
+    /// head of the user chain, the default head if the user has no activity
+    pub fn chain_head(&self, user: &UserId) -> ChainHead {
+        self.chain_heads
+            .get(user.to_stable_ref())
+            .map(|head| head.into_inner())
+            .unwrap_or_default()
+    }
+
+    /// head of the chain over every activity, certified along with the head of every user
+    pub fn chain_root(&self) -> ChainHead {
+        self.chain_root.get()
+    }
+
+    /// the canister certified data, the root hash of [PatientLog::chain_tree]
+    pub fn certified_data(&self) -> Hash {
+        fork_hash(
+            &labeled_hash(CHAIN_HEADS_LABEL, &self.certified_heads.root_hash()),
+            &labeled_hash(CHAIN_ROOT_LABEL, &leaf_hash(&self.chain_root.get().encode()))
+        )
+    }
+
+    /// hash tree with the user head under `chain_heads/<nik>` and the root under `chain_root`.
+    /// every other user head is pruned, each leaf is a SCALE encoded [ChainHead]
+    pub fn chain_tree(&self, user: &UserId) -> HashTree<'_> {
+        fork(
+            labeled(CHAIN_HEADS_LABEL, self.certified_heads.witness(user.as_ref())),
+            labeled(CHAIN_ROOT_LABEL, HashTree::Leaf(self.chain_root.get().encode().into()))
+        )
+    }
+
+    /// [PatientLog::chain_tree] of the user, CBOR encoded the way certificates are
+    pub fn chain_witness(&self, user: &UserId) -> Vec<u8> {
+        let mut serializer = serde_cbor::Serializer::new(vec![]);
+        serializer.self_describe().expect("witness should be encodable");
+        self.chain_tree(user).serialize(&mut serializer).expect("witness should be encodable");
+
+        serializer.into_inner()
+    }
+
+    fn certify(&self) {
+        set_certified_data(&self.certified_data());
+    }
+
+    /// recompute the chain of the user from the stored activities and compare it with the stored head.
+    /// `known` is a head the user saw earlier, it has to be part of the current chain.
+    /// activities that aren't chained yet, see [PatientLog::chain_older_entries], are not covered
+    pub fn verify_chain(&self, user: &UserId, known: Option<&ChainHead>) -> ChainVerification {
+        let head = self.chain_head(user);
+        let chained = self.chain_root.get().len;
+        let mut computed = ChainHead::default();
+        let mut known_matches = known.map(|known| *known == ChainHead::default());
+
+        for index in self.log_map_index.get_batch(user).unwrap_or_default() {
+            if index >= chained {
+                break;
+            }
+
+            let Some(entry) = self.activity_log.get_bytes(index) else {
+                continue;
+            };
+
+            computed = computed.next(index, &entry);
+            if let Some(known) = known.filter(|known| known.len == computed.len) {
+                known_matches = Some(*known == computed);
+            }
+        }
+
+        ChainVerification {
+            valid: computed == head && known_matches != Some(false),
+            head,
+            computed,
+            known_matches,
+        }
+    }
+
+    /// activities of the user matching the filter, recorded before the `before` log index.
+    /// newest first, walks the user index backwards and stops after [MAX_LOG_SCAN] activities.
+    /// returns the log index to continue from if there may be more activities
+    pub fn find_logs(
+        &self,
+        user: &UserId,
+        filter: &LogFilter,
+        before: Option<u64>,
+        limit: usize
+    ) -> (Vec<Activity>, Option<u64>) {
+        let mut activities = vec![];
+        let mut cursor = before.unwrap_or(u64::MAX);
+
+        for _ in 0..MAX_LOG_SCAN {
+            let Some(index) = self.log_map_index.before(user, cursor) else {
+                return (activities, None);
+            };
+            cursor = index;
+
+            let Some(activity) = self.activity_log.get(index).map(Stable::into_inner) else {
+                continue;
+            };
+
+            // activities are appended in time order, nothing older can match
+            if filter.from.is_some_and(|from| activity.timestamp < from) {
+                return (activities, None);
+            }
+
+            if filter.matches(&activity) {
+                activities.push(activity);
+                if activities.len() == limit {
+                    break;
+                }
+            }
+        }
+
+        (activities, Some(cursor))
     }
 
     pub fn get_logs(&self, user: &UserId) -> Option<Vec<Stable<Activity, Candid>>> {
@@ -44,11 +244,117 @@ impl PatientLog {
 pub struct ActivityIndexMemory;
 pub struct ActivityEntryMemory;
 
+/// head of a hash chain over activities, `hash` covers the first `len` activities
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct ChainHead {
+    pub hash: H256,
+    pub len: u64,
+}
+
+impl_max_size!(for ChainHead: H256, u64);
+impl_mem_bound!(for ChainHead: bounded; fixed_size: true);
+
+impl ChainHead {
+    /// the head after chaining the log entry, the log index is hashed too so entries can't be reordered.
+    /// the entry is hashed as stored, re-encoding an older entry as the current [Activity] changes it
+    pub fn next(&self, index: u64, entry: &[u8]) -> Self {
+        let mut hasher = tiny_keccak::Keccak::v256();
+        let mut hash = [0u8; 32];
+        hasher.update(self.hash.as_ref());
+        hasher.update(&index.to_le_bytes());
+        hasher.update(entry);
+        hasher.finalize(&mut hash);
+
+        Self {
+            hash: H256::from(hash),
+            len: self.len + 1,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChainVerification {
+    /// the recomputed chain matches the stored head and the known head, if given
+    pub valid: bool,
+    /// the stored head
+    pub head: ChainHead,
+    /// the head recomputed from the stored activities
+    pub computed: ChainHead,
+    /// whether the known head is part of the recomputed chain, none if no known head is given
+    pub known_matches: Option<bool>,
+}
+
+/// chain head of every user
+pub struct LogChainHeads(ic_stable_structures::BTreeMap<Stable<NIK>, Stable<ChainHead>, Memory>);
+
+impl LogChainHeads {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut LogChainHeads: ic_stable_structures::BTreeMap<Stable<NIK>, Stable<ChainHead>, Memory>);
+
+/// head of the chain over every activity of every user
+pub struct LogChainRoot(Cell<Stable<ChainHead>, Memory>);
+
+impl LogChainRoot {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(
+            memory_manager
+                .get_memory::<_, Self>(|m| Cell::init(m, ChainHead::default().to_stable()))
+                .unwrap()
+        )
+    }
+
+    pub fn get(&self) -> ChainHead {
+        self.0.get().clone().into_inner()
+    }
+
+    pub fn set(&mut self, head: ChainHead) {
+        self.0.set(head.to_stable()).expect("chain head should fit the cell");
+    }
+}
+
+// certified data is only available inside a canister
+#[cfg(target_arch = "wasm32")]
+fn set_certified_data(data: &Hash) {
+    ic_cdk::api::set_certified_data(data);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn set_certified_data(_data: &Hash) {}
+
 #[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub enum ActivityType {
     Updated,
     Accessed,
     Revoked,
+    ClaimConfirmed,
+    ClaimRejected,
+    /// a provider opened a break-glass session without the patient consent
+    EmergencyAccess,
+    /// a provider issued a new emr
+    EmrCreated,
+    /// a provider read an emr through a session
+    EmrRead,
+    /// the provider finished the session
+    SessionFinished,
+    /// the patient let a group member read their emrs
+    GroupAccessGranted,
+    /// the patient took back the emr access of a group member
+    GroupAccessRevoked,
+    /// an admin looked up the patient info
+    AdminRead,
+    /// someone asked to recover the patient account from a new principal
+    RecoveryRequested,
+    /// an admin approved a recovery, it can be completed after the cooling-off period
+    RecoveryApproved,
+    RecoveryRejected,
+    /// a recovery was cancelled from one of the patient devices
+    RecoveryCancelled,
+    /// the NIK was bound to the principal of a recovery, every older device was revoked
+    RecoveryCompleted,
 }
 
 impl_max_size!(for ActivityType: ActivityType);
@@ -58,24 +364,110 @@ impl_mem_bound!(for ActivityType: bounded; fixed_size: true);
 pub struct Activity {
     pub activity_type: ActivityType,
     pub timestamp: Timestamp,
+    /// the nil id for activities done by the patient or an admin, see [Activity::provider]
     pub provider_id: ProviderId,
     pub user_id: UserId,
+    /// purpose of use of the session this activity happened in, none for older entries
+    pub purpose: Option<Purpose>,
+    /// the emr the activity is about, if any
+    pub emr_id: Option<EmrId>,
+    /// the session the activity happened in, if any
+    pub session_id: Option<SessionId>,
+    /// the admin for [ActivityType::AdminRead]
+    pub admin: Option<Principal>,
+    /// the group member for [ActivityType::GroupAccessGranted] and [ActivityType::GroupAccessRevoked]
+    pub group_member: Option<NIK>,
+    /// the provider that did the activity, none for activities done by the patient or an admin.
+    /// also none for older entries, [Activity::provider] falls back to `provider_id` for those
+    pub provider: Option<ProviderId>,
 }
 
 impl Activity {
     pub fn new(activity_type: ActivityType, provider_id: ProviderId, user_id: UserId) -> Self {
+        Self::by_patient(activity_type, user_id).with_provider(provider_id)
+    }
+
+    /// an activity without a provider, see [Activity::with_admin] and [Activity::with_group_member]
+    pub fn by_patient(activity_type: ActivityType, user_id: UserId) -> Self {
         Self {
             activity_type,
             timestamp: Timestamp::new(),
-            provider_id,
+            provider_id: ProviderId::default(),
             user_id,
+            purpose: None,
+            emr_id: None,
+            session_id: None,
+            admin: None,
+            group_member: None,
+            provider: None,
         }
     }
+
+    pub fn with_provider(mut self, provider_id: ProviderId) -> Self {
+        self.provider_id = provider_id.clone();
+        self.provider = Some(provider_id);
+        self
+    }
+
+    /// the provider that did the activity, none for activities done by the patient or an admin
+    pub fn provider(&self) -> Option<&ProviderId> {
+        self.provider
+            .as_ref()
+            .or(Some(&self.provider_id).filter(|id| **id != ProviderId::default()))
+    }
+
+    pub fn with_purpose(mut self, purpose: Option<Purpose>) -> Self {
+        self.purpose = purpose;
+        self
+    }
+
+    pub fn with_emr(mut self, emr_id: EmrId) -> Self {
+        self.emr_id = Some(emr_id);
+        self
+    }
+
+    pub fn with_session(mut self, session_id: SessionId) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    pub fn with_admin(mut self, admin: Principal) -> Self {
+        self.admin = Some(admin);
+        self
+    }
+
+    pub fn with_group_member(mut self, group_member: NIK) -> Self {
+        self.group_member = Some(group_member);
+        self
+    }
 }
 
-impl_max_size!(for Activity: 165);
+// older entries were encoded with at most 240 bytes, the new optional fields take the rest
+impl_max_size!(for Activity: 640);
 impl_mem_bound!(for Activity: bounded; fixed_size: false);
 
+/// filters for [PatientLog::find_logs], every filter that is set has to match
+#[derive(Default)]
+pub struct LogFilter {
+    pub purpose: Option<Purpose>,
+    /// any of the given types, every type if empty
+    pub activity_types: Vec<ActivityType>,
+    /// inclusive
+    pub from: Option<Timestamp>,
+    /// inclusive
+    pub to: Option<Timestamp>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, activity: &Activity) -> bool {
+        (self.purpose.is_none() || activity.purpose == self.purpose) &&
+            (self.activity_types.is_empty() ||
+                self.activity_types.contains(&activity.activity_type)) &&
+            self.from.is_none_or(|from| activity.timestamp >= from) &&
+            self.to.is_none_or(|to| activity.timestamp <= to)
+    }
+}
+
 #[cfg(test)]
 mod activity_test {
     use std::str::FromStr;
@@ -88,18 +480,58 @@ mod activity_test {
     fn test_len_encoded() {
         use candid::{ Encode, Decode };
 
-        let activity = Activity {
-            activity_type: ActivityType::Updated,
+        let user_id = H256::from_str(
+            "9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c"
+        ).unwrap();
+        let activity = Activity::new(
+            ActivityType::GroupAccessGranted,
+            id!("adad9d46-a795-4445-ac10-8f2d150064ba"),
+            user_id.clone()
+        )
+            .with_purpose(Some(Purpose::Research))
+            .with_emr(id!("b2a1c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d"))
+            .with_session(id!("c3b2a1d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d"))
+            .with_admin(Principal::from_slice(&[1u8; 29]))
+            .with_group_member(user_id);
+
+        let encoded = Encode!(&activity).unwrap();
+        println!("encoded: {:?}", encoded.len());
+        let decoded = Decode!(&encoded, Activity).unwrap();
+        assert_eq!(decoded, activity);
+        assert!(encoded.len() <= 640);
+    }
+
+    #[test]
+    fn test_decode_older_entry() {
+        use candid::{ Encode, Decode };
+
+        // the layout before the emr, session and actor fields were added
+        #[derive(CandidType)]
+        struct OlderActivity {
+            activity_type: ActivityType,
+            timestamp: Timestamp,
+            provider_id: ProviderId,
+            user_id: UserId,
+            purpose: Option<Purpose>,
+        }
+
+        let provider_id = id!("adad9d46-a795-4445-ac10-8f2d150064ba");
+        let older = OlderActivity {
+            activity_type: ActivityType::Accessed,
             timestamp: Timestamp::new(),
-            provider_id: id!("adad9d46-a795-4445-ac10-8f2d150064ba"),
+            provider_id: provider_id.clone(),
             user_id: H256::from_str(
                 "9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c"
             ).unwrap(),
+            purpose: None,
         };
 
-        let encoded = Encode!(&activity).unwrap();
-        println!("encoded: {:?}", encoded.len());
-        let decoded = Decode!(&encoded, Activity).unwrap();
+        let decoded = Decode!(&Encode!(&older).unwrap(), Activity).unwrap();
+        assert_eq!(decoded.provider_id, provider_id);
+        assert_eq!(decoded.provider, None);
+        assert_eq!(decoded.provider(), Some(&provider_id));
+        assert_eq!(decoded.emr_id, None);
+        assert_eq!(decoded.session_id, None);
     }
 }
 
@@ -121,6 +553,13 @@ impl ActivityLogEntry {
         self.0.get(index)
     }
 
+    /// the candid encoded entry as stored
+    pub fn get_bytes(&self, index: u64) -> Option<Vec<u8>> {
+        let mut entry = vec![];
+        self.0.read_entry(index, &mut entry).ok()?;
+        Some(entry)
+    }
+
     pub fn get_batch(&self, indexed: &[u64]) -> Vec<Option<Stable<Activity, Candid>>> {
         let mut batch = Vec::with_capacity(indexed.len());
 
@@ -177,6 +616,18 @@ impl LogMapIndex {
         self.0.insert(nik.to_stable(), index)
     }
 
+    /// the newest log index of the user below `index`
+    pub fn before(&self, nik: &NIK, index: u64) -> Option<u64> {
+        let bound = (nik.clone().to_stable(), U64::from(index).to_stable());
+
+        self.0
+            .inner()
+            .iter_upper_bound(&bound)
+            .next()
+            .filter(|((key, _), _)| key.as_inner() == nik)
+            .map(|((_, index), _)| index.into_inner().into())
+    }
+
     pub fn get_batch(&self, nik: &NIK) -> Option<Vec<u64>> {
         let idxs = self.0.get_set_associated_by_key(nik.to_stable_ref());
 
@@ -244,4 +695,153 @@ mod test {
             assert!(retrieved_activity.unwrap().eq(activity.to_stable_ref()));
         }
     }
+
+    #[test]
+    fn test_find_logs() {
+        let memory_manager = memory_manager!();
+        let mut log = PatientLog::init(&memory_manager);
+
+        let provider_id = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let user = UserId::from_str(
+            "9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c"
+        ).unwrap();
+
+        for activity_type in [
+            ActivityType::Accessed,
+            ActivityType::EmrRead,
+            ActivityType::EmrRead,
+            ActivityType::SessionFinished,
+        ] {
+            log.record(activity_type, provider_id.clone(), user.clone());
+        }
+
+        // activities of another user are never returned
+        log.record(ActivityType::Accessed, provider_id.clone(), UserId::from([1u8; 32]));
+
+        let (all, next) = log.find_logs(&user, &LogFilter::default(), None, 10);
+        assert_eq!(all.len(), 4);
+        assert_eq!(next, None);
+        // newest first
+        assert_eq!(all[0].activity_type, ActivityType::SessionFinished);
+        assert_eq!(all[3].activity_type, ActivityType::Accessed);
+
+        let reads = LogFilter {
+            activity_types: vec![ActivityType::EmrRead],
+            ..Default::default()
+        };
+        let (page, next) = log.find_logs(&user, &reads, None, 1);
+        assert_eq!(page.len(), 1);
+        assert_eq!(next, Some(2));
+        let (page, next) = log.find_logs(&user, &reads, next, 1);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].activity_type, ActivityType::EmrRead);
+        let (page, next) = log.find_logs(&user, &reads, next, 1);
+        assert!(page.is_empty());
+        assert_eq!(next, None);
+
+        let after = LogFilter {
+            from: Some(all[1].timestamp),
+            ..Default::default()
+        };
+        let (page, _) = log.find_logs(&user, &after, None, 10);
+        assert!(page.iter().all(|activity| activity.timestamp >= all[1].timestamp));
+        assert!(page.len() >= 2);
+
+        // a page never looks at more than the scan limit
+        for _ in 0..MAX_LOG_SCAN {
+            log.record(ActivityType::Accessed, provider_id.clone(), user.clone());
+        }
+        let (page, next) = log.find_logs(&user, &reads, None, 10);
+        assert!(page.is_empty());
+        assert_eq!(next, Some(5));
+        let (page, next) = log.find_logs(&user, &reads, next, 10);
+        assert_eq!(page.len(), 2);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn test_chain() {
+        let memory_manager = memory_manager!();
+        let mut log = PatientLog::init(&memory_manager);
+
+        let provider_id = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let user = UserId::from([1u8; 32]);
+        let other = UserId::from([2u8; 32]);
+
+        log.record(ActivityType::Accessed, provider_id.clone(), user.clone());
+        let known = log.chain_head(&user);
+
+        // the head covers the entry as stored
+        let entry = log.activity_log.get_bytes(0).unwrap();
+        assert_eq!(known, ChainHead::default().next(0, &entry));
+        log.record(ActivityType::Accessed, provider_id.clone(), other.clone());
+        log.record(ActivityType::EmrRead, provider_id.clone(), user.clone());
+
+        assert_eq!(log.chain_head(&user).len, 2);
+        assert_eq!(log.chain_head(&other).len, 1);
+        assert_eq!(log.chain_root().len, 3);
+
+        let verification = log.verify_chain(&user, Some(&known));
+        assert!(verification.valid);
+        assert_eq!(verification.known_matches, Some(true));
+        assert_eq!(verification.computed, log.chain_head(&user));
+
+        // a head that was never part of the chain
+        let forged = ChainHead { hash: UserId::from([9u8; 32]), len: 1 };
+        let verification = log.verify_chain(&user, Some(&forged));
+        assert!(!verification.valid);
+        assert_eq!(verification.known_matches, Some(false));
+
+        // the witness of each user proves their head against the certified data
+        let certified = log.certified_data();
+        assert_eq!(log.chain_tree(&user).reconstruct(), certified);
+        assert_eq!(log.chain_tree(&other).reconstruct(), certified);
+        assert!(!log.chain_witness(&user).is_empty());
+
+        log.record(ActivityType::EmrRead, provider_id.clone(), other.clone());
+        assert_ne!(log.certified_data(), certified);
+        assert_eq!(log.chain_tree(&user).reconstruct(), log.certified_data());
+
+        // a rewritten entry no longer matches the stored head
+        log.chain_heads.insert(user.clone().to_stable(), forged.to_stable());
+        assert!(!log.verify_chain(&user, None).valid);
+        assert!(log.verify_chain(&other, None).valid);
+    }
+
+    #[test]
+    fn test_chain_older_entries() {
+        let memory_manager = memory_manager!();
+        let provider_id = id!("60673662-792a-4e50-b7aa-eccf7e4146a3");
+        let user = UserId::from([1u8; 32]);
+
+        // entries recorded before the chain existed
+        let mut activity_log = ActivityLogEntry::init(&memory_manager);
+        let mut log_map_index = LogMapIndex::init(&memory_manager);
+        for _ in 0..3 {
+            let activity = Activity::new(ActivityType::Updated, provider_id.clone(), user.clone());
+            let index = activity_log.add(&activity);
+            log_map_index.add(user.clone(), index);
+        }
+
+        let mut log = PatientLog::init(&memory_manager);
+        assert_eq!(log.chain_root().len, 0);
+
+        // new activities wait for the older ones
+        log.record(ActivityType::EmrRead, provider_id.clone(), user.clone());
+        assert_eq!(log.chain_root().len, 0);
+        assert!(log.verify_chain(&user, None).valid);
+
+        assert!(log.chain_older_entries(2));
+        assert_eq!(log.chain_head(&user).len, 2);
+        assert!(log.verify_chain(&user, None).valid);
+
+        assert!(!log.chain_older_entries(2));
+        assert_eq!(log.chain_root().len, 4);
+        assert_eq!(log.chain_head(&user).len, 4);
+        assert!(log.verify_chain(&user, None).valid);
+
+        // once caught up new activities are chained right away
+        log.record(ActivityType::EmrRead, provider_id.clone(), user.clone());
+        assert_eq!(log.chain_head(&user).len, 5);
+    }
 }
@@ -2,14 +2,31 @@ use canister_common::generate_memory_id;
 
 use crate::{
     config::CanisterConfig,
-    consent::{InnerConsentMap, ProviderConsentSet, SessionMap},
-    log::{ActivityEntryMemory, ActivityIndexMemory, LogMapIndex},
+    consent::{
+        ConsentExpiryIndex, ConsentSessionSet, InnerConsentMap, LegacyConsentFlag,
+        PatientSessionIndex, PendingConsentSet, ProviderConsentSet, ProviderSessionIndex,
+        SessionInfoMap, SessionMap, SessionPurposeMap,
+    },
+    device::{DeviceMap, PendingLinkExpiryIndex, PendingLinkMap, PendingLinkRequesterMap},
+    emergency::{EmergencyAccessIndex, EmergencyAccessMap, EmergencyReviewQueue},
+    kyc::{KycCaseMap, KycHistoryEntryMemory, KycHistoryIndex, KycHistoryIndexMemory},
+    log::{ActivityEntryMemory, ActivityIndexMemory, LogChainHeads, LogChainRoot, LogMapIndex},
+    notification::{InboxMetaMap, NotificationMap, NotificationPreferenceMap},
+    receipt::{ReceiptEntryMemory, ReceiptIndexMemory, ReceiptMapIndex},
+    recovery::{OpenRecoveryMap, PrincipalRecoveryIndex, RecoveryRequestMap, RecoveryReviewQueue},
     registry::{
-        AdminMap, EmrBindingMap, GroupConsentMap, GroupMap, HeaderStatusMap, InfoMap,
-        InnerGroupConsentMap, OwnerMap,
+        AdminMap, EmrBindingMap, GroupAccessMap, GroupConsentMap, GroupMap, HeaderStatusMap,
+        InfoMap, InnerGroupConsentMap, OwnerMap, PatientRegistrationMap,
+    },
+    search::{
+        PatientBirthDateIndex, PatientKycCount, PatientKycIndex, PatientKycRegisteredIndex,
+        PatientNameIndex, PatientRegisteredIndex,
     },
+    throttle::{CallerFailureMap, PrefixFailureMap, ThrottleStatsMemory},
 };
 
+/// needed since the module is imported
+pub struct RateLimiterMemory;
 pub struct UpgradeMemory;
 generate_memory_id!(
     UpgradeMemory,
@@ -27,5 +44,49 @@ generate_memory_id!(
     ActivityIndexMemory,
     LogMapIndex,
     InnerConsentMap,
-    SessionMap
+    SessionMap,
+    ConsentExpiryIndex,
+    PendingConsentSet,
+    CallerFailureMap,
+    PrefixFailureMap,
+    // previously hardcoded to id 20, keep it there
+    GroupAccessMap,
+    ThrottleStatsMemory,
+    RateLimiterMemory,
+    ConsentSessionSet,
+    SessionInfoMap,
+    ProviderSessionIndex,
+    ReceiptEntryMemory,
+    ReceiptIndexMemory,
+    ReceiptMapIndex,
+    SessionPurposeMap,
+    EmergencyAccessMap,
+    EmergencyReviewQueue,
+    EmergencyAccessIndex,
+    DeviceMap,
+    PendingLinkMap,
+    RecoveryRequestMap,
+    RecoveryReviewQueue,
+    OpenRecoveryMap,
+    KycCaseMap,
+    KycHistoryEntryMemory,
+    KycHistoryIndexMemory,
+    KycHistoryIndex,
+    PatientNameIndex,
+    PatientBirthDateIndex,
+    PatientKycIndex,
+    PatientRegistrationMap,
+    PatientRegisteredIndex,
+    PatientKycRegisteredIndex,
+    LogChainHeads,
+    LogChainRoot,
+    NotificationMap,
+    InboxMetaMap,
+    NotificationPreferenceMap,
+    LegacyConsentFlag,
+    PatientSessionIndex,
+    PatientKycCount,
+    PendingLinkExpiryIndex,
+    PendingLinkRequesterMap,
+    PrincipalRecoveryIndex
 );
@@ -0,0 +1,416 @@
+//! patient notification inbox.
+//!
+//! things that change the patient data or who can see it, e.g. a new emr or an opened session, are put in the
+//! inbox of the patient so they don't happen silently. a patient can mute the kinds they don't care about,
+//! muted notifications are not stored at all. only the latest [MAX_NOTIFICATIONS] of a patient are kept.
+use candid::CandidType;
+use canister_common::{
+    common::{EmrId, ProviderId, Timestamp},
+    deref, impl_max_size, impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{Candid, Memory, Stable, ToStable},
+};
+use parity_scale_codec::{Decode, Encode};
+use serde::Deserialize;
+
+use crate::{
+    consent::{Purpose, SessionId},
+    kyc::KycCaseStatus,
+    recovery::RecoveryStatus,
+    registry::{GroupId, NIK},
+};
+
+pub const MAX_NOTIFICATIONS: u64 = 200;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NotificationKind {
+    EmrIssued,
+    EmrUpdated,
+    SessionOpened,
+    SessionFinished,
+    GroupInvitation,
+    KycDecision,
+    /// can't be muted, the remaining devices are the only way to notice a recovery the patient
+    /// didn't ask for
+    AccountRecovery,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum NotificationEvent {
+    EmrIssued {
+        emr_id: EmrId,
+        provider_id: ProviderId,
+    },
+    EmrUpdated {
+        emr_id: EmrId,
+        provider_id: ProviderId,
+    },
+    /// a provider opened a session on the patient data, through a consent or break-glass access
+    SessionOpened {
+        session_id: SessionId,
+        provider_id: ProviderId,
+        purpose: Purpose,
+    },
+    SessionFinished {
+        session_id: SessionId,
+        provider_id: ProviderId,
+    },
+    /// the patient was added to a group
+    GroupInvitation { group_id: GroupId },
+    /// an admin approved, denied or asked for more documents on the patient kyc case
+    KycDecision { status: KycCaseStatus },
+    /// a recovery of the patient account was requested, reviewed, cancelled or completed
+    AccountRecovery { id: u64, status: RecoveryStatus },
+}
+
+impl NotificationEvent {
+    pub fn kind(&self) -> NotificationKind {
+        match self {
+            Self::EmrIssued { .. } => NotificationKind::EmrIssued,
+            Self::EmrUpdated { .. } => NotificationKind::EmrUpdated,
+            Self::SessionOpened { .. } => NotificationKind::SessionOpened,
+            Self::SessionFinished { .. } => NotificationKind::SessionFinished,
+            Self::GroupInvitation { .. } => NotificationKind::GroupInvitation,
+            Self::KycDecision { .. } => NotificationKind::KycDecision,
+            Self::AccountRecovery { .. } => NotificationKind::AccountRecovery,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    /// increasing per patient, a newer notification has a larger id
+    pub id: u64,
+    pub event: NotificationEvent,
+    pub created_at: Timestamp,
+    pub read_at: Option<Timestamp>,
+}
+
+impl_max_size!(for Notification: 512);
+impl_mem_bound!(for Notification: bounded; fixed_size: false);
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NotificationPreferences {
+    /// kinds the patient does not want to be notified about, every kind is on by default
+    pub muted: Vec<NotificationKind>,
+}
+
+impl NotificationPreferences {
+    pub fn is_muted(&self, kind: NotificationKind) -> bool {
+        kind != NotificationKind::AccountRecovery && self.muted.contains(&kind)
+    }
+
+    // a kind muted twice is muted once
+    fn normalized(mut self) -> Self {
+        self.muted.sort();
+        self.muted.dedup();
+        self
+    }
+}
+
+impl_max_size!(for NotificationPreferences: 64);
+impl_mem_bound!(for NotificationPreferences: bounded; fixed_size: false);
+
+/// bookkeeping of a patient inbox so counting doesn't need to walk the notifications
+#[derive(Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct InboxMeta {
+    next_id: u64,
+    len: u64,
+    unread: u64,
+}
+
+impl_max_size!(for InboxMeta: u64, u64, u64);
+impl_mem_bound!(for InboxMeta: bounded; fixed_size: true);
+
+type NotificationKey = (Stable<NIK>, u64);
+
+pub struct NotificationMap(
+    ic_stable_structures::BTreeMap<NotificationKey, Stable<Notification, Candid>, Memory>,
+);
+
+impl NotificationMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut NotificationMap: ic_stable_structures::BTreeMap<NotificationKey, Stable<Notification, Candid>, Memory>);
+
+pub struct InboxMetaMap(ic_stable_structures::BTreeMap<Stable<NIK>, Stable<InboxMeta>, Memory>);
+
+impl InboxMetaMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut InboxMetaMap: ic_stable_structures::BTreeMap<Stable<NIK>, Stable<InboxMeta>, Memory>);
+
+pub struct NotificationPreferenceMap(
+    ic_stable_structures::BTreeMap<Stable<NIK>, Stable<NotificationPreferences, Candid>, Memory>,
+);
+
+impl NotificationPreferenceMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut NotificationPreferenceMap: ic_stable_structures::BTreeMap<Stable<NIK>, Stable<NotificationPreferences, Candid>, Memory>);
+
+pub struct Inbox {
+    notifications: NotificationMap,
+    meta: InboxMetaMap,
+    preferences: NotificationPreferenceMap,
+}
+
+impl Inbox {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            notifications: NotificationMap::init(memory_manager),
+            meta: InboxMetaMap::init(memory_manager),
+            preferences: NotificationPreferenceMap::init(memory_manager),
+        }
+    }
+
+    fn meta(&self, nik: &NIK) -> InboxMeta {
+        self.meta
+            .get(nik.to_stable_ref())
+            .map(Stable::into_inner)
+            .unwrap_or_default()
+    }
+
+    fn key(nik: &NIK, id: u64) -> NotificationKey {
+        (nik.clone().to_stable(), id)
+    }
+
+    /// put the event in the patient inbox, returns the notification id or [None] if the patient muted its kind
+    pub fn notify(&mut self, nik: &NIK, event: NotificationEvent, now: Timestamp) -> Option<u64> {
+        if self.preferences(nik).is_muted(event.kind()) {
+            return None;
+        }
+
+        let mut meta = self.meta(nik);
+        let id = meta.next_id;
+        self.notifications.insert(
+            Self::key(nik, id),
+            Notification {
+                id,
+                event,
+                created_at: now,
+                read_at: None,
+            }
+            .to_stable(),
+        );
+        meta.next_id += 1;
+        meta.len += 1;
+        meta.unread += 1;
+
+        // drop the oldest notification once the inbox is full
+        if meta.len > MAX_NOTIFICATIONS {
+            let oldest = self
+                .notifications
+                .range(Self::key(nik, 0)..)
+                .next()
+                .map(|(key, notification)| (key, notification.into_inner()));
+
+            if let Some((key, notification)) = oldest {
+                self.notifications.remove(&key);
+                meta.len -= 1;
+                if notification.read_at.is_none() {
+                    meta.unread -= 1;
+                }
+            }
+        }
+
+        self.meta.insert(nik.clone().to_stable(), meta.to_stable());
+        Some(id)
+    }
+
+    /// notifications of the patient, newest first, along with the number of notifications across every page
+    pub fn list(
+        &self,
+        nik: &NIK,
+        unread_only: bool,
+        page: u64,
+        limit: usize,
+    ) -> (Vec<Notification>, u64) {
+        let mut notifications = self
+            .notifications
+            .range(Self::key(nik, 0)..=Self::key(nik, u64::MAX))
+            .map(|(_, notification)| notification.into_inner())
+            .filter(|notification| !unread_only || notification.read_at.is_none())
+            .collect::<Vec<_>>();
+        notifications.reverse();
+        let total = notifications.len() as u64;
+
+        let notifications = notifications
+            .into_iter()
+            .skip((page as usize).saturating_mul(limit))
+            .take(limit)
+            .collect();
+
+        (notifications, total)
+    }
+
+    pub fn unread(&self, nik: &NIK) -> u64 {
+        self.meta(nik).unread
+    }
+
+    /// mark the given notifications as read, returns how many were marked.
+    /// unknown and already read ids are skipped
+    pub fn mark_read(&mut self, nik: &NIK, ids: &[u64], now: Timestamp) -> u64 {
+        let mut marked = 0;
+
+        for id in ids {
+            let key = Self::key(nik, *id);
+            let Some(mut notification) = self.notifications.get(&key).map(Stable::into_inner)
+            else {
+                continue;
+            };
+
+            if notification.read_at.is_some() {
+                continue;
+            }
+
+            notification.read_at = Some(now);
+            self.notifications.insert(key, notification.to_stable());
+            marked += 1;
+        }
+
+        if marked > 0 {
+            let mut meta = self.meta(nik);
+            meta.unread = meta.unread.saturating_sub(marked);
+            self.meta.insert(nik.clone().to_stable(), meta.to_stable());
+        }
+
+        marked
+    }
+
+    pub fn mark_all_read(&mut self, nik: &NIK, now: Timestamp) -> u64 {
+        let unread = self
+            .notifications
+            .range(Self::key(nik, 0)..=Self::key(nik, u64::MAX))
+            .filter(|(_, notification)| notification.read_at.is_none())
+            .map(|((_, id), _)| id)
+            .collect::<Vec<_>>();
+
+        self.mark_read(nik, &unread, now)
+    }
+
+    pub fn preferences(&self, nik: &NIK) -> NotificationPreferences {
+        self.preferences
+            .get(nik.to_stable_ref())
+            .map(Stable::into_inner)
+            .unwrap_or_default()
+    }
+
+    pub fn set_preferences(&mut self, nik: &NIK, preferences: NotificationPreferences) {
+        self.preferences.insert(
+            nik.clone().to_stable(),
+            preferences.normalized().to_stable(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::{id, memory_manager};
+
+    use super::*;
+
+    fn issued() -> NotificationEvent {
+        NotificationEvent::EmrIssued {
+            emr_id: id!("b2a1c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d"),
+            provider_id: id!("60673662-792a-4e50-b7aa-eccf7e4146a3"),
+        }
+    }
+
+    #[test]
+    fn test_inbox() {
+        let memory_manager = memory_manager!();
+        let mut inbox = Inbox::init(&memory_manager);
+        let (nik, other) = (NIK::from([1u8; 32]), NIK::from([2u8; 32]));
+
+        for _ in 0..3 {
+            inbox.notify(&nik, issued(), Timestamp::new());
+        }
+        inbox.notify(
+            &other,
+            NotificationEvent::KycDecision {
+                status: KycCaseStatus::Approved,
+            },
+            Timestamp::new(),
+        );
+        assert_eq!(inbox.unread(&nik), 3);
+        assert_eq!(inbox.unread(&other), 1);
+
+        let (page, total) = inbox.list(&nik, false, 0, 2);
+        assert_eq!(total, 3);
+        assert_eq!(page.iter().map(|n| n.id).collect::<Vec<_>>(), vec![2, 1]);
+
+        // unknown and already read ids are skipped
+        assert_eq!(inbox.mark_read(&nik, &[1, 1, 42], Timestamp::new()), 1);
+        assert_eq!(inbox.mark_read(&nik, &[1], Timestamp::new()), 0);
+        assert_eq!(inbox.unread(&nik), 2);
+
+        let (unread, total) = inbox.list(&nik, true, 0, 10);
+        assert_eq!(total, 2);
+        assert_eq!(unread.iter().map(|n| n.id).collect::<Vec<_>>(), vec![2, 0]);
+
+        assert_eq!(inbox.mark_all_read(&nik, Timestamp::new()), 2);
+        assert_eq!(inbox.unread(&nik), 0);
+        assert_eq!(inbox.unread(&other), 1);
+    }
+
+    #[test]
+    fn test_preferences() {
+        let memory_manager = memory_manager!();
+        let mut inbox = Inbox::init(&memory_manager);
+        let nik = NIK::from([1u8; 32]);
+
+        inbox.set_preferences(
+            &nik,
+            NotificationPreferences {
+                muted: vec![NotificationKind::EmrIssued, NotificationKind::EmrIssued],
+            },
+        );
+        assert_eq!(
+            inbox.preferences(&nik).muted,
+            vec![NotificationKind::EmrIssued]
+        );
+
+        assert_eq!(inbox.notify(&nik, issued(), Timestamp::new()), None);
+        assert_eq!(inbox.unread(&nik), 0);
+        assert!(inbox.list(&nik, false, 0, 10).0.is_empty());
+
+        // recoveries are always delivered
+        inbox.set_preferences(
+            &nik,
+            NotificationPreferences {
+                muted: vec![NotificationKind::AccountRecovery],
+            },
+        );
+        let recovery = NotificationEvent::AccountRecovery {
+            id: 0,
+            status: RecoveryStatus::Pending,
+        };
+        assert!(inbox.notify(&nik, recovery, Timestamp::new()).is_some());
+    }
+
+    #[test]
+    fn test_inbox_is_capped() {
+        let memory_manager = memory_manager!();
+        let mut inbox = Inbox::init(&memory_manager);
+        let nik = NIK::from([1u8; 32]);
+
+        for _ in 0..MAX_NOTIFICATIONS + 5 {
+            inbox.notify(&nik, issued(), Timestamp::new());
+        }
+
+        let (notifications, total) = inbox.list(&nik, false, 0, 1);
+        assert_eq!(total, MAX_NOTIFICATIONS);
+        assert_eq!(inbox.unread(&nik), MAX_NOTIFICATIONS);
+        assert_eq!(notifications[0].id, MAX_NOTIFICATIONS + 4);
+    }
+}
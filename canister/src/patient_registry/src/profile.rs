@@ -0,0 +1,561 @@
+//! the V2 patient profile, UTF-8 text fields plus contact and clinical details.
+//!
+//! V1 profiles are migrated lazily, they're converted to V2 the next time the patient updates their info.
+//! clients that don't ask for a version keep getting V1, see [Patient::versioned].
+use candid::CandidType;
+use canister_common::{common::AsciiRecordsKey, impl_max_size, impl_mem_bound, impl_range_bound};
+use serde::Deserialize;
+use tiny_keccak::Hasher;
+
+use crate::registry::{KycStatus, Patient, NIK, V1};
+
+const MAX_NAME_LEN: usize = 128;
+const MAX_PLACE_OF_BIRTH_LEN: usize = 64;
+const MAX_ADDRESS_LEN: usize = 256;
+const MAX_SHORT_FIELD_LEN: usize = 16;
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_ALLERGIES: usize = 16;
+const MAX_ALLERGY_LEN: usize = 64;
+const MAX_EMERGENCY_CONTACTS: usize = 3;
+const MAX_RELATION_LEN: usize = 32;
+const MAX_KYC_DATE_LEN: usize = 32;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ProfileError {
+    #[error("{0} must be at most {1} bytes long")]
+    TooLong(&'static str, usize),
+
+    #[error("{0} must not be empty")]
+    Empty(&'static str),
+
+    #[error("{0} must only contain ascii characters")]
+    NotAscii(&'static str),
+
+    #[error("at most {1} {0} are allowed")]
+    TooMany(&'static str, usize),
+
+    #[error("invalid phone number, use the international format e.g. +6281234567890")]
+    InvalidPhone,
+
+    #[error("invalid email address")]
+    InvalidEmail,
+
+    #[error("invalid language tag, use a BCP 47 tag e.g. id or en-US")]
+    InvalidLanguage,
+
+    #[error("date of birth must use the YYYY-MM-DD format")]
+    InvalidDateOfBirth,
+
+    #[error("NIK must be 16 digits")]
+    InvalidNik,
+
+    #[error("NIK does not belong to the patient")]
+    NikMismatch,
+
+    #[error("date of birth does not match the NIK")]
+    DateOfBirthMismatch,
+
+    #[error("date of birth was verified during kyc and can't be changed")]
+    DateOfBirthVerified,
+}
+
+pub type ProfileResult<T = ()> = Result<T, ProfileError>;
+
+/// which profile version a client wants in responses
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PatientVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BloodType {
+    APositive,
+    ANegative,
+    BPositive,
+    BNegative,
+    AbPositive,
+    AbNegative,
+    OPositive,
+    ONegative,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EmergencyContact {
+    pub name: String,
+    /// e.g. spouse, parent
+    pub relation: String,
+    pub phone: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct V2 {
+    pub name: String,
+    pub place_of_birth: String,
+    /// YYYY-MM-DD, checked against the birth date encoded in the NIK
+    pub date_of_birth: String,
+    pub address: String,
+    pub marital_status: String,
+    pub gender: String,
+    pub phone: Option<String>,
+    pub email: Option<String>,
+    pub blood_type: Option<BloodType>,
+    pub allergies: Vec<String>,
+    pub emergency_contacts: Vec<EmergencyContact>,
+    /// BCP 47 language tag, e.g. id or en-US
+    pub preferred_language: Option<String>,
+    pub kyc_status: KycStatus,
+    pub kyc_date: String,
+}
+
+// text fields are capped at ~2.5kb in total, the rest is the candid type table.
+// benchmarked by encoding a profile with every field at its max size, see the tests
+impl_max_size!(for V2: 3584);
+impl_mem_bound!(for V2: bounded; fixed_size: false);
+impl_range_bound!(V2);
+
+fn check_len(field: &'static str, value: &str, max: usize) -> ProfileResult {
+    if value.len() > max {
+        return Err(ProfileError::TooLong(field, max));
+    }
+
+    Ok(())
+}
+
+fn check_required(field: &'static str, value: &str, max: usize) -> ProfileResult {
+    if value.trim().is_empty() {
+        return Err(ProfileError::Empty(field));
+    }
+
+    check_len(field, value, max)
+}
+
+fn validate_phone(phone: &str) -> ProfileResult {
+    let digits = phone.strip_prefix('+').unwrap_or(phone);
+
+    if !(8..=15).contains(&digits.len()) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ProfileError::InvalidPhone);
+    }
+
+    Ok(())
+}
+
+fn validate_email(email: &str) -> ProfileResult {
+    check_len("email", email, MAX_EMAIL_LEN)?;
+
+    let valid = email.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty()
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && !email.contains(char::is_whitespace)
+            && !domain.contains('@')
+    });
+
+    match valid {
+        true => Ok(()),
+        false => Err(ProfileError::InvalidEmail),
+    }
+}
+
+fn validate_language(tag: &str) -> ProfileResult {
+    let valid = tag.len() <= MAX_SHORT_FIELD_LEN
+        && tag.split('-').all(|part| {
+            (1..=8).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+        && tag
+            .split('-')
+            .next()
+            .is_some_and(|primary| (2..=3).contains(&primary.len()));
+
+    match valid {
+        true => Ok(()),
+        false => Err(ProfileError::InvalidLanguage),
+    }
+}
+
+/// (year, month, day) of a YYYY-MM-DD date
+fn parse_date(date: &str) -> ProfileResult<(u16, u8, u8)> {
+    let mut parts = date.splitn(3, '-');
+    let mut next = |len: usize| {
+        parts
+            .next()
+            .filter(|part| part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|part| part.parse::<u16>().ok())
+            .ok_or(ProfileError::InvalidDateOfBirth)
+    };
+
+    let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(ProfileError::InvalidDateOfBirth);
+    }
+
+    Ok((year, month as u8, day as u8))
+}
+
+/// hash a raw NIK the same way clients do before sending it, keccak256 of the 16 digits
+pub fn hash_nik(raw: &str) -> NIK {
+    let mut hasher = tiny_keccak::Keccak::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(raw.as_bytes());
+    hasher.finalize(&mut hash);
+
+    NIK::from(hash)
+}
+
+/// (two digit year, month, day) encoded in the 7th to 12th digit of a NIK.
+/// the day is offset by 40 for women
+fn birth_date_from_nik(raw: &str) -> ProfileResult<(u8, u8, u8)> {
+    if raw.len() != 16 || !raw.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ProfileError::InvalidNik);
+    }
+
+    let digits = |range: std::ops::Range<usize>| raw[range].parse::<u8>().unwrap();
+    let (day, month, year) = (digits(6..8), digits(8..10), digits(10..12));
+    let day = if day > 40 { day - 40 } else { day };
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(ProfileError::InvalidNik);
+    }
+
+    Ok((year, month, day))
+}
+
+impl V2 {
+    pub fn validate(&self) -> ProfileResult {
+        check_required("name", &self.name, MAX_NAME_LEN)?;
+        check_len(
+            "place of birth",
+            &self.place_of_birth,
+            MAX_PLACE_OF_BIRTH_LEN,
+        )?;
+        parse_date(&self.date_of_birth)?;
+        check_len("address", &self.address, MAX_ADDRESS_LEN)?;
+        check_len("marital status", &self.marital_status, MAX_SHORT_FIELD_LEN)?;
+        check_len("gender", &self.gender, MAX_SHORT_FIELD_LEN)?;
+        // both are codes shown as is by V1 clients and group details
+        if !self.marital_status.is_ascii() {
+            return Err(ProfileError::NotAscii("marital status"));
+        }
+
+        if !self.gender.is_ascii() {
+            return Err(ProfileError::NotAscii("gender"));
+        }
+        check_len("kyc date", &self.kyc_date, MAX_KYC_DATE_LEN)?;
+
+        if let Some(ref phone) = self.phone {
+            validate_phone(phone)?;
+        }
+
+        if let Some(ref email) = self.email {
+            validate_email(email)?;
+        }
+
+        if let Some(ref language) = self.preferred_language {
+            validate_language(language)?;
+        }
+
+        if self.allergies.len() > MAX_ALLERGIES {
+            return Err(ProfileError::TooMany("allergies", MAX_ALLERGIES));
+        }
+
+        for allergy in &self.allergies {
+            check_required("allergy", allergy, MAX_ALLERGY_LEN)?;
+        }
+
+        if self.emergency_contacts.len() > MAX_EMERGENCY_CONTACTS {
+            return Err(ProfileError::TooMany(
+                "emergency contacts",
+                MAX_EMERGENCY_CONTACTS,
+            ));
+        }
+
+        for contact in &self.emergency_contacts {
+            check_required("emergency contact name", &contact.name, MAX_NAME_LEN)?;
+            check_required(
+                "emergency contact relation",
+                &contact.relation,
+                MAX_RELATION_LEN,
+            )?;
+            validate_phone(&contact.phone)?;
+        }
+
+        Ok(())
+    }
+
+    /// check the raw NIK hashes to the patient NIK and its encoded birth date matches the profile.
+    /// the raw NIK is only used for the check, it's never stored
+    pub fn validate_against_nik(&self, raw_nik: &str, nik: &NIK) -> ProfileResult {
+        if &hash_nik(raw_nik) != nik {
+            return Err(ProfileError::NikMismatch);
+        }
+
+        let (year, month, day) = parse_date(&self.date_of_birth)?;
+        if birth_date_from_nik(raw_nik)? != ((year % 100) as u8, month, day) {
+            return Err(ProfileError::DateOfBirthMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// the date of birth is also checked against the identity card by an admin during kyc,
+    /// once the kyc is approved the profile can't change it anymore
+    pub fn validate_update(&self, current: &Patient) -> ProfileResult {
+        if current.kyc_status() == &KycStatus::Approved
+            && current.date_of_birth() != self.date_of_birth
+        {
+            return Err(ProfileError::DateOfBirthVerified);
+        }
+
+        Ok(())
+    }
+
+    /// the V1 view of the profile, lossy for fields that don't fit V1.
+    /// non ascii characters are replaced with `?` and long fields are truncated
+    pub fn to_v1(&self) -> V1 {
+        V1 {
+            name: lossy_ascii(&self.name),
+            place_of_birth: lossy_ascii(&self.place_of_birth),
+            date_of_birth: lossy_ascii(&self.date_of_birth),
+            address: lossy_ascii(&self.address),
+            martial_status: lossy_ascii(&self.marital_status),
+            gender: lossy_ascii(&self.gender),
+            kyc_status: self.kyc_status.clone(),
+            kyc_date: lossy_ascii(&self.kyc_date),
+        }
+    }
+
+    /// apply an update made by a V1 client. only the fields the client changed are taken,
+    /// so UTF-8 fields that were shown lossy to the client are kept as is
+    pub fn apply_v1(&mut self, v1: V1) {
+        let seen = self.to_v1();
+
+        macro_rules! apply {
+            ($($v1:ident => $v2:ident),*) => {
+                $(
+                    if v1.$v1 != seen.$v1 {
+                        self.$v2 = v1.$v1.to_string();
+                    }
+                )*
+            };
+        }
+
+        apply!(
+            name => name,
+            place_of_birth => place_of_birth,
+            date_of_birth => date_of_birth,
+            address => address,
+            martial_status => marital_status,
+            gender => gender,
+            kyc_date => kyc_date
+        );
+        self.kyc_status = v1.kyc_status;
+    }
+}
+
+impl From<V1> for V2 {
+    fn from(v1: V1) -> Self {
+        Self {
+            name: v1.name.to_string(),
+            place_of_birth: v1.place_of_birth.to_string(),
+            date_of_birth: v1.date_of_birth.to_string(),
+            address: v1.address.to_string(),
+            marital_status: v1.martial_status.to_string(),
+            gender: v1.gender.to_string(),
+            kyc_status: v1.kyc_status,
+            kyc_date: v1.kyc_date.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+fn lossy_ascii<const N: usize>(s: &str) -> AsciiRecordsKey<N> {
+    let ascii = s
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '?' })
+        .take(N)
+        .collect::<String>();
+
+    AsciiRecordsKey::new(ascii).expect("ascii and at most N bytes")
+}
+
+impl Patient {
+    /// the profile in the version the client asked for, V1 if they didn't ask
+    pub fn versioned(self, version: Option<PatientVersion>) -> Self {
+        match (version.unwrap_or_default(), self) {
+            (PatientVersion::V1, Self::V2(v2)) => Self::V1(v2.to_v1()),
+            (PatientVersion::V2, Self::V1(v1)) => Self::V2(v1.into()),
+            (_, patient) => patient,
+        }
+    }
+
+    /// the profile as V2, migrating V1 profiles
+    pub fn into_v2(self) -> V2 {
+        match self {
+            Self::V1(v1) => v1.into(),
+            Self::V2(v2) => v2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{Decode, Encode};
+
+    use super::*;
+
+    fn profile() -> V2 {
+        V2 {
+            name: "Ni Luh Putu Ayu Déwi".to_string(),
+            place_of_birth: "Denpasar".to_string(),
+            date_of_birth: "1990-05-12".to_string(),
+            phone: Some("+6281234567890".to_string()),
+            email: Some("ayu@example.com".to_string()),
+            preferred_language: Some("id".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(profile().validate(), Ok(()));
+
+        let invalid = [
+            V2 {
+                name: " ".to_string(),
+                ..profile()
+            },
+            V2 {
+                date_of_birth: "12-05-1990".to_string(),
+                ..profile()
+            },
+            V2 {
+                phone: Some("0812-3456".to_string()),
+                ..profile()
+            },
+            V2 {
+                email: Some("ayu@example".to_string()),
+                ..profile()
+            },
+            V2 {
+                preferred_language: Some("indonesian".to_string()),
+                ..profile()
+            },
+            V2 {
+                allergies: vec!["penicillin".to_string(); 17],
+                ..profile()
+            },
+        ];
+
+        for profile in invalid {
+            assert!(profile.validate().is_err(), "{:?}", profile);
+        }
+    }
+
+    #[test]
+    fn test_validate_against_nik() {
+        // a woman born on the 12th of may 1990, the day is offset by 40
+        let raw = "5171015205900001";
+        let nik = hash_nik(raw);
+
+        assert_eq!(profile().validate_against_nik(raw, &nik), Ok(()));
+        assert_eq!(
+            profile().validate_against_nik("5171011205900001", &nik),
+            Err(ProfileError::NikMismatch)
+        );
+
+        let other_birthday = V2 {
+            date_of_birth: "1990-05-13".to_string(),
+            ..profile()
+        };
+        assert_eq!(
+            other_birthday.validate_against_nik(raw, &nik),
+            Err(ProfileError::DateOfBirthMismatch)
+        );
+
+        let short = "51710152059";
+        assert_eq!(
+            profile().validate_against_nik(short, &hash_nik(short)),
+            Err(ProfileError::InvalidNik)
+        );
+    }
+
+    #[test]
+    fn test_validate_update() {
+        let other_birthday = V2 {
+            date_of_birth: "1990-05-13".to_string(),
+            ..profile()
+        };
+        assert_eq!(
+            other_birthday.validate_update(&Patient::V2(profile())),
+            Ok(())
+        );
+
+        let verified = Patient::V2(V2 {
+            kyc_status: KycStatus::Approved,
+            ..profile()
+        });
+        assert_eq!(profile().validate_update(&verified), Ok(()));
+        assert_eq!(
+            other_birthday.validate_update(&verified),
+            Err(ProfileError::DateOfBirthVerified)
+        );
+    }
+
+    #[test]
+    fn test_v1_round_trip() {
+        let mut v2 = profile();
+        let v1 = v2.to_v1();
+        assert_eq!(v1.name.to_string(), "Ni Luh Putu Ayu D?wi");
+
+        // the lossy name sent back by a V1 client doesn't overwrite the UTF-8 one
+        let mut update = v1.clone();
+        update.address = AsciiRecordsKey::new("Jl. Sunset Road").unwrap();
+        v2.apply_v1(update);
+        assert_eq!(v2.name, "Ni Luh Putu Ayu Déwi");
+        assert_eq!(v2.address, "Jl. Sunset Road");
+        assert_eq!(v2.phone, profile().phone);
+
+        let patient = Patient::V1(v1.clone());
+        assert_eq!(patient.clone().versioned(None), patient);
+        assert_eq!(
+            Patient::V2(profile()).versioned(None),
+            Patient::V1(v1.clone())
+        );
+        assert!(matches!(
+            patient.versioned(Some(PatientVersion::V2)),
+            Patient::V2(_)
+        ));
+    }
+
+    #[test]
+    fn test_len_encoded() {
+        let contact = EmergencyContact {
+            name: "a".repeat(MAX_NAME_LEN),
+            relation: "a".repeat(MAX_RELATION_LEN),
+            phone: "+".to_string() + &"1".repeat(15),
+        };
+
+        let patient = Patient::V2(V2 {
+            name: "a".repeat(MAX_NAME_LEN),
+            place_of_birth: "a".repeat(MAX_PLACE_OF_BIRTH_LEN),
+            date_of_birth: "1990-05-12".to_string(),
+            address: "a".repeat(MAX_ADDRESS_LEN),
+            marital_status: "a".repeat(MAX_SHORT_FIELD_LEN),
+            gender: "a".repeat(MAX_SHORT_FIELD_LEN),
+            phone: Some("+".to_string() + &"1".repeat(15)),
+            email: Some("a".repeat(MAX_EMAIL_LEN)),
+            blood_type: Some(BloodType::AbNegative),
+            allergies: vec!["a".repeat(MAX_ALLERGY_LEN); MAX_ALLERGIES],
+            emergency_contacts: vec![contact; MAX_EMERGENCY_CONTACTS],
+            preferred_language: Some("a".repeat(MAX_SHORT_FIELD_LEN)),
+            kyc_status: KycStatus::Pending,
+            kyc_date: "a".repeat(MAX_KYC_DATE_LEN),
+        });
+
+        let encoded = Encode!(&patient).unwrap();
+        assert!(encoded.len() <= Patient::max_size(), "{}", encoded.len());
+        assert_eq!(Decode!(&encoded, Patient).unwrap(), patient);
+    }
+}
@@ -0,0 +1,400 @@
+//! consent receipts following the kantara initiative consent receipt specification v1.1.
+//!
+//! a receipt is issued when a provider opens a session under a consent and again when the session is finished.
+//! receipts are kept in stable memory as is, the json document is only rendered when the patient downloads it.
+use candid::CandidType;
+use canister_common::{
+    common::{ProviderId, Timestamp},
+    impl_max_size, impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{Candid, Memory, Scale, Stable, StableSet, ToStable},
+};
+use ic_stable_structures::Log;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consent::{ConsentCode, ConsentScope, Purpose, SessionId, SessionSnapshot},
+    log::U64,
+    registry::NIK,
+};
+
+const RECEIPT_VERSION: &str = "KI-CR-v1.1.0";
+const JURISDICTION: &str = "ID";
+const LANGUAGE: &str = "id";
+const COLLECTION_METHOD: &str = "consent code shared by the patient with the provider";
+const EMERGENCY_COLLECTION_METHOD: &str =
+    "emergency access opened by the provider without the patient, reviewed afterwards";
+const SERVICE: &str = "medblock electronic medical records";
+
+pub struct ReceiptEntryMemory;
+pub struct ReceiptIndexMemory;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReceiptEvent {
+    Claimed,
+    Finished,
+}
+
+impl ReceiptEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Claimed => "claimed",
+            Self::Finished => "finished",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConsentReceipt {
+    pub event: ReceiptEvent,
+    pub code: ConsentCode,
+    pub session_id: SessionId,
+    pub nik: NIK,
+    pub provider_id: ProviderId,
+    pub scope: Option<ConsentScope>,
+    /// optional only to be able to decode receipts issued before purposes existed
+    pub purpose: Option<Purpose>,
+    pub claimed_at: Timestamp,
+    pub expires_at: Option<Timestamp>,
+    pub issued_at: Timestamp,
+}
+
+// a full consent scope is ~2kb, see the consent max size
+impl_max_size!(for ConsentReceipt: 2304);
+impl_mem_bound!(for ConsentReceipt: bounded; fixed_size: false);
+
+impl ConsentReceipt {
+    pub fn new(
+        event: ReceiptEvent,
+        session_id: SessionId,
+        provider_id: ProviderId,
+        session: &SessionSnapshot,
+        now: Timestamp,
+    ) -> Self {
+        Self {
+            event,
+            code: session.consent.code,
+            session_id,
+            nik: session.consent.nik.clone(),
+            provider_id,
+            scope: session.consent.scope.clone(),
+            purpose: Some(session.purpose),
+            claimed_at: session.info.claimed_at,
+            expires_at: session.consent.expires_at,
+            issued_at: now,
+        }
+    }
+
+    /// the receipt id, unique since every session gets a random id and at most one receipt per event
+    pub fn receipt_id(&self) -> String {
+        format!("{}-{}", self.session_id, self.event.as_str())
+    }
+
+    /// render the receipt as a kantara consent receipt json document
+    pub fn to_json(&self) -> String {
+        let termination = match (self.event, self.expires_at) {
+            (ReceiptEvent::Finished, _) => format!("finished at {}", secs(&self.issued_at)),
+            (ReceiptEvent::Claimed, Some(expires_at)) => {
+                format!(
+                    "finished by either party or expires at {}",
+                    secs(&expires_at)
+                )
+            }
+            (ReceiptEvent::Claimed, None) => "finished by either party".to_string(),
+        };
+
+        let pii_category = match self.scope {
+            Some(ref scope) if !scope.keys.is_empty() => {
+                scope.keys.iter().map(ToString::to_string).collect()
+            }
+            _ => vec!["all medical records".to_string()],
+        };
+
+        let purpose = self.purpose.unwrap_or_default();
+
+        // break-glass sessions are opened without the patient, they didn't consent to them
+        let (collection_method, consent_type) = match purpose {
+            Purpose::Emergency => (EMERGENCY_COLLECTION_METHOD, "EMERGENCY"),
+            _ => (COLLECTION_METHOD, "EXPLICIT"),
+        };
+        let purpose = purpose.as_str();
+
+        let receipt = KantaraReceipt {
+            version: RECEIPT_VERSION,
+            jurisdiction: JURISDICTION,
+            consent_timestamp: secs(&self.claimed_at),
+            collection_method,
+            consent_receipt_id: self.receipt_id(),
+            language: LANGUAGE,
+            pii_principal_id: self.nik.to_string(),
+            pii_controllers: vec![PiiController {
+                pii_controller: self.provider_id.to_string(),
+                on_behalf: false,
+                contact: String::new(),
+                address: String::new(),
+                email: String::new(),
+                phone: String::new(),
+            }],
+            services: vec![Service {
+                service: SERVICE,
+                purposes: vec![KantaraPurpose {
+                    purpose,
+                    consent_type,
+                    purpose_category: vec![purpose],
+                    pii_category,
+                    primary_purpose: true,
+                    termination,
+                    third_party_disclosure: false,
+                }],
+            }],
+            sensitive: true,
+            spi_cat: vec!["health"],
+            medblock: Extension {
+                event: self.event.as_str(),
+                consent_code: self.code.to_string(),
+                session_id: self.session_id.to_string(),
+                issued_at: secs(&self.issued_at),
+                expires_at: self.expires_at.as_ref().map(secs),
+                scope: self.scope.as_ref().map(|scope| ScopeExtension {
+                    emr_ids: scope.emr_ids.iter().map(ToString::to_string).collect(),
+                    providers: scope.providers.iter().map(ToString::to_string).collect(),
+                    keys: scope.keys.iter().map(ToString::to_string).collect(),
+                    read_write: matches!(scope.access, crate::consent::ConsentAccess::ReadWrite),
+                }),
+            },
+        };
+
+        serde_json::to_string(&receipt).expect("receipt is always serializable")
+    }
+}
+
+fn secs(timestamp: &Timestamp) -> u64 {
+    timestamp.as_duration().as_secs()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KantaraReceipt {
+    version: &'static str,
+    jurisdiction: &'static str,
+    consent_timestamp: u64,
+    collection_method: &'static str,
+    #[serde(rename = "consentReceiptID")]
+    consent_receipt_id: String,
+    language: &'static str,
+    pii_principal_id: String,
+    pii_controllers: Vec<PiiController>,
+    services: Vec<Service>,
+    sensitive: bool,
+    spi_cat: Vec<&'static str>,
+    /// fields not covered by the specification
+    medblock: Extension,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PiiController {
+    pii_controller: String,
+    on_behalf: bool,
+    contact: String,
+    address: String,
+    email: String,
+    phone: String,
+}
+
+#[derive(Serialize)]
+struct Service {
+    service: &'static str,
+    purposes: Vec<KantaraPurpose>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KantaraPurpose {
+    purpose: &'static str,
+    consent_type: &'static str,
+    purpose_category: Vec<&'static str>,
+    pii_category: Vec<String>,
+    primary_purpose: bool,
+    termination: String,
+    third_party_disclosure: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Extension {
+    event: &'static str,
+    consent_code: String,
+    session_id: String,
+    issued_at: u64,
+    expires_at: Option<u64>,
+    scope: Option<ScopeExtension>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScopeExtension {
+    emr_ids: Vec<String>,
+    providers: Vec<String>,
+    keys: Vec<String>,
+    read_write: bool,
+}
+
+pub struct ConsentReceipts {
+    entries: ReceiptLogEntry,
+    index: ReceiptMapIndex,
+}
+
+impl ConsentReceipts {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            entries: ReceiptLogEntry::init(memory_manager),
+            index: ReceiptMapIndex::init(memory_manager),
+        }
+    }
+
+    /// store the receipt, returns the id used to download it
+    pub fn issue(&mut self, receipt: ConsentReceipt) -> u64 {
+        let id = self.entries.0.append(receipt.to_stable_ref()).expect("OOM");
+        self.index.add(receipt.nik, id);
+        id
+    }
+
+    /// receipts of the patient along with their id, oldest first
+    pub fn list(&self, nik: &NIK) -> Vec<(u64, ConsentReceipt)> {
+        self.index
+            .get(nik)
+            .into_iter()
+            .filter_map(|id| self.get(nik, id).map(|receipt| (id, receipt)))
+            .collect()
+    }
+
+    /// [None] if the receipt does not exist or belongs to another patient
+    pub fn get(&self, nik: &NIK, id: u64) -> Option<ConsentReceipt> {
+        self.entries
+            .0
+            .get(id)
+            .map(Stable::into_inner)
+            .filter(|receipt| receipt.nik.eq(nik))
+    }
+}
+
+pub struct ReceiptLogEntry(Log<Stable<ConsentReceipt, Candid>, Memory, Memory>);
+
+impl ReceiptLogEntry {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let index_mem = memory_manager.get_memory::<_, ReceiptIndexMemory>(|mem| mem);
+        let data_mem = memory_manager.get_memory::<_, ReceiptEntryMemory>(|mem| mem);
+
+        Self(Log::init(index_mem, data_mem).unwrap())
+    }
+}
+
+/// receipt ids per patient
+pub struct ReceiptMapIndex(StableSet<Stable<NIK>, Stable<U64>>);
+
+impl ReceiptMapIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(StableSet::init::<Self>(memory_manager))
+    }
+
+    pub fn add(&mut self, nik: NIK, id: u64) {
+        let id: Stable<U64, Scale> = U64::from(id).to_stable();
+        self.0.insert(nik.to_stable(), id)
+    }
+
+    pub fn get(&self, nik: &NIK) -> Vec<u64> {
+        self.0
+            .get_set_associated_by_key(nik.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| id.into_inner().into())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use canister_common::{common::AsciiRecordsKey, id, memory_manager};
+
+    use super::*;
+    use crate::consent::{Consent, ConsentAccess, PartialConsent, SessionInfo};
+
+    fn receipt(nik: &NIK, event: ReceiptEvent) -> ConsentReceipt {
+        let consent = Consent::from_partial(
+            PartialConsent::new(nik.clone()).with_scope(Some(ConsentScope {
+                emr_ids: vec![],
+                providers: vec![],
+                keys: vec![AsciiRecordsKey::new("diagnosis").unwrap()],
+                access: ConsentAccess::ReadOnly,
+            })),
+            ConsentCode::from_u64(123456),
+        );
+        let now = Timestamp::new();
+
+        ConsentReceipt::new(
+            event,
+            id!("e74de94d-56ba-422a-aeb7-a0adb88e7ef3"),
+            id!("60673662-792a-4e50-b7aa-eccf7e4146a3"),
+            &SessionSnapshot {
+                consent,
+                info: SessionInfo {
+                    claimed_at: now,
+                    last_access: now,
+                },
+                purpose: Purpose::Insurance,
+            },
+            now,
+        )
+    }
+
+    #[test]
+    fn test_receipts() {
+        let memory_manager = memory_manager!();
+        let mut receipts = ConsentReceipts::init(&memory_manager);
+
+        let nik = NIK::from_str("9b11530da02ee90864b5d8ef14c95782e9c75548e4877e9396394ab33e7c9e9c")
+            .unwrap();
+        let other =
+            NIK::from_str("3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709")
+                .unwrap();
+
+        let claimed = receipts.issue(receipt(&nik, ReceiptEvent::Claimed));
+        let finished = receipts.issue(receipt(&nik, ReceiptEvent::Finished));
+        receipts.issue(receipt(&other, ReceiptEvent::Claimed));
+
+        let list = receipts.list(&nik);
+        assert_eq!(
+            list.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![claimed, finished]
+        );
+
+        // patients can't download each other receipts
+        assert!(receipts.get(&other, claimed).is_none());
+
+        let json = receipts.get(&nik, finished).unwrap().to_json();
+        assert!(json.contains(r#""version":"KI-CR-v1.1.0""#));
+        assert!(
+            json.contains(r#""consentReceiptID":"e74de94d-56ba-422a-aeb7-a0adb88e7ef3-finished""#)
+        );
+        assert!(json.contains(r#""piiCategory":["diagnosis"]"#));
+        assert!(json.contains(r#""purpose":"insurance""#));
+        assert!(json.contains(&format!(r#""piiPrincipalId":"{}""#, nik)));
+        assert!(json.contains(r#""consentType":"EXPLICIT""#));
+        assert!(!json.contains("policyUrl"));
+
+        // break-glass sessions are not rendered as a consent given by the patient
+        let emergency = ConsentReceipt {
+            purpose: Some(Purpose::Emergency),
+            ..receipt(&nik, ReceiptEvent::Claimed)
+        }
+        .to_json();
+        assert!(emergency.contains(r#""consentType":"EMERGENCY""#));
+        assert!(emergency.contains(&format!(
+            r#""collectionMethod":"{}""#,
+            EMERGENCY_COLLECTION_METHOD
+        )));
+    }
+}
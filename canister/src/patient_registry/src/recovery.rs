@@ -0,0 +1,580 @@
+//! account recovery for patients that lost every device linked to their NIK.
+//!
+//! the patient submits a request with evidence from a new principal and an admin reviews it.
+//! a NIK can have several open requests, so a request from someone else can't block the real patient.
+//! open requests are capped per NIK and per principal so nobody can flood a patient log and inbox.
+//! an approved request only takes effect after a cooling-off period, during which any device still linked
+//! to the patient can cancel it. once it's over the new principal completes the recovery,
+//! every old device is revoked, the other open requests are closed and the NIK is bound to the new principal.
+use std::time::Duration;
+
+use candid::{CandidType, Principal};
+use canister_common::{
+    common::Timestamp,
+    deref, impl_max_size, impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{Candid, Memory, Stable, StableSet, ToStable},
+};
+use serde::Deserialize;
+
+use crate::{
+    device::Devices,
+    log::{ActivityType, U64},
+    registry::{OwnerMap, NIK},
+};
+
+// change this if you want to change how long an approved recovery waits before it can be completed
+pub const COOLING_OFF: Duration = Duration::from_secs(60 * 60 * 72); // 72 hours
+
+const MAX_EVIDENCE_LEN: usize = 2000;
+const MAX_NOTE_LEN: usize = 500;
+
+// max amount of pending or approved requests for a single NIK
+pub const MAX_OPEN_PER_NIK: usize = 5;
+// max amount of pending or approved requests submitted by a single principal
+pub const MAX_OPEN_PER_PRINCIPAL: usize = 3;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryStatus {
+    /// waiting for an admin to review it
+    Pending,
+    /// waiting for the cooling-off period to pass
+    Approved,
+    Rejected,
+    /// cancelled from one of the patient devices
+    Cancelled,
+    Completed,
+}
+
+impl RecoveryStatus {
+    fn is_open(&self) -> bool {
+        matches!(self, Self::Pending | Self::Approved)
+    }
+
+    /// the patient log entry recorded when a request reaches this status
+    pub fn activity_type(&self) -> ActivityType {
+        match self {
+            Self::Pending => ActivityType::RecoveryRequested,
+            Self::Approved => ActivityType::RecoveryApproved,
+            Self::Rejected => ActivityType::RecoveryRejected,
+            Self::Cancelled => ActivityType::RecoveryCancelled,
+            Self::Completed => ActivityType::RecoveryCompleted,
+        }
+    }
+}
+
+/// a step of the recovery, every status change is kept
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryEvent {
+    pub status: RecoveryStatus,
+    pub by: Principal,
+    pub at: Timestamp,
+    pub note: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryRequest {
+    pub nik: NIK,
+    /// the principal the NIK gets bound to once the recovery completes
+    pub principal: Principal,
+    pub evidence: String,
+    pub status: RecoveryStatus,
+    /// set once approved, the recovery can't be completed before this
+    pub effective_at: Option<Timestamp>,
+    pub history: Vec<RecoveryEvent>,
+}
+
+// evidence is capped at 2000 bytes and at most 4 events with a 500 bytes note each are kept
+impl_max_size!(for RecoveryRequest: 4608);
+impl_mem_bound!(for RecoveryRequest: bounded; fixed_size: false);
+
+impl RecoveryRequest {
+    fn push(&mut self, status: RecoveryStatus, by: Principal, at: Timestamp, note: Option<String>) {
+        self.status = status;
+        self.history.push(RecoveryEvent {
+            status,
+            by,
+            at,
+            note,
+        });
+    }
+}
+
+fn validate_note(note: &Option<String>) -> Result<(), String> {
+    match note {
+        Some(note) if note.len() > MAX_NOTE_LEN => Err(format!(
+            "note can be at most {} characters long",
+            MAX_NOTE_LEN
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub struct Recoveries {
+    requests: RecoveryRequestMap,
+    review_queue: RecoveryReviewQueue,
+    open: OpenRecoveryMap,
+    by_principal: PrincipalRecoveryIndex,
+}
+
+impl Recoveries {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let mut recoveries = Self {
+            requests: RecoveryRequestMap::init(memory_manager),
+            review_queue: RecoveryReviewQueue::init(memory_manager),
+            open: OpenRecoveryMap::init(memory_manager),
+            by_principal: PrincipalRecoveryIndex::init(memory_manager),
+        };
+
+        // index the requests opened before the principal index existed
+        if recoveries.by_principal.is_empty() && recoveries.open.len() > 0 {
+            let open = recoveries
+                .open
+                .inner()
+                .iter()
+                .filter_map(|((_, id), _)| {
+                    let request = recoveries.requests.get(&id)?.into_inner();
+                    Some((request.principal, id))
+                })
+                .collect::<Vec<_>>();
+
+            for key in open {
+                recoveries.by_principal.insert(key, ());
+            }
+        }
+
+        recoveries
+    }
+
+    /// amount of pending or approved requests submitted by the principal
+    fn open_by(&self, principal: Principal) -> usize {
+        self.by_principal
+            .range((principal, U64::from(0).to_stable())..)
+            .take_while(|((by, _), _)| by == &principal)
+            .count()
+    }
+
+    /// queue a recovery request for review, a principal can only have one open request per NIK.
+    /// the NIK is not checked here so the response doesn't tell whether it's registered
+    pub fn submit(
+        &mut self,
+        nik: NIK,
+        principal: Principal,
+        evidence: String,
+        now: Timestamp,
+    ) -> Result<u64, String> {
+        let evidence = evidence.trim().to_string();
+        if evidence.is_empty() || evidence.len() > MAX_EVIDENCE_LEN {
+            return Err(format!(
+                "evidence must be 1 to {} characters long",
+                MAX_EVIDENCE_LEN
+            ));
+        }
+
+        if self
+            .open(&nik)
+            .iter()
+            .any(|(_, request)| request.principal == principal)
+        {
+            return Err("a recovery is already in progress for this NIK".to_string());
+        }
+
+        if self.open.total_associated_of_key(nik.to_stable_ref()) >= MAX_OPEN_PER_NIK {
+            return Err("too many recoveries are in progress for this NIK".to_string());
+        }
+
+        if self.open_by(principal) >= MAX_OPEN_PER_PRINCIPAL {
+            return Err(format!(
+                "at most {} recoveries can be in progress at once",
+                MAX_OPEN_PER_PRINCIPAL
+            ));
+        }
+
+        let id = self
+            .requests
+            .last_key_value()
+            .map_or(0, |(id, _)| u64::from(id.into_inner()) + 1);
+        let key = U64::from(id).to_stable();
+
+        let mut request = RecoveryRequest {
+            nik: nik.clone(),
+            principal,
+            evidence,
+            status: RecoveryStatus::Pending,
+            effective_at: None,
+            history: vec![],
+        };
+        request.push(RecoveryStatus::Pending, principal, now, None);
+
+        self.requests.insert(key.clone(), request.to_stable());
+        self.review_queue.insert(key.clone(), ());
+        self.open.insert(nik.to_stable(), key.clone());
+        self.by_principal.insert((principal, key), ());
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<RecoveryRequest> {
+        self.requests
+            .get(&U64::from(id).to_stable())
+            .map(Stable::into_inner)
+    }
+
+    /// the pending or approved requests for the NIK, oldest first
+    pub fn open(&self, nik: &NIK) -> Vec<(u64, RecoveryRequest)> {
+        self.open
+            .get_set_associated_by_key(nik.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| {
+                let id = u64::from(id.into_inner());
+                self.get(id).map(|request| (id, request))
+            })
+            .collect()
+    }
+
+    /// requests waiting for a review, oldest first
+    pub fn pending(&self) -> Vec<(u64, RecoveryRequest)> {
+        self.review_queue
+            .iter()
+            .filter_map(|(id, _)| {
+                let id = u64::from(id.into_inner());
+                self.get(id).map(|request| (id, request))
+            })
+            .collect()
+    }
+
+    pub fn pending_len(&self) -> u64 {
+        self.review_queue.len()
+    }
+
+    /// approve or reject a pending request, an approved request starts its cooling-off period
+    pub fn review(
+        &mut self,
+        id: u64,
+        reviewer: Principal,
+        approve: bool,
+        note: Option<String>,
+        now: Timestamp,
+    ) -> Result<RecoveryRequest, String> {
+        validate_note(&note)?;
+
+        self.update(id, RecoveryStatus::Pending, |request| match approve {
+            true => {
+                request.effective_at = Some(now.after(COOLING_OFF));
+                request.push(RecoveryStatus::Approved, reviewer, now, note);
+            }
+            false => request.push(RecoveryStatus::Rejected, reviewer, now, note),
+        })
+    }
+
+    /// cancel every open request for the NIK, called from one of the devices still linked to the patient
+    pub fn cancel(
+        &mut self,
+        nik: &NIK,
+        by: Principal,
+        now: Timestamp,
+    ) -> Result<Vec<(u64, RecoveryRequest)>, String> {
+        let open = self.open(nik);
+        if open.is_empty() {
+            return Err("no recovery in progress".to_string());
+        }
+
+        open.into_iter()
+            .map(|(id, request)| {
+                let request = self.update(id, request.status, |request| {
+                    request.push(RecoveryStatus::Cancelled, by, now, None)
+                })?;
+                Ok((id, request))
+            })
+            .collect()
+    }
+
+    /// finish an approved request once the cooling-off period is over.
+    /// every device bound to the NIK is revoked and the NIK is bound to the principal of the request
+    pub fn complete(
+        &mut self,
+        owner_map: &mut OwnerMap,
+        devices: &mut Devices,
+        id: u64,
+        caller: Principal,
+        now: Timestamp,
+    ) -> Result<RecoveryRequest, String> {
+        let request = self.get(id).ok_or("recovery request does not exist")?;
+
+        if request.status != RecoveryStatus::Approved {
+            return Err(format!("recovery request is {:?}", request.status));
+        }
+
+        if request.principal != caller {
+            return Err("recovery was requested by another principal".to_string());
+        }
+
+        if owner_map.is_valid_owner(&caller) {
+            return Err("caller is already linked to a patient".to_string());
+        }
+
+        if request
+            .effective_at
+            .is_none_or(|effective_at| now < effective_at)
+        {
+            return Err("recovery is still in its cooling-off period".to_string());
+        }
+
+        devices.forget(&request.nik);
+        for principal in owner_map.get_principals(&request.nik) {
+            owner_map.revoke(&principal).map_err(|e| e.to_string())?;
+        }
+        owner_map
+            .bind(caller, request.nik.clone())
+            .map_err(|e| e.to_string())?;
+
+        let completed = self.update(id, RecoveryStatus::Approved, |request| {
+            request.push(RecoveryStatus::Completed, caller, now, None)
+        })?;
+
+        // the NIK is bound to the caller now, the other requests can't go anywhere
+        for (other, request) in self.open(&completed.nik) {
+            self.update(other, request.status, |request| {
+                let note = format!("recovery {} was completed", id);
+                request.push(RecoveryStatus::Cancelled, caller, now, Some(note))
+            })?;
+        }
+
+        Ok(completed)
+    }
+
+    /// apply a status change to a request currently in the expected status, closing it if it's no longer open
+    fn update(
+        &mut self,
+        id: u64,
+        expected: RecoveryStatus,
+        f: impl FnOnce(&mut RecoveryRequest),
+    ) -> Result<RecoveryRequest, String> {
+        let key = U64::from(id).to_stable();
+        let mut request = self
+            .requests
+            .get(&key)
+            .ok_or("recovery request does not exist")?
+            .into_inner();
+
+        if request.status != expected {
+            return Err(format!("recovery request is {:?}", request.status));
+        }
+
+        f(&mut request);
+
+        if request.status != RecoveryStatus::Pending {
+            self.review_queue.remove(&key);
+        }
+        if !request.status.is_open() {
+            self.open
+                .inner_mut()
+                .remove(&(request.nik.clone().to_stable(), key.clone()));
+            self.by_principal.remove(&(request.principal, key.clone()));
+        }
+        self.requests.insert(key, request.clone().to_stable());
+
+        Ok(request)
+    }
+}
+
+pub struct RecoveryRequestMap(
+    ic_stable_structures::BTreeMap<Stable<U64>, Stable<RecoveryRequest, Candid>, Memory>,
+);
+
+impl RecoveryRequestMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut RecoveryRequestMap: ic_stable_structures::BTreeMap<Stable<U64>, Stable<RecoveryRequest, Candid>, Memory>);
+
+/// ids of requests not reviewed yet
+pub struct RecoveryReviewQueue(ic_stable_structures::BTreeMap<Stable<U64>, (), Memory>);
+
+impl RecoveryReviewQueue {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut RecoveryReviewQueue: ic_stable_structures::BTreeMap<Stable<U64>, (), Memory>);
+
+/// the pending or approved requests of each NIK
+pub struct OpenRecoveryMap(StableSet<Stable<NIK>, Stable<U64>>);
+
+impl OpenRecoveryMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(StableSet::init::<Self>(memory_manager))
+    }
+}
+
+deref!(mut OpenRecoveryMap: StableSet<Stable<NIK>, Stable<U64>>);
+
+/// the pending or approved requests of each requesting principal
+pub struct PrincipalRecoveryIndex(
+    ic_stable_structures::BTreeMap<(Principal, Stable<U64>), (), Memory>,
+);
+
+impl PrincipalRecoveryIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PrincipalRecoveryIndex: ic_stable_structures::BTreeMap<(Principal, Stable<U64>), (), Memory>);
+
+#[cfg(test)]
+mod tests {
+    use canister_common::memory_manager;
+
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn test_recovery() {
+        let memory_manager = memory_manager!();
+        let mut owner_map = OwnerMap::init(&memory_manager);
+        let mut devices = Devices::init(&memory_manager);
+        let mut recoveries = Recoveries::init(&memory_manager);
+
+        let nik = NIK::from([1u8; 32]);
+        let (lost, laptop, new, admin) = (principal(1), principal(2), principal(3), principal(4));
+        owner_map.bind(lost, nik.clone()).unwrap();
+        owner_map.link(laptop, nik.clone()).unwrap();
+        let now = Timestamp::new();
+
+        assert!(recoveries
+            .submit(nik.clone(), new, "  ".to_string(), now)
+            .is_err());
+        let id = recoveries
+            .submit(nik.clone(), new, "photo of my id card".to_string(), now)
+            .unwrap();
+        assert!(recoveries
+            .submit(nik.clone(), new, "again".to_string(), now)
+            .is_err());
+
+        // someone else asking for the same NIK doesn't block the patient
+        let (attacker, other_nik) = (principal(5), NIK::from([2u8; 32]));
+        let other = recoveries
+            .submit(nik.clone(), attacker, "trust me".to_string(), now)
+            .unwrap();
+        assert!(recoveries
+            .submit(other_nik, attacker, "unregistered".to_string(), now)
+            .is_ok());
+        assert_eq!(recoveries.pending_len(), 3);
+        assert_eq!(recoveries.open(&nik).len(), 2);
+
+        // can't complete before the review
+        assert!(recoveries
+            .complete(&mut owner_map, &mut devices, id, new, now)
+            .is_err());
+
+        let request = recoveries.review(id, admin, true, None, now).unwrap();
+        assert_eq!(request.status, RecoveryStatus::Approved);
+        assert_eq!(recoveries.pending_len(), 2);
+        assert!(recoveries.review(id, admin, false, None, now).is_err());
+
+        // still cooling off
+        assert!(recoveries
+            .complete(&mut owner_map, &mut devices, id, new, now)
+            .is_err());
+
+        let later = now.after(COOLING_OFF);
+        assert!(recoveries
+            .complete(&mut owner_map, &mut devices, id, admin, later)
+            .is_err());
+        let request = recoveries
+            .complete(&mut owner_map, &mut devices, id, new, later)
+            .unwrap();
+        assert_eq!(request.status, RecoveryStatus::Completed);
+        assert_eq!(request.history.len(), 3);
+
+        assert!(!owner_map.is_valid_owner(&lost));
+        assert!(!owner_map.is_valid_owner(&laptop));
+        assert_eq!(owner_map.get_nik(&new).unwrap().into_inner(), nik);
+        assert!(recoveries.open(&nik).is_empty());
+        assert_eq!(
+            recoveries.get(other).unwrap().status,
+            RecoveryStatus::Cancelled
+        );
+    }
+
+    #[test]
+    fn test_cancel_recovery() {
+        let memory_manager = memory_manager!();
+        let mut recoveries = Recoveries::init(&memory_manager);
+
+        let nik = NIK::from([1u8; 32]);
+        let (owner, attacker, admin) = (principal(1), principal(2), principal(3));
+        let now = Timestamp::new();
+
+        let id = recoveries
+            .submit(nik.clone(), attacker, "trust me".to_string(), now)
+            .unwrap();
+        recoveries.review(id, admin, true, None, now).unwrap();
+
+        recoveries
+            .submit(nik.clone(), principal(4), "me too".to_string(), now)
+            .unwrap();
+
+        // every open request is cancelled at once
+        let requests = recoveries.cancel(&nik, owner, now).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests
+            .iter()
+            .all(|(_, request)| request.status == RecoveryStatus::Cancelled));
+        assert!(recoveries.cancel(&nik, owner, now).is_err());
+
+        // a rejected request also frees the NIK for a new one
+        let id = recoveries
+            .submit(nik.clone(), attacker, "trust me".to_string(), now)
+            .unwrap();
+        recoveries
+            .review(id, admin, false, Some("no evidence".to_string()), now)
+            .unwrap();
+        assert!(recoveries.open(&nik).is_empty());
+        assert_eq!(recoveries.get(id).unwrap().status, RecoveryStatus::Rejected);
+    }
+
+    #[test]
+    fn test_open_recovery_caps() {
+        let memory_manager = memory_manager!();
+        let mut recoveries = Recoveries::init(&memory_manager);
+
+        let (victim, attacker, admin) = (NIK::from([1u8; 32]), principal(1), principal(2));
+        let now = Timestamp::new();
+        let evidence = || "trust me".to_string();
+
+        // a single principal can't keep many requests open, whatever NIK it targets
+        for id in 0..MAX_OPEN_PER_PRINCIPAL {
+            let nik = NIK::from([10 + id as u8; 32]);
+            recoveries.submit(nik, attacker, evidence(), now).unwrap();
+        }
+        assert!(recoveries
+            .submit(victim.clone(), attacker, evidence(), now)
+            .is_err());
+
+        // closing one frees a slot
+        recoveries.review(0, admin, false, None, now).unwrap();
+        recoveries
+            .submit(victim.clone(), attacker, evidence(), now)
+            .unwrap();
+
+        // many principals can't keep many requests open for the same NIK either
+        for id in 1..MAX_OPEN_PER_NIK {
+            recoveries
+                .submit(victim.clone(), principal(10 + id as u8), evidence(), now)
+                .unwrap();
+        }
+        assert!(recoveries
+            .submit(victim.clone(), principal(100), evidence(), now)
+            .is_err());
+        assert_eq!(recoveries.open(&victim).len(), MAX_OPEN_PER_NIK);
+    }
+}
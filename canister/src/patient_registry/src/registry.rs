@@ -2,7 +2,7 @@ use std::str::FromStr;
 
 use candid::{CandidType, Principal};
 use canister_common::{
-    common::{AsciiRecordsKey, EmrHeader, Get, Id, Timestamp, UserId, H256},
+    common::{AsciiRecordsKey, EmrHeader, Id, Timestamp, UserId, H256},
     impl_max_size, impl_mem_bound, impl_range_bound, metrics,
     mmgr::MemoryManager,
     opaque_metrics,
@@ -11,10 +11,15 @@ use canister_common::{
     statistics::traits::{Metrics, OpaqueMetrics},
 };
 
-use ic_stable_structures::memory_manager::MemoryId;
 use serde::Deserialize;
 
-use crate::{api::ReadEmrByIdRequest, declarations};
+use crate::{
+    api::ReadEmrByIdRequest,
+    code::{self, RawCode},
+    declarations,
+    profile::V2,
+    search::PatientIndex,
+};
 
 /// Limit the number of members in a group to 16 to prevent memory overflow, realistically no group should have more than 16 members but we might need to increase this in the future depending on the use case.
 pub const MAX_GROUP_MEMBERS: usize = 16;
@@ -56,6 +61,41 @@ impl PatientRegistry {
     }
 }
 
+// encounter inter-canister call
+impl PatientRegistry {
+    pub async fn do_call_get_encounter_emrs(
+        encounter_id: String,
+        registry: declarations::provider_registry::ProviderRegistry,
+    ) -> declarations::provider_registry::EncounterEmrsResponse {
+        match registry
+            .get_encounter_emrs(declarations::provider_registry::EncounterRequest { encounter_id })
+            .await
+            .map_err(CallError::from)
+        {
+            Ok((response,)) => response,
+            Err(e) => {
+                ic_cdk::trap(&format!("ERROR: Error calling get_encounter_emrs: {:?}", e));
+            }
+        }
+    }
+
+    pub async fn do_call_encounter_list_patient(
+        arg: declarations::provider_registry::EncounterListPatientRequest,
+        registry: declarations::provider_registry::ProviderRegistry,
+    ) -> declarations::provider_registry::EncounterListResponse {
+        match registry
+            .encounter_list_patient(arg)
+            .await
+            .map_err(CallError::from)
+        {
+            Ok((response,)) => response,
+            Err(e) => {
+                ic_cdk::trap(&format!("ERROR: Error calling encounter_list_patient: {:?}", e));
+            }
+        }
+    }
+}
+
 impl PatientRegistry {
     // modified update_patient_info to handle both initial and subsequent updates
     // prerequisite: nik must be bound to an owner first
@@ -87,6 +127,12 @@ impl PatientRegistry {
         Ok(())
     }
 
+    /// forget an emr purged by the emr registry retention job, no-op if it was never issued here
+    pub fn purge(&mut self, header: EmrHeader) {
+        self.emr_binding_map.remove(header.user_id.clone(), header.clone());
+        self.header_status_map.remove(header);
+    }
+
     pub fn get_patient_info_with_principal(
         &self,
         patient_principal: Principal,
@@ -99,6 +145,14 @@ impl PatientRegistry {
     pub fn get_patient_info(&self, patient: NIK) -> PatientBindingMapResult<Patient> {
         self.info_map.get(patient)
     }
+
+    /// mirror the kyc case status on the patient info, no-op if the patient hasn't filled their info yet
+    pub fn sync_kyc_status(&mut self, nik: NIK, kyc_status: KycStatus) {
+        if let Ok(mut patient) = self.info_map.get(nik.clone()) {
+            patient.update_kyc_status(kyc_status);
+            self.info_map.update(nik, patient).unwrap();
+        }
+    }
 }
 
 impl OpaqueMetrics for PatientRegistry {
@@ -262,12 +316,6 @@ pub struct GroupAccessMap(ic_stable_structures::BTreeMap<GroupAccessKey, Stable<
 /// Granter cannot see the grantee's EMRs, but grantee can see the granter's EMRs
 type GroupAccessKey = (Stable<NIK>, Stable<NIK>);
 
-impl Get<MemoryId> for GroupAccessMap {
-    fn get() -> MemoryId {
-        MemoryId::new(20)
-    }
-}
-
 metrics!(GroupAccessMap: GroupAccesses);
 
 impl Metrics<GroupAccesses> for GroupAccessMap {
@@ -407,6 +455,20 @@ impl OwnerMap {
         Ok(())
     }
 
+    /// bind another device to a nik that is already registered, see [crate::device]
+    pub fn link(&mut self, owner: Owner, nik: NIK) -> PatientBindingMapResult {
+        if self.get_nik(&owner).is_ok() {
+            return Err(PatientRegistryError::UserExist);
+        }
+
+        if !self.is_nik_in_use(&nik) {
+            return Err(PatientRegistryError::UserDoesNotExist);
+        }
+
+        let _ = self.0.insert(owner, nik.to_stable());
+        Ok(())
+    }
+
     pub fn rebind(&mut self, owner: Owner, nik: NIK) -> PatientBindingMapResult {
         if self.get_nik(&owner).is_err() {
             return Err(PatientRegistryError::UserDoesNotExist);
@@ -423,12 +485,19 @@ impl OwnerMap {
             .ok_or(PatientRegistryError::UserDoesNotExist)
     }
 
-    /// returns a list of all NIKs in the owner map
+    /// returns a list of all NIKs in the owner map, once per patient even if they linked more devices
     pub fn get_all_nik(&self) -> Vec<Stable<NIK>> {
-        self.0.iter().map(|(_, nik)| nik.clone()).collect()
+        let mut seen = std::collections::BTreeSet::new();
+
+        self.0
+            .iter()
+            .map(|(_, nik)| nik)
+            .filter(|nik| seen.insert(nik.clone().into_inner()))
+            .collect()
     }
 
-    /// gets the principal associated with a NIK by iterating through the map
+    /// gets a principal associated with a NIK by iterating through the map,
+    /// this can be any of the devices linked to the patient
     pub fn get_principal(&self, nik: &NIK) -> PatientBindingMapResult<Owner> {
         self.0
             .iter()
@@ -437,6 +506,15 @@ impl OwnerMap {
             .ok_or(PatientRegistryError::UserDoesNotExist)
     }
 
+    /// every device bound to the NIK
+    pub fn get_principals(&self, nik: &NIK) -> Vec<Owner> {
+        self.0
+            .iter()
+            .filter(|(_, stored_nik)| stored_nik.as_ref() == nik)
+            .map(|(principal, _)| principal)
+            .collect()
+    }
+
     pub fn init(memory_manager: &MemoryManager) -> Self {
         Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
     }
@@ -522,6 +600,27 @@ mod test_owner_map {
         assert!(owner_map.is_valid_owner(&owner));
     }
 
+    #[test]
+    fn test_link() {
+        let mut owner_map = OwnerMap::init(&MemoryManager::init());
+        let owner = ic_principal::Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let device = ic_principal::Principal::anonymous();
+        let nik = NIK::from([0u8; 32]);
+
+        assert_eq!(
+            owner_map.link(device, nik.clone()).unwrap_err(),
+            PatientRegistryError::UserDoesNotExist
+        );
+        owner_map.bind(owner, nik.clone()).unwrap();
+        owner_map.link(device, nik.clone()).unwrap();
+        assert_eq!(
+            owner_map.link(device, nik.clone()).unwrap_err(),
+            PatientRegistryError::UserExist
+        );
+        assert_eq!(owner_map.get_nik(&device).unwrap(), nik.clone().to_stable());
+        assert_eq!(owner_map.get_all_nik().len(), 1);
+    }
+
     #[test]
     fn test_get_all_nik() {
         let mut owner_map = OwnerMap::init(&MemoryManager::init());
@@ -658,6 +757,13 @@ impl EmrBindingMap {
         self.0.insert(nik.to_stable(), header.to_stable());
         Ok(())
     }
+
+    pub fn remove(&mut self, nik: NIK, header: EmrHeader) -> bool {
+        self.0
+            .inner_mut()
+            .remove(&(nik.to_stable(), header.to_stable()))
+            .is_some()
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -739,6 +845,10 @@ impl HeaderStatusMap {
     pub fn get(&self, header: &Stable<EmrHeader>) -> Option<Stable<HeaderStatus, Candid>> {
         self.0.get(header)
     }
+
+    pub fn remove(&mut self, header: EmrHeader) -> Option<HeaderStatus> {
+        self.0.remove(&header.to_stable()).map(Stable::into_inner)
+    }
 }
 
 #[cfg(test)]
@@ -780,15 +890,118 @@ mod test_emr_binding_map {
             vec![header.to_stable()]
         );
     }
+
+    #[test]
+    fn test_purge() {
+        let mut registry = PatientRegistry::init(&MemoryManager::init());
+        let nik = NIK::from([0u8; 32]);
+
+        let emr_id = id!("92fa73e0-0450-4b73-9cc2-dbd703b99f56");
+        let provider_id = id!("92fa73e0-0450-4b73-9cc2-dbd703b99f56");
+        let header = EmrHeader::new(nik.clone(), emr_id, provider_id, Principal::anonymous());
+
+        registry.issue_for(nik.clone(), header.clone()).unwrap();
+        registry.purge(header.clone());
+
+        assert!(!registry.emr_binding_map.is_owner_of(nik.clone(), header.clone()));
+        assert!(registry.header_status_map.get(&header.clone().to_stable()).is_none());
+        assert!(registry.emr_binding_map.emr_list(&nik, 0, 3).is_err());
+
+        // purging twice, e.g. when the purge log is read again, is a no-op
+        registry.purge(header);
+    }
 }
 
-pub struct InfoMap(ic_stable_structures::BTreeMap<Stable<NIK>, Stable<Patient, Candid>, Memory>);
+/// the time a patient info was first stored
+pub struct PatientRegistrationMap(ic_stable_structures::BTreeMap<Stable<NIK>, Stable<Timestamp>, Memory>);
 
-impl InfoMap {
+impl PatientRegistrationMap {
     pub fn init(memory_manager: &MemoryManager) -> Self {
         Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
     }
 
+    pub fn get(&self, nik: &NIK) -> Option<Timestamp> {
+        self.0
+            .get(nik.to_stable_ref())
+            .map(|registered_at| registered_at.into_inner())
+    }
+
+    pub fn insert(&mut self, nik: &NIK, registered_at: Timestamp) {
+        self.0.insert(nik.clone().to_stable(), registered_at.to_stable());
+    }
+}
+
+/// patient info along with the search indexes over it, see [PatientIndex].
+/// the last field is the next patient to index while the indexes are rebuilt
+pub struct InfoMap(
+    ic_stable_structures::BTreeMap<Stable<NIK>, Stable<Patient, Candid>, Memory>,
+    PatientIndex,
+    PatientRegistrationMap,
+    Option<NIK>,
+);
+
+impl InfoMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let mut info_map = Self(
+            memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init),
+            PatientIndex::init(memory_manager),
+            PatientRegistrationMap::init(memory_manager),
+            None,
+        );
+
+        // patients stored before the indexes existed, or an index that got out of sync.
+        // the indexes are filled in batches afterwards, see [InfoMap::rebuild_index]
+        if !info_map.1.is_synced(info_map.0.len()) {
+            info_map.1.clear();
+            info_map.3 = Some(NIK::from([0u8; 32]));
+        }
+
+        info_map
+    }
+
+    /// index up to `limit` patients of a pending rebuild, in NIK order.
+    /// patients stored or updated meanwhile are indexed right away, indexing them again is a no-op.
+    /// returns whether there are patients left to index
+    pub fn rebuild_index(&mut self, limit: u64) -> bool {
+        let Some(start) = self.3.take() else {
+            return false;
+        };
+
+        let batch = self.0
+            .range(start.to_stable()..)
+            .take(limit.saturating_add(1) as usize)
+            .map(|(nik, patient)| (nik.into_inner(), patient.into_inner()))
+            .collect::<Vec<_>>();
+
+        for (i, (nik, patient)) in batch.into_iter().enumerate() {
+            if i as u64 == limit {
+                self.3 = Some(nik);
+                break;
+            }
+
+            let registered_at = self.registered_key(&nik);
+            self.1.insert(&nik, &patient, registered_at);
+        }
+
+        self.3.is_some()
+    }
+
+    pub fn index(&self) -> &PatientIndex {
+        &self.1
+    }
+
+    /// [None] for patients stored before registration times were recorded
+    pub fn registered_at(&self, nik: &NIK) -> Option<Timestamp> {
+        self.2.get(nik)
+    }
+
+    /// the registration time in nanoseconds, patients without one sort before every other patient
+    pub fn registered_key(&self, nik: &NIK) -> u64 {
+        self.registered_at(nik)
+            .map(|registered_at| registered_at.inner())
+            .unwrap_or_default()
+    }
+
     pub fn get(&self, nik: NIK) -> PatientBindingMapResult<Patient> {
         let key = nik.to_stable();
 
@@ -799,11 +1012,14 @@ impl InfoMap {
     }
 
     pub fn set(&mut self, nik: NIK, patient: Patient) -> PatientBindingMapResult {
-        let key = nik.to_stable();
+        let key = nik.clone().to_stable();
         if self.0.contains_key(&key) {
             return Err(PatientRegistryError::UserExist);
         }
 
+        let registered_at = Timestamp::new();
+        self.2.insert(&nik, registered_at);
+        self.1.insert(&nik, &patient, registered_at.inner());
         let result = self.0.insert(key, patient.to_stable());
         assert!(result.is_none(), "info should not exist");
 
@@ -811,13 +1027,16 @@ impl InfoMap {
     }
 
     pub fn update(&mut self, nik: NIK, patient: Patient) -> PatientBindingMapResult {
-        let key = nik.to_stable();
+        let key = nik.clone().to_stable();
         if !self.0.contains_key(&key) {
             return Err(PatientRegistryError::UserDoesNotExist);
         }
 
-        let result = self.0.insert(key, patient.to_stable());
-        assert!(result.is_some(), "info should exist");
+        let result = self.0.insert(key, patient.clone().to_stable());
+        let previous = result.expect("info should exist");
+        let registered_at = self.registered_key(&nik);
+        self.1.remove(&nik, previous.as_inner(), registered_at);
+        self.1.insert(&nik, &patient, registered_at);
 
         Ok(())
     }
@@ -835,6 +1054,7 @@ mod info_test {
         let patient = Patient::V1(V1::default());
 
         assert_eq!(info_map.set(nik.clone(), patient.clone()).unwrap(), ());
+        assert!(info_map.registered_at(&nik).is_some());
         assert_eq!(
             info_map.set(nik.clone(), patient.clone()).unwrap_err(),
             PatientRegistryError::UserExist
@@ -842,29 +1062,81 @@ mod info_test {
 
         assert_eq!(info_map.get(nik.clone()).unwrap(), patient);
     }
+
+    #[test]
+    fn test_rebuild_index() {
+        let memory_manager = MemoryManager::init();
+        let mut info_map = InfoMap::init(&memory_manager);
+        assert!(!info_map.rebuild_index(2));
+
+        for i in 0..3u8 {
+            info_map.set(NIK::from([i; 32]), Patient::V1(V1::default())).unwrap();
+        }
+        info_map.1.clear();
+
+        // the indexes are out of sync, they're rebuilt in batches rather than on init
+        let mut info_map = InfoMap::init(&memory_manager);
+        assert!(!info_map.index().is_synced(3));
+
+        // patients stored during the rebuild are indexed once
+        info_map.set(NIK::from([9u8; 32]), Patient::V1(V1::default())).unwrap();
+
+        assert!(info_map.rebuild_index(2));
+        assert!(!info_map.index().is_synced(4));
+        assert!(!info_map.rebuild_index(2));
+        assert!(info_map.index().is_synced(4));
+        assert!(!info_map.rebuild_index(2));
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, PartialOrd, Ord)]
 pub enum Patient {
     V1(V1),
+    V2(V2),
 }
 
 impl Patient {
-    pub fn name(&self) -> &AsciiRecordsKey<64> {
+    /// lossy for V2 names that don't fit V1, see [V2::to_v1]
+    pub fn name(&self) -> AsciiRecordsKey<64> {
+        match self {
+            Self::V1(v1) => v1.name.clone(),
+            Self::V2(v2) => v2.to_v1().name,
+        }
+    }
+
+    /// the full name, unlike [Patient::name] it's never lossy
+    pub fn name_utf8(&self) -> String {
+        match self {
+            Self::V1(v1) => v1.name.to_string(),
+            Self::V2(v2) => v2.name.clone(),
+        }
+    }
+
+    pub fn date_of_birth(&self) -> String {
+        match self {
+            Self::V1(v1) => v1.date_of_birth.to_string(),
+            Self::V2(v2) => v2.date_of_birth.clone(),
+        }
+    }
+
+    pub fn gender(&self) -> String {
         match self {
-            Self::V1(v1) => &v1.name,
+            Self::V1(v1) => v1.gender.to_string(),
+            Self::V2(v2) => v2.gender.clone(),
         }
     }
 
     pub fn kyc_status(&self) -> &KycStatus {
         match self {
             Self::V1(v1) => &v1.kyc_status,
+            Self::V2(v2) => &v2.kyc_status,
         }
     }
 
     pub fn update_kyc_status(&mut self, kyc_status: KycStatus) {
         match self {
             Self::V1(v1) => v1.kyc_status = kyc_status,
+            Self::V2(v2) => v2.kyc_status = kyc_status,
         }
     }
 }
@@ -875,27 +1147,40 @@ impl From<V1> for Patient {
     }
 }
 
+impl From<V2> for Patient {
+    fn from(v2: V2) -> Self {
+        Self::V2(v2)
+    }
+}
+
 impl Default for Patient {
     fn default() -> Self {
         // change this if upgrading to a new version
-        Self::V1(Default::default())
+        Self::V2(Default::default())
     }
 }
 impl_mem_bound!(for Patient: bounded; fixed_size: false);
 impl_range_bound!(Patient);
 impl Patient {
+    // V2 is the largest version
     pub const fn max_size() -> usize {
-        V1::max_size()
+        V2::max_size()
     }
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord,
+    parity_scale_codec::Encode, parity_scale_codec::Decode,
+)]
 pub enum KycStatus {
     Denied,
     Pending,
     Approved,
 }
 
+impl_max_size!(for KycStatus: 1);
+impl_mem_bound!(for KycStatus: bounded; fixed_size: true);
+
 impl Default for KycStatus {
     fn default() -> Self {
         Self::Pending
@@ -1223,10 +1508,6 @@ impl GroupConsentMap {
         group_consent_code: GroupConsentCode,
         nik: NIK,
     ) -> GroupConsentMapResult {
-        let serialized: Stable<GroupConsentCode, Candid> = group_consent_code.to_stable();
-        println!("serialized: {:?}", serialized);
-        assert!(serialized.0.len() == 6);
-
         self.inner_map
             .0
             .insert(group_consent_code.to_stable(), nik.to_stable());
@@ -1252,17 +1533,16 @@ impl GroupConsentMap {
     }
 }
 
-const ALLOWED_CHAR: [char; 10] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
-const CODE_LEN: usize = 6;
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct GroupConsentCode([u8; CODE_LEN]);
-impl_max_size!(for GroupConsentCode: 14);
+pub struct GroupConsentCode(RawCode);
+// 14 bytes for numeric and 18 bytes for alphanumeric codes
+impl_max_size!(for GroupConsentCode: 18);
 impl_mem_bound!(for GroupConsentCode: bounded; fixed_size:false);
 impl_range_bound!(GroupConsentCode);
 
 impl GroupConsentCode {
     pub fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.0).unwrap()
+        code::as_str(&self.0)
     }
 
     pub fn from_text(text: &str) -> Result<Self, String> {
@@ -1270,15 +1550,17 @@ impl GroupConsentCode {
     }
 
     pub fn from_u64(u: u64) -> Self {
-        let str = u.to_string();
-        let str = &str[str.len() - CODE_LEN..];
-        let mut code = [0; CODE_LEN];
+        GroupConsentCode(code::numeric(u))
+    }
 
-        for (i, c) in str.chars().enumerate() {
-            code[i] = c as u8;
-        }
+    /// the longer alphanumeric code, harder to guess than the numeric one
+    pub fn alphanumeric_from_u64(u: u64) -> Self {
+        GroupConsentCode(code::alphanumeric(u))
+    }
 
-        GroupConsentCode(code)
+    /// leading characters of the code, used to track failed claim attempts
+    pub fn prefix(&self) -> [u8; code::PREFIX_LEN] {
+        code::prefix(&self.0)
     }
 }
 
@@ -1286,21 +1568,7 @@ impl FromStr for GroupConsentCode {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() != CODE_LEN {
-            return Err("invalid length".to_string());
-        }
-
-        let mut code = [0; CODE_LEN];
-
-        for (i, c) in s.chars().enumerate() {
-            if !ALLOWED_CHAR.contains(&c) {
-                return Err("invalid character".to_string());
-            }
-
-            code[i] = c as u8;
-        }
-
-        Ok(GroupConsentCode(code))
+        code::parse(s).map(GroupConsentCode)
     }
 }
 
@@ -1340,10 +1608,13 @@ impl std::fmt::Debug for GroupConsentCode {
 }
 
 impl GroupConsentMap {
-    pub fn generate_code(&mut self) -> GroupConsentCode {
+    pub fn generate_code(&mut self, long: bool) -> GroupConsentCode {
         let random = self.rng.raw_random_u64();
 
-        GroupConsentCode::from_u64(random)
+        match long {
+            true => GroupConsentCode::alphanumeric_from_u64(random),
+            false => GroupConsentCode::from_u64(random),
+        }
     }
 }
 
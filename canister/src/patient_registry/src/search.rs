@@ -0,0 +1,673 @@
+//! secondary indexes over the patient info for admin search, kept in sync by [InfoMap](crate::registry::InfoMap).
+//!
+//! every index is a set of (key, NIK) pairs so patients sharing a key are listed with a range scan, in NIK order.
+use canister_common::{
+    deref, impl_max_size, impl_mem_bound,
+    mmgr::MemoryManager,
+    stable::{Memory, Stable, ToStable},
+};
+use parity_scale_codec::{Decode, Encode};
+
+use crate::registry::{KycStatus, Patient, NIK};
+
+// V2 names are capped at 128 bytes, V1 dates at 32
+const MAX_KEY_LEN: usize = 128;
+
+// shortest name prefix a search accepts, shorter prefixes match too many patients
+pub const MIN_NAME_PREFIX_LEN: usize = 3;
+
+// max amount of index entries looked at for a single search page
+const MAX_SEARCH_SCAN: usize = 1_000;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SearchError {
+    #[error("at least one of name, date of birth or kyc status is required")]
+    NoFilter,
+
+    #[error("name must be at least {0} characters long")]
+    NameTooShort(usize),
+}
+
+/// normalized text key, see [SearchKey::name]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Encode, Decode)]
+pub struct SearchKey(String);
+
+// 128 bytes plus the compact length prefix
+impl_max_size!(for SearchKey: 130);
+impl_mem_bound!(for SearchKey: bounded; fixed_size: false);
+
+impl SearchKey {
+    /// lowercase with whitespace collapsed, so "  Ni  Luh " matches "ni luh"
+    pub fn name(name: &str) -> Self {
+        let normalized = name
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+
+        Self::truncated(normalized)
+    }
+
+    pub fn date(date: &str) -> Self {
+        Self::truncated(date.trim().to_string())
+    }
+
+    // lowercasing can grow a string, cut it back at a char boundary
+    fn truncated(mut s: String) -> Self {
+        let mut len = s.len().min(MAX_KEY_LEN);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        s.truncate(len);
+        Self(s)
+    }
+
+    pub fn starts_with(&self, prefix: &SearchKey) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+}
+
+type IndexTree<K> = ic_stable_structures::BTreeMap<(K, Stable<NIK>), (), Memory>;
+
+// the smallest NIK, used as the start of range scans
+fn min_nik() -> Stable<NIK> {
+    NIK::from([0u8; 32]).to_stable()
+}
+
+pub struct PatientNameIndex(IndexTree<Stable<SearchKey>>);
+
+impl PatientNameIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PatientNameIndex: IndexTree<Stable<SearchKey>>);
+
+pub struct PatientBirthDateIndex(IndexTree<Stable<SearchKey>>);
+
+impl PatientBirthDateIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PatientBirthDateIndex: IndexTree<Stable<SearchKey>>);
+
+pub struct PatientKycIndex(IndexTree<Stable<KycStatus>>);
+
+impl PatientKycIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PatientKycIndex: IndexTree<Stable<KycStatus>>);
+
+/// patients ordered by the time they were registered, in nanoseconds
+pub struct PatientRegisteredIndex(IndexTree<u64>);
+
+impl PatientRegisteredIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PatientRegisteredIndex: IndexTree<u64>);
+
+/// same as [PatientRegisteredIndex] but grouped by kyc status first
+pub struct PatientKycRegisteredIndex(IndexTree<(Stable<KycStatus>, u64)>);
+
+impl PatientKycRegisteredIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+}
+
+deref!(mut PatientKycRegisteredIndex: IndexTree<(Stable<KycStatus>, u64)>);
+
+/// number of patients per kyc status, so page totals don't need a scan
+pub struct PatientKycCount(ic_stable_structures::BTreeMap<Stable<KycStatus>, u64, Memory>);
+
+impl PatientKycCount {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(ic_stable_structures::BTreeMap::init))
+    }
+
+    pub fn count(&self, kyc_status: &KycStatus) -> u64 {
+        self.0
+            .get(&kyc_status.clone().to_stable())
+            .unwrap_or_default()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.iter().map(|(_, count)| count).sum()
+    }
+
+    fn add(&mut self, kyc_status: &KycStatus) {
+        let count = self.count(kyc_status) + 1;
+        self.0.insert(kyc_status.clone().to_stable(), count);
+    }
+
+    fn sub(&mut self, kyc_status: &KycStatus) {
+        let count = self.count(kyc_status).saturating_sub(1);
+        self.0.insert(kyc_status.clone().to_stable(), count);
+    }
+}
+
+deref!(mut PatientKycCount: ic_stable_structures::BTreeMap<Stable<KycStatus>, u64, Memory>);
+
+/// a page of search results along with the index entry of the first patient of the next page
+#[derive(Debug, PartialEq, Eq)]
+pub struct SearchPage {
+    pub niks: Vec<NIK>,
+    /// normalized name or date of birth of the next patient and their NIK, [None] on the last page.
+    /// the key is empty when searching by kyc status only
+    pub next: Option<(String, NIK)>,
+}
+
+/// a page of patients in registration order along with the key of the first patient of the next page
+pub struct PatientPage {
+    pub niks: Vec<NIK>,
+    /// registration time and NIK of the next patient, [None] on the last page
+    pub next: Option<(u64, NIK)>,
+    /// patients with the kyc status, or every patient, the registration window isn't applied
+    pub total: u64,
+}
+
+pub struct PatientIndex {
+    name: PatientNameIndex,
+    date_of_birth: PatientBirthDateIndex,
+    kyc_status: PatientKycIndex,
+    registered: PatientRegisteredIndex,
+    kyc_registered: PatientKycRegisteredIndex,
+    kyc_count: PatientKycCount,
+}
+
+impl PatientIndex {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            name: PatientNameIndex::init(memory_manager),
+            date_of_birth: PatientBirthDateIndex::init(memory_manager),
+            kyc_status: PatientKycIndex::init(memory_manager),
+            registered: PatientRegisteredIndex::init(memory_manager),
+            kyc_registered: PatientKycRegisteredIndex::init(memory_manager),
+            kyc_count: PatientKycCount::init(memory_manager),
+        }
+    }
+
+    /// every patient has exactly one entry per index, an index added after the patients were stored is empty
+    pub fn is_synced(&self, patients: u64) -> bool {
+        [
+            self.name.len(),
+            self.date_of_birth.len(),
+            self.kyc_status.len(),
+            self.registered.len(),
+            self.kyc_registered.len(),
+            self.kyc_count.total(),
+        ]
+        .iter()
+        .all(|len| *len == patients)
+    }
+
+    pub fn clear(&mut self) {
+        self.name.clear_new();
+        self.date_of_birth.clear_new();
+        self.kyc_status.clear_new();
+        self.registered.clear_new();
+        self.kyc_registered.clear_new();
+        self.kyc_count.clear_new();
+    }
+
+    pub fn insert(&mut self, nik: &NIK, patient: &Patient, registered_at: u64) {
+        let nik = nik.clone().to_stable();
+
+        // re-inserting a patient under the same keys doesn't add a patient
+        if !self.registered.contains_key(&(registered_at, nik.clone())) {
+            self.kyc_count.add(patient.kyc_status());
+        }
+
+        self.name.insert(
+            (
+                SearchKey::name(&patient.name_utf8()).to_stable(),
+                nik.clone(),
+            ),
+            (),
+        );
+        self.date_of_birth.insert(
+            (
+                SearchKey::date(&patient.date_of_birth()).to_stable(),
+                nik.clone(),
+            ),
+            (),
+        );
+        self.kyc_status
+            .insert((patient.kyc_status().clone().to_stable(), nik.clone()), ());
+        self.registered.insert((registered_at, nik.clone()), ());
+        self.kyc_registered.insert(
+            (
+                (patient.kyc_status().clone().to_stable(), registered_at),
+                nik,
+            ),
+            (),
+        );
+    }
+
+    pub fn remove(&mut self, nik: &NIK, patient: &Patient, registered_at: u64) {
+        let nik = nik.clone().to_stable();
+
+        if self
+            .registered
+            .remove(&(registered_at, nik.clone()))
+            .is_some()
+        {
+            self.kyc_count.sub(patient.kyc_status());
+        }
+
+        self.name.remove(&(
+            SearchKey::name(&patient.name_utf8()).to_stable(),
+            nik.clone(),
+        ));
+        self.date_of_birth.remove(&(
+            SearchKey::date(&patient.date_of_birth()).to_stable(),
+            nik.clone(),
+        ));
+        self.kyc_status
+            .remove(&(patient.kyc_status().clone().to_stable(), nik.clone()));
+        self.kyc_registered.remove(&(
+            (patient.kyc_status().clone().to_stable(), registered_at),
+            nik,
+        ));
+    }
+
+    /// patients matching every given filter, ordered by the first given filter.
+    /// the name index is scanned first since it's the most selective, the exact filters are then
+    /// point lookups. `cursor` is the `next` entry of the previous page, a page looks at
+    /// [MAX_SEARCH_SCAN] entries at most
+    pub fn search(
+        &self,
+        name: Option<&str>,
+        date_of_birth: Option<&str>,
+        kyc_status: Option<&KycStatus>,
+        cursor: Option<(String, NIK)>,
+        limit: usize,
+    ) -> Result<SearchPage, SearchError> {
+        let (entries, date_of_birth, kyc_status) = match (name, date_of_birth, kyc_status) {
+            (Some(name), date_of_birth, kyc_status) => {
+                (self.name_range(name, cursor)?, date_of_birth, kyc_status)
+            }
+            (None, Some(date_of_birth), kyc_status) => {
+                (self.date_range(date_of_birth, cursor), None, kyc_status)
+            }
+            (None, None, Some(kyc_status)) => (self.kyc_range(kyc_status, cursor), None, None),
+            (None, None, None) => return Err(SearchError::NoFilter),
+        };
+
+        let date_of_birth = date_of_birth.map(|date| SearchKey::date(date).to_stable());
+        let matches = |nik: &NIK| {
+            date_of_birth.as_ref().is_none_or(|date| {
+                self.date_of_birth
+                    .contains_key(&(date.clone(), nik.clone().to_stable()))
+            }) && kyc_status.is_none_or(|status| {
+                self.kyc_status
+                    .contains_key(&(status.clone().to_stable(), nik.clone().to_stable()))
+            })
+        };
+
+        let mut niks = vec![];
+        let mut next = None;
+        for (scanned, (key, nik)) in entries.enumerate() {
+            if scanned == MAX_SEARCH_SCAN || (niks.len() == limit && matches(&nik)) {
+                next = Some((key, nik));
+                break;
+            }
+
+            if matches(&nik) {
+                niks.push(nik);
+            }
+        }
+
+        Ok(SearchPage { niks, next })
+    }
+
+    // patients whose normalized name starts with the prefix, ordered by name
+    fn name_range<'a>(
+        &'a self,
+        prefix: &str,
+        cursor: Option<(String, NIK)>,
+    ) -> Result<Box<dyn Iterator<Item = (String, NIK)> + 'a>, SearchError> {
+        let prefix = SearchKey::name(prefix);
+        if prefix.0.chars().count() < MIN_NAME_PREFIX_LEN {
+            return Err(SearchError::NameTooShort(MIN_NAME_PREFIX_LEN));
+        }
+
+        let start = match cursor {
+            Some((name, nik)) if SearchKey(name.clone()).starts_with(&prefix) => {
+                (SearchKey(name).to_stable(), nik.to_stable())
+            }
+            _ => (prefix.clone().to_stable(), min_nik()),
+        };
+
+        Ok(Box::new(
+            self.name
+                .range(start..)
+                .take_while(move |((name, _), _)| name.starts_with(&prefix))
+                .map(|((name, nik), _)| (name.into_inner().0, nik.into_inner())),
+        ))
+    }
+
+    fn date_range<'a>(
+        &'a self,
+        date_of_birth: &str,
+        cursor: Option<(String, NIK)>,
+    ) -> Box<dyn Iterator<Item = (String, NIK)> + 'a> {
+        let date = SearchKey::date(date_of_birth);
+        let start = match cursor {
+            Some((key, nik)) if key == date.0 => nik.to_stable(),
+            _ => min_nik(),
+        };
+
+        Box::new(
+            self.date_of_birth
+                .range((date.clone().to_stable(), start)..)
+                .take_while(move |((key, _), _)| key.as_inner() == &date)
+                .map(|((key, nik), _)| (key.into_inner().0, nik.into_inner())),
+        )
+    }
+
+    fn kyc_range<'a>(
+        &'a self,
+        kyc_status: &'a KycStatus,
+        cursor: Option<(String, NIK)>,
+    ) -> Box<dyn Iterator<Item = (String, NIK)> + 'a> {
+        let start = cursor.map_or_else(min_nik, |(_, nik)| nik.to_stable());
+
+        Box::new(
+            self.kyc_status
+                .range((kyc_status.clone().to_stable(), start)..)
+                .take_while(move |((key, _), _)| key.as_inner() == kyc_status)
+                .map(|((_, nik), _)| (String::new(), nik.into_inner())),
+        )
+    }
+
+    /// patients registered within `from..=to`, optionally with the given kyc status, oldest first.
+    /// `cursor` is the `next` key of the previous page, so pages stay stable while patients are added or updated
+    pub fn registered(
+        &self,
+        kyc_status: Option<&KycStatus>,
+        from: Option<u64>,
+        to: Option<u64>,
+        cursor: Option<(u64, NIK)>,
+        limit: usize,
+    ) -> PatientPage {
+        let from = from.unwrap_or_default();
+        let to = to.unwrap_or(u64::MAX);
+        let start = match cursor {
+            Some((registered_at, nik)) if registered_at >= from => (registered_at, nik.to_stable()),
+            _ => (from, min_nik()),
+        };
+
+        let total = match kyc_status {
+            Some(kyc_status) => self.kyc_count.count(kyc_status),
+            None => self.registered.len(),
+        };
+
+        let mut page = self
+            .registered_range(kyc_status, start)
+            .take_while(|(registered_at, _)| *registered_at <= to)
+            .take(limit.saturating_add(1))
+            .collect::<Vec<_>>();
+
+        let next = if page.len() > limit { page.pop() } else { None };
+
+        PatientPage {
+            niks: page.into_iter().map(|(_, nik)| nik).collect(),
+            next,
+            total,
+        }
+    }
+
+    // registration time and NIK of the patients from `start` onwards, in registration order
+    fn registered_range<'a>(
+        &'a self,
+        kyc_status: Option<&'a KycStatus>,
+        (registered_at, nik): (u64, Stable<NIK>),
+    ) -> Box<dyn Iterator<Item = (u64, NIK)> + 'a> {
+        match kyc_status {
+            Some(kyc_status) => Box::new(
+                self.kyc_registered
+                    .range(((kyc_status.clone().to_stable(), registered_at), nik)..)
+                    .take_while(move |(((key, _), _), _)| key.as_inner() == kyc_status)
+                    .map(|(((_, registered_at), nik), _)| (registered_at, nik.into_inner())),
+            ),
+            None => Box::new(
+                self.registered
+                    .range((registered_at, nik)..)
+                    .map(|((registered_at, nik), _)| (registered_at, nik.into_inner())),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::memory_manager;
+
+    use super::*;
+    use crate::profile::V2;
+
+    fn patient(name: &str, date_of_birth: &str, kyc_status: KycStatus) -> Patient {
+        Patient::V2(V2 {
+            name: name.to_string(),
+            date_of_birth: date_of_birth.to_string(),
+            kyc_status,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_search_key() {
+        assert_eq!(
+            SearchKey::name("  Ni  Luh\tPUTU "),
+            SearchKey::name("ni luh putu")
+        );
+        assert!(SearchKey::name("Déwi Ayu").starts_with(&SearchKey::name("DÉWI")));
+        assert_eq!(SearchKey::name(&"é".repeat(100)).0.len(), 128);
+    }
+
+    // every match of a search, on a single page
+    fn search(
+        index: &PatientIndex,
+        name: Option<&str>,
+        date_of_birth: Option<&str>,
+        kyc_status: Option<&KycStatus>,
+    ) -> Result<Vec<NIK>, SearchError> {
+        index
+            .search(name, date_of_birth, kyc_status, None, 10)
+            .map(|page| page.niks)
+    }
+
+    #[test]
+    fn test_index() {
+        let memory_manager = memory_manager!();
+        let mut index = PatientIndex::init(&memory_manager);
+
+        let (ayu, agus, budi) = (
+            NIK::from([1u8; 32]),
+            NIK::from([2u8; 32]),
+            NIK::from([3u8; 32]),
+        );
+        index.insert(
+            &ayu,
+            &patient("Ayu Lestari", "1990-05-12", KycStatus::Pending),
+            1,
+        );
+        index.insert(
+            &agus,
+            &patient("Agus", "1990-05-12", KycStatus::Approved),
+            2,
+        );
+        index.insert(&budi, &patient("Budi", "1985-01-01", KycStatus::Pending), 3);
+        assert!(index.is_synced(3));
+
+        let by_name = |name| search(&index, Some(name), None, None);
+        assert_eq!(by_name("ayu"), Ok(vec![ayu.clone()]));
+        assert_eq!(by_name("AYU  l"), Ok(vec![ayu.clone()]));
+        assert_eq!(by_name("cin"), Ok(vec![]));
+        // too short prefixes would match most patients
+        assert_eq!(by_name(" a "), Err(SearchError::NameTooShort(3)));
+        assert_eq!(by_name(""), Err(SearchError::NameTooShort(3)));
+        assert_eq!(
+            search(&index, None, Some("1990-05-12"), None),
+            Ok(vec![ayu.clone(), agus.clone()])
+        );
+        assert_eq!(
+            search(&index, None, None, Some(&KycStatus::Pending)),
+            Ok(vec![ayu.clone(), budi.clone()])
+        );
+
+        index.remove(
+            &ayu,
+            &patient("Ayu Lestari", "1990-05-12", KycStatus::Pending),
+            1,
+        );
+        index.insert(
+            &ayu,
+            &patient("Ayu Lestari", "1990-05-12", KycStatus::Approved),
+            1,
+        );
+        assert!(index.is_synced(3));
+        assert_eq!(
+            search(&index, None, None, Some(&KycStatus::Approved)),
+            Ok(vec![ayu.clone(), agus.clone()])
+        );
+        assert_eq!(
+            search(&index, None, None, Some(&KycStatus::Pending)),
+            Ok(vec![budi.clone()])
+        );
+
+        assert_eq!(search(&index, None, None, None), Err(SearchError::NoFilter));
+        assert_eq!(
+            search(
+                &index,
+                Some("ayu"),
+                Some("1990-05-12"),
+                Some(&KycStatus::Approved)
+            ),
+            Ok(vec![ayu.clone()])
+        );
+        assert_eq!(
+            search(&index, Some("agu"), Some("1985-01-01"), None),
+            Ok(vec![])
+        );
+        assert_eq!(
+            search(&index, None, Some("1990-05-12"), Some(&KycStatus::Pending)),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_search_page() {
+        let memory_manager = memory_manager!();
+        let mut index = PatientIndex::init(&memory_manager);
+
+        let niks = (1..=5u8).map(|i| NIK::from([i; 32])).collect::<Vec<_>>();
+        for (i, nik) in niks.iter().enumerate() {
+            let kyc_status = if i % 2 == 0 {
+                KycStatus::Pending
+            } else {
+                KycStatus::Approved
+            };
+            index.insert(
+                nik,
+                &patient(&format!("Ayu {}", i), "1990-05-12", kyc_status),
+                i as u64,
+            );
+        }
+
+        let first = index.search(Some("ayu"), None, None, None, 2).unwrap();
+        assert_eq!(first.niks, niks[..2].to_vec());
+        assert_eq!(first.next, Some(("ayu 2".to_string(), niks[2].clone())));
+
+        // a patient sorted before the cursor does not shift the next page
+        index.insert(
+            &NIK::from([9u8; 32]),
+            &patient("Ayu 0", "1990-05-12", KycStatus::Pending),
+            9,
+        );
+        let second = index
+            .search(Some("ayu"), None, None, first.next, 2)
+            .unwrap();
+        assert_eq!(second.niks, niks[2..4].to_vec());
+        let last = index
+            .search(Some("ayu"), None, None, second.next, 2)
+            .unwrap();
+        assert_eq!(last.niks, niks[4..].to_vec());
+        assert_eq!(last.next, None);
+
+        // filters are applied within the page
+        let pending = index
+            .search(None, Some("1990-05-12"), Some(&KycStatus::Pending), None, 2)
+            .unwrap();
+        assert_eq!(pending.niks, vec![niks[0].clone(), niks[2].clone()]);
+        let pending = index
+            .search(
+                None,
+                Some("1990-05-12"),
+                Some(&KycStatus::Pending),
+                pending.next,
+                2,
+            )
+            .unwrap();
+        assert_eq!(pending.niks, vec![niks[4].clone(), NIK::from([9u8; 32])]);
+        assert_eq!(pending.next, None);
+    }
+
+    #[test]
+    fn test_registered_page() {
+        let memory_manager = memory_manager!();
+        let mut index = PatientIndex::init(&memory_manager);
+
+        let niks = (1..=5u8).map(|i| NIK::from([i; 32])).collect::<Vec<_>>();
+        for (i, nik) in niks.iter().enumerate() {
+            let kyc_status = if i % 2 == 0 {
+                KycStatus::Pending
+            } else {
+                KycStatus::Approved
+            };
+            index.insert(
+                nik,
+                &patient("Ayu", "1990-05-12", kyc_status),
+                i as u64 * 10,
+            );
+        }
+
+        let first = index.registered(None, None, None, None, 2);
+        assert_eq!(first.niks, niks[..2].to_vec());
+        assert_eq!(first.next, Some((20, niks[2].clone())));
+        assert_eq!(first.total, 5);
+
+        // a patient registered before the cursor does not shift the next page
+        index.insert(
+            &NIK::from([9u8; 32]),
+            &patient("Budi", "1985-01-01", KycStatus::Pending),
+            5,
+        );
+        let second = index.registered(None, None, None, first.next, 2);
+        assert_eq!(second.niks, niks[2..4].to_vec());
+        let last = index.registered(None, None, None, second.next, 2);
+        assert_eq!(last.niks, niks[4..].to_vec());
+        assert_eq!(last.next, None);
+        assert_eq!(last.total, 6);
+
+        let pending = index.registered(Some(&KycStatus::Pending), Some(10), Some(40), None, 10);
+        assert_eq!(pending.niks, vec![niks[2].clone(), niks[4].clone()]);
+        // the total ignores the registration window
+        assert_eq!(pending.total, 4);
+
+        let approved = index.registered(Some(&KycStatus::Approved), None, Some(15), None, 10);
+        assert_eq!(approved.niks, vec![niks[1].clone()]);
+        assert_eq!(approved.next, None);
+    }
+}
@@ -0,0 +1,416 @@
+use std::time::Duration;
+
+use candid::{CandidType, Principal};
+use canister_common::{
+    common::Timestamp,
+    impl_max_size, impl_mem_bound, metrics,
+    mmgr::MemoryManager,
+    stable::{Candid, Memory, Stable, ToStable},
+    statistics::traits::Metrics,
+};
+use ic_stable_structures::{BTreeMap, Cell};
+use parity_scale_codec::{Decode, Encode};
+use serde::Deserialize;
+
+use crate::code::PREFIX_LEN;
+
+// failed attempts allowed for a single caller before it gets locked out
+const CALLER_MAX_FAILURES: u32 = 5;
+
+// failed attempts allowed for codes sharing the same prefix, higher than the caller limit as
+// many callers share the same prefix. catches attackers spreading attempts across principals,
+// a locked prefix only rejects callers that failed recently so honest callers can still claim.
+const PREFIX_MAX_FAILURES: u32 = 50;
+
+// lockout after the first failure past the limit, doubled for every failure after that
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60 * 24); // 1 day
+
+// failures older than this are forgotten, as long as the caller is not locked out
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+// max amount of stale records removed in a single prune
+const MAX_PRUNE_PER_ROUND: usize = 500;
+
+#[derive(thiserror::Error, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum ThrottleError {
+    #[error("too many failed attempts, try again in {0} seconds")]
+    CallerLocked(u64),
+
+    #[error("too many failed attempts for this code, try again in {0} seconds")]
+    CodeLocked(u64),
+}
+
+pub type ThrottleResult<T = ()> = Result<T, ThrottleError>;
+
+/// leading characters of a consent or group consent code
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub struct CodePrefix([u8; PREFIX_LEN]);
+impl_max_size!(for CodePrefix: [u8; PREFIX_LEN]);
+impl_mem_bound!(for CodePrefix: bounded; fixed_size: true);
+
+impl From<[u8; PREFIX_LEN]> for CodePrefix {
+    fn from(prefix: [u8; PREFIX_LEN]) -> Self {
+        Self(prefix)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FailureRecord {
+    failures: u32,
+    last_failure: Option<Timestamp>,
+    locked_until: Option<Timestamp>,
+}
+
+impl_max_size!(for FailureRecord: 64);
+impl_mem_bound!(for FailureRecord: bounded; fixed_size: false);
+
+impl FailureRecord {
+    fn is_locked(&self, now: &Timestamp) -> bool {
+        self.locked_until.is_some_and(|until| now < &until)
+    }
+
+    fn remaining_lockout(&self, now: &Timestamp) -> u64 {
+        self.locked_until
+            .map(|until| {
+                until
+                    .as_duration()
+                    .saturating_sub(now.as_duration())
+                    .as_secs()
+            })
+            .unwrap_or_default()
+    }
+
+    fn is_stale(&self, now: &Timestamp) -> bool {
+        !self.is_locked(now)
+            && self.last_failure.is_none_or(|last| {
+                now.as_duration().saturating_sub(last.as_duration()) > FAILURE_WINDOW
+            })
+    }
+
+    /// returns true if this failure caused a new lockout
+    fn fail(&mut self, max_failures: u32, now: &Timestamp) -> bool {
+        if self.is_stale(now) {
+            *self = Self::default();
+        }
+
+        self.failures += 1;
+        self.last_failure = Some(*now);
+
+        self.lock(max_failures, now)
+    }
+
+    /// undo a single failure, lifting the lockout if it's back under the limit
+    fn forgive(&mut self, max_failures: u32) {
+        self.failures = self.failures.saturating_sub(1);
+
+        if self.failures <= max_failures {
+            self.locked_until = None;
+        }
+    }
+
+    fn lock(&mut self, max_failures: u32, now: &Timestamp) -> bool {
+        if self.failures <= max_failures {
+            return false;
+        }
+
+        // exponential backoff, capped so the shift can't overflow
+        let exponent = (self.failures - max_failures - 1).min(16);
+        let lockout = BASE_LOCKOUT.saturating_mul(1 << exponent).min(MAX_LOCKOUT);
+
+        self.locked_until = Some(now.after(lockout));
+
+        true
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ThrottleStats {
+    failed_claims: u64,
+    lockouts: u64,
+}
+
+impl_max_size!(for ThrottleStats: 64);
+impl_mem_bound!(for ThrottleStats: bounded; fixed_size: false);
+
+pub struct CallerFailureMap(BTreeMap<Principal, Stable<FailureRecord, Candid>, Memory>);
+
+impl CallerFailureMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(BTreeMap::init))
+    }
+}
+
+pub struct PrefixFailureMap(BTreeMap<Stable<CodePrefix>, Stable<FailureRecord, Candid>, Memory>);
+
+impl PrefixFailureMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(BTreeMap::init))
+    }
+}
+
+pub struct ThrottleStatsMemory;
+
+/// tracks failed consent and group consent code claims, locking out callers and code prefixes
+/// that fail too often. a locked prefix only applies to callers with recent failures of their own.
+/// attempts are recorded as failed up front and forgiven on success, so the
+/// failure sticks even if the claim traps afterwards.
+pub struct ClaimThrottle {
+    callers: CallerFailureMap,
+    prefixes: PrefixFailureMap,
+    stats: Cell<Stable<ThrottleStats, Candid>, Memory>,
+}
+
+metrics!(ClaimThrottle: FailedClaims, Lockouts);
+
+impl Metrics<FailedClaims> for ClaimThrottle {
+    fn metrics_name() -> &'static str {
+        "claim_throttle"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "failed_claims"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.stats.get().failed_claims.to_string()
+    }
+}
+
+impl Metrics<Lockouts> for ClaimThrottle {
+    fn metrics_name() -> &'static str {
+        "claim_throttle"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "lockouts"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.stats.get().lockouts.to_string()
+    }
+}
+
+impl ClaimThrottle {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        let stats = memory_manager
+            .get_memory::<_, ThrottleStatsMemory>(|m| {
+                Cell::init(m, ThrottleStats::default().to_stable())
+            })
+            .unwrap();
+
+        Self {
+            callers: CallerFailureMap::init(memory_manager),
+            prefixes: PrefixFailureMap::init(memory_manager),
+            stats,
+        }
+    }
+
+    fn update_stats(&mut self, f: impl FnOnce(&mut ThrottleStats)) {
+        let mut stats = self.stats.get().clone().into_inner();
+        f(&mut stats);
+        self.stats.set(stats.to_stable()).unwrap();
+    }
+
+    /// check whether the caller or the code prefix is locked out, and if not record the attempt as failed.
+    /// the prefix lockout is skipped for callers without recent failures.
+    /// call [ClaimThrottle::succeed] if the claim went through.
+    pub fn attempt(
+        &mut self,
+        caller: Principal,
+        prefix: CodePrefix,
+        now: &Timestamp,
+    ) -> ThrottleResult {
+        let mut caller_record = self
+            .callers
+            .0
+            .get(&caller)
+            .map(|r| r.into_inner())
+            .unwrap_or_default();
+
+        if caller_record.is_locked(now) {
+            return Err(ThrottleError::CallerLocked(
+                caller_record.remaining_lockout(now),
+            ));
+        }
+
+        let mut prefix_record = self
+            .prefixes
+            .0
+            .get(&prefix.to_stable())
+            .map(|r| r.into_inner())
+            .unwrap_or_default();
+
+        if prefix_record.is_locked(now) && !caller_record.is_stale(now) {
+            return Err(ThrottleError::CodeLocked(
+                prefix_record.remaining_lockout(now),
+            ));
+        }
+
+        let locked = caller_record.fail(CALLER_MAX_FAILURES, now) as u64
+            + prefix_record.fail(PREFIX_MAX_FAILURES, now) as u64;
+
+        self.callers.0.insert(caller, caller_record.to_stable());
+        self.prefixes
+            .0
+            .insert(prefix.to_stable(), prefix_record.to_stable());
+
+        self.update_stats(|stats| {
+            stats.failed_claims += 1;
+            stats.lockouts += locked;
+        });
+
+        Ok(())
+    }
+
+    /// the attempt was successful, clear the caller failures and undo the recorded failure for the prefix
+    pub fn succeed(&mut self, caller: Principal, prefix: CodePrefix) {
+        self.callers.0.remove(&caller);
+
+        if let Some(record) = self.prefixes.0.get(&prefix.to_stable()) {
+            let mut record = record.into_inner();
+            record.forgive(PREFIX_MAX_FAILURES);
+            self.prefixes
+                .0
+                .insert(prefix.to_stable(), record.to_stable());
+        }
+
+        self.update_stats(|stats| {
+            stats.failed_claims = stats.failed_claims.saturating_sub(1);
+        });
+    }
+
+    /// remove records that are no longer locked and whose failures are outside the window
+    pub fn prune(&mut self, now: &Timestamp) -> usize {
+        let callers = self
+            .callers
+            .0
+            .iter()
+            .filter(|(_, record)| record.is_stale(now))
+            .map(|(caller, _)| caller)
+            .take(MAX_PRUNE_PER_ROUND)
+            .collect::<Vec<_>>();
+
+        let prefixes = self
+            .prefixes
+            .0
+            .iter()
+            .filter(|(_, record)| record.is_stale(now))
+            .map(|(prefix, _)| prefix)
+            .take(MAX_PRUNE_PER_ROUND)
+            .collect::<Vec<_>>();
+
+        for caller in callers.iter() {
+            self.callers.0.remove(caller);
+        }
+
+        for prefix in prefixes.iter() {
+            self.prefixes.0.remove(prefix);
+        }
+
+        callers.len() + prefixes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::memory_manager;
+
+    use super::*;
+
+    #[test]
+    fn test_caller_lockout() {
+        let memory_manager = memory_manager!();
+        let mut throttle = ClaimThrottle::init(&memory_manager);
+
+        let caller = Principal::anonymous();
+        let prefix = CodePrefix(*b"123");
+        let now = Timestamp::new();
+
+        for _ in 0..=CALLER_MAX_FAILURES {
+            assert!(throttle.attempt(caller, prefix, &now).is_ok());
+        }
+
+        assert!(matches!(
+            throttle.attempt(caller, prefix, &now),
+            Err(ThrottleError::CallerLocked(secs)) if secs == BASE_LOCKOUT.as_secs()
+        ));
+
+        // the lockout doubles on every failure past the limit
+        let later = now.after(BASE_LOCKOUT);
+        assert!(throttle.attempt(caller, prefix, &later).is_ok());
+        assert!(matches!(
+            throttle.attempt(caller, prefix, &later),
+            Err(ThrottleError::CallerLocked(secs)) if secs == BASE_LOCKOUT.as_secs() * 2
+        ));
+
+        assert_eq!(throttle.stats.get().lockouts, 2);
+
+        // a success clears the caller
+        throttle.succeed(caller, prefix);
+        assert!(throttle.attempt(caller, prefix, &later).is_ok());
+    }
+
+    #[test]
+    fn test_prefix_lockout() {
+        let memory_manager = memory_manager!();
+        let mut throttle = ClaimThrottle::init(&memory_manager);
+
+        let prefix = CodePrefix(*b"123");
+        let now = Timestamp::new();
+
+        // spread across callers so only the prefix gets locked
+        for i in 0..=PREFIX_MAX_FAILURES {
+            let caller = Principal::from_slice(&i.to_le_bytes());
+            assert!(throttle.attempt(caller, prefix, &now).is_ok());
+        }
+
+        // callers taking part in the attack are locked out of the prefix
+        let attacker = Principal::from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            throttle.attempt(attacker, prefix, &now),
+            Err(ThrottleError::CodeLocked(_))
+        ));
+        assert!(throttle
+            .attempt(attacker, CodePrefix(*b"456"), &now)
+            .is_ok());
+
+        // an honest caller can still claim a code under the prefix
+        let honest = Principal::from_slice(&[0xff]);
+        assert!(throttle.attempt(honest, prefix, &now).is_ok());
+        throttle.succeed(honest, prefix);
+        assert!(throttle.attempt(honest, prefix, &now).is_ok());
+
+        // a new principal gets a single attempt during the attack
+        let rotated = Principal::from_slice(&[0xfe]);
+        assert!(throttle.attempt(rotated, prefix, &now).is_ok());
+        assert!(matches!(
+            throttle.attempt(rotated, prefix, &now),
+            Err(ThrottleError::CodeLocked(_))
+        ));
+    }
+
+    #[test]
+    fn test_prune() {
+        let memory_manager = memory_manager!();
+        let mut throttle = ClaimThrottle::init(&memory_manager);
+
+        let now = Timestamp::new();
+        throttle
+            .attempt(Principal::anonymous(), CodePrefix(*b"123"), &now)
+            .unwrap();
+
+        assert_eq!(throttle.prune(&now), 0);
+        assert_eq!(throttle.prune(&now.after(FAILURE_WINDOW * 2)), 2);
+        assert!(throttle.callers.0.is_empty());
+    }
+}
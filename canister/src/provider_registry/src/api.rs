@@ -2,11 +2,13 @@ use candid::{ CandidType, Principal };
 use canister_common::{
     common::{ AsciiRecordsKey, EmrBody, EmrFragment, EmrId, ProviderId, UserId },
     from,
+    rate_limit::RateLimit,
 };
 use serde::Deserialize;
 
 use crate::{
     declarations::emr_registry::{ CreateEmrRequest, CreateEmrResponse },
+    encounter::{ Encounter, EncounterId },
     registry::provider::Provider,
 };
 
@@ -14,6 +16,8 @@ use crate::{
 pub struct IssueEmrRequest {
     pub emr: EmrBody,
     pub user_id: UserId,
+    /// attach the issued emr to an in progress encounter of the calling provider
+    pub encounter_id: Option<EncounterId>,
 }
 
 impl IssueEmrRequest {
@@ -73,6 +77,7 @@ pub struct RegisternewProviderResponse {
 #[derive(CandidType, Deserialize)]
 pub struct UpdateEmrRequest {
     pub fields: Vec<EmrFragment>,
+    /// must be issued by the calling provider or shared with it through a session with write access
     pub header: canister_common::common::EmrHeader,
 }
 
@@ -100,6 +105,89 @@ impl UpdateEmrRequest {
     }
 }
 
+fn to_emr_registry_header(
+    header: canister_common::common::EmrHeader
+) -> crate::declarations::emr_registry::Header {
+    crate::declarations::emr_registry::Header {
+        provider_id: header.provider_id.to_string(),
+        user_id: header.user_id.to_string(),
+        emr_id: header.emr_id.to_string(),
+        registry_id: header.registry_id.to_principal(),
+    }
+}
+
+/// convert the header returned by the emr registry back into the common header type
+pub fn from_emr_registry_header(
+    header: &crate::declarations::emr_registry::Header
+) -> canister_common::common::EmrHeader {
+    // safe to unwrap as the header is produced by the emr registry from valid ids
+    canister_common::common::EmrHeader {
+        emr_id: header.emr_id.parse().unwrap(),
+        provider_id: header.provider_id.parse().unwrap(),
+        user_id: header.user_id.parse().unwrap(),
+        registry_id: header.registry_id.into(),
+    }
+}
+
+pub fn to_patient_registry_header(
+    header: canister_common::common::EmrHeader
+) -> crate::declarations::patient_registry::EmrHeader {
+    crate::declarations::patient_registry::EmrHeader {
+        provider_id: header.provider_id.to_string(),
+        user_id: header.user_id.to_string(),
+        emr_id: header.emr_id.to_string(),
+        registry_id: header.registry_id.to_principal(),
+    }
+}
+
+/// convert the header returned by the patient registry back into the common header type
+pub fn from_patient_registry_header(
+    header: &crate::declarations::patient_registry::EmrHeader
+) -> canister_common::common::EmrHeader {
+    // safe to unwrap as the patient registry only echoes back headers we sent
+    canister_common::common::EmrHeader {
+        emr_id: header.emr_id.parse().unwrap(),
+        provider_id: header.provider_id.parse().unwrap(),
+        user_id: header.user_id.parse().unwrap(),
+        registry_id: header.registry_id.into(),
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LinkEmrRequest {
+    /// the emr the link starts from, must be issued by the calling provider
+    pub from: canister_common::common::EmrHeader,
+    /// must be issued by the calling provider or shared with it through a session
+    pub to: canister_common::common::EmrHeader,
+    pub link_type: crate::declarations::emr_registry::LinkType,
+}
+
+impl LinkEmrRequest {
+    pub fn to_args(self) -> crate::declarations::emr_registry::LinkEmrRequest {
+        crate::declarations::emr_registry::LinkEmrRequest {
+            from: to_emr_registry_header(self.from),
+            to: to_emr_registry_header(self.to),
+            link_type: self.link_type,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReadEmrLinksRequest {
+    /// must be issued by the calling provider
+    pub header: canister_common::common::EmrHeader,
+    pub depth: u8,
+}
+
+impl ReadEmrLinksRequest {
+    pub fn to_args(self) -> crate::declarations::emr_registry::ReadEmrLinksRequest {
+        crate::declarations::emr_registry::ReadEmrLinksRequest {
+            header: to_emr_registry_header(self.header),
+            depth: self.depth,
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct UpdateEmrResponse {
     // empty for now
@@ -176,3 +264,57 @@ impl GetProviderListResponse {
 }
 
 
+
+#[derive(CandidType, Deserialize)]
+pub struct StartEncounterRequest {
+    pub user_id: UserId,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterRequest {
+    pub encounter_id: EncounterId,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterResponse {
+    pub encounter: Encounter,
+}
+
+from!(EncounterResponse: Encounter as value {
+    encounter: value
+});
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterListProviderRequest {
+    pub page: u64,
+    pub limit: u8,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterListPatientRequest {
+    pub user_id: UserId,
+    pub page: u64,
+    pub limit: u8,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterListResponse {
+    pub encounters: Vec<Encounter>,
+}
+
+from!(EncounterListResponse: Vec<Encounter> as value {
+    encounters: value
+});
+
+#[derive(CandidType, Deserialize)]
+pub struct EncounterEmrsResponse {
+    pub encounter: Encounter,
+    pub emrs: Vec<canister_common::common::EmrHeader>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateRateLimitRequest {
+    pub method: String,
+    /// remove the limit for the method if not set
+    pub limit: Option<RateLimit>,
+}
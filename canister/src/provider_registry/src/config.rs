@@ -1,9 +1,12 @@
+use std::time::Duration;
+
 use candid::{ CandidType, Principal };
 use canister_common::{
     impl_max_size,
     impl_mem_bound,
     metrics,
     mmgr::MemoryManager,
+    rate_limit::{ MethodRateLimit, RateLimit, RateLimits },
     stable::{ Candid, Memory, Stable, ToStable },
     statistics::traits::Metrics,
 };
@@ -20,6 +23,9 @@ pub struct CanisterConfig {
     patient_registry: Principal,
     emr_registries: Vec<Principal>,
     authorized_metrics_collectors: Vec<Principal>,
+
+    // optional so configs stored before rate limits existed still decode, `None` means the default limits
+    rate_limits: Option<RateLimits>,
 }
 
 metrics!(CanisterConfig: EmrRegistry,PatientRegistry,MetricsCollector);
@@ -106,6 +112,7 @@ impl Default for CanisterConfig {
             patient_registry: Principal::anonymous(),
             emr_registries: vec![Principal::anonymous()],
             authorized_metrics_collectors: vec![],
+            rate_limits: None,
         }
     }
 }
@@ -148,6 +155,10 @@ impl CanisterConfig {
         self.patient_registry = principal;
     }
 
+    pub fn is_patient_registry(&self, principal: &Principal) -> bool {
+        self.patient_registry.eq(principal)
+    }
+
     pub fn is_canister_owner(&self, principal: &Principal) -> bool {
         self.owner.eq(principal)
     }
@@ -171,4 +182,29 @@ impl CanisterConfig {
     pub fn is_authorized_metrics_collector(&self, collector: &Principal) -> bool {
         self.authorized_metrics_collectors.contains(collector)
     }
+
+    /// limit for the method, `None` if the method is not rate limited
+    pub fn rate_limit(&self, method: &str) -> Option<RateLimit> {
+        match self.rate_limits {
+            Some(ref limits) => limits.get(method),
+            None => Self::default_rate_limits().get(method),
+        }
+    }
+
+    pub fn rate_limits(&self) -> Vec<MethodRateLimit> {
+        self.rate_limits.clone().unwrap_or_else(Self::default_rate_limits).list().to_vec()
+    }
+
+    /// set the limit for the method, `None` removes the limit entirely
+    pub fn update_rate_limit(&mut self, method: String, limit: Option<RateLimit>) {
+        let limits = self.rate_limits.get_or_insert_with(Self::default_rate_limits);
+        limits.set(method, limit);
+    }
+
+    /// expensive methods callable by anyone are limited by default
+    fn default_rate_limits() -> RateLimits {
+        RateLimits::new([
+            ("register_new_provider", RateLimit::new(5, Duration::from_secs(60 * 10))),
+        ])
+    }
 }
@@ -0,0 +1,309 @@
+use candid::CandidType;
+use canister_common::{
+    common::{ EmrHeader, Id, Timestamp, UserId },
+    impl_max_size,
+    impl_mem_bound,
+    metrics,
+    mmgr::MemoryManager,
+    stable::{ Candid, Memory, Stable, StableSet, ToStable },
+    statistics::traits::Metrics,
+};
+use ic_stable_structures::BTreeMap;
+use serde::Deserialize;
+
+use crate::registry::InternalProviderId;
+
+pub type EncounterId = Id;
+
+#[derive(thiserror::Error, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum EncounterError {
+    #[error("encounter not found")]
+    NotFound,
+
+    #[error("encounter belongs to another provider")]
+    NotOwner,
+
+    #[error("encounter is already finished or cancelled")]
+    NotInProgress,
+
+    #[error("encounter belongs to another patient")]
+    PatientMismatch,
+
+    #[error("provider has no open session with the patient")]
+    NoSession,
+}
+
+pub type EncounterResult<T = ()> = Result<T, EncounterError>;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncounterStatus {
+    InProgress,
+    Finished,
+    Cancelled,
+}
+
+/// a single visit of a patient to a provider, emrs issued during the visit are attached to it
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Encounter {
+    pub id: EncounterId,
+    pub provider: InternalProviderId,
+    pub patient: UserId,
+    pub started_at: Timestamp,
+    pub ended_at: Option<Timestamp>,
+    pub status: EncounterStatus,
+}
+
+// ~130 bytes benchmarked for candid encoding
+impl_max_size!(for Encounter: 256);
+impl_mem_bound!(for Encounter: bounded; fixed_size: false);
+
+impl Encounter {
+    pub fn new(id: EncounterId, provider: InternalProviderId, patient: UserId) -> Self {
+        Self {
+            id,
+            provider,
+            patient,
+            started_at: Timestamp::new(),
+            ended_at: None,
+            status: EncounterStatus::InProgress,
+        }
+    }
+
+    pub fn is_in_progress(&self) -> bool {
+        self.status == EncounterStatus::InProgress
+    }
+
+    fn end(&mut self, status: EncounterStatus) -> EncounterResult {
+        if !self.is_in_progress() {
+            return Err(EncounterError::NotInProgress);
+        }
+
+        self.status = status;
+        self.ended_at = Some(Timestamp::new());
+
+        Ok(())
+    }
+}
+
+pub struct EncounterMap(BTreeMap<Stable<EncounterId>, Stable<Encounter, Candid>, Memory>);
+
+impl EncounterMap {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self(memory_manager.get_memory::<_, Self>(BTreeMap::init))
+    }
+}
+
+/// emrs attached to an encounter
+pub struct EncounterEmrMap(StableSet<Stable<EncounterId>, Stable<EmrHeader>>);
+
+/// encounters started by a provider
+pub struct ProviderEncounterMap(StableSet<Stable<InternalProviderId>, Stable<EncounterId>>);
+
+/// encounters of a patient
+pub struct PatientEncounterMap(StableSet<Stable<UserId>, Stable<EncounterId>>);
+
+pub struct Encounters {
+    encounters: EncounterMap,
+    emrs: EncounterEmrMap,
+    by_provider: ProviderEncounterMap,
+    by_patient: PatientEncounterMap,
+}
+
+metrics!(Encounters: EncounterCount);
+
+impl Metrics<EncounterCount> for Encounters {
+    fn metrics_name() -> &'static str {
+        "encounters"
+    }
+
+    fn metrics_measurements() -> &'static str {
+        "len"
+    }
+
+    fn update_measurements(&self) {
+        // no-op
+    }
+
+    fn get_measurements(&self) -> String {
+        self.encounters.0.len().to_string()
+    }
+}
+
+impl Encounters {
+    pub fn init(memory_manager: &MemoryManager) -> Self {
+        Self {
+            encounters: EncounterMap::init(memory_manager),
+            emrs: EncounterEmrMap(StableSet::init::<EncounterEmrMap>(memory_manager)),
+            by_provider: ProviderEncounterMap(
+                StableSet::init::<ProviderEncounterMap>(memory_manager)
+            ),
+            by_patient: PatientEncounterMap(StableSet::init::<PatientEncounterMap>(memory_manager)),
+        }
+    }
+
+    pub fn start(&mut self, encounter: Encounter) {
+        self.by_provider.0.insert(encounter.provider.clone().to_stable(), encounter.id.clone().to_stable());
+        self.by_patient.0.insert(encounter.patient.clone().to_stable(), encounter.id.clone().to_stable());
+        self.encounters.0.insert(encounter.id.clone().to_stable(), encounter.to_stable());
+    }
+
+    pub fn get(&self, id: &EncounterId) -> EncounterResult<Encounter> {
+        self.encounters.0
+            .get(id.to_stable_ref())
+            .map(|encounter| encounter.into_inner())
+            .ok_or(EncounterError::NotFound)
+    }
+
+    /// get an encounter owned by the given provider
+    pub fn get_owned(
+        &self,
+        id: &EncounterId,
+        provider: &InternalProviderId
+    ) -> EncounterResult<Encounter> {
+        let encounter = self.get(id)?;
+
+        if encounter.provider.ne(provider) {
+            return Err(EncounterError::NotOwner);
+        }
+
+        Ok(encounter)
+    }
+
+    /// end an in progress encounter, no more emrs can be attached to it afterwards
+    pub fn end(
+        &mut self,
+        id: &EncounterId,
+        provider: &InternalProviderId,
+        status: EncounterStatus
+    ) -> EncounterResult<Encounter> {
+        let mut encounter = self.get_owned(id, provider)?;
+        encounter.end(status)?;
+
+        self.encounters.0.insert(id.clone().to_stable(), encounter.clone().to_stable());
+
+        Ok(encounter)
+    }
+
+    /// make sure an emr for `patient` can be attached to the encounter, checked before the emr is issued
+    pub fn ensure_attachable(
+        &self,
+        id: &EncounterId,
+        provider: &InternalProviderId,
+        patient: &UserId
+    ) -> EncounterResult<()> {
+        let encounter = self.get_owned(id, provider)?;
+
+        if !encounter.is_in_progress() {
+            return Err(EncounterError::NotInProgress);
+        }
+
+        if encounter.patient.ne(patient) {
+            return Err(EncounterError::PatientMismatch);
+        }
+
+        Ok(())
+    }
+
+    pub fn attach(&mut self, id: &EncounterId, header: EmrHeader) {
+        self.emrs.0.insert(id.clone().to_stable(), header.to_stable());
+    }
+
+    pub fn emrs(&self, id: &EncounterId) -> Vec<EmrHeader> {
+        self.emrs.0
+            .get_set_associated_by_key(id.to_stable_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|header| header.into_inner())
+            .collect()
+    }
+
+    fn resolve(&self, ids: Option<Vec<Stable<EncounterId>>>) -> Vec<Encounter> {
+        ids.unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| self.get(&id).ok())
+            .collect()
+    }
+
+    pub fn list_by_provider(
+        &self,
+        provider: &InternalProviderId,
+        page: u64,
+        limit: u64
+    ) -> Vec<Encounter> {
+        self.resolve(
+            self.by_provider.0.get_set_associated_by_key_paged(provider.to_stable_ref(), page, limit)
+        )
+    }
+
+    pub fn list_by_patient(&self, patient: &UserId, page: u64, limit: u64) -> Vec<Encounter> {
+        self.resolve(
+            self.by_patient.0.get_set_associated_by_key_paged(patient.to_stable_ref(), page, limit)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use canister_common::{ common::H256, id };
+
+    use super::*;
+
+    fn header(patient: &UserId, provider: &InternalProviderId, emr_id: Id) -> EmrHeader {
+        EmrHeader {
+            emr_id,
+            provider_id: provider.clone(),
+            user_id: patient.clone(),
+            registry_id: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_encounter_lifecycle() {
+        let memory_manager = MemoryManager::init();
+        let mut encounters = Encounters::init(&memory_manager);
+
+        let provider = id!("b0e6abc0-5b4f-49b8-b1cf-9f4a452ff22d");
+        let other_provider = id!("be06a4e7-bc46-4740-8397-ea00d9933cc1");
+        let patient = H256::from([1u8; 32]);
+        let other_patient = H256::from([2u8; 32]);
+
+        let encounter_id = id!("6c5dd2ec-0fe0-40dc-ae33-234252be26ed");
+        encounters.start(Encounter::new(encounter_id.clone(), provider.clone(), patient.clone()));
+
+        assert_eq!(
+            encounters.ensure_attachable(&encounter_id, &other_provider, &patient),
+            Err(EncounterError::NotOwner)
+        );
+        assert_eq!(
+            encounters.ensure_attachable(&encounter_id, &provider, &other_patient),
+            Err(EncounterError::PatientMismatch)
+        );
+        assert!(encounters.ensure_attachable(&encounter_id, &provider, &patient).is_ok());
+
+        let triage = header(&patient, &provider, id!("5d5dd2ec-0fe0-40dc-ae33-234252be26ed"));
+        let lab = header(&patient, &provider, id!("4e5dd2ec-0fe0-40dc-ae33-234252be26ed"));
+        encounters.attach(&encounter_id, triage.clone());
+        encounters.attach(&encounter_id, lab.clone());
+
+        let mut emrs = encounters.emrs(&encounter_id);
+        emrs.sort();
+        let mut expected = vec![triage, lab];
+        expected.sort();
+        assert_eq!(emrs, expected);
+
+        let encounter = encounters.end(&encounter_id, &provider, EncounterStatus::Finished).unwrap();
+        assert!(encounter.ended_at.is_some());
+        assert_eq!(
+            encounters.ensure_attachable(&encounter_id, &provider, &patient),
+            Err(EncounterError::NotInProgress)
+        );
+        assert_eq!(
+            encounters.end(&encounter_id, &provider, EncounterStatus::Cancelled),
+            Err(EncounterError::NotInProgress)
+        );
+
+        assert_eq!(encounters.list_by_patient(&patient, 0, 10), vec![encounter.clone()]);
+        assert_eq!(encounters.list_by_provider(&provider, 0, 10), vec![encounter]);
+        assert!(encounters.list_by_patient(&other_patient, 0, 10).is_empty());
+    }
+}
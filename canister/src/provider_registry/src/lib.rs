@@ -5,27 +5,31 @@ use api::{
     GetProviderListRequest, GetProviderListResponse, IssueEmrResponse, PingResult,
     ProviderInfoRequest, ProviderInfoResponse, RegisternewProviderRequest,
     RegisternewProviderResponse, SuspendRequest, UnSuspendRequest, UpdateEmrRegistryRequest,
-    UpdatePatientRegistryRequest,
+    UpdatePatientRegistryRequest, UpdateRateLimitRequest,
 };
 use candid::{Decode, Encode, Principal};
 use canister_common::{
-    common::{freeze::FreezeThreshold, guard::verified_caller},
+    common::{freeze::FreezeThreshold, guard::verified_caller, Timestamp},
     id_generator::IdGenerator,
     log,
     mmgr::MemoryManager,
     random::CanisterRandomSource,
+    rate_limit::{MethodRateLimit, RateLimiter},
     register_log,
     stable::{Candid, Memory, Stable},
     statistics::{self, traits::OpaqueMetrics},
 };
 
+use declarations::patient_registry::ConsentAccess;
 use ic_stable_structures::Cell;
-use memory::{FreezeThresholdMemory, UpgradeMemory};
+use encounter::EncounterStatus;
+use memory::{FreezeThresholdMemory, PurgeCursorMemory, RateLimiterMemory, UpgradeMemory};
 use registry::ProviderRegistry;
 
 pub mod api;
 mod config;
 mod declarations;
+mod encounter;
 mod memory;
 mod registry;
 mod types;
@@ -36,11 +40,23 @@ const CANISTER_CYCLE_THRESHOLD: u128 = 300_000;
 // change this if you want to change the interval of the metrics collection
 const METRICS_INTERVAL: Duration = Duration::from_secs(60 * 5); // 5 minutes
 
+// change this if you want to change how often full rate limit buckets are removed
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+// change this if you want to change how often emrs purged by the emr registry are dropped
+const PURGE_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+// change this if you want to change the amount of purged emrs dropped on every purge sync tick
+const PURGE_SYNC_BATCH_SIZE: u64 = 100;
+
 pub struct State {
     providers: ProviderRegistry,
     config: Cell<Stable<config::CanisterConfig, Candid>, Memory>,
     memory_manager: MemoryManager,
     freeze_threshold: Cell<Stable<FreezeThreshold, Candid>, Memory>,
+    rate_limiter: RateLimiter,
+    /// index of the next emr registry purge log entry to sync
+    purge_cursor: Cell<u64, Memory>,
 }
 
 register_log!("provider");
@@ -150,6 +166,62 @@ fn only_authorized_metrics_collector() -> Result<(), String> {
     })
 }
 
+// guard function
+fn only_patient_registry() -> Result<(), String> {
+    let caller = verified_caller()?;
+
+    with_state(|s| {
+        if !s.config.get().is_patient_registry(&caller) {
+            return Err(
+                "[PROVIDER_REGISTRY_LIB] Only patient registry can call this method.".to_string(),
+            );
+        }
+
+        Ok(())
+    })
+}
+
+// guard function
+fn rate_limit_register_new_provider() -> Result<(), String> {
+    consume_rate_limit("register_new_provider")
+}
+
+/// take a token from the caller bucket for the method, no-op if the method is not rate limited
+fn consume_rate_limit(method: &str) -> Result<(), String> {
+    let caller = verified_caller()?;
+
+    with_state_mut(|s| {
+        let Some(limit) = s.config.get().rate_limit(method) else {
+            return Ok(());
+        };
+
+        s.rate_limiter
+            .consume(caller, method, &limit, Timestamp::new())
+            .map_err(|e| format!("[PROVIDER_REGISTRY_LIB] {}", e))
+    })
+}
+
+/// drop ingress messages from callers that already ran out of tokens, before they cost us any cycles.
+/// the token itself is taken in the guard function as state changes made here are discarded.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let caller = ic_cdk::caller();
+
+    let result = with_state(|s| {
+        s.config
+            .get()
+            .rate_limit(&method)
+            .map(|limit| s.rate_limiter.check(caller, &method, &limit, Timestamp::new()))
+    });
+
+    if let Some(Err(e)) = result {
+        ic_cdk::trap(&format!("[PROVIDER_REGISTRY_LIB] {}", e));
+    }
+
+    ic_cdk::api::call::accept_message();
+}
+
 #[ic_cdk::pre_upgrade]
 fn pre_upgrade() {
     serialize_canister_metrics();
@@ -212,6 +284,65 @@ fn start_collect_metrics_job() {
     });
 }
 
+fn start_rate_limit_prune_job() {
+    ic_cdk_timers::set_timer_interval(RATE_LIMIT_PRUNE_INTERVAL, || {
+        let removed = with_state_mut(|s| s.rate_limiter.prune(Timestamp::new()));
+        log!("removed {} full rate limit buckets", removed);
+    });
+}
+
+fn start_purge_sync_job() {
+    ic_cdk_timers::set_timer_interval(PURGE_SYNC_INTERVAL, || ic_cdk::spawn(sync_purged_emrs()));
+}
+
+/// drop emrs purged by the emr registry retention job here and in the patient registry.
+/// the cursor only moves once both registries dropped the batch, a failed call is retried on the next tick
+async fn sync_purged_emrs() {
+    let emr_registry = with_state(|s| s.config.get().emr_registry());
+    let patient_registry = with_state(|s| s.config.get().patient_registry());
+    let from = with_state(|s| *s.purge_cursor.get());
+
+    let args = declarations::emr_registry::PurgedEmrsRequest {
+        from,
+        limit: PURGE_SYNC_BATCH_SIZE,
+    };
+    let response = match emr_registry.purged_emrs(args).await {
+        Ok((response,)) => response,
+        Err(e) => {
+            log!("failed to read purged emrs: {:?}", e);
+            return;
+        }
+    };
+
+    if !response.headers.is_empty() {
+        let headers = response
+            .headers
+            .iter()
+            .map(|header| declarations::patient_registry::EmrHeader {
+                provider_id: header.provider_id.clone(),
+                user_id: header.user_id.clone(),
+                emr_id: header.emr_id.clone(),
+                registry_id: header.registry_id,
+            })
+            .collect();
+        let args = declarations::patient_registry::NotifyPurgedRequest { headers };
+
+        if let Err(e) = patient_registry.notify_purged(args).await {
+            log!("failed to notify purged emrs: {:?}", e);
+            return;
+        }
+
+        with_state_mut(|s| {
+            for header in response.headers.iter() {
+                s.providers.purge(&api::from_emr_registry_header(header));
+            }
+        });
+        log!("dropped {} purged emrs", response.headers.len());
+    }
+
+    with_state_mut(|s| s.purge_cursor.set(response.next)).unwrap();
+}
+
 fn deserialize_canister_metrics() {
     let mem = with_state(|s| s.memory_manager.get_memory::<_, UpgradeMemory>(|mem| mem));
 
@@ -267,6 +398,11 @@ fn init_state() -> State {
             CANISTER_CYCLE_THRESHOLD,
             &memory_manager,
         ),
+        rate_limiter: RateLimiter::init::<RateLimiterMemory>(&memory_manager),
+        // safe to unwrap, we're using layout version 1
+        purge_cursor: memory_manager
+            .get_memory::<_, PurgeCursorMemory>(|m| Cell::init(m, 0))
+            .unwrap(),
         memory_manager,
     }
 }
@@ -276,7 +412,9 @@ fn initialize() {
     STATE.replace(Some(state));
     log!("canister state initialized");
     initialize_id_generator();
-    start_collect_metrics_job()
+    start_collect_metrics_job();
+    start_rate_limit_prune_job();
+    start_purge_sync_job();
 }
 
 #[ic_cdk::post_upgrade]
@@ -361,14 +499,27 @@ fn emr_list_provider(req: types::EmrListProviderRequest) -> types::EmrListProvid
     })
 }
 
+/// errors if the emr was issued but the encounter was finished or cancelled while it was being
+/// created, the emr is kept and can still be read but is not attached to the encounter
 #[ic_cdk::update(guard = "only_provider")]
-async fn issue_emr(req: api::IssueEmrRequest) -> api::IssueEmrResponse {
-    let emr_id = with_id_generator_mut(|generator| generator.generate_id());
-    let args = with_state(|s| s.providers.build_args_call_emr_canister(req, emr_id)).unwrap();
-
+async fn issue_emr(req: api::IssueEmrRequest) -> Result<api::IssueEmrResponse, String> {
     // safe to unwrap as the provider id comes from canister
     let provider_principal = verified_caller().unwrap();
 
+    // validate the encounter before issuing, so we dont end up with an emr that can't be attached
+    let encounter_id = req.encounter_id.clone();
+    let patient = req.user_id.clone();
+    if let Some(encounter_id) = &encounter_id {
+        with_state(|s| {
+            s.providers
+                .ensure_encounter_attachable(&provider_principal, encounter_id, &req.user_id)
+        })
+        .unwrap();
+    }
+
+    let emr_id = with_id_generator_mut(|generator| generator.generate_id());
+    let args = with_state(|s| s.providers.build_args_call_emr_canister(req, emr_id)).unwrap();
+
     let emr_registry = with_state(|s| s.config.get().emr_registry());
     let patient_registry = with_state(|s| s.config.get().patient_registry());
 
@@ -382,7 +533,26 @@ async fn issue_emr(req: api::IssueEmrRequest) -> api::IssueEmrResponse {
     })
     .unwrap();
 
-    IssueEmrResponse::from(response)
+    if let Some(encounter_id) = encounter_id {
+        with_state_mut(|s| {
+            // the encounter may have been finished or cancelled while the emr was being created
+            s.providers
+                .ensure_encounter_attachable(&provider_principal, &encounter_id, &patient)
+                .map_err(|e| {
+                    format!(
+                        "emr {} was issued but not attached to the encounter: {}",
+                        response.header.emr_id, e
+                    )
+                })?;
+
+            let header = api::from_emr_registry_header(&response.header);
+            s.providers.attach_to_encounter(&encounter_id, header);
+
+            Ok::<_, String>(())
+        })?;
+    }
+
+    Ok(IssueEmrResponse::from(response))
 }
 
 #[ic_cdk::query(composite = true, guard = "only_authorized_metrics_collector")]
@@ -399,7 +569,7 @@ async fn ping() -> PingResult {
     }
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "rate_limit_register_new_provider")]
 async fn register_new_provider(req: RegisternewProviderRequest) -> RegisternewProviderResponse {
     let id = with_id_generator_mut(|g| g.generate_id());
 
@@ -421,6 +591,11 @@ fn is_valid_provider(req: Principal) -> bool {
 
 #[ic_cdk::update(guard = "only_provider")]
 async fn update_emr(req: crate::api::UpdateEmrRequest) -> crate::api::UpdateEmrResponse {
+    let provider = verified_caller().unwrap();
+
+    let writable = shared_emrs(&provider, vec![req.header.clone()], ConsentAccess::ReadWrite).await;
+    with_state(|s| s.providers.ensure_visible(&provider, &req.header, &writable)).unwrap();
+
     let emr_registry = with_state(|s| s.config.get().emr_registry());
     let patient_registry = with_state(|s| s.config.get().patient_registry());
 
@@ -429,6 +604,135 @@ async fn update_emr(req: crate::api::UpdateEmrRequest) -> crate::api::UpdateEmrR
     crate::api::UpdateEmrResponse {}
 }
 
+/// the emrs among `headers` the provider didn't issue but can access through a session with the patient
+async fn shared_emrs(
+    provider: &Principal,
+    headers: Vec<canister_common::common::EmrHeader>,
+    access: ConsentAccess,
+) -> Vec<canister_common::common::EmrHeader> {
+    let (provider_id, headers) = with_state(|s| {
+        let headers = headers
+            .into_iter()
+            .filter(|header| s.providers.ensure_issued_by(provider, header).is_err())
+            .collect::<Vec<_>>();
+
+        (s.providers.internal_id(provider), headers)
+    });
+
+    let patient_registry = with_state(|s| s.config.get().patient_registry());
+    ProviderRegistry::do_call_readable_emrs(provider_id.unwrap(), headers, access, patient_registry)
+        .await
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+async fn link_emr(req: crate::api::LinkEmrRequest) {
+    let provider = verified_caller().unwrap();
+    with_state(|s| s.providers.ensure_issued_by(&provider, &req.from)).unwrap();
+
+    let readable = shared_emrs(&provider, vec![req.to.clone()], ConsentAccess::ReadOnly).await;
+    with_state(|s| s.providers.ensure_visible(&provider, &req.to, &readable)).unwrap();
+
+    let emr_registry = with_state(|s| s.config.get().emr_registry());
+    ProviderRegistry::do_call_link_emr(req.to_args(), emr_registry).await
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+async fn unlink_emr(req: crate::api::LinkEmrRequest) {
+    let provider = verified_caller().unwrap();
+    with_state(|s| s.providers.ensure_issued_by(&provider, &req.from)).unwrap();
+
+    let emr_registry = with_state(|s| s.config.get().emr_registry());
+    ProviderRegistry::do_call_unlink_emr(req.to_args(), emr_registry).await
+}
+
+#[ic_cdk::query(composite = true, guard = "only_provider")]
+async fn read_emr_links(
+    req: crate::api::ReadEmrLinksRequest,
+) -> declarations::emr_registry::ReadEmrLinksResponse {
+    let provider = verified_caller().unwrap();
+    with_state(|s| s.providers.ensure_issued_by(&provider, &req.header)).unwrap();
+
+    let emr_registry = with_state(|s| s.config.get().emr_registry());
+    let graph = ProviderRegistry::do_call_read_emr_links(req.to_args(), emr_registry).await;
+
+    // only show the emrs the provider issued or can read through a session
+    let nodes = graph.nodes.iter().map(api::from_emr_registry_header).collect();
+    let readable = shared_emrs(&provider, nodes, ConsentAccess::ReadOnly).await;
+    with_state(|s| s.providers.visible_links(&provider, graph, &readable))
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+async fn start_encounter(req: api::StartEncounterRequest) -> api::EncounterResponse {
+    let provider = verified_caller().unwrap();
+    let provider_id = with_state(|s| s.providers.internal_id(&provider)).unwrap();
+
+    let patient_registry = with_state(|s| s.config.get().patient_registry());
+    let has_session =
+        ProviderRegistry::do_call_has_session(provider_id, &req.user_id, patient_registry).await;
+
+    let id = with_id_generator_mut(|g| g.generate_id());
+    with_state_mut(|s| s.providers.start_encounter(&provider, req.user_id, id, has_session))
+        .unwrap()
+        .into()
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+fn finish_encounter(req: api::EncounterRequest) -> api::EncounterResponse {
+    let provider = verified_caller().unwrap();
+
+    with_state_mut(|s| {
+        s.providers
+            .end_encounter(&provider, &req.encounter_id, EncounterStatus::Finished)
+    })
+    .unwrap()
+    .into()
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+fn cancel_encounter(req: api::EncounterRequest) -> api::EncounterResponse {
+    let provider = verified_caller().unwrap();
+
+    with_state_mut(|s| {
+        s.providers
+            .end_encounter(&provider, &req.encounter_id, EncounterStatus::Cancelled)
+    })
+    .unwrap()
+    .into()
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+fn encounter_list_provider(req: api::EncounterListProviderRequest) -> api::EncounterListResponse {
+    let provider = verified_caller().unwrap();
+
+    with_state(|s| {
+        let limit = s.config.get().max_item_per_response().min(req.limit);
+
+        s.providers
+            .encounter_list_provider(&provider, req.page, limit as u64)
+    })
+    .unwrap()
+    .into()
+}
+
+/// emrs attached to an encounter, the patient registry is responsible for checking the caller access to the patient
+#[ic_cdk::query(guard = "only_patient_registry")]
+fn get_encounter_emrs(req: api::EncounterRequest) -> api::EncounterEmrsResponse {
+    let (encounter, emrs) = with_state(|s| s.providers.encounter_with_emrs(&req.encounter_id)).unwrap();
+
+    api::EncounterEmrsResponse { encounter, emrs }
+}
+
+#[ic_cdk::query(guard = "only_patient_registry")]
+fn encounter_list_patient(req: api::EncounterListPatientRequest) -> api::EncounterListResponse {
+    with_state(|s| {
+        let limit = s.config.get().max_item_per_response().min(req.limit);
+
+        s.providers
+            .encounter_list_patient(&req.user_id, req.page, limit as u64)
+    })
+    .into()
+}
+
 #[ic_cdk::update(guard = "only_canister_owner")]
 fn update_emr_registry_principal(req: UpdateEmrRegistryRequest) {
     with_state_mut(|s| {
@@ -443,6 +747,25 @@ fn update_emr_registry_principal(req: UpdateEmrRegistryRequest) {
     })
 }
 
+#[ic_cdk::update(guard = "only_canister_owner")]
+fn update_rate_limit(req: UpdateRateLimitRequest) {
+    with_state_mut(|s| {
+        let mut config = s.config.get().to_owned();
+
+        config.update_rate_limit(req.method, req.limit);
+
+        match s.config.set(config) {
+            Ok(_) => (),
+            Err(e) => ic_cdk::trap(&format!("failed to update rate limit: {:?}", e)),
+        }
+    })
+}
+
+#[ic_cdk::query(guard = "only_canister_owner")]
+fn get_rate_limits() -> Vec<MethodRateLimit> {
+    with_state(|s| s.config.get().rate_limits())
+}
+
 #[ic_cdk::update(guard = "only_canister_owner")]
 fn update_patient_registry_principal(req: UpdatePatientRegistryRequest) {
     with_state_mut(|s| {
@@ -1,9 +1,15 @@
 use canister_common::{ generate_memory_id };
 
-use crate::{ config::CanisterConfig, registry::{ Issued, Providers, ProvidersBindings } };
+use crate::{
+    config::CanisterConfig,
+    encounter::{ EncounterEmrMap, EncounterMap, PatientEncounterMap, ProviderEncounterMap },
+    registry::{ Issued, Providers, ProvidersBindings },
+};
 
 /// needed since the module is imported
 pub struct FreezeThresholdMemory;
+pub struct RateLimiterMemory;
+pub struct PurgeCursorMemory;
 pub struct UpgradeMemory;
 generate_memory_id!(
     UpgradeMemory,
@@ -11,5 +17,11 @@ generate_memory_id!(
     ProvidersBindings,
     Issued,
     FreezeThresholdMemory,
-    CanisterConfig
+    CanisterConfig,
+    EncounterMap,
+    EncounterEmrMap,
+    ProviderEncounterMap,
+    PatientEncounterMap,
+    RateLimiterMemory,
+    PurgeCursorMemory
 );
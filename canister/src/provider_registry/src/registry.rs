@@ -5,7 +5,7 @@ use candid::{ CandidType };
 use canister_common::random::CallError;
 use canister_common::stable::Candid;
 use canister_common::statistics::traits::{ Metrics };
-use canister_common::common::{ self, EmrId, PrincipalBytes };
+use canister_common::common::{ self, EmrHeader, EmrId, PrincipalBytes, UserId };
 use ic_principal::Principal;
 use ic_stable_structures::{ BTreeMap };
 use parity_scale_codec::{ Decode, Encode };
@@ -27,8 +27,14 @@ use canister_common::{
 };
 
 use crate::api::{ IssueEmrRequest, GetProviderListResponse };
-use crate::declarations::emr_registry::{ CreateEmrRequest, CreateEmrResponse };
-use crate::declarations::patient_registry::IssueRequest;
+use crate::encounter::{ Encounter, EncounterError, EncounterId, EncounterStatus, Encounters };
+use crate::declarations::emr_registry::{ CreateEmrRequest, CreateEmrResponse, ReadEmrLinksResponse };
+use crate::declarations::patient_registry::{
+    ConsentAccess,
+    HasSessionRequest,
+    IssueRequest,
+    ReadableEmrsRequest,
+};
 
 use self::provider::{ Provider, V1 };
 
@@ -37,6 +43,7 @@ pub enum RegistryError {
     #[error(transparent)] IssueMapError(#[from] IssueMapError),
     #[error(transparent)] ProviderBindingMapError(#[from] ProviderBindingMapError),
     #[error("{0}")] ExternalCallError(#[from] CallError),
+    #[error(transparent)] EncounterError(#[from] EncounterError),
 }
 
 pub type ProviderRegistryResult<T = ()> = Result<T, RegistryError>;
@@ -45,6 +52,7 @@ pub struct ProviderRegistry {
     providers: Providers,
     providers_bindings: ProvidersBindings,
     issued: Issued,
+    encounters: Encounters,
 }
 
 impl ProviderRegistry {
@@ -187,6 +195,209 @@ impl ProviderRegistry {
     }
 }
 
+// encounters
+impl ProviderRegistry {
+    /// start an encounter with a patient, `has_session` tells whether the patient registry knows the
+    /// patient and the provider has a session open with them
+    pub fn start_encounter(
+        &mut self,
+        provider: &ProviderPrincipal,
+        patient: UserId,
+        id: EncounterId,
+        has_session: bool
+    ) -> ProviderRegistryResult<Encounter> {
+        let provider = self.providers_bindings.get_internal_id(provider)?.into_inner();
+
+        if !has_session {
+            return Err(EncounterError::NoSession.into());
+        }
+
+        let encounter = Encounter::new(id, provider, patient);
+
+        self.encounters.start(encounter.clone());
+
+        Ok(encounter)
+    }
+
+    pub fn end_encounter(
+        &mut self,
+        provider: &ProviderPrincipal,
+        id: &EncounterId,
+        status: EncounterStatus
+    ) -> ProviderRegistryResult<Encounter> {
+        let provider = self.providers_bindings.get_internal_id(provider)?.into_inner();
+        Ok(self.encounters.end(id, &provider, status)?)
+    }
+
+    /// make sure the calling provider can issue an emr for `patient` under the given encounter
+    pub fn ensure_encounter_attachable(
+        &self,
+        provider: &ProviderPrincipal,
+        id: &EncounterId,
+        patient: &UserId
+    ) -> ProviderRegistryResult<()> {
+        let provider = self.providers_bindings.get_internal_id(provider)?.into_inner();
+        Ok(self.encounters.ensure_attachable(id, &provider, patient)?)
+    }
+
+    pub fn attach_to_encounter(&mut self, id: &EncounterId, header: EmrHeader) {
+        self.encounters.attach(id, header)
+    }
+
+    pub fn encounter_list_provider(
+        &self,
+        provider: &ProviderPrincipal,
+        page: u64,
+        limit: u64
+    ) -> ProviderRegistryResult<Vec<Encounter>> {
+        let provider = self.providers_bindings.get_internal_id(provider)?.into_inner();
+        Ok(self.encounters.list_by_provider(&provider, page, limit))
+    }
+
+    pub fn encounter_list_patient(&self, patient: &UserId, page: u64, limit: u64) -> Vec<Encounter> {
+        self.encounters.list_by_patient(patient, page, limit)
+    }
+
+    /// the encounter along with every emr attached to it
+    pub fn encounter_with_emrs(
+        &self,
+        id: &EncounterId
+    ) -> ProviderRegistryResult<(Encounter, Vec<EmrHeader>)> {
+        let encounter = self.encounters.get(id)?;
+        Ok((encounter, self.encounters.emrs(id)))
+    }
+}
+
+// link emr inter-canister call
+impl ProviderRegistry {
+    /// ensure the emr is issued by the given provider, links can only be managed from emrs the provider issued
+    pub fn ensure_issued_by(
+        &self,
+        provider: &ProviderPrincipal,
+        header: &EmrHeader
+    ) -> ProviderRegistryResult<()> {
+        let registry_id = header.registry_id.clone().to_principal();
+
+        self.is_issued_by(provider, header.emr_id.clone(), registry_id)
+            .then_some(())
+            .ok_or(IssueMapError::EmrNotFound.into())
+    }
+
+    /// ensure the emr is issued by the given provider or is one of the `readable` emrs shared with it
+    pub fn ensure_visible(
+        &self,
+        provider: &ProviderPrincipal,
+        header: &EmrHeader,
+        readable: &[EmrHeader]
+    ) -> ProviderRegistryResult<()> {
+        if readable.contains(header) {
+            return Ok(());
+        }
+
+        self.ensure_issued_by(provider, header)
+    }
+
+    /// drop the nodes the provider can't see from a link graph, along with every edge touching them
+    pub fn visible_links(
+        &self,
+        provider: &ProviderPrincipal,
+        graph: ReadEmrLinksResponse,
+        readable: &[EmrHeader]
+    ) -> ReadEmrLinksResponse {
+        let is_visible = |header: &crate::declarations::emr_registry::Header| {
+            let header = crate::api::from_emr_registry_header(header);
+            self.ensure_visible(provider, &header, readable).is_ok()
+        };
+
+        let nodes = graph.nodes
+            .into_iter()
+            .filter(|node| is_visible(node))
+            .collect();
+        let edges = graph.edges
+            .into_iter()
+            .filter(|edge| is_visible(&edge.from) && is_visible(&edge.to))
+            .collect();
+
+        ReadEmrLinksResponse { nodes, edges }
+    }
+
+    pub fn internal_id(
+        &self,
+        provider: &ProviderPrincipal
+    ) -> ProviderRegistryResult<InternalProviderId> {
+        Ok(self.providers_bindings.get_internal_id(provider)?.into_inner())
+    }
+
+    /// the subset of `headers` the provider can access through one of its sessions with the patient
+    pub async fn do_call_readable_emrs(
+        provider_id: InternalProviderId,
+        headers: Vec<EmrHeader>,
+        access: ConsentAccess,
+        patient_registry: crate::declarations::patient_registry::PatientRegistry
+    ) -> Vec<EmrHeader> {
+        if headers.is_empty() {
+            return vec![];
+        }
+
+        let args = ReadableEmrsRequest {
+            provider_id: provider_id.to_string(),
+            headers: headers.into_iter().map(crate::api::to_patient_registry_header).collect(),
+            access: Some(access),
+        };
+
+        match patient_registry.readable_emrs(args).await.map_err(CallError::from) {
+            Ok((response,)) =>
+                response.headers.iter().map(crate::api::from_patient_registry_header).collect(),
+            Err(e) => ic_cdk::trap(&format!("ERROR: error calling readable_emrs : {}", e)),
+        }
+    }
+
+    /// whether the patient is registered and the provider has a session open with them
+    pub async fn do_call_has_session(
+        provider_id: InternalProviderId,
+        patient: &UserId,
+        patient_registry: crate::declarations::patient_registry::PatientRegistry
+    ) -> bool {
+        let args = HasSessionRequest {
+            provider_id: provider_id.to_string(),
+            nik: patient.to_string(),
+        };
+
+        match patient_registry.has_session(args).await.map_err(CallError::from) {
+            Ok((has_session,)) => has_session,
+            Err(e) => ic_cdk::trap(&format!("ERROR: error calling has_session : {}", e)),
+        }
+    }
+
+    pub async fn do_call_link_emr(
+        args: crate::declarations::emr_registry::LinkEmrRequest,
+        emr_registry: crate::declarations::emr_registry::EmrRegistry
+    ) {
+        if let Err(e) = emr_registry.link_emr(args).await.map_err(CallError::from) {
+            ic_cdk::trap(&format!("ERROR: error calling link_emr : {}", e))
+        }
+    }
+
+    pub async fn do_call_unlink_emr(
+        args: crate::declarations::emr_registry::LinkEmrRequest,
+        emr_registry: crate::declarations::emr_registry::EmrRegistry
+    ) {
+        if let Err(e) = emr_registry.unlink_emr(args).await.map_err(CallError::from) {
+            ic_cdk::trap(&format!("ERROR: error calling unlink_emr : {}", e))
+        }
+    }
+
+    pub async fn do_call_read_emr_links(
+        args: crate::declarations::emr_registry::ReadEmrLinksRequest,
+        emr_registry: crate::declarations::emr_registry::EmrRegistry
+    ) -> crate::declarations::emr_registry::ReadEmrLinksResponse {
+        match emr_registry.read_emr_links(args).await.map_err(CallError::from) {
+            Ok((response,)) => response,
+            Err(e) => ic_cdk::trap(&format!("ERROR: error calling read_emr_links : {}", e)),
+        }
+    }
+}
+
 metrics!(ProviderRegistry: RegistryMetrics);
 
 impl Metrics<RegistryMetrics> for ProviderRegistry {
@@ -207,6 +418,7 @@ impl Metrics<RegistryMetrics> for ProviderRegistry {
             opaque_metrics!(self.providers),
             opaque_metrics!(self.providers_bindings),
             opaque_metrics!(self.issued),
+            opaque_metrics!(self.encounters),
         ].join("\n")
     }
 }
@@ -216,8 +428,9 @@ impl ProviderRegistry {
         let providers = Providers::init(memory_manager);
         let providers_bindings = ProvidersBindings::init(memory_manager);
         let issued = Issued::init(memory_manager);
+        let encounters = Encounters::init(memory_manager);
 
-        Self { providers, providers_bindings, issued }
+        Self { providers, providers_bindings, issued, encounters }
     }
 
     /// check a given emr id is validly issued by some provider principal, this function uses internal provider id to resolve the given provider.
@@ -271,6 +484,15 @@ impl ProviderRegistry {
         Ok(())
     }
 
+    /// forget an emr purged by the emr registry retention job, no-op if it was never issued here
+    pub fn purge(&mut self, header: &EmrHeader) {
+        self.issued.revoke_emr(
+            &header.provider_id,
+            header.emr_id.clone(),
+            header.registry_id.clone().to_principal()
+        );
+    }
+
     /// check a given principal is valid and registered as provider
     pub fn is_valid_provider(&self, provider: &ProviderPrincipal) -> bool {
         self.providers_bindings.contains_key(provider)
@@ -476,6 +698,21 @@ impl Issued {
         Ok(())
     }
 
+    /// returns false if the emr was not issued by the provider
+    pub fn revoke_emr(
+        &mut self,
+        provider: &InternalProviderId,
+        emr_id: Id,
+        canister_id: Principal
+    ) -> bool {
+        let emr = (Emr {
+            canister_id: PrincipalBytes::from(canister_id),
+            id: emr_id,
+        }).to_stable();
+
+        self.0.inner_mut().remove(&(provider.clone().to_stable(), emr)).is_some()
+    }
+
     pub fn get_issued(
         &self,
         provider: InternalProviderId,
@@ -747,6 +984,121 @@ mod provider_test {
         assert_eq!(provider, provider);
     }
 
+    #[test]
+    fn test_purge() {
+        let memory_manager = MemoryManager::init();
+        let mut registry = ProviderRegistry::init(&memory_manager);
+
+        let provider = Id::new(&[1u8; 10]);
+        let emr_id = Id::new(&[2u8; 10]);
+        let canister_id = Principal::from_text("aaaaa-aa").unwrap();
+        let header = EmrHeader::new(
+            UserId::default(),
+            emr_id.clone(),
+            provider.clone(),
+            canister_id
+        );
+
+        registry.issued.issue_emr(&provider, emr_id.clone(), canister_id).unwrap();
+        registry.purge(&header);
+        assert!(!registry.issued.is_issued_by(provider.clone(), emr_id.clone(), canister_id));
+
+        // purging twice, e.g. when the purge log is read again, is a no-op
+        registry.purge(&header);
+        assert!(!registry.issued.revoke_emr(&provider, emr_id, canister_id));
+    }
+
+    #[test]
+    fn test_start_encounter() {
+        let memory_manager = MemoryManager::init();
+        let mut registry = ProviderRegistry::init(&memory_manager);
+
+        let provider_principal = Principal::from_text("aaaaa-aa").unwrap();
+        registry
+            .register_new_provider(
+                provider_principal,
+                AsciiRecordsKey::<64>::new("test").unwrap(),
+                AsciiRecordsKey::<64>::new("test").unwrap(),
+                Id::new(&[1u8; 10])
+            )
+            .unwrap();
+
+        let patient = UserId::from([1u8; 32]);
+        let id = Id::new(&[2u8; 10]);
+
+        // unknown patients or patients that didn't share a session can't have an encounter
+        assert!(
+            matches!(
+                registry.start_encounter(&provider_principal, patient.clone(), id.clone(), false),
+                Err(RegistryError::EncounterError(EncounterError::NoSession))
+            )
+        );
+        assert!(registry.encounter_with_emrs(&id).is_err());
+
+        let encounter = registry.start_encounter(&provider_principal, patient, id.clone(), true);
+        assert!(encounter.unwrap().is_in_progress());
+        assert!(registry.encounter_with_emrs(&id).is_ok());
+    }
+
+    #[test]
+    fn test_visible_links() {
+        use crate::declarations::emr_registry::{ EmrLinkEdge, Header, LinkType };
+
+        let memory_manager = MemoryManager::init();
+        let mut registry = ProviderRegistry::init(&memory_manager);
+
+        let provider_principal = Principal::from_text("aaaaa-aa").unwrap();
+        let provider = Id::new(&[1u8; 10]);
+        let other_provider = Id::new(&[3u8; 10]);
+        let canister_id = Principal::anonymous();
+        registry
+            .register_new_provider(
+                provider_principal,
+                AsciiRecordsKey::<64>::new("test").unwrap(),
+                AsciiRecordsKey::<64>::new("test").unwrap(),
+                provider.clone()
+            )
+            .unwrap();
+
+        let header = |provider: &Id, byte: u8| {
+            EmrHeader::new(UserId::default(), Id::new(&[byte; 10]), provider.clone(), canister_id)
+        };
+        let issued = header(&provider, 4);
+        let shared = header(&other_provider, 5);
+        let hidden = header(&other_provider, 6);
+        registry.issued.issue_emr(&provider, issued.emr_id.clone(), canister_id).unwrap();
+
+        // a link can only point to an emr the provider issued or was shared with it
+        let readable = vec![shared.clone()];
+        assert!(registry.ensure_visible(&provider_principal, &issued, &readable).is_ok());
+        assert!(registry.ensure_visible(&provider_principal, &shared, &readable).is_ok());
+        assert!(registry.ensure_visible(&provider_principal, &hidden, &readable).is_err());
+
+        let node = |header: &EmrHeader| Header {
+            provider_id: header.provider_id.to_string(),
+            user_id: header.user_id.to_string(),
+            emr_id: header.emr_id.to_string(),
+            registry_id: header.registry_id.clone().to_principal(),
+        };
+        let edge = |from: &EmrHeader, to: &EmrHeader| EmrLinkEdge {
+            from: node(from),
+            to: node(to),
+            link_type: LinkType::FollowsUp,
+        };
+        let graph = ReadEmrLinksResponse {
+            nodes: vec![node(&issued), node(&shared), node(&hidden)],
+            edges: vec![edge(&shared, &issued), edge(&hidden, &issued), edge(&hidden, &shared)],
+        };
+
+        let graph = registry.visible_links(&provider_principal, graph, &readable);
+        assert_eq!(
+            graph.nodes.iter().map(crate::api::from_emr_registry_header).collect::<Vec<_>>(),
+            vec![issued.clone(), shared.clone()]
+        );
+        assert_eq!(graph.edges.len(), 1);
+        assert_eq!(crate::api::from_emr_registry_header(&graph.edges[0].from), shared);
+    }
+
     #[test]
     fn test_metrics() {
         let memory_manager = MemoryManager::init();